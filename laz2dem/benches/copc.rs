@@ -0,0 +1,71 @@
+use criterion::{Criterion, criterion_group, criterion_main};
+use las::Reader;
+use laz2dem::{copc::PointReader, options::Options};
+use maptile::bbox::BBox;
+use std::{env, hint::black_box, path::PathBuf};
+
+/// Points of the reader within the bbox
+fn count(mut reader: PointReader, bbox: &BBox) -> usize {
+    let mut chunk = Vec::new();
+
+    let mut count = 0;
+
+    loop {
+        chunk.clear();
+
+        if reader.read_chunk(&mut chunk).unwrap() == 0 {
+            return count;
+        }
+
+        count += chunk
+            .iter()
+            .filter(|point| bbox.contains(point.x, point.y))
+            .count();
+    }
+}
+
+fn read(criterion: &mut Criterion) {
+    let Some(path) = env::var_os("LAZ2DEM_BENCH_COPC").map(PathBuf::from) else {
+        eprintln!("Set LAZ2DEM_BENCH_COPC to the path of a COPC file to benchmark its reading");
+
+        return;
+    };
+
+    let bounds = Reader::from_path(&path).unwrap().header().bounds();
+
+    // centered square of 5 % of the extent
+    let margin = (1.0 - 0.05_f64.sqrt()) / 2.0;
+
+    let (width, height) = (bounds.max.x - bounds.min.x, bounds.max.y - bounds.min.y);
+
+    let bbox = BBox::new(
+        bounds.min.x + width * margin,
+        bounds.min.y + height * margin,
+        bounds.max.x - width * margin,
+        bounds.max.y - height * margin,
+    );
+
+    let options = Options::default();
+
+    let mut group = criterion.benchmark_group("points within 5 % of the COPC extent");
+
+    group.sample_size(10);
+
+    group.bench_function("octree nodes", |bencher| {
+        bencher.iter(|| {
+            count(
+                PointReader::open(black_box(&options), &path, &bbox).unwrap(),
+                &bbox,
+            )
+        })
+    });
+
+    group.bench_function("full scan", |bencher| {
+        bencher.iter(|| count(PointReader::Full(Reader::from_path(&path).unwrap()), &bbox))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, read);
+criterion_main!(benches);
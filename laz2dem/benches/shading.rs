@@ -0,0 +1,68 @@
+use criterion::{Criterion, criterion_group, criterion_main};
+use laz2dem::{
+    options::Options,
+    shading::hillshade,
+    terrain::{Elevations, Grid, Rasters},
+};
+use std::hint::black_box;
+
+/// Rolling terrain of the size in pixels
+fn terrain(size: usize) -> Elevations<'static> {
+    Elevations::F64(
+        (0..size * size)
+            .map(|index| {
+                let (x, y) = ((index % size) as f64, (index / size) as f64);
+
+                100.0 * (x / 37.0).sin() * (y / 23.0).cos() + x * 0.3
+            })
+            .collect(),
+    )
+}
+
+/// Benchmark of `hillshade` of the square terrain of the size by the shadings
+fn bench(criterion: &mut Criterion, size: usize, shadings: &str) {
+    let options = Options::from_json(&format!(r#"{{"shadings": "{shadings}"}}"#)).unwrap();
+
+    let elevations = terrain(size);
+
+    let grid = Grid {
+        elevations: &elevations,
+        rows: size,
+        cols: size,
+        cell_size: 1.0,
+        mercator_y: None,
+    };
+
+    let rasters = Rasters::new(&grid, options.z_factor, &options.shadings.0);
+
+    criterion.bench_function(
+        &format!(
+            "hillshade {size}x{size} of {} shadings",
+            options.shadings.0.len()
+        ),
+        |bencher| bencher.iter(|| hillshade(black_box(&options), &grid, &rasters)),
+    );
+}
+
+fn shade(criterion: &mut Criterion) {
+    bench(
+        criterion,
+        512,
+        "igor,5060FF60,135+igor,E0D000B0,315+igor-slope,000000FF",
+    );
+
+    // size of a supertile of 8 x 8 tiles of 256 pixels
+    bench(
+        criterion,
+        2048,
+        "igor,5060FF60,135+igor,E0D000B0,315+oblique,00000080,315,45+igor-slope,000000FF",
+    );
+}
+
+// a sample of the 2048 x 2048 grid takes seconds
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = shade
+}
+criterion_main!(benches);
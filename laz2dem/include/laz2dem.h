@@ -0,0 +1,60 @@
+#ifndef LAZ2DEM_H
+#define LAZ2DEM_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// Success
+#define LAZ2DEM_OK 0
+
+// Null pointer, empty grid or too small output buffer
+#define LAZ2DEM_INVALID_ARGUMENT 1
+
+// Invalid JSON or options of the config
+#define LAZ2DEM_INVALID_CONFIG 2
+
+// Panic caught at the boundary
+#define LAZ2DEM_PANIC 3
+
+// Opaque options of the shading
+typedef struct Laz2demConfig Laz2demConfig;
+
+// Config of the NUL-terminated JSON of `Options::from_json`, or null on error.
+// Release it with `laz2dem_config_free`.
+//
+// # Safety
+//
+// `json` must be null or point to a NUL-terminated string.
+Laz2demConfig *laz2dem_config_new(const char *json);
+
+// Releases the config; null is ignored.
+//
+// # Safety
+//
+// `config` must be null or returned by `laz2dem_config_new` and not released yet.
+void laz2dem_config_free(Laz2demConfig *config);
+
+// Shades the `rows` x `cols` elevations in meters, rows from the north and NaN for no data, of pixels of
+// `cell_size` ground meters as `--mode hillshade` does, with the z-factor overriding the one of the config.
+// Writes the RGBA pixels of the same order to the output of at least `rows * cols * 4` bytes.
+//
+// # Safety
+//
+// `config` must be returned by `laz2dem_config_new`, `elevations` must point to `rows * cols` doubles
+// and `output` to `output_len` bytes.
+int laz2dem_shade(const Laz2demConfig *config,
+                  const double *elevations,
+                  size_t rows,
+                  size_t cols,
+                  double cell_size,
+                  double z_factor,
+                  uint8_t *output,
+                  size_t output_len);
+
+// Message of the last error in the thread, or null. It is valid until the next error in the thread.
+const char *laz2dem_last_error(void);
+
+#endif  /* LAZ2DEM_H */
@@ -0,0 +1,26 @@
+import numpy as np
+import numpy.typing as npt
+
+class Shading:
+    """Shading of the `--shadings` syntax, e.g. `Shading("oblique,000000FF,315,45,blend=multiply")`"""
+
+    def __init__(self, shading: str) -> None: ...
+    @staticmethod
+    def igor(azimuth: float, color: str = "000000FF", weight: float = 1.0) -> Shading: ...
+    @staticmethod
+    def oblique(
+        azimuth: float, altitude: float, color: str = "000000FF", weight: float = 1.0
+    ) -> Shading: ...
+    @staticmethod
+    def oblique_slope(altitude: float, color: str = "000000FF", weight: float = 1.0) -> Shading: ...
+
+def compute_hillshade(
+    elevations: npt.NDArray[np.float64],
+    z_factor: float = 1.0,
+    shadings: list[Shading] | None = None,
+    contrast: float = 1.0,
+    brightness: float = 0.0,
+    cell_size: float = 1.0,
+) -> npt.NDArray[np.uint8]:
+    """RGB image (rows, cols, 3) of `--mode hillshade` of the elevations in meters of the rows from the north,
+    NaN for no data, of pixels of `cell_size` ground meters, composed on the white background."""
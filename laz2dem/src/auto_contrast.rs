@@ -0,0 +1,264 @@
+use crate::{
+    error::Error,
+    options::{Mode, Options},
+    rasterization::{Surface, interpolate_supertile, read_laz_tile, shade_supertile},
+    shading::rgba_to_rgb,
+    shared_types::{Job, Source},
+};
+use image::RgbImage;
+use log::{info, warn};
+use maptile::tile::Tile;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use rusqlite::{Connection, OpenFlags};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+/// Supertiles interpolated for the auto contrast
+pub type Sampled = HashMap<Tile, Option<Surface>>;
+
+/// Percentiles of the illumination mapped to black and white
+const PERCENTILES: (f64, f64) = (0.02, 0.98);
+
+/// Outputs with `--contrast` and `--brightness` derived for `--auto-contrast` from the supertiles sampled evenly
+/// from the jobs, and the sampled supertiles interpolated for it so that their points aren't read again,
+/// `None` if they have less than `--min-points`
+pub fn auto_contrast(
+    outputs: &[Options],
+    jobs: &[Job],
+    resumed: &HashSet<(u8, u32, u32)>,
+) -> Result<(Vec<Options>, Sampled), Error> {
+    let mut outputs = outputs.to_vec();
+
+    if !outputs
+        .iter()
+        .any(|output| output.auto_contrast && output.mode == Mode::Hillshade)
+    {
+        return Ok((outputs, HashMap::new()));
+    }
+
+    let options = &outputs[0];
+
+    let tile_metas: Vec<_> = jobs
+        .iter()
+        .filter_map(|job| match job {
+            Job::Rasterize(tile_meta)
+                if !resumed.contains(&(
+                    tile_meta.tile.zoom,
+                    tile_meta.tile.x,
+                    tile_meta.tile.y,
+                )) =>
+            {
+                Some(tile_meta)
+            }
+            _ => None,
+        })
+        .collect();
+
+    // the jobs go along the Morton curve so the samples cover the whole bbox
+    let step = tile_metas
+        .len()
+        .div_ceil(options.auto_contrast_samples)
+        .max(1);
+
+    let samples: Vec<_> = tile_metas.into_iter().step_by(step).collect();
+
+    info!(
+        "Sampling {} supertiles for the auto contrast",
+        samples.len()
+    );
+
+    let laztile_conn = match options.source() {
+        Source::LazTileDb(path) => Some(Mutex::new(
+            Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(
+                |source| Error::Sqlite {
+                    path: path.display().to_string(),
+                    source,
+                },
+            )?,
+        )),
+        Source::LazIndexDb(_) | Source::LazDir(_) => None,
+    };
+
+    let sampled: HashMap<_, _> = samples
+        .into_par_iter()
+        .map(|tile_meta| {
+            if let Some(laztile_conn) = &laztile_conn {
+                read_laz_tile(&laztile_conn.lock().unwrap(), tile_meta, options)?;
+            }
+
+            Ok((tile_meta.tile, interpolate_supertile(options, tile_meta)?))
+        })
+        .collect::<Result<_, Error>>()?;
+
+    for output in outputs
+        .iter_mut()
+        .filter(|output| output.auto_contrast && output.mode == Mode::Hillshade)
+    {
+        // illumination as shaded without the final adjustments, which keep 0.0 and 1.0 of the gamma
+        let sample_options = Options {
+            contrast: 1.0,
+            brightness: 0.0,
+            gamma: 1.0,
+            aerial_perspective: 0.0,
+            ..output.clone()
+        };
+
+        let mut histogram = [0_u64; 256];
+
+        for surface in sampled.values().flatten() {
+            let img = shade_supertile(&sample_options, surface).into_rgba8();
+
+            // as written to opaque output, and as transparent output overlaid on the background looks
+            add_to_histogram(
+                &mut histogram,
+                surface,
+                &rgba_to_rgb(&img, output.background_color.0),
+            );
+        }
+
+        let Some((contrast, brightness)) = stretch(&histogram) else {
+            warn!(
+                "Too few shaded pixels for the auto contrast of {}, keeping --contrast {} --brightness {}",
+                output.output.display(),
+                output.contrast,
+                output.brightness
+            );
+
+            continue;
+        };
+
+        output.contrast = contrast;
+
+        output.brightness = brightness;
+
+        info!(
+            "AUTO CONTRAST {}: --contrast {:.4} --brightness {:.4}",
+            output.output.display(),
+            output.contrast,
+            output.brightness
+        );
+    }
+
+    Ok((outputs, sampled))
+}
+
+/// Adds the gray values of the shaded supertile composed on the background, except of the pixels without data
+fn add_to_histogram(histogram: &mut [u64; 256], surface: &Surface, img: &RgbImage) {
+    let width = surface.width_pixels;
+
+    // of `--supersample`
+    let factor = width / img.width();
+
+    for (x, image_y, pixel) in img.enumerate_pixels() {
+        // image rows go from north to south, the elevations from south to north
+        let y = surface.height_pixels - 1 - image_y * factor;
+
+        if surface
+            .elevations
+            .get((y * width + x * factor) as usize)
+            .is_nan()
+        {
+            continue;
+        }
+
+        let [r, g, b] = pixel.0;
+
+        histogram[usize::from(u16::from(r) + u16::from(g) + u16::from(b)) / 3] += 1;
+    }
+}
+
+/// Contrast and brightness mapping the low percentile of the histogram to 0.0 and the high one to 1.0,
+/// `None` if it is empty or flat
+fn stretch(histogram: &[u64; 256]) -> Option<(f64, f64)> {
+    let (low, high) = percentiles(histogram).filter(|(low, high)| high > low)?;
+
+    let contrast = 1.0 / (high - low);
+
+    Some((contrast, -contrast * (low - 0.5) - 0.5))
+}
+
+/// Values of `PERCENTILES` of the histogram of the 8 bit values, `None` if it is empty
+fn percentiles(histogram: &[u64; 256]) -> Option<(f64, f64)> {
+    let total: u64 = histogram.iter().sum();
+
+    if total == 0 {
+        return None;
+    }
+
+    let percentile = |fraction: f64| {
+        let rank = ((fraction * total as f64).ceil() as u64).max(1);
+
+        let mut cumulative = 0;
+
+        let value = histogram
+            .iter()
+            .position(|count| {
+                cumulative += count;
+
+                cumulative >= rank
+            })
+            .expect("rank within the total");
+
+        value as f64 / 255.0
+    };
+
+    Some((percentile(PERCENTILES.0), percentile(PERCENTILES.1)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{options::ElevationPrecision, terrain::Elevations};
+    use image::{Rgb, Rgba, RgbaImage};
+    use maptile::bbox::BBox;
+    use std::{iter, time::Instant};
+
+    #[test]
+    fn low_contrast_black_shading_is_stretched_to_the_full_range() {
+        let size = 64;
+
+        // black shading of alpha 100 - 163, i.e. gray 155 - 92 on white
+        let img = RgbaImage::from_fn(size, size, |x, _| Rgba([0, 0, 0, 100 + x as u8]));
+
+        let surface = Surface {
+            elevations: Elevations::new(
+                ElevationPrecision::F32,
+                iter::repeat_n(100.0, (size * size) as usize),
+            ),
+            point_count: 0,
+            thinned_count: 0,
+            spilled_count: 0,
+            bbox: BBox {
+                min_x: 0.0,
+                min_y: 0.0,
+                max_x: 64.0,
+                max_y: 64.0,
+            },
+            width_pixels: size,
+            height_pixels: size,
+            interpolated: Instant::now(),
+        };
+
+        let mut histogram = [0; 256];
+
+        add_to_histogram(
+            &mut histogram,
+            &surface,
+            &rgba_to_rgb(&img, Rgb([255, 255, 255])),
+        );
+
+        let (contrast, brightness) = stretch(&histogram).unwrap();
+
+        let stretched = |gray: f64| contrast * (gray / 255.0 - 0.5) + 0.5 + brightness;
+
+        assert!(stretched(93.0).abs() < 0.05, "{}", stretched(93.0));
+
+        assert!(
+            (stretched(154.0) - 1.0).abs() < 0.05,
+            "{}",
+            stretched(154.0)
+        );
+    }
+}
@@ -0,0 +1,256 @@
+use crate::{
+    clip::Clip,
+    config,
+    debug_tile::debug_tile,
+    dry_run::dry_run,
+    error::Error,
+    incremental::Changes,
+    journal, logger,
+    options::{ExistingFileAction, Options},
+    rasterization::rasterize,
+    read::{
+        Skipped, overlapping_files, read_batches, read_files, read_points, resolve_bbox, tile_metas,
+    },
+    serve::serve,
+    shared_types::{Job, Source},
+    timings::{Phase, TIMINGS},
+};
+use log::{info, warn};
+use std::{
+    env, iter,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::sync_channel,
+    },
+    thread,
+    time::Instant,
+};
+
+/// Set by the first Ctrl-C
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Renders the output as set by the command line arguments and the `--config` file,
+/// or returns the definitions of the presets for `--preset list` for `main` to print
+pub fn run() -> Result<Option<String>, Error> {
+    let options = config::options(env::args_os())?;
+
+    if options.preset.as_deref() == Some("list") {
+        return Ok(Some(config::preset_list()));
+    }
+
+    logger::init(&options);
+
+    // the rasterizing threads and the parallel iterators within them share the CPUs
+    if let Err(err) = rayon::ThreadPoolBuilder::new()
+        .num_threads(options.compute_threads())
+        .build_global()
+    {
+        warn!("Error configuring the compute threads: {err}");
+    }
+
+    handle_interrupt();
+
+    render(options, &INTERRUPTED).map(|()| None)
+}
+
+#[cfg(unix)]
+fn handle_interrupt() {
+    extern "C" fn on_interrupt(_: libc::c_int) {
+        // only async-signal-safe calls here
+        if INTERRUPTED.swap(true, Ordering::Relaxed) {
+            unsafe { libc::_exit(130) };
+        }
+
+        let message = b"\nCancelling after the files and supertiles in progress, press Ctrl-C again to exit immediately\n";
+
+        unsafe { libc::write(libc::STDERR_FILENO, message.as_ptr().cast(), message.len()) };
+    }
+
+    unsafe {
+        libc::signal(
+            libc::SIGINT,
+            on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        )
+    };
+}
+
+#[cfg(not(unix))]
+fn handle_interrupt() {}
+
+/// Renders the output as set by the options, like the binary does with them. Once `cancel` is set, e.g. from another
+/// thread, the files and the supertiles in progress are finished, the outputs are closed consistently and
+/// `Error::Cancelled` is returned; the rest can be rendered later with `--resume` or `--existing-file-action continue`.
+///
+/// # Examples
+///
+/// ```no_run
+/// use laz2dem::{options::Options, render};
+/// use maptile::bbox::BBox;
+/// use std::sync::atomic::AtomicBool;
+///
+/// let options = Options {
+///     output: "hillshading.mbtiles".into(),
+///     laz_dir: Some("laz".into()),
+///     bbox_3857: Some(BBox::new(1_900_000.0, 6_200_000.0, 1_910_000.0, 6_210_000.0)),
+///     zoom_level: 17,
+///     ..Options::default()
+/// };
+///
+/// render(options, &AtomicBool::new(false))?;
+/// # Ok::<(), laz2dem::error::Error>(())
+/// ```
+pub fn render(mut options: Options, cancel: &AtomicBool) -> Result<(), Error> {
+    options.bbox = resolve_bbox(&options)?;
+
+    options.clip_polygon = options.clip.as_deref().map(Clip::read).transpose()?;
+
+    if options.incremental && options.existing_file_action.is_none() {
+        options.existing_file_action = Some(ExistingFileAction::Update);
+    }
+
+    if let Some(slope_classes) = options.slope_classes.take() {
+        options.shadings.0.push(slope_classes.into());
+    }
+
+    options.validate()?;
+
+    info!(
+        "Rendering EPSG:3857 bbox {},{},{},{}",
+        options.bbox.min_x, options.bbox.min_y, options.bbox.max_x, options.bbox.max_y
+    );
+
+    let outputs: Vec<_> = iter::once(options.clone())
+        .chain(options.layer_options())
+        .collect();
+
+    if let Some(tile) = options.debug_tile {
+        return debug_tile(&options, tile);
+    }
+
+    if options.serve {
+        return serve(&options);
+    }
+
+    let mut tile_metas = tile_metas(&options)?;
+
+    if let Some(clip) = &options.clip_polygon {
+        let count = tile_metas.len();
+
+        tile_metas.retain(|tile_meta| clip.intersects(&tile_meta.bbox));
+
+        info!(
+            "Skipping {} of {count} supertiles out of the clip polygon",
+            count - tile_metas.len()
+        );
+    }
+
+    let changes = match options.source() {
+        Source::LazIndexDb(path) if options.incremental => Some(Changes::read(&options, &path)?),
+        _ => None,
+    };
+
+    if let Some(changes) = &changes {
+        tile_metas.retain(|tile_meta| changes.is_affected(&tile_meta.bbox));
+
+        info!(
+            "Regenerating {} supertiles affected by {} changed files",
+            tile_metas.len(),
+            changes.file_count()
+        );
+    }
+
+    let supertiles: Vec<_> = tile_metas.iter().map(|tile_meta| tile_meta.tile).collect();
+
+    // supertiles written by a previous run are not read again
+    let resumed_jobs: Vec<_> = if options.resume {
+        let recorded = journal::recorded(&options.output);
+
+        let (resumed, remaining) = tile_metas.into_iter().partition(|tile_meta| {
+            recorded.contains(&(tile_meta.tile.zoom, tile_meta.tile.x, tile_meta.tile.y))
+        });
+
+        tile_metas = remaining;
+
+        resumed.into_iter().map(Job::Rasterize).collect()
+    } else {
+        Vec::new()
+    };
+
+    let started = Instant::now();
+
+    let skipped = Skipped::default();
+
+    let files = read_files(&options, &skipped)?;
+
+    TIMINGS.record(Phase::List, started.elapsed());
+
+    if options.dry_run {
+        return dry_run(&options, &tile_metas, files.as_deref());
+    }
+
+    if let (Some(max_open_tiles), Some(files)) = (options.max_open_tiles, &files) {
+        let (sender, receiver) = sync_channel(0);
+
+        thread::scope(|scope| {
+            let reading = scope.spawn(|| {
+                read_batches(
+                    &options,
+                    tile_metas,
+                    files,
+                    max_open_tiles,
+                    &skipped,
+                    sender,
+                    cancel,
+                )
+            });
+
+            // batches read before an error are still rasterized
+            let rasterized = rasterize(&outputs, &supertiles, resumed_jobs, Some(receiver), cancel);
+
+            // the reading stops once the rasterizing fails
+            rasterized.and(reading.join().unwrap())
+        })?;
+    } else {
+        if let Some(files) = &files {
+            read_points(
+                &options,
+                &tile_metas,
+                &overlapping_files(files, &tile_metas),
+                &skipped,
+                cancel,
+            )?;
+        }
+
+        let mut jobs: Vec<_> = tile_metas.into_iter().map(Job::Rasterize).collect();
+
+        jobs.extend(resumed_jobs);
+
+        jobs.sort_by_cached_key(|job| job.tile().morton_code());
+
+        rasterize(&outputs, &supertiles, jobs, None, cancel)?;
+    }
+
+    skipped.print_summary();
+
+    TIMINGS.log_summary();
+
+    if let Some(path) = &options.timings_out {
+        TIMINGS.write(path)?;
+    }
+
+    // the changed files are regenerated next time
+    if cancel.load(Ordering::Relaxed) {
+        return Err(Error::Cancelled);
+    }
+
+    if let Some(changes) = changes {
+        // unread files are regenerated next time
+        if skipped.files.load(Ordering::Relaxed) > 0 {
+            warn!("Not saving the state of the changed files as some of them were skipped");
+        } else {
+            changes.save()?;
+        }
+    }
+
+    Ok(())
+}
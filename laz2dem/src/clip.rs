@@ -0,0 +1,273 @@
+use crate::{error::Error, read::new_proj};
+use maptile::bbox::BBox;
+use serde_json::Value;
+use std::{fs::read_to_string, path::Path};
+
+/// Edges per horizontal band of the polygon on average
+const EDGES_PER_BAND: usize = 4;
+
+/// Clip polygon in EPSG:3857, with the edges of all its rings indexed by horizontal bands
+/// so that a point is tested only against the edges of its band
+#[derive(Clone, Debug, PartialEq)]
+pub struct Clip {
+    /// Edges of the outer rings and of the holes; the even-odd rule honors the holes
+    edges: Vec<[(f64, f64); 2]>,
+    bbox: BBox,
+    /// Indices of the edges crossing the band
+    bands: Vec<Vec<usize>>,
+    band_height: f64,
+}
+
+impl Clip {
+    /// Reads the Polygon and MultiPolygon geometries of the GeoJSON file, in WGS84 or in EPSG:3857
+    /// if its `crs` names it or its coordinates are out of the WGS84 range
+    pub fn read(path: &Path) -> Result<Self, Error> {
+        let invalid = |message: String| {
+            Error::InvalidOptions(format!(
+                "Invalid clip polygon {}: {message}",
+                path.display()
+            ))
+        };
+
+        let geojson = read_to_string(path).map_err(|source| Error::Io {
+            path: path.display().to_string(),
+            source,
+        })?;
+
+        let json = serde_json::from_str(&geojson).map_err(|error| invalid(error.to_string()))?;
+
+        let mut rings = Vec::new();
+
+        collect_rings(&json, &mut rings).map_err(invalid)?;
+
+        if rings.is_empty() {
+            return Err(invalid("no Polygon or MultiPolygon geometry".to_owned()));
+        }
+
+        let crs_3857 = json
+            .get("crs")
+            .and_then(|crs| crs.get("properties"))
+            .and_then(|properties| properties.get("name"))
+            .and_then(Value::as_str)
+            .is_some_and(|name| name.contains("3857") || name.contains("900913"));
+
+        let out_of_wgs84 = rings
+            .iter()
+            .flatten()
+            .any(|&(x, y)| x.abs() > 180.0 || y.abs() > 90.0);
+
+        if !crs_3857 && !out_of_wgs84 {
+            let proj = new_proj("EPSG:4326", "EPSG:3857")?;
+
+            for coord in rings.iter_mut().flatten() {
+                *coord = proj.convert(*coord).map_err(|source| Error::Proj {
+                    context: format!("projecting the clip polygon {}", path.display()),
+                    source: source.into(),
+                })?;
+            }
+        }
+
+        let edges: Vec<_> = rings
+            .iter()
+            .flat_map(|ring| ring.iter().zip(ring.iter().cycle().skip(1)))
+            .map(|(&a, &b)| [a, b])
+            .filter(|[a, b]| a != b)
+            .collect();
+
+        Ok(Self::new(edges))
+    }
+
+    fn new(edges: Vec<[(f64, f64); 2]>) -> Self {
+        let mut bbox = BBox::new(f64::MAX, f64::MAX, f64::MIN, f64::MIN);
+
+        for &[(x, y), _] in &edges {
+            bbox = BBox::new(
+                bbox.min_x.min(x),
+                bbox.min_y.min(y),
+                bbox.max_x.max(x),
+                bbox.max_y.max(y),
+            );
+        }
+
+        let band_count = (edges.len() / EDGES_PER_BAND).max(1);
+
+        let band_height = bbox.height() / band_count as f64;
+
+        let mut clip = Self {
+            edges,
+            bbox,
+            bands: vec![Vec::new(); band_count],
+            band_height,
+        };
+
+        for (i, [a, b]) in clip.edges.iter().enumerate() {
+            for band in clip.band(a.1.min(b.1))..=clip.band(a.1.max(b.1)) {
+                clip.bands[band].push(i);
+            }
+        }
+
+        clip
+    }
+
+    fn band(&self, y: f64) -> usize {
+        (((y - self.bbox.min_y) / self.band_height) as usize).min(self.bands.len() - 1)
+    }
+
+    /// Edges of the bands between the ys, some of them repeated
+    fn edges_between(&self, min_y: f64, max_y: f64) -> impl Iterator<Item = &[(f64, f64); 2]> {
+        let bands: &[_] = if max_y < self.bbox.min_y || min_y > self.bbox.max_y {
+            &[]
+        } else {
+            &self.bands[self.band(min_y.max(self.bbox.min_y))..=self.band(max_y)]
+        };
+
+        bands.iter().flatten().map(|&i| &self.edges[i])
+    }
+
+    pub fn contains(&self, x: f64, y: f64) -> bool {
+        if !self.bbox.contains(x, y) {
+            return false;
+        }
+
+        // crossings of the ray to the right
+        self.bands[self.band(y)]
+            .iter()
+            .map(|&i| &self.edges[i])
+            .filter(|[a, b]| {
+                (a.1 > y) != (b.1 > y) && x < a.0 + (y - a.1) * (b.0 - a.0) / (b.1 - a.1)
+            })
+            .count()
+            % 2
+            == 1
+    }
+
+    /// Whether the point is in the polygon or within the distance from its edges
+    pub fn contains_buffered(&self, x: f64, y: f64, distance: f64) -> bool {
+        if !self.bbox.to_extended(distance).contains(x, y) {
+            return false;
+        }
+
+        self.contains(x, y)
+            || self
+                .edges_between(y - distance, y + distance)
+                .any(|&[a, b]| segment_distance((x, y), a, b) <= distance)
+    }
+
+    pub fn intersects(&self, bbox: &BBox) -> bool {
+        if bbox.min_x > self.bbox.max_x
+            || bbox.max_x < self.bbox.min_x
+            || bbox.min_y > self.bbox.max_y
+            || bbox.max_y < self.bbox.min_y
+        {
+            return false;
+        }
+
+        // bbox within the polygon, or crossed by its edges or containing them
+        self.contains(bbox.min_x, bbox.min_y)
+            || self
+                .edges_between(bbox.min_y, bbox.max_y)
+                .any(|&[a, b]| segment_intersects(a, b, bbox))
+    }
+}
+
+fn segment_distance(point: (f64, f64), a: (f64, f64), b: (f64, f64)) -> f64 {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+
+    let t = (((point.0 - a.0) * dx + (point.1 - a.1) * dy) / (dx * dx + dy * dy)).clamp(0.0, 1.0);
+
+    (point.0 - a.0 - t * dx).hypot(point.1 - a.1 - t * dy)
+}
+
+/// Liang–Barsky clipping of the segment by the bbox
+fn segment_intersects(a: (f64, f64), b: (f64, f64), bbox: &BBox) -> bool {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+
+    let (mut t0, mut t1) = (0.0_f64, 1.0_f64);
+
+    for (p, q) in [
+        (-dx, a.0 - bbox.min_x),
+        (dx, bbox.max_x - a.0),
+        (-dy, a.1 - bbox.min_y),
+        (dy, bbox.max_y - a.1),
+    ] {
+        if p == 0.0 {
+            if q < 0.0 {
+                return false;
+            }
+        } else if p < 0.0 {
+            t0 = t0.max(q / p);
+        } else {
+            t1 = t1.min(q / p);
+        }
+
+        if t0 > t1 {
+            return false;
+        }
+    }
+
+    true
+}
+
+fn array(json: &Value) -> Result<&[Value], String> {
+    json.as_array()
+        .map(Vec::as_slice)
+        .ok_or_else(|| "expected an array".to_owned())
+}
+
+fn polygon(json: &Value, rings: &mut Vec<Vec<(f64, f64)>>) -> Result<(), String> {
+    for ring in array(json)? {
+        let ring = array(ring)?
+            .iter()
+            .map(|position| match array(position)? {
+                [x, y, ..] => x
+                    .as_f64()
+                    .zip(y.as_f64())
+                    .ok_or_else(|| "invalid position".to_owned()),
+                _ => Err("invalid position".to_owned()),
+            })
+            .collect::<Result<_, _>>()?;
+
+        rings.push(ring);
+    }
+
+    Ok(())
+}
+
+/// Rings of the Polygon and MultiPolygon geometries of the GeoJSON object
+fn collect_rings(json: &Value, rings: &mut Vec<Vec<(f64, f64)>>) -> Result<(), String> {
+    let member = |key: &str| {
+        json.get(key)
+            .ok_or_else(|| format!("missing `{key}` of a GeoJSON object"))
+    };
+
+    match member("type")?.as_str() {
+        Some("FeatureCollection") => {
+            for feature in array(member("features")?)? {
+                collect_rings(feature, rings)?;
+            }
+        }
+        Some("Feature") => match member("geometry")? {
+            Value::Null => {}
+            geometry => collect_rings(geometry, rings)?,
+        },
+        Some("GeometryCollection") => {
+            for geometry in array(member("geometries")?)? {
+                collect_rings(geometry, rings)?;
+            }
+        }
+        Some("Polygon") => polygon(member("coordinates")?, rings)?,
+        Some("MultiPolygon") => {
+            for coordinates in array(member("coordinates")?)? {
+                polygon(coordinates, rings)?;
+            }
+        }
+        Some(other) => {
+            return Err(format!(
+                "unsupported geometry {other}, only Polygon and MultiPolygon are supported"
+            ));
+        }
+        None => return Err("`type` of a GeoJSON object must be a string".to_owned()),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,328 @@
+use crate::{
+    error::Error,
+    options::Options,
+    shading::{PRESETS, Preset},
+};
+use clap::{Arg, ArgGroup, Command, CommandFactory, FromArgMatches, parser::ValueSource};
+use serde::Deserialize;
+use std::{ffi::OsString, fs::read_to_string, path::Path};
+use toml::{Table, Value};
+
+/// Options of the command line over the ones of the `--config` file over the ones of the `--preset`.
+/// The output, the source, the extent and the zoom level are required unless the preset is `list`.
+pub fn options(args: impl IntoIterator<Item = OsString>) -> Result<Options, Error> {
+    let mut command = Options::command();
+
+    // assigns the args to their groups
+    command.build();
+
+    // placeholders of the required options which may be in the config, checked by `check_required`
+    let relaxed = [
+        ("output", "-"),
+        ("zoom_level", "0"),
+        ("shadings", PRESETS[0].shadings),
+    ]
+    .into_iter()
+    .fold(Options::command(), |relaxed, (id, placeholder)| {
+        relaxed.mut_arg(id, |arg| {
+            arg.required(false)
+                .default_value(placeholder)
+                .hide_default_value(true)
+        })
+    })
+    .mut_group("exclusive", |group| group.required(false))
+    .mut_group("extent", |group| group.required(false));
+
+    let matches = relaxed.get_matches_from(args);
+
+    let cli = Options::from_arg_matches(&matches).unwrap_or_else(|error| error.exit());
+
+    let mut table = match &cli.config {
+        Some(path) => read(path)?,
+        None => Table::new(),
+    };
+
+    let cli_table =
+        Table::try_from(&cli).map_err(|error| Error::InvalidOptions(error.to_string()))?;
+
+    for arg in command.get_arguments() {
+        if matches.value_source(arg.get_id().as_str()) != Some(ValueSource::CommandLine) {
+            continue;
+        }
+
+        // e.g. `--laz-dir` replaces `laz_index_db` of the config
+        for group in groups(&command).filter(|group| group.get_args().any(|id| id == arg.get_id()))
+        {
+            for id in group.get_args() {
+                table.remove(&key(&command, id.as_str()));
+            }
+        }
+
+        let key = key(&command, arg.get_id().as_str());
+
+        if let Some(value) = cli_table.get(&key) {
+            table.insert(key, value.clone());
+        }
+    }
+
+    let preset = table
+        .get("preset")
+        .and_then(Value::as_str)
+        .map(str::to_owned);
+
+    let listing = preset.as_deref() == Some("list");
+
+    if let Some(name) = preset.filter(|_| !listing) {
+        let preset = Preset::get(&name).ok_or_else(|| {
+            Error::InvalidOptions(format!("Unknown preset {name}, see --preset list"))
+        })?;
+
+        for (key, value) in preset_table(preset) {
+            table.entry(key).or_insert(value);
+        }
+    }
+
+    if !listing {
+        check_required(&command, &table)?;
+    }
+
+    let mut options =
+        Options::deserialize(table).map_err(|error| Error::InvalidOptions(error.to_string()))?;
+
+    options.config = cli.config;
+
+    Ok(options)
+}
+
+/// Definitions of the presets printed by `--preset list`
+pub fn preset_list() -> String {
+    PRESETS
+        .iter()
+        .map(|preset| format!("{}: {}\n", preset.name, preset.args().join(" ")))
+        .collect()
+}
+
+/// Options of the config file, validated against `Options` for the errors with the lines
+fn read(path: &Path) -> Result<Table, Error> {
+    let config = read_to_string(path).map_err(|source| Error::Io {
+        path: path.display().to_string(),
+        source,
+    })?;
+
+    let invalid =
+        |error: toml::de::Error| Error::InvalidOptions(format!("{}: {error}", path.display()));
+
+    toml::from_str::<Options>(&config).map_err(invalid)?;
+
+    toml::from_str(&config).map_err(invalid)
+}
+
+/// Key of the option of the arg, its long flag with `_`
+fn key(command: &Command, id: &str) -> String {
+    command
+        .get_arguments()
+        .find(|arg| arg.get_id() == id)
+        .and_then(Arg::get_long)
+        .unwrap_or(id)
+        .replace('-', "_")
+}
+
+/// Groups of the mutually exclusive args of which one is required, the source and the extent
+fn groups(command: &Command) -> impl Iterator<Item = &ArgGroup> {
+    command.get_groups().filter(|group| group.is_required_set())
+}
+
+fn preset_table(preset: &Preset) -> Table {
+    Table::from_iter([
+        ("shadings".to_owned(), Value::from(preset.shadings)),
+        ("contrast".to_owned(), Value::from(preset.contrast)),
+        ("brightness".to_owned(), Value::from(preset.brightness)),
+        ("z_factor".to_owned(), Value::from(preset.z_factor)),
+    ])
+}
+
+/// Checks the required args and groups of `Options` as clap does on the command line
+fn check_required(command: &Command, table: &Table) -> Result<(), Error> {
+    let name = |id: &str| {
+        let key = key(command, id);
+
+        if key == "output" {
+            "<OUTPUT>".to_owned()
+        } else {
+            format!("--{}", key.replace('_', "-"))
+        }
+    };
+
+    for arg in command.get_arguments().filter(|arg| arg.is_required_set()) {
+        if !table.contains_key(&key(command, arg.get_id().as_str())) {
+            return Err(Error::InvalidOptions(format!(
+                "{} is required on the command line or in the config",
+                name(arg.get_id().as_str())
+            )));
+        }
+    }
+
+    for group in groups(command) {
+        let ids: Vec<_> = group.get_args().map(|id| id.as_str()).collect();
+
+        let given = ids
+            .iter()
+            .filter(|id| table.contains_key(&key(command, id)))
+            .count();
+
+        if given != 1 {
+            return Err(Error::InvalidOptions(format!(
+                "Exactly one of {} is required",
+                ids.iter().map(|id| name(id)).collect::<Vec<_>>().join(", ")
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, path::PathBuf};
+
+    fn example() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/run.toml")
+    }
+
+    /// Options of the args with the config, which is removed then
+    fn options_of(name: &str, config: &str, args: &[&str]) -> Result<Options, Error> {
+        let path = std::env::temp_dir().join(format!("laz2dem-{}-{name}.toml", std::process::id()));
+
+        fs::write(&path, config).unwrap();
+
+        let options = options(
+            ["laz2dem", "--config", path.to_str().unwrap()]
+                .iter()
+                .chain(args)
+                .map(OsString::from),
+        );
+
+        fs::remove_file(&path).unwrap();
+
+        options
+    }
+
+    #[test]
+    fn example_config_gives_the_options() {
+        let options = self::options(
+            [
+                "laz2dem",
+                "--config",
+                example().to_str().unwrap(),
+                "out.mbtiles",
+            ]
+            .map(OsString::from),
+        )
+        .unwrap();
+
+        assert_eq!(options.laz_index_db, Some(PathBuf::from("index.sqlite")));
+
+        assert_eq!((options.zoom_level, options.unit_zoom_level), (17, 12));
+
+        assert_eq!(options.bbox_3857.unwrap().max_y, 6_200_000.0);
+
+        assert_eq!(options.shadings.0.len(), 3);
+
+        assert_eq!(options.shadings.0[1].weight, 0.5);
+    }
+
+    #[test]
+    fn options_round_trip_through_toml() {
+        let options = self::options(
+            [
+                "laz2dem",
+                "--config",
+                example().to_str().unwrap(),
+                "out.mbtiles",
+            ]
+            .map(OsString::from),
+        )
+        .unwrap();
+
+        let config = toml::to_string(&options).unwrap();
+
+        let mut read = toml::from_str::<Options>(&config).unwrap();
+
+        read.config = options.config.clone();
+
+        assert_eq!(read, options, "{config}");
+    }
+
+    #[test]
+    fn command_line_overrides_the_config_which_overrides_the_preset() {
+        let options = options_of(
+            "override",
+            "preset = \"swiss\"\ncontrast = 2.0\nzoom_level = 17\nlaz_index_db = \"index.sqlite\"\nbbox = [0, 0, 1, 1]\n",
+            &["out.mbtiles", "--zoom-level=18", "--laz-dir=dir"],
+        )
+        .unwrap();
+
+        // of the preset
+        assert_eq!(options.z_factor, 1.5);
+
+        assert_eq!(options.contrast, 2.0);
+
+        assert_eq!(options.zoom_level, 18);
+
+        assert_eq!(
+            (options.laz_dir, options.laz_index_db),
+            (Some(PathBuf::from("dir")), None)
+        );
+    }
+
+    #[test]
+    fn errors_of_the_config_name_the_line() {
+        let error = options_of(
+            "method",
+            "zoom_level = 17\nlaz_dir = \"dir\"\n[[shadings]]\ncolor = \"#000000FF\"\nmethod = { type = \"sunny\" }\n",
+            &["out.mbtiles"],
+        )
+        .unwrap_err()
+        .to_string();
+
+        assert!(error.contains("line 5"), "{error}");
+
+        assert!(error.contains("unknown variant `sunny`"), "{error}");
+
+        let error = options_of(
+            "color",
+            "zoom_level = 17\nlaz_dir = \"dir\"\n[[shadings]]\nmethod = { type = \"igor-slope\" }\n",
+            &["out.mbtiles"],
+        )
+        .unwrap_err()
+        .to_string();
+
+        assert!(error.contains("line 3"), "{error}");
+
+        assert!(error.contains("missing field `color`"), "{error}");
+    }
+
+    #[test]
+    fn required_options_are_checked_after_the_config() {
+        let error = |config: &str| {
+            options_of("required", config, &["out.mbtiles"])
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert_eq!(
+            error("shadings = \"igor-slope,000000FF\"\nlaz_dir = \"dir\"\nbbox = [0, 0, 1, 1]\n"),
+            "--zoom-level is required on the command line or in the config"
+        );
+
+        assert_eq!(
+            error(
+                "shadings = \"igor-slope,000000FF\"\nzoom_level = 17\nlaz_dir = \"dir\"\nlaz_index_db = \"index.sqlite\"\nbbox = [0, 0, 1, 1]\n"
+            ),
+            "Exactly one of --laz-tile-db, --laz-index-db, --laz-dir is required"
+        );
+
+        assert!(options_of("list", "preset = \"list\"\n", &[]).is_ok());
+    }
+}
@@ -0,0 +1,106 @@
+use crate::options::Options;
+use las::{
+    CopcEntryReader, Header, Point, Reader,
+    copc::{CopcInfoVlr, Entry},
+};
+use log::debug;
+use maptile::bbox::BBox;
+use std::{fs::File, io::BufReader, path::Path};
+
+/// Points decoded at once from a file without the COPC octree
+const READ_CHUNK_POINTS: u64 = 65_536;
+
+/// Reader of the points of a file, only of the octree nodes intersecting the bbox for a COPC file
+pub enum PointReader {
+    Copc {
+        reader: Box<CopcEntryReader<'static, BufReader<File>>>,
+        entries: Vec<Entry>,
+    },
+    Full(Reader),
+}
+
+impl PointReader {
+    /// Reader of the points within the bbox in the projection of the file
+    pub fn open(options: &Options, path: &Path, bbox: &BBox) -> las::Result<Self> {
+        let reader = Reader::from_path(path)?;
+
+        let Some(entries) = entries(options, path, reader.header(), bbox) else {
+            return Ok(PointReader::Full(reader));
+        };
+
+        Ok(PointReader::Copc {
+            reader: Box::new(CopcEntryReader::new(BufReader::new(File::open(path)?))?),
+            entries,
+        })
+    }
+
+    /// Number of the points to read
+    pub fn number_of_points(&self) -> u64 {
+        match self {
+            PointReader::Copc { entries, .. } => {
+                entries.iter().map(|entry| entry.point_count as u64).sum()
+            }
+            PointReader::Full(reader) => reader.header().number_of_points(),
+        }
+    }
+
+    /// Reads the next chunk of the points, or the points of the next node, returning 0 once all are read
+    pub fn read_chunk(&mut self, chunk: &mut Vec<Point>) -> las::Result<u64> {
+        match self {
+            PointReader::Copc { reader, entries } => match entries.pop() {
+                Some(entry) => reader.read_entry_points(&entry, chunk),
+                None => Ok(0),
+            },
+            PointReader::Full(reader) => reader.read_points_into(READ_CHUNK_POINTS, chunk),
+        }
+    }
+}
+
+/// Octree nodes of a COPC file with points intersecting the bbox, limited to the level of the point spacing
+/// reaching the pixel size with `--copc-thinning`, or `None` for a file without the octree
+fn entries(options: &Options, path: &Path, header: &Header, bbox: &BBox) -> Option<Vec<Entry>> {
+    let info = header.copc_info_vlr()?;
+
+    let hierarchy = header.copc_hierarchy_evlr()?;
+
+    // the spacing halves with every level
+    let max_level = options.copc_thinning.then(|| {
+        let pixel_size = 1.0 / options.max_pixels_per_ground_meter();
+
+        (info.spacing / pixel_size).log2().ceil().max(0.0) as i32
+    });
+
+    let mut node_count = 0;
+
+    let entries: Vec<_> = hierarchy
+        .iter_entries()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.point_count > 0)
+        .inspect(|_| node_count += 1)
+        .filter(|entry| max_level.is_none_or(|max_level| entry.key.l <= max_level))
+        .filter(|entry| node_intersects(&info, entry, bbox))
+        .copied()
+        .collect();
+
+    debug!(
+        "COPC {}: reading {} of {node_count} octree nodes down to the level {}",
+        path.display(),
+        entries.len(),
+        max_level.map_or_else(|| "of the leaves".to_owned(), |level| level.to_string())
+    );
+
+    Some(entries)
+}
+
+fn node_intersects(info: &CopcInfoVlr, entry: &Entry, bbox: &BBox) -> bool {
+    let size = info.halfsize * 2.0 / (1_u64 << entry.key.l) as f64;
+
+    let min_x = info.center_x - info.halfsize + size * entry.key.x as f64;
+
+    let min_y = info.center_y - info.halfsize + size * entry.key.y as f64;
+
+    min_x <= bbox.max_x
+        && min_x + size >= bbox.min_x
+        && min_y <= bbox.max_y
+        && min_y + size >= bbox.min_y
+}
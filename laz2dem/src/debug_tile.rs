@@ -0,0 +1,191 @@
+use crate::{
+    error::Error,
+    geotiff::{GeoReference, Raster, write_geotiff},
+    options::{Options, TileRange},
+    rasterization::{Surface, interpolate_supertile, shade_supertile},
+    read::read_supertile,
+    shading::{PixelContext, compute_hillshade},
+    terrain::Grid,
+};
+use image::Luma;
+use log::{info, warn};
+use maptile::tile::Tile;
+use std::{
+    f64::consts::FRAC_PI_2,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+/// Renders the supertile of `--debug-tile` and dumps its points, elevations, slopes, aspects and shaded image
+pub fn debug_tile(options: &Options, tile: TileRange) -> Result<(), Error> {
+    let offset = tile.zoom - options.unit_zoom_level;
+
+    let supertile = Tile {
+        zoom: options.unit_zoom_level,
+        x: tile.x.0 >> offset,
+        y: tile.y.0 >> offset,
+    };
+
+    info!(
+        "DEBUG supertile {}/{}/{} of the tile {}/{}/{}",
+        supertile.zoom, supertile.x, supertile.y, tile.zoom, tile.x.0, tile.y.0
+    );
+
+    let path = |name: &str| {
+        PathBuf::from(format!(
+            "{}-{}-{}-{}-{name}",
+            options.output.with_extension("").display(),
+            supertile.zoom,
+            supertile.x,
+            supertile.y
+        ))
+    };
+
+    let tile_meta = read_supertile(options, supertile)?;
+
+    let points_path = path("points.csv");
+
+    let points_error = write_error(&points_path);
+
+    let mut writer = BufWriter::new(File::create(&points_path).map_err(&points_error)?);
+
+    writeln!(writer, "x,y,z,collection").map_err(&points_error)?;
+
+    for (collection, points) in [
+        ("ground", &tile_meta.points),
+        ("water", &tile_meta.water_points),
+        ("surface", &tile_meta.surface_points),
+    ] {
+        for point in points.lock().unwrap().iter() {
+            writeln!(
+                writer,
+                "{},{},{},{collection}",
+                point.position.x, point.position.y, point.height
+            )
+            .map_err(&points_error)?;
+        }
+    }
+
+    writer.flush().map_err(&points_error)?;
+
+    info!("DEBUG points written to {}", points_path.display());
+
+    let Some(surface) = interpolate_supertile(options, &tile_meta)? else {
+        warn!("DEBUG supertile has too few points to be rendered");
+
+        return Ok(());
+    };
+
+    let Surface {
+        elevations,
+        bbox,
+        width_pixels,
+        height_pixels,
+        ..
+    } = &surface;
+
+    let geo_reference = GeoReference {
+        min_x: bbox.min_x,
+        max_y: bbox.max_y,
+        pixel_size: bbox.width() / f64::from(*width_pixels),
+    };
+
+    let write_raster = |name: &str, data: Vec<f32>| -> Result<(), Error> {
+        let path = path(name);
+
+        write_geotiff(
+            &path,
+            Raster {
+                width: *width_pixels,
+                height: *height_pixels,
+                channels: 1,
+                data,
+            },
+            &geo_reference,
+            Some(f64::NAN),
+        )?;
+
+        info!("DEBUG {name} written to {}", path.display());
+
+        Ok(())
+    };
+
+    // image rows go from north to south, the elevations from south to north
+    write_raster(
+        "elevation.tif",
+        (0..*height_pixels)
+            .rev()
+            .flat_map(|y| {
+                (0..*width_pixels)
+                    .map(move |x| elevations.get((y * width_pixels + x) as usize) as f32)
+            })
+            .collect(),
+    )?;
+
+    // the surface is of the `--supersample` times the resolution
+    let supersampled = options.supersampled();
+
+    let pixels_per_meter = supersampled.pixels_per_meter();
+
+    let grid = Grid {
+        elevations,
+        rows: *height_pixels as usize,
+        cols: *width_pixels as usize,
+        cell_size: 1.0 / pixels_per_meter,
+        mercator_y: options
+            .mercator_scale
+            .then_some(bbox.min_y + 0.5 / pixels_per_meter),
+    };
+
+    let derivative = |compute: fn(&PixelContext) -> f64| {
+        compute_hillshade(
+            &grid,
+            options.z_factor,
+            options.derivative_method,
+            supersampled.derivative_radius,
+            |pixel| Luma([compute(pixel) as f32]),
+        )
+        .into_raw()
+    };
+
+    write_raster(
+        "slope.tif",
+        derivative(|pixel| {
+            if pixel.elevation.is_nan() {
+                f64::NAN
+            } else {
+                pixel.slope.to_degrees()
+            }
+        }),
+    )?;
+
+    // downslope direction clockwise from north, NaN for flat pixels
+    write_raster(
+        "aspect.tif",
+        derivative(|pixel| {
+            if pixel.elevation.is_nan() || pixel.slope == 0.0 {
+                f64::NAN
+            } else {
+                (pixel.aspect + FRAC_PI_2).to_degrees().rem_euclid(360.0)
+            }
+        }),
+    )?;
+
+    let image_path = path("image.png");
+
+    shade_supertile(options, &surface)
+        .save(&image_path)
+        .map_err(|error| write_error(&image_path)(io::Error::other(error)))?;
+
+    info!("DEBUG image written to {}", image_path.display());
+
+    Ok(())
+}
+
+fn write_error(path: &Path) -> impl Fn(io::Error) -> Error + '_ {
+    |source| Error::Write {
+        path: path.display().to_string(),
+        source,
+    }
+}
@@ -0,0 +1,83 @@
+use crate::{options::Dedup, shared_types::PointWithHeight};
+use spade::Point2;
+use std::collections::BTreeMap;
+
+/// Merges the points of every cell of the grid of `cell_size` meters, e.g. of the overlapping flight lines,
+/// to the lowest one or to the one of the mean position and height
+pub fn dedup(points: Vec<PointWithHeight>, cell_size: f64, dedup: Dedup) -> Vec<PointWithHeight> {
+    // lowest point, sums of x, y and height and the count
+    let mut cells = BTreeMap::<_, (PointWithHeight, f64, f64, f64, usize)>::new();
+
+    for point in points {
+        let cell = (
+            (point.position.x / cell_size).floor() as i64,
+            (point.position.y / cell_size).floor() as i64,
+        );
+
+        let (lowest, x, y, height, count) = cells.entry(cell).or_insert((point, 0.0, 0.0, 0.0, 0));
+
+        if point.height < lowest.height {
+            *lowest = point;
+        }
+
+        *x += point.position.x;
+
+        *y += point.position.y;
+
+        *height += point.height;
+
+        *count += 1;
+    }
+
+    cells
+        .into_values()
+        .map(|(lowest, x, y, height, count)| match dedup {
+            Dedup::Mean => {
+                let count = count as f64;
+
+                PointWithHeight {
+                    position: Point2::new(x / count, y / count),
+                    height: height / count,
+                    water: lowest.water,
+                }
+            }
+            Dedup::Min | Dedup::Off => lowest,
+        })
+        .collect()
+}
+
+/// Keeps at most `max_points` lowest points of every cell of the grid of `cell_size` meters,
+/// ties broken by the position so that the selection does not depend on the order of reading
+pub fn thin(
+    points: Vec<PointWithHeight>,
+    cell_size: f64,
+    max_points: usize,
+) -> Vec<PointWithHeight> {
+    let mut cells = BTreeMap::<_, Vec<PointWithHeight>>::new();
+
+    for point in points {
+        cells
+            .entry((
+                (point.position.x / cell_size).floor() as i64,
+                (point.position.y / cell_size).floor() as i64,
+            ))
+            .or_default()
+            .push(point);
+    }
+
+    cells
+        .into_values()
+        .flat_map(|mut points| {
+            points.sort_unstable_by(|a, b| {
+                a.height
+                    .total_cmp(&b.height)
+                    .then(a.position.x.total_cmp(&b.position.x))
+                    .then(a.position.y.total_cmp(&b.position.y))
+            });
+
+            points.truncate(max_points);
+
+            points
+        })
+        .collect()
+}
@@ -0,0 +1,68 @@
+use crate::shared_types::PointWithHeight;
+use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
+use std::collections::HashMap;
+
+/// Removes points whose height differs by more than `threshold` meters from the median height
+/// of the other points within `radius` meters. Points without neighbours are kept.
+/// Every point is judged against all the original points so the result does not depend on their order.
+pub fn despike(points: Vec<PointWithHeight>, radius: f64, threshold: f64) -> Vec<PointWithHeight> {
+    let cell = |point: &PointWithHeight| {
+        (
+            (point.position.x / radius).floor() as i64,
+            (point.position.y / radius).floor() as i64,
+        )
+    };
+
+    let mut cells = HashMap::<_, Vec<usize>>::new();
+
+    for (i, point) in points.iter().enumerate() {
+        cells.entry(cell(point)).or_default().push(i);
+    }
+
+    let radius2 = radius * radius;
+
+    let keep: Vec<_> = points
+        .par_iter()
+        .enumerate()
+        .map(|(i, point)| {
+            let (cx, cy) = cell(point);
+
+            let mut heights: Vec<_> = (cx - 1..=cx + 1)
+                .flat_map(|x| (cy - 1..=cy + 1).map(move |y| (x, y)))
+                .filter_map(|key| cells.get(&key))
+                .flatten()
+                .filter(|&&j| j != i)
+                .map(|&j| &points[j])
+                .filter(|other| {
+                    let dx = other.position.x - point.position.x;
+                    let dy = other.position.y - point.position.y;
+
+                    dx * dx + dy * dy <= radius2
+                })
+                .map(|other| other.height)
+                .collect();
+
+            if heights.is_empty() {
+                return true;
+            }
+
+            heights.sort_unstable_by(f64::total_cmp);
+
+            let middle = heights.len() / 2;
+
+            let median = if heights.len() % 2 == 0 {
+                (heights[middle - 1] + heights[middle]) / 2.0
+            } else {
+                heights[middle]
+            };
+
+            (point.height - median).abs() <= threshold
+        })
+        .collect();
+
+    points
+        .into_iter()
+        .zip(keep)
+        .filter_map(|(point, keep)| keep.then_some(point))
+        .collect()
+}
@@ -0,0 +1,106 @@
+use crate::{error::Error, options::Options, read::SourceFile, shared_types::TileMeta};
+use serde_json::json;
+use std::{collections::HashSet, fs};
+
+/// Validates the output and prints JSON of the shadings, the supertiles, the files to read and
+/// the estimated number of the output tiles
+pub fn dry_run(
+    options: &Options,
+    tile_metas: &[TileMeta],
+    files: Option<&[SourceFile]>,
+) -> Result<(), Error> {
+    validate_output(options)?;
+
+    // only the custom shadings of a library user can't be serialized
+    let shadings = serde_json::to_value(&options.shadings)
+        .map_err(|error| Error::InvalidOptions(error.to_string()))?;
+
+    let supertiles: Vec<_> = tile_metas
+        .iter()
+        .map(|tile_meta| {
+            [
+                tile_meta.tile.zoom.into(),
+                tile_meta.tile.x,
+                tile_meta.tile.y,
+            ]
+        })
+        .collect();
+
+    let mut total_size = 0;
+
+    // read per supertile from the LAZ tile DB if `None`
+    let files = files.map(|files| {
+        files
+            .iter()
+            .map(|file| {
+                let size = fs::metadata(&file.path).map_or(0, |metadata| metadata.len());
+
+                total_size += size;
+
+                json!({"path": file.path, "size": size, "points": file.point_count})
+            })
+            .collect::<Vec<_>>()
+    });
+
+    let json = json!({
+        "shadings": shadings,
+        "supertiles": supertiles,
+        "files": files,
+        "total_size": total_size,
+        "tile_count": tile_count(options, tile_metas),
+    });
+
+    println!("{json}");
+
+    Ok(())
+}
+
+/// Tiles of the supertiles and of their overviews down to `--min-zoom`
+fn tile_count(options: &Options, tile_metas: &[TileMeta]) -> usize {
+    let mut count = 0;
+
+    for zoom in options.min_zoom.max(options.unit_zoom_level)..=options.zoom_level {
+        count += tile_metas.len() << (2 * (zoom - options.unit_zoom_level));
+    }
+
+    let mut tiles: HashSet<_> = tile_metas.iter().map(|tile_meta| tile_meta.tile).collect();
+
+    for _ in options.min_zoom..options.unit_zoom_level {
+        tiles = tiles.iter().filter_map(|tile| tile.parent()).collect();
+
+        count += tiles.len();
+    }
+
+    count
+}
+
+fn validate_output(options: &Options) -> Result<(), Error> {
+    let output = &options.output;
+
+    if options.is_directory() {
+        if output.exists() && !output.is_dir() {
+            return Err(Error::InvalidOptions(format!(
+                "Output {} is not a directory",
+                output.display()
+            )));
+        }
+
+        return Ok(());
+    }
+
+    if output.exists() && options.existing_file_action.is_none() {
+        return Err(Error::InvalidOptions(
+            "Output file already exists. Specify --existing-file-action.".to_owned(),
+        ));
+    }
+
+    match output.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() && !parent.is_dir() => {
+            Err(Error::InvalidOptions(format!(
+                "Directory {} of the output does not exist",
+                parent.display()
+            )))
+        }
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,134 @@
+use crate::{options::Encoding, shading::PixelContext};
+use image::{ImageBuffer, Rgba, RgbaImage};
+
+/// Encodes the elevation of the pixel; pixels without data get `nodata_elevation` or are transparent
+pub fn encode_elevation(
+    pixel: &PixelContext,
+    encoding: Encoding,
+    nodata_elevation: Option<f64>,
+) -> Rgba<u8> {
+    let elevation = if pixel.elevation.is_nan() {
+        nodata_elevation
+    } else {
+        Some(pixel.elevation)
+    };
+
+    match elevation {
+        Some(elevation) => {
+            let [r, g, b] = encode(encoding, elevation);
+
+            Rgba([r, g, b, 255])
+        }
+        None => Rgba([0, 0, 0, 0]),
+    }
+}
+
+/// Encodes elevation in meters to RGB, clamping it to the range of the encoding
+pub fn encode(encoding: Encoding, elevation: f64) -> [u8; 3] {
+    match encoding {
+        Encoding::TerrainRgb => {
+            let value = ((elevation + 10_000.0) * 10.0)
+                .round()
+                .clamp(0.0, f64::from(0xFF_FF_FF)) as u32;
+
+            [(value >> 16) as u8, (value >> 8) as u8, value as u8]
+        }
+        Encoding::Terrarium => {
+            let value = ((elevation + 32_768.0) * 256.0)
+                .round()
+                .clamp(0.0, f64::from(0xFF_FF_FF)) as u32;
+
+            [(value >> 16) as u8, (value >> 8) as u8, value as u8]
+        }
+    }
+}
+
+/// Decodes elevation in meters from RGB
+pub fn decode(encoding: Encoding, [r, g, b]: [u8; 3]) -> f64 {
+    let value = f64::from(u32::from(r) << 16 | u32::from(g) << 8 | u32::from(b));
+
+    match encoding {
+        Encoding::TerrainRgb => value / 10.0 - 10_000.0,
+        Encoding::Terrarium => value / 256.0 - 32_768.0,
+    }
+}
+
+/// Composes encoded child tiles (by index in `Tile::children`) and downsamples them to the tile size
+/// by averaging decoded elevations of every 2x2 block; transparent pixels are skipped.
+pub fn compose_elevation_overview(
+    children: impl Iterator<Item = (usize, RgbaImage)>,
+    tile_size: u32,
+    encoding: Encoding,
+) -> RgbaImage {
+    let half = tile_size / 2;
+
+    let mut img = ImageBuffer::new(tile_size, tile_size);
+
+    for (i, child) in children {
+        let offset_x = (i & 1) as u32 * half;
+        let offset_y = (i >> 1) as u32 * half;
+
+        for y in 0..half {
+            for x in 0..half {
+                let (sum, count) = [(0, 0), (1, 0), (0, 1), (1, 1)]
+                    .into_iter()
+                    .map(|(dx, dy)| child.get_pixel(x * 2 + dx, y * 2 + dy))
+                    .filter(|pixel| pixel[3] > 0)
+                    .fold((0.0, 0), |(sum, count), pixel| {
+                        (
+                            sum + decode(encoding, [pixel[0], pixel[1], pixel[2]]),
+                            count + 1,
+                        )
+                    });
+
+                if count > 0 {
+                    let [r, g, b] = encode(encoding, sum / f64::from(count));
+
+                    img.put_pixel(offset_x + x, offset_y + y, Rgba([r, g, b, 255]));
+                }
+            }
+        }
+    }
+
+    img
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_the_elevation_of_the_encodings() {
+        assert_eq!(encode(Encoding::TerrainRgb, 0.0), [0x01, 0x86, 0xA0]);
+        assert_eq!(encode(Encoding::Terrarium, 0.0), [0x80, 0x00, 0x00]);
+
+        for encoding in [Encoding::TerrainRgb, Encoding::Terrarium] {
+            assert_eq!(decode(encoding, encode(encoding, 1234.5)), 1234.5);
+
+            // below the range of the encoding
+            assert_eq!(encode(encoding, -40_000.0), [0, 0, 0]);
+        }
+    }
+
+    #[test]
+    fn overview_averages_the_decoded_elevations_of_the_data_pixels() {
+        let mut child = RgbaImage::new(2, 2);
+
+        for (x, elevation) in [(0, 100.0), (1, 200.0)] {
+            let [r, g, b] = encode(Encoding::TerrainRgb, elevation);
+
+            child.put_pixel(x, 0, Rgba([r, g, b, 255]));
+        }
+
+        let overview =
+            compose_elevation_overview([(0, child)].into_iter(), 2, Encoding::TerrainRgb);
+
+        let Rgba([r, g, b, a]) = *overview.get_pixel(0, 0);
+
+        assert_eq!(a, 255);
+        assert_eq!(decode(Encoding::TerrainRgb, [r, g, b]), 150.0);
+
+        // of no child
+        assert_eq!(overview.get_pixel(1, 1)[3], 0);
+    }
+}
@@ -0,0 +1,84 @@
+use std::{
+    error,
+    fmt::{self, Debug, Display, Formatter},
+    io,
+};
+
+/// Error of reading the points or of writing the outputs
+pub enum Error {
+    Io {
+        path: String,
+        source: io::Error,
+    },
+    Write {
+        path: String,
+        source: io::Error,
+    },
+    #[cfg(feature = "native")]
+    Las {
+        path: String,
+        source: las::Error,
+    },
+    #[cfg(feature = "native")]
+    Sqlite {
+        path: String,
+        source: rusqlite::Error,
+    },
+    /// Of `--serve`
+    #[cfg(feature = "native")]
+    Listen {
+        address: String,
+        source: io::Error,
+    },
+    Proj {
+        /// What was being transformed
+        context: String,
+        source: Box<dyn error::Error + Send + Sync>,
+    },
+    InvalidOptions(String),
+    /// Stopped by the cancellation flag of `render`
+    Cancelled,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io { path, source } => write!(f, "Failed to read {path}: {source}"),
+            Error::Write { path, source } => write!(f, "Failed to write {path}: {source}"),
+            #[cfg(feature = "native")]
+            Error::Las { path, source } => write!(f, "Failed to read LAS file {path}: {source}"),
+            #[cfg(feature = "native")]
+            Error::Sqlite { path, source } => write!(f, "Failed to query DB {path}: {source}"),
+            #[cfg(feature = "native")]
+            Error::Listen { address, source } => {
+                write!(f, "Failed to listen on {address}: {source}")
+            }
+            Error::Proj { context, source } => write!(f, "PROJ failed {context}: {source}"),
+            Error::InvalidOptions(message) => write!(f, "{message}"),
+            Error::Cancelled => write!(f, "Cancelled"),
+        }
+    }
+}
+
+// returned from `main` which prints the error with `Debug`
+impl Debug for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } | Error::Write { source, .. } => Some(source),
+            #[cfg(feature = "native")]
+            Error::Las { source, .. } => Some(source),
+            #[cfg(feature = "native")]
+            Error::Sqlite { source, .. } => Some(source),
+            #[cfg(feature = "native")]
+            Error::Listen { source, .. } => Some(source),
+            Error::Proj { source, .. } => Some(source.as_ref()),
+            Error::InvalidOptions(_) | Error::Cancelled => None,
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use crate::{
+    options::Options,
+    shading::hillshade,
+    terrain::{Elevations, Grid, Rasters},
+};
+use std::{
+    any::Any,
+    cell::RefCell,
+    ffi::{CStr, CString, c_char, c_int},
+    panic::{AssertUnwindSafe, catch_unwind},
+    ptr, slice,
+};
+
+/// Success
+pub const LAZ2DEM_OK: c_int = 0;
+
+/// Null pointer, empty grid or too small output buffer
+pub const LAZ2DEM_INVALID_ARGUMENT: c_int = 1;
+
+/// Invalid JSON or options of the config
+pub const LAZ2DEM_INVALID_CONFIG: c_int = 2;
+
+/// Panic caught at the boundary
+pub const LAZ2DEM_PANIC: c_int = 3;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Opaque options of the shading
+pub struct Laz2demConfig(Options);
+
+/// Runs the body of an exported function, keeping the message of its error or panic for `laz2dem_last_error`
+fn guard<T>(body: impl FnOnce() -> Result<T, (c_int, String)>) -> Result<T, c_int> {
+    let (code, message) = match catch_unwind(AssertUnwindSafe(body)) {
+        Ok(Ok(value)) => return Ok(value),
+        Ok(Err(error)) => error,
+        Err(panic) => (LAZ2DEM_PANIC, panic_message(panic.as_ref())),
+    };
+
+    LAST_ERROR.with_borrow_mut(|last_error| {
+        *last_error = Some(CString::new(message.replace('\0', " ")).unwrap_or_default());
+    });
+
+    Err(code)
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    panic
+        .downcast_ref::<&str>()
+        .map(|message| (*message).to_owned())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "panic".to_owned())
+}
+
+fn invalid_argument(message: &str) -> (c_int, String) {
+    (LAZ2DEM_INVALID_ARGUMENT, message.to_owned())
+}
+
+/// Config of the NUL-terminated JSON of `Options::from_json`, or null on error.
+/// Release it with `laz2dem_config_free`.
+///
+/// # Safety
+///
+/// `json` must be null or point to a NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn laz2dem_config_new(json: *const c_char) -> *mut Laz2demConfig {
+    guard(|| {
+        if json.is_null() {
+            return Err(invalid_argument("json is null"));
+        }
+
+        let json = unsafe { CStr::from_ptr(json) }
+            .to_str()
+            .map_err(|_| invalid_argument("json is not UTF-8"))?;
+
+        Options::from_json(json)
+            .map(|options| Box::into_raw(Box::new(Laz2demConfig(options))))
+            .map_err(|error| (LAZ2DEM_INVALID_CONFIG, error.to_string()))
+    })
+    .unwrap_or(ptr::null_mut())
+}
+
+/// Releases the config; null is ignored.
+///
+/// # Safety
+///
+/// `config` must be null or returned by `laz2dem_config_new` and not released yet.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn laz2dem_config_free(config: *mut Laz2demConfig) {
+    if !config.is_null() {
+        drop(unsafe { Box::from_raw(config) });
+    }
+}
+
+/// Shades the `rows` x `cols` elevations in meters, rows from the north and NaN for no data, of pixels of
+/// `cell_size` ground meters as `--mode hillshade` does, with the z-factor overriding the one of the config.
+/// Writes the RGBA pixels of the same order to the output of at least `rows * cols * 4` bytes.
+///
+/// # Safety
+///
+/// `config` must be returned by `laz2dem_config_new`, `elevations` must point to `rows * cols` doubles
+/// and `output` to `output_len` bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn laz2dem_shade(
+    config: *const Laz2demConfig,
+    elevations: *const f64,
+    rows: usize,
+    cols: usize,
+    cell_size: f64,
+    z_factor: f64,
+    output: *mut u8,
+    output_len: usize,
+) -> c_int {
+    guard(|| {
+        if config.is_null() || elevations.is_null() || output.is_null() {
+            return Err(invalid_argument("null pointer"));
+        }
+
+        let len = rows
+            .checked_mul(cols)
+            .filter(|len| *len > 0)
+            .ok_or_else(|| invalid_argument("empty or too large grid"))?;
+
+        if output_len / 4 < len {
+            return Err(invalid_argument(
+                "output is smaller than rows * cols * 4 bytes",
+            ));
+        }
+
+        let mut options = unsafe { &*config }.0.clone();
+
+        options.z_factor = z_factor;
+
+        let elevations = unsafe { slice::from_raw_parts(elevations, len) };
+
+        // row 0 of the grid is the southmost one
+        let elevations =
+            Elevations::F64(elevations.chunks(cols).rev().flatten().copied().collect());
+
+        let grid = Grid {
+            elevations: &elevations,
+            rows,
+            cols,
+            cell_size,
+            mercator_y: None,
+        };
+
+        let rasters = Rasters::new(&grid, options.z_factor, &options.shadings.0);
+
+        let img = hillshade(&options, &grid, &rasters);
+
+        unsafe { slice::from_raw_parts_mut(output, len * 4) }.copy_from_slice(&img);
+
+        Ok(LAZ2DEM_OK)
+    })
+    .unwrap_or_else(|code| code)
+}
+
+/// Message of the last error in the thread, or null. It is valid until the next error in the thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn laz2dem_last_error() -> *const c_char {
+    LAST_ERROR.with_borrow(|last_error| {
+        last_error
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
@@ -0,0 +1,172 @@
+use crate::terrain::Grid;
+use image::RgbaImage;
+#[cfg(feature = "native")]
+use rayon::{
+    iter::{IndexedParallelIterator, ParallelIterator},
+    slice::ParallelSliceMut,
+};
+
+/// Computes the rows of `cols` values by their index, in parallel by rayon with the feature `native`
+pub fn for_each_row<T, F>(values: &mut [T], cols: usize, compute_row: F)
+where
+    T: Send,
+    F: Fn((usize, &mut [T])) + Send + Sync,
+{
+    #[cfg(feature = "native")]
+    values
+        .par_chunks_mut(cols)
+        .enumerate()
+        .for_each(compute_row);
+
+    #[cfg(not(feature = "native"))]
+    values.chunks_mut(cols).enumerate().for_each(compute_row);
+}
+
+/// Mean of the valid elevations in the square window of `radius` pixels around every pixel.
+/// Being separable, it sums rows first and then columns of the row sums. The window is cut by the edges of the grid.
+pub fn mean_filter(grid: &Grid, radius: usize) -> Vec<f64> {
+    let Grid { rows, cols, .. } = *grid;
+
+    let mut row_sums = vec![(0.0, 0u32); rows * cols];
+
+    for_each_row(&mut row_sums, cols, |(y, row)| {
+        for (x, sum) in row.iter_mut().enumerate() {
+            *sum = (x.saturating_sub(radius)..(x + radius + 1).min(cols))
+                .map(|x| grid.elevation(y * cols + x))
+                .filter(|value| !value.is_nan())
+                .fold((0.0, 0), |(sum, count), value| (sum + value, count + 1));
+        }
+    });
+
+    let mut mean = vec![f64::NAN; rows * cols];
+
+    for_each_row(&mut mean, cols, |(y, row)| {
+        for (x, value) in row.iter_mut().enumerate() {
+            let (sum, count) = (y.saturating_sub(radius)..(y + radius + 1).min(rows))
+                .map(|y| row_sums[y * cols + x])
+                .fold((0.0, 0), |(sum, count), (row_sum, row_count)| {
+                    (sum + row_sum, count + row_count)
+                });
+
+            if count > 0 {
+                *value = sum / f64::from(count);
+            }
+        }
+    });
+
+    mean
+}
+
+/// Gaussian blur of the raster with the standard deviation of `sigma` pixels, ignoring NaN values.
+/// The kernel spans three standard deviations and is cut by the edges of the raster; sigma 0.0 keeps the raster.
+pub fn gaussian_blur<F>(sample: F, rows: usize, cols: usize, sigma: f64) -> Vec<f64>
+where
+    F: Fn(usize) -> f64 + Sync,
+{
+    if sigma <= 0.0 {
+        return (0..rows * cols).map(sample).collect();
+    }
+
+    let radius = (sigma * 3.0).ceil() as usize;
+
+    let kernel: Vec<_> = (0..=radius)
+        .map(|i| (-((i * i) as f64) / (2.0 * sigma * sigma)).exp())
+        .collect();
+
+    let blur = |line: &dyn Fn(usize) -> f64, i: usize, len: usize| {
+        let (sum, weights) = (i.saturating_sub(radius)..(i + radius + 1).min(len))
+            .map(|j| (line(j), kernel[i.abs_diff(j)]))
+            .filter(|(value, _)| !value.is_nan())
+            .fold((0.0, 0.0), |(sum, weights), (value, weight)| {
+                (sum + value * weight, weights + weight)
+            });
+
+        if weights > 0.0 {
+            sum / weights
+        } else {
+            f64::NAN
+        }
+    };
+
+    let mut horizontal = vec![0.0; rows * cols];
+
+    for_each_row(&mut horizontal, cols, |(y, row)| {
+        for (x, value) in row.iter_mut().enumerate() {
+            *value = blur(&|x| sample(y * cols + x), x, cols);
+        }
+    });
+
+    let mut blurred = vec![0.0; rows * cols];
+
+    for_each_row(&mut blurred, cols, |(y, row)| {
+        for (x, value) in row.iter_mut().enumerate() {
+            *value = blur(&|y| horizontal[y * cols + x], y, rows);
+        }
+    });
+
+    blurred
+}
+
+/// Boosts local contrast by adding `amount` times the difference of the luminance from its gaussian blur
+/// to every color channel, clamping the result
+pub fn unsharp_mask(img: &mut RgbaImage, radius: f64, amount: f64) {
+    let (width, height) = img.dimensions();
+
+    let luminance: Vec<_> = img
+        .pixels()
+        .map(|pixel| {
+            0.2126 * f64::from(pixel[0])
+                + 0.7152 * f64::from(pixel[1])
+                + 0.0722 * f64::from(pixel[2])
+        })
+        .collect();
+
+    let blurred = gaussian_blur(
+        |index| luminance[index],
+        height as usize,
+        width as usize,
+        radius,
+    );
+
+    for ((pixel, luminance), blurred) in img.pixels_mut().zip(luminance).zip(blurred) {
+        let delta = amount * (luminance - blurred);
+
+        for channel in &mut pixel.0[..3] {
+            *channel = (f64::from(*channel) + delta).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gaussian_blur_of_zero_sigma_keeps_the_raster() {
+        let raster = [1.0, 5.0, f64::NAN, 2.0];
+
+        let blurred = gaussian_blur(|index| raster[index], 2, 2, 0.0);
+
+        assert_eq!(blurred[..2], raster[..2]);
+        assert!(blurred[2].is_nan());
+    }
+
+    #[test]
+    fn gaussian_blur_of_large_sigma_flattens_the_step_over_the_missing_values() {
+        // step from 0.0 to 10.0 in the middle of the rows, with a column of missing values
+        let raster: Vec<_> = (0..16 * 16)
+            .map(|index| match index % 16 {
+                5 => f64::NAN,
+                x if x < 8 => 0.0,
+                _ => 10.0,
+            })
+            .collect();
+
+        let blurred = gaussian_blur(|index| raster[index], 16, 16, 100.0);
+
+        for value in blurred {
+            // weights renormalized over the valid values, nearly uniform for such sigma
+            assert!((value - 8.0 * 10.0 / 15.0).abs() < 0.1, "{value}");
+        }
+    }
+}
@@ -0,0 +1,426 @@
+use crate::{error::Error, journal::write_atomically};
+use flate2::{Compression, write::ZlibEncoder};
+use image::DynamicImage;
+use std::{io::Write, path::Path};
+
+const TILE_SIZE: u32 = 256;
+
+/// Sample type of the TIFF raster
+pub trait Sample: Copy + Default {
+    const BITS: u16;
+
+    /// 1 for unsigned integer, 3 for floating point
+    const FORMAT: u16;
+
+    fn to_f64(self) -> f64;
+
+    fn from_f64(value: f64) -> Self;
+
+    fn write_le(self, data: &mut Vec<u8>);
+}
+
+impl Sample for u8 {
+    const BITS: u16 = 8;
+
+    const FORMAT: u16 = 1;
+
+    fn to_f64(self) -> f64 {
+        f64::from(self)
+    }
+
+    fn from_f64(value: f64) -> Self {
+        value.round() as u8
+    }
+
+    fn write_le(self, data: &mut Vec<u8>) {
+        data.push(self);
+    }
+}
+
+impl Sample for u16 {
+    const BITS: u16 = 16;
+
+    const FORMAT: u16 = 1;
+
+    fn to_f64(self) -> f64 {
+        f64::from(self)
+    }
+
+    fn from_f64(value: f64) -> Self {
+        value.round() as u16
+    }
+
+    fn write_le(self, data: &mut Vec<u8>) {
+        data.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl Sample for f32 {
+    const BITS: u16 = 32;
+
+    const FORMAT: u16 = 3;
+
+    fn to_f64(self) -> f64 {
+        f64::from(self)
+    }
+
+    fn from_f64(value: f64) -> Self {
+        value as f32
+    }
+
+    fn write_le(self, data: &mut Vec<u8>) {
+        data.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+/// Pixel interleaved raster with rows from north to south.
+/// 1 channel is gray, 2 gray with alpha, 3 RGB and 4 RGB with alpha.
+pub struct Raster<S> {
+    pub width: u32,
+    pub height: u32,
+    pub channels: u16,
+    pub data: Vec<S>,
+}
+
+impl<S: Sample> Raster<S> {
+    /// Raster of the half size averaging the pixels, ignoring NaNs
+    fn downsample(&self) -> Self {
+        let width = self.width.div_ceil(2);
+
+        let height = self.height.div_ceil(2);
+
+        let channels = self.channels as usize;
+
+        let mut data = Vec::with_capacity(width as usize * height as usize * channels);
+
+        for y in 0..height {
+            for x in 0..width {
+                for channel in 0..channels {
+                    let (sum, count) = [(0, 0), (1, 0), (0, 1), (1, 1)]
+                        .into_iter()
+                        .map(|(dx, dy)| (x * 2 + dx, y * 2 + dy))
+                        .filter(|&(x, y)| x < self.width && y < self.height)
+                        .map(|(x, y)| {
+                            self.data[(y as usize * self.width as usize + x as usize) * channels
+                                + channel]
+                                .to_f64()
+                        })
+                        .filter(|value| !value.is_nan())
+                        .fold((0.0, 0), |(sum, count), value| (sum + value, count + 1));
+
+                    data.push(S::from_f64(if count == 0 {
+                        f64::NAN
+                    } else {
+                        sum / count as f64
+                    }));
+                }
+            }
+        }
+
+        Self {
+            width,
+            height,
+            channels: self.channels,
+            data,
+        }
+    }
+
+    /// DEFLATE compressed tiles in the row-major order, padded to the full tile size
+    fn tiles(&self) -> Vec<Vec<u8>> {
+        let channels = self.channels as usize;
+
+        let mut tiles = Vec::new();
+
+        for tile_y in 0..self.height.div_ceil(TILE_SIZE) {
+            for tile_x in 0..self.width.div_ceil(TILE_SIZE) {
+                let mut data = Vec::new();
+
+                for y in tile_y * TILE_SIZE..(tile_y + 1) * TILE_SIZE {
+                    for x in tile_x * TILE_SIZE..(tile_x + 1) * TILE_SIZE {
+                        for channel in 0..channels {
+                            if x < self.width && y < self.height {
+                                self.data[(y as usize * self.width as usize + x as usize)
+                                    * channels
+                                    + channel]
+                                    .write_le(&mut data);
+                            } else {
+                                S::default().write_le(&mut data);
+                            }
+                        }
+                    }
+                }
+
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+
+                encoder.write_all(&data).unwrap();
+
+                tiles.push(encoder.finish().unwrap());
+            }
+        }
+
+        tiles
+    }
+}
+
+/// Location of the raster in EPSG:3857
+pub struct GeoReference {
+    pub min_x: f64,
+    pub max_y: f64,
+    /// In meters
+    pub pixel_size: f64,
+}
+
+/// IFD entry with the little endian values
+struct Entry {
+    tag: u16,
+    /// 2 ASCII, 3 SHORT, 4 LONG, 12 DOUBLE
+    field_type: u16,
+    count: u32,
+    values: Vec<u8>,
+}
+
+impl Entry {
+    fn shorts(tag: u16, values: &[u16]) -> Self {
+        Self {
+            tag,
+            field_type: 3,
+            count: values.len() as u32,
+            values: values
+                .iter()
+                .flat_map(|value| value.to_le_bytes())
+                .collect(),
+        }
+    }
+
+    fn longs(tag: u16, values: &[u32]) -> Self {
+        Self {
+            tag,
+            field_type: 4,
+            count: values.len() as u32,
+            values: values
+                .iter()
+                .flat_map(|value| value.to_le_bytes())
+                .collect(),
+        }
+    }
+
+    fn doubles(tag: u16, values: &[f64]) -> Self {
+        Self {
+            tag,
+            field_type: 12,
+            count: values.len() as u32,
+            values: values
+                .iter()
+                .flat_map(|value| value.to_le_bytes())
+                .collect(),
+        }
+    }
+
+    fn ascii(tag: u16, value: &str) -> Self {
+        let mut values = value.as_bytes().to_vec();
+
+        values.push(0);
+
+        Self {
+            tag,
+            field_type: 2,
+            count: values.len() as u32,
+            values,
+        }
+    }
+}
+
+/// Writes tiled DEFLATE compressed GeoTIFF in EPSG:3857 with the overviews down to the tile size
+pub fn write_geotiff<S: Sample>(
+    path: &Path,
+    raster: Raster<S>,
+    geo_reference: &GeoReference,
+    nodata: Option<f64>,
+) -> Result<(), Error> {
+    let mut levels = vec![raster];
+
+    while levels
+        .last()
+        .is_some_and(|level| level.width.max(level.height) > TILE_SIZE)
+    {
+        let overview = levels.last().unwrap().downsample();
+
+        levels.push(overview);
+    }
+
+    // little endian, offset of the first IFD patched later
+    let mut file = b"II\x2a\x00\0\0\0\0".to_vec();
+
+    let mut next_ifd_offset_position = 4;
+
+    for (level, raster) in levels.iter().enumerate() {
+        let mut tile_offsets = Vec::new();
+
+        let mut tile_byte_counts = Vec::new();
+
+        for tile in raster.tiles() {
+            tile_offsets.push(file.len() as u32);
+
+            tile_byte_counts.push(tile.len() as u32);
+
+            file.extend_from_slice(&tile);
+        }
+
+        let channels = raster.channels as usize;
+
+        let mut entries = vec![
+            // reduced resolution image
+            Entry::longs(254, &[u32::from(level > 0)]),
+            Entry::longs(256, &[raster.width]),
+            Entry::longs(257, &[raster.height]),
+            Entry::shorts(258, &vec![S::BITS; channels]),
+            // Adobe DEFLATE
+            Entry::shorts(259, &[8]),
+            // BlackIsZero or RGB
+            Entry::shorts(262, &[if channels < 3 { 1 } else { 2 }]),
+            Entry::shorts(277, &[raster.channels]),
+            // chunky
+            Entry::shorts(284, &[1]),
+            Entry::longs(322, &[TILE_SIZE]),
+            Entry::longs(323, &[TILE_SIZE]),
+            Entry::longs(324, &tile_offsets),
+            Entry::longs(325, &tile_byte_counts),
+        ];
+
+        if channels == 2 || channels == 4 {
+            // unassociated alpha
+            entries.push(Entry::shorts(338, &[2]));
+        }
+
+        entries.push(Entry::shorts(339, &vec![S::FORMAT; channels]));
+
+        if level == 0 {
+            // ModelPixelScaleTag
+            entries.push(Entry::doubles(
+                33550,
+                &[geo_reference.pixel_size, geo_reference.pixel_size, 0.0],
+            ));
+
+            // ModelTiepointTag of the top left corner
+            entries.push(Entry::doubles(
+                33922,
+                &[0.0, 0.0, 0.0, geo_reference.min_x, geo_reference.max_y, 0.0],
+            ));
+
+            // GeoKeyDirectoryTag: projected model, pixel is area, EPSG:3857
+            entries.push(Entry::shorts(
+                34735,
+                &[1, 1, 0, 3, 1024, 0, 1, 1, 1025, 0, 1, 1, 3072, 0, 1, 3857],
+            ));
+        }
+
+        if let Some(nodata) = nodata {
+            // GDAL_NODATA
+            entries.push(Entry::ascii(42113, &nodata.to_string().to_lowercase()));
+        }
+
+        if file.len() % 2 == 1 {
+            file.push(0);
+        }
+
+        let ifd_offset = file.len();
+
+        file[next_ifd_offset_position..next_ifd_offset_position + 4]
+            .copy_from_slice(&(ifd_offset as u32).to_le_bytes());
+
+        // values not fitting the entries follow the IFD
+        let mut values_offset = ifd_offset + 2 + entries.len() * 12 + 4;
+
+        let mut values = Vec::new();
+
+        file.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+
+        for entry in &entries {
+            file.extend_from_slice(&entry.tag.to_le_bytes());
+
+            file.extend_from_slice(&entry.field_type.to_le_bytes());
+
+            file.extend_from_slice(&entry.count.to_le_bytes());
+
+            if entry.values.len() <= 4 {
+                let mut inline = entry.values.clone();
+
+                inline.resize(4, 0);
+
+                file.extend_from_slice(&inline);
+            } else {
+                file.extend_from_slice(&(values_offset as u32).to_le_bytes());
+
+                values.extend_from_slice(&entry.values);
+
+                if values.len() % 2 == 1 {
+                    values.push(0);
+                }
+
+                values_offset = ifd_offset + 2 + entries.len() * 12 + 4 + values.len();
+            }
+        }
+
+        next_ifd_offset_position = file.len();
+
+        // no next IFD unless patched
+        file.extend_from_slice(&[0; 4]);
+
+        file.extend_from_slice(&values);
+    }
+
+    write_atomically(path, file)
+}
+
+/// Writes the 8 or 16 bit image, other ones as 8 bit RGBA
+pub fn write_image_geotiff(
+    path: &Path,
+    img: &DynamicImage,
+    geo_reference: &GeoReference,
+) -> Result<(), Error> {
+    match img {
+        DynamicImage::ImageLuma8(_)
+        | DynamicImage::ImageLumaA8(_)
+        | DynamicImage::ImageRgb8(_)
+        | DynamicImage::ImageRgba8(_) => write_geotiff(
+            path,
+            image_raster(img, img.as_bytes().to_vec()),
+            geo_reference,
+            None,
+        ),
+        DynamicImage::ImageLuma16(buffer) => write_geotiff(
+            path,
+            image_raster(img, buffer.as_raw().clone()),
+            geo_reference,
+            None,
+        ),
+        DynamicImage::ImageLumaA16(buffer) => write_geotiff(
+            path,
+            image_raster(img, buffer.as_raw().clone()),
+            geo_reference,
+            None,
+        ),
+        DynamicImage::ImageRgb16(buffer) => write_geotiff(
+            path,
+            image_raster(img, buffer.as_raw().clone()),
+            geo_reference,
+            None,
+        ),
+        DynamicImage::ImageRgba16(buffer) => write_geotiff(
+            path,
+            image_raster(img, buffer.as_raw().clone()),
+            geo_reference,
+            None,
+        ),
+        _ => write_image_geotiff(path, &DynamicImage::from(img.to_rgba8()), geo_reference),
+    }
+}
+
+fn image_raster<S>(img: &DynamicImage, data: Vec<S>) -> Raster<S> {
+    Raster {
+        width: img.width(),
+        height: img.height(),
+        channels: u16::from(img.color().channel_count()),
+        data,
+    }
+}
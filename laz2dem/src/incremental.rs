@@ -0,0 +1,192 @@
+use crate::{
+    error::Error,
+    options::Options,
+    read::{new_proj, project_bounds},
+};
+use maptile::bbox::BBox;
+use rusqlite::{Connection, Row};
+use std::path::{Path, PathBuf, absolute};
+
+/// State of the files of the LAZ index when the output was last regenerated
+const CREATE_STATE_SQL: &str = "CREATE TABLE IF NOT EXISTS laz2dem_state (output VARCHAR, file VARCHAR, size INTEGER, mtime INTEGER, min_x NUMBER, min_y NUMBER, max_x NUMBER, max_y NUMBER, PRIMARY KEY (output, file))";
+
+/// Indexed files added or changed since the last regeneration, with the previous bounds if changed
+const CHANGED_SQL: &str = "SELECT i.file, i.size, i.mtime, i.min_x, i.min_y, i.max_x, i.max_y, {crs}, s.min_x, s.min_y, s.max_x, s.max_y FROM laz_index i LEFT JOIN laz2dem_state s ON s.output = ?1 AND s.file = i.file WHERE s.file IS NULL OR s.size IS NOT i.size OR s.mtime IS NOT i.mtime";
+
+const REMOVED_SQL: &str = "SELECT file, min_x, min_y, max_x, max_y FROM laz2dem_state s WHERE output = ?1 AND NOT EXISTS (SELECT 1 FROM laz_index WHERE file = s.file)";
+
+/// Changes of the LAZ index since the last regeneration of the output
+pub struct Changes {
+    index_path: PathBuf,
+    output: String,
+    /// Bounds in EPSG:3857 of the added, changed and removed files, the previous ones too
+    bounds: Vec<BBox>,
+    /// Added and changed files with the size, mtime and bounds in EPSG:3857
+    changed: Vec<(String, Option<i64>, Option<i64>, BBox)>,
+    removed: Vec<String>,
+}
+
+impl Changes {
+    /// Compares the files of the LAZ index DB to the state of the output stored in it
+    pub fn read(options: &Options, index_path: &Path) -> Result<Self, Error> {
+        let sqlite = |source| Error::Sqlite {
+            path: index_path.display().to_string(),
+            source,
+        };
+
+        let conn = Connection::open(index_path).map_err(sqlite)?;
+
+        conn.execute(CREATE_STATE_SQL, ()).map_err(sqlite)?;
+
+        if conn.prepare("SELECT mtime FROM laz_index LIMIT 0").is_err() {
+            return Err(Error::InvalidOptions(
+                "Incremental regeneration requires the LAZ index DB written by the current lazindex"
+                    .to_owned(),
+            ));
+        }
+
+        let crs = if conn.prepare("SELECT crs FROM laz_index LIMIT 0").is_ok() {
+            "i.crs"
+        } else {
+            "NULL"
+        };
+
+        let output = output_key(options);
+
+        let bbox = |row: &Row, i| -> rusqlite::Result<_> {
+            Ok(BBox::new(
+                row.get(i)?,
+                row.get(i + 1)?,
+                row.get(i + 2)?,
+                row.get(i + 3)?,
+            ))
+        };
+
+        let mut bounds = Vec::new();
+
+        let mut changed = Vec::new();
+
+        let mut stmt = conn
+            .prepare(&CHANGED_SQL.replace("{crs}", crs))
+            .map_err(sqlite)?;
+
+        let mut rows = stmt.query((&output,)).map_err(sqlite)?;
+
+        while let Some(row) = rows.next().map_err(sqlite)? {
+            let file: String = row.get(0).map_err(sqlite)?;
+
+            let crs = row
+                .get::<_, Option<String>>(7)
+                .map_err(sqlite)?
+                .or_else(|| options.source_projection.clone())
+                .ok_or_else(|| {
+                    Error::InvalidOptions(format!(
+                        "Unknown CRS of the changed file {file}; use --source-projection"
+                    ))
+                })?;
+
+            let proj = Some(crs)
+                .filter(|crs| crs != "EPSG:3857")
+                .map(|crs| new_proj(&crs, "EPSG:3857"))
+                .transpose()?;
+
+            let file_bounds = project_bounds(proj.as_ref(), bbox(row, 3).map_err(sqlite)?, &file)?;
+
+            bounds.push(file_bounds);
+
+            // previous bounds if the file was already rendered
+            if row.get::<_, Option<f64>>(8).map_err(sqlite)?.is_some() {
+                bounds.push(bbox(row, 8).map_err(sqlite)?);
+            }
+
+            changed.push((
+                file,
+                row.get(1).map_err(sqlite)?,
+                row.get(2).map_err(sqlite)?,
+                file_bounds,
+            ));
+        }
+
+        let mut removed = Vec::new();
+
+        let mut stmt = conn.prepare(REMOVED_SQL).map_err(sqlite)?;
+
+        let mut rows = stmt.query((&output,)).map_err(sqlite)?;
+
+        while let Some(row) = rows.next().map_err(sqlite)? {
+            removed.push(row.get(0).map_err(sqlite)?);
+
+            bounds.push(bbox(row, 1).map_err(sqlite)?);
+        }
+
+        Ok(Self {
+            index_path: index_path.to_path_buf(),
+            output,
+            bounds,
+            changed,
+            removed,
+        })
+    }
+
+    /// Number of the added, changed and removed files
+    pub fn file_count(&self) -> usize {
+        self.changed.len() + self.removed.len()
+    }
+
+    pub fn is_affected(&self, bbox: &BBox) -> bool {
+        self.bounds.iter().any(|bounds| {
+            bounds.max_x >= bbox.min_x
+                && bounds.min_x <= bbox.max_x
+                && bounds.max_y >= bbox.min_y
+                && bounds.min_y <= bbox.max_y
+        })
+    }
+
+    /// Stores the state of the changed files, to be called once the affected tiles are written
+    pub fn save(&self) -> Result<(), Error> {
+        let sqlite = |source| Error::Sqlite {
+            path: self.index_path.display().to_string(),
+            source,
+        };
+
+        let mut conn = Connection::open(&self.index_path).map_err(sqlite)?;
+
+        let tx = conn.transaction().map_err(sqlite)?;
+
+        {
+            let mut stmt = tx.prepare("INSERT OR REPLACE INTO laz2dem_state (output, file, size, mtime, min_x, min_y, max_x, max_y) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)").map_err(sqlite)?;
+
+            for (file, size, mtime, bounds) in &self.changed {
+                stmt.execute((
+                    &self.output,
+                    file,
+                    size,
+                    mtime,
+                    bounds.min_x,
+                    bounds.min_y,
+                    bounds.max_x,
+                    bounds.max_y,
+                ))
+                .map_err(sqlite)?;
+            }
+
+            let mut stmt = tx
+                .prepare("DELETE FROM laz2dem_state WHERE output = ?1 AND file = ?2")
+                .map_err(sqlite)?;
+
+            for file in &self.removed {
+                stmt.execute((&self.output, file)).map_err(sqlite)?;
+            }
+        }
+
+        tx.commit().map_err(sqlite)
+    }
+}
+
+/// Output path identifying the state so that the index can be shared by more outputs
+fn output_key(options: &Options) -> String {
+    absolute(&options.output)
+        .unwrap_or_else(|_| options.output.clone())
+        .display()
+        .to_string()
+}
@@ -0,0 +1,88 @@
+use crate::error::Error;
+use maptile::tile::Tile;
+use std::{
+    collections::HashSet,
+    fs::{File, OpenOptions, read_to_string, rename, write},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+const FILE_NAME: &str = "journal";
+
+/// Append-only list of the supertiles completely written to the output directory
+pub struct Journal {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl Journal {
+    /// Appends to the journal of the previous runs if `resume`, otherwise starts a new one
+    pub fn open(dir: &Path, resume: bool) -> Result<Self, Error> {
+        let path = dir.join(FILE_NAME);
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(resume)
+            .write(true)
+            .truncate(!resume)
+            .open(&path)
+            .map_err(|source| Error::Write {
+                path: path.display().to_string(),
+                source,
+            })?;
+
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Records the supertile once all its tiles are written
+    pub fn record(&self, tile: Tile) -> Result<(), Error> {
+        // single write so that a killed run leaves at most the last line incomplete
+        self.file
+            .lock()
+            .unwrap()
+            .write_all(format!("{}/{}/{}\n", tile.zoom, tile.x, tile.y).as_bytes())
+            .map_err(|source| Error::Write {
+                path: self.path.display().to_string(),
+                source,
+            })
+    }
+}
+
+/// Zoom, x and y of the supertiles recorded by the previous runs
+pub fn recorded(dir: &Path) -> HashSet<(u8, u32, u32)> {
+    let Ok(journal) = read_to_string(dir.join(FILE_NAME)) else {
+        return HashSet::new();
+    };
+
+    journal
+        .split_inclusive('\n')
+        .filter_map(|line| {
+            // incomplete if the run was killed while writing it
+            let mut parts = line.strip_suffix('\n')?.split('/');
+
+            Some((
+                parts.next()?.parse().ok()?,
+                parts.next()?.parse().ok()?,
+                parts.next()?.parse().ok()?,
+            ))
+        })
+        .collect()
+}
+
+/// Writes the file through a temporary one so that an interrupted run doesn't leave it truncated
+pub fn write_atomically(path: &Path, data: impl AsRef<[u8]>) -> Result<(), Error> {
+    let mut temp_path = path.as_os_str().to_owned();
+
+    temp_path.push(".tmp");
+
+    write(&temp_path, data)
+        .and_then(|()| rename(&temp_path, path))
+        .map_err(|source| Error::Write {
+            path: path.display().to_string(),
+            source,
+        })
+}
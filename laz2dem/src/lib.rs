@@ -0,0 +1,139 @@
+//! Interpolation of the ground points to the DEM and its shading, as used by the `laz2dem` binary.
+//!
+//! The API covered by the semantic versioning of the crate consists of [`render`], [`render_tile`],
+//! the modules [`error`], [`options`], [`progress`], [`shading`], [`shared_types`] and [`terrain`].
+//! The other public items serve the binary and may change in any release.
+//!
+//! Without the default feature `native` only the shading compiles, e.g. for WebAssembly with the feature `wasm`,
+//! and it shades sequentially without rayon.
+
+#[cfg(feature = "native")]
+mod auto_contrast;
+#[cfg(feature = "native")]
+#[doc(hidden)]
+pub mod cli;
+#[cfg(feature = "native")]
+mod clip;
+#[cfg(feature = "native")]
+mod config;
+#[cfg(feature = "native")]
+#[doc(hidden)]
+pub mod copc;
+#[cfg(feature = "native")]
+mod debug_tile;
+#[cfg(feature = "native")]
+mod dedup;
+#[cfg(feature = "native")]
+mod despike;
+#[cfg(feature = "native")]
+mod dry_run;
+#[cfg(feature = "native")]
+mod encoding;
+pub mod error;
+#[cfg(feature = "ffi")]
+mod ffi;
+#[cfg(all(feature = "ffi", panic = "abort"))]
+compile_error!(
+    "the feature `ffi` catches the panics at the boundary, build it with `--profile ffi`"
+);
+mod filters;
+#[cfg(feature = "native")]
+mod geotiff;
+#[cfg(feature = "native")]
+mod incremental;
+#[cfg(feature = "native")]
+mod journal;
+#[cfg(feature = "native")]
+mod logger;
+pub mod options;
+#[cfg(feature = "native")]
+mod pmtiles;
+#[cfg(feature = "native")]
+pub mod progress;
+#[cfg(feature = "python")]
+mod python;
+#[cfg(feature = "native")]
+mod rasterization;
+#[cfg(feature = "native")]
+mod read;
+#[cfg(feature = "native")]
+mod remote;
+#[cfg(feature = "native")]
+mod schema;
+mod serialization;
+#[cfg(feature = "native")]
+mod serve;
+pub mod shading;
+pub mod shared_types;
+mod spill;
+#[cfg(feature = "native")]
+mod stats;
+#[cfg(feature = "native")]
+mod surface;
+pub mod terrain;
+#[cfg(feature = "native")]
+mod tile_directory;
+#[cfg(feature = "native")]
+mod timings;
+#[cfg(feature = "wasm")]
+mod wasm;
+#[cfg(feature = "native")]
+mod water;
+
+#[cfg(feature = "native")]
+pub use cli::render;
+
+#[cfg(feature = "native")]
+#[doc(hidden)]
+pub use read::detect_crs;
+
+#[cfg(feature = "native")]
+use {
+    error::Error,
+    image::RgbaImage,
+    maptile::{bbox::BBox, tile::Tile, tile::mercator_to_tile_coords},
+    options::Options,
+    rasterization::{interpolate_supertile, shade_supertile},
+    shared_types::{PointUse, TileMeta},
+    spade::Point2,
+};
+
+/// Interpolates the ground points given as `[x, y, elevation]` in EPSG:3857 meters within the bbox and shades them
+/// according to the mode and the shadings of the options. The bbox includes the `--buffer` which the image is not
+/// cropped by. `None` if there are less than `--min-points` points.
+#[cfg(feature = "native")]
+pub fn render_tile(
+    points: &[[f64; 3]],
+    bbox: BBox,
+    options: &Options,
+) -> Result<Option<RgbaImage>, Error> {
+    options.validate()?;
+
+    let (x, y) = mercator_to_tile_coords(
+        (bbox.min_x + bbox.max_x) / 2.0,
+        (bbox.min_y + bbox.max_y) / 2.0,
+        options.unit_zoom_level,
+    );
+
+    let tile_meta = TileMeta::new(
+        Tile {
+            zoom: options.unit_zoom_level,
+            x,
+            y,
+        },
+        bbox,
+    );
+
+    let point_use = PointUse {
+        terrain: true,
+        water: false,
+        surface: false,
+    };
+
+    for &[x, y, elevation] in points {
+        tile_meta.push(&point_use, Point2::new(x, y), elevation);
+    }
+
+    Ok(interpolate_supertile(options, &tile_meta)?
+        .map(|surface| shade_supertile(options, &surface).into_rgba8()))
+}
@@ -0,0 +1,64 @@
+use crate::options::{LogFormat, Options};
+use log::{LevelFilter, Log, Metadata, Record};
+use serde_json::json;
+use std::{
+    env,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Logger writing the records to stderr
+struct Logger {
+    format: LogFormat,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        match self.format {
+            LogFormat::Text => eprintln!("{} {}", record.level(), record.args()),
+            LogFormat::Json => eprintln!(
+                "{}",
+                json!({
+                    "time": SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap()
+                        .as_secs_f64(),
+                    "level": record.level(),
+                    "target": record.target(),
+                    "message": record.args().to_string(),
+                })
+            ),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Logs at `--log-level`, warnings and errors only if `--quiet`, otherwise at the level of `RUST_LOG`
+/// defaulting to `info`
+pub fn init(options: &Options) {
+    let level = options.log_level.unwrap_or_else(|| {
+        if options.quiet {
+            LevelFilter::Warn
+        } else {
+            env::var("RUST_LOG")
+                .ok()
+                .and_then(|level| level.parse().ok())
+                .unwrap_or(LevelFilter::Info)
+        }
+    });
+
+    log::set_boxed_logger(Box::new(Logger {
+        format: options.log_format,
+    }))
+    .unwrap();
+
+    log::set_max_level(level);
+}
@@ -1,25 +1,16 @@
-mod options;
-mod progress;
-mod rasterization;
-mod read;
-mod schema;
-mod shading;
-mod shared_types;
+use laz2dem::{cli, error::Error};
+use std::process;
 
-use clap::Parser;
-use options::Options;
-use rasterization::rasterize;
-use read::read;
-use shared_types::Job;
+fn main() -> Result<(), Error> {
+    match cli::run() {
+        Ok(Some(preset_list)) => {
+            print!("{preset_list}");
 
-fn main() {
-    let options = Options::parse();
-
-    let tile_metas = read(&options);
-
-    let mut jobs: Vec<_> = tile_metas.into_iter().map(Job::Rasterize).collect();
-
-    jobs.sort_by_cached_key(|job| job.tile().morton_code());
-
-    rasterize(&options, jobs);
+            Ok(())
+        }
+        Ok(None) => Ok(()),
+        // 128 + SIGINT as of a process killed by Ctrl-C
+        Err(Error::Cancelled) => process::exit(130),
+        Err(error) => Err(error),
+    }
 }
@@ -1,19 +1,56 @@
-use crate::shared_types::{Shadings, Source};
+#[cfg(feature = "native")]
+use crate::{clip::Clip, progress::ProgressHook};
+use crate::{
+    serialization,
+    shading::PRESETS,
+    shared_types::{ShadingMethod, Shadings, SlopeClassesShadingParams, Source, parse_color},
+};
 use clap::{ArgGroup, Parser, ValueEnum};
+use log::{LevelFilter, warn};
 use maptile::{bbox::BBox, constants::WEB_MERCATOR_EXTENT};
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
 use std::{
+    collections::HashSet,
+    error::Error,
+    f64::consts::PI,
     fmt::{Display, Formatter},
-    num::ParseIntError,
+    iter,
     path::PathBuf,
     str::FromStr,
+    thread::available_parallelism,
 };
 
-#[derive(Clone, Debug, Parser, PartialEq)]
-#[clap(group = ArgGroup::new("exclusive").required(true))]
+/// Options of the rendering, serialized with the keys of the long flags with `_` and the shadings as objects,
+/// missing options of the deserialized ones take their defaults.
+///
+/// # Examples
+///
+/// ```
+/// use laz2dem::options::Options;
+///
+/// let options = Options::from_json(r#"{"shadings": "igor,000000FF,315", "z_factor": 2}"#)?;
+///
+/// assert_eq!(options.z_factor, 2.0);
+/// assert_eq!(options.shadings.0.len(), 1);
+/// assert_eq!(options.zoom_level, Options::default().zoom_level);
+/// # Ok::<(), laz2dem::error::Error>(())
+/// ```
+#[derive(Clone, Debug, Parser, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+#[clap(about = None, long_about = None, group = ArgGroup::new("exclusive").required(true), group = ArgGroup::new("extent").required(true), args_override_self = true)]
 pub struct Options {
-    /// Output mbtiles file
+    /// Output mbtiles file, or PMTiles archive if it has the `.pmtiles` extension, or directory of GeoTIFFs if `--geotiff`
+    /// or of the tiles if `--tile-directory`, or the address to listen at if `--serve`
     pub output: PathBuf,
 
+    /// TOML file of the options named as the long flags with `_` (e.g. `zoom_level = 17`,
+    /// `bbox = [1900000, 6200000, 1910000, 6210000]`) and of `[[shadings]]` tables with `color`, the optional `weight`,
+    /// `blend`, `contrast` and `brightness` and the `method` of its `type` and params with angles in degrees
+    /// (e.g. `method = { type = "igor", azimuth = 315 }`); options on the command line override it
+    #[clap(long)]
+    #[serde(skip)]
+    pub config: Option<PathBuf>,
+
     /// Source as LAZ tile DB
     #[clap(long, group = "exclusive")]
     pub laz_tile_db: Option<PathBuf>,
@@ -22,32 +59,225 @@ pub struct Options {
     #[clap(long, group = "exclusive")]
     pub laz_index_db: Option<PathBuf>,
 
-    /// EPSG:3857 bounding box to render
+    /// Source as directory of *.laz files
+    #[clap(long, group = "exclusive")]
+    pub laz_dir: Option<PathBuf>,
+
+    /// Read *.laz files of `--laz-dir` also from its subdirectories
+    #[clap(long, requires = "laz_dir")]
+    pub laz_dir_recursive: bool,
+
+    /// Pattern of names of the files of `--laz-dir` with `*` for any characters and `?` for a single character
+    #[clap(long, default_value = "*.laz", requires = "laz_dir")]
+    pub laz_dir_pattern: String,
+
+    /// Directory caching the *.laz files of `--laz-index-db` referred by `http://` or `https://` URLs,
+    /// downloaded whole (also COPC files); `laz2dem-cache` in the temporary directory by default
     #[clap(long)]
+    pub cache_dir: Option<PathBuf>,
+
+    /// Size of the cache of the downloaded *.laz files in MB above which the least recently used ones are removed
+    #[clap(long, default_value_t = 10_240)]
+    pub cache_size: u64,
+
+    /// Number of retries of a failed download of a *.laz file
+    #[clap(long, default_value_t = 3)]
+    pub fetch_retries: usize,
+
+    /// EPSG:3857 bounding box to render
+    #[clap(long = "bbox", value_name = "BBOX", group = "extent")]
+    #[serde(rename = "bbox", with = "serialization::bbox")]
+    pub bbox_3857: Option<BBox>,
+
+    /// WGS84 bounding box to render as `min_lon,min_lat,max_lon,max_lat`, latitudes within the Web Mercator limits
+    #[clap(long, group = "extent")]
+    #[serde(with = "serialization::bbox")]
+    pub bbox_wgs84: Option<BBox>,
+
+    /// Tiles to render as `zoom/x1-x2/y1-y2` (inclusive ranges), e.g. `12/2240-2245/1420-1424`
+    #[clap(long, group = "extent")]
+    #[serde(deserialize_with = "serialization::flag::deserialize")]
+    pub tiles: Option<TileRange>,
+
+    /// Render only the supertile of the tile `zoom/x/y` of a zoom level from `--unit-zoom-level` to `--zoom-level`
+    /// and dump next to the output its points as CSV, its elevations, slopes and aspects in degrees as float GeoTIFFs
+    /// and its shaded image as PNG, named `<output>-<zoom>-<x>-<y>-<name>` after the supertile
+    #[clap(long, group = "extent", conflicts_with_all = ["serve", "dry_run", "resume"])]
+    #[serde(deserialize_with = "serialization::flag::deserialize")]
+    pub debug_tile: Option<TileRange>,
+
+    /// EPSG:3857 bounding box of `--bbox`, `--bbox-wgs84`, `--tiles` or `--debug-tile`
+    #[clap(skip = BBox::new(0.0, 0.0, 0.0, 0.0))]
+    #[serde(skip)]
     pub bbox: BBox,
 
-    /// Projection of points if reading from *.laz; default is EPSG:3857
-    #[clap(long, conflicts_with = "laz_tile_db")]
+    /// GeoJSON file of the Polygon or MultiPolygon to clip the output to, in WGS84 or in EPSG:3857.
+    /// Supertiles out of it are skipped, points out of it beyond the buffer are not read
+    /// and pixels out of it have no data.
+    #[clap(long)]
+    pub clip: Option<PathBuf>,
+
+    /// Polygon of `--clip`
+    #[cfg(feature = "native")]
+    #[clap(skip)]
+    #[serde(skip)]
+    pub clip_polygon: Option<Clip>,
+
+    /// Receiver of the progress of `render` instead of logging it
+    #[cfg(feature = "native")]
+    #[clap(skip)]
+    #[serde(skip)]
+    pub progress: Option<ProgressHook>,
+
+    /// Projection of points if reading from *.laz as EPSG code or PROJ definition, e.g. `EPSG:25833`.
+    /// LAZ index DB by `lazindex` stores the projection detected from every *.laz file and this one applies only to files without it.
+    /// Former LAZ index DBs without it use the projection detected from the first *.laz file if not set.
+    /// Files of `--laz-dir` use the projection detected from their header and this one if there is none.
+    #[clap(long, alias = "source-crs", conflicts_with = "laz_tile_db")]
     pub source_projection: Option<String>,
 
+    /// Surface model to render:
+    /// - `dtm` - digital terrain model interpolated from the points
+    /// - `dsm` - digital surface model of the highest first return of every pixel, including canopy and buildings;
+    ///   LAZ tile DB contains only the points kept by `laztile` (ground)
+    /// - `chm` - canopy height model, `dsm` minus `dtm` clamped to 0.0; `--include-classes` select the points of the `dtm`,
+    ///   `dsm` is made of the first returns of all classes except `--exclude-classes`
+    #[clap(long, value_enum, default_value_t = SurfaceModel::Dtm, verbatim_doc_comment)]
+    pub surface: SurfaceModel,
+
+    /// `,` separated LAS classification codes (0 - 255) of the points to read, e.g. `2,9`; empty for all classes.
+    /// Default is `2` (ground) for `dtm` and `chm` surface and all classes for `dsm` one.
+    /// LAZ tile DB contains only the points kept by `laztile` (ground).
+    #[clap(long)]
+    pub include_classes: Option<Classes>,
+
+    /// `,` separated LAS classification codes of the points not to read, even if included by `--include-classes`
+    #[clap(long, default_value = "")]
+    pub exclude_classes: Classes,
+
     /// Max zoom level of tiles to generate
     #[clap(long)]
     pub zoom_level: u8,
 
+    /// Min zoom level of the overview tiles composed from the tiles of the higher zoom level
+    #[clap(long, default_value_t = 0)]
+    pub min_zoom: u8,
+
     /// If LAZ tile DB is used then use value of `--zoom-level` argument of `laztile`
     /// If LAZ index is used then use zoom level to determine size of tile to process at once.
     #[clap(long, default_value_t = 16)]
     pub unit_zoom_level: u8,
 
-    /// Shadings; `+` separated componets of shading. Shading component is <method>,method_param1[,method_param2...].
+    /// Read and rasterize *.laz files in batches of supertiles, so that at most this number of supertiles is held in memory
+    /// (half being read and half being rasterized) instead of reading all of them first.
+    /// Files overlapping supertiles of more batches are read repeatedly. LAZ tile DB is always read per supertile.
+    #[clap(long)]
+    pub max_open_tiles: Option<usize>,
+
+    /// Memory in MB for the points of the supertiles held at once (`--max-open-tiles` or all of them).
+    /// Points of *.laz of a supertile over its share are spilled to a temporary file and read back for the interpolation.
+    #[clap(long)]
+    pub max_memory: Option<u64>,
+
+    /// Directory of the temporary files of `--max-memory` [default: system temporary directory]
+    #[clap(long, requires = "max_memory")]
+    pub spill_dir: Option<PathBuf>,
+
+    /// Threads reading *.laz files, each holding one of them open, which bounds the open files [default: number of CPUs]
+    #[clap(long)]
+    pub read_threads: Option<usize>,
+
+    /// Threads rasterizing the supertiles and of the parallel decompression, interpolation and shading
+    /// [default: number of CPUs]
+    #[clap(long)]
+    pub compute_threads: Option<usize>,
+
+    /// Number of points of a tile buffered by a thread reading *.laz before they are moved to the tile
+    #[clap(long, default_value_t = 65_536)]
+    pub read_buffer_points: usize,
+
+    /// Read only the octree levels of COPC files down to the point spacing of the pixel size,
+    /// thinning the points at low zoom levels
+    #[clap(long)]
+    pub copc_thinning: bool,
+
+    /// Fail on the first unreadable *.laz file or point instead of skipping the file with an error
+    #[clap(long)]
+    pub strict: bool,
+
+    /// Log only warnings and errors, without the progress.
+    /// Otherwise the level is set by `--log-level` or `RUST_LOG`, `info` by default.
+    #[clap(long, conflicts_with = "log_level")]
+    pub quiet: bool,
+
+    /// Log level overriding `RUST_LOG`; `off`, `error`, `warn`, `info` or `debug` (every file and tile) or `trace`
+    #[clap(long)]
+    pub log_level: Option<LevelFilter>,
+
+    /// Format of the log records written to stderr
+    #[clap(long, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// Fraction of the points of a file failing to project above which the source CRS of the file is reported as probably wrong
+    #[clap(long, default_value_t = 0.01)]
+    pub max_unprojectable_fraction: f64,
+
+    /// Shadings; `+` separated componets of shading. Shading component is <method>,<color>,method_param1[,method_param2...].
+    /// Colors are `RRGGBBAA`, `RRGGBB` (opaque), optionally prefixed by `#`, or `black`, `white`, `gray`, `red`, `green`,
+    /// `blue`, `yellow`, `orange`, `brown` or `transparent`.
     /// ‎
     /// Methods:
     /// - `oblique` - params: azimuth in degrees, alitutde in degrees
-    /// - `igor` - params: azimuth in degrees
+    /// - `igor` - params: azimuths in degrees (0 - 360), each optionally with weight as `<azimuth>*<weight>`;
+    ///   more azimuths expand to the shadings of the single azimuths sharing the color and the optional params,
+    ///   e.g. `igor,000000A0,315,15*0.5,75*0.5`, with their weights multiplying the `weight` param
     /// - `slope` - params: alitutde in degrees
+    /// - `multidirectional` - params: altitude in degrees, optional azimuths in degrees (default 225,270,315,360)
+    /// - `cast-shadow` - params: azimuth in degrees, altitude in degrees, max distance of the occluding terrain in meters (at most the buffer)
+    /// - `sky-view-factor` - params: horizon search radius in meters (at most the buffer), optional number of directions (default 16)
+    /// - `positive-openness` - darkens concave relief; params: horizon search radius in meters (at most the buffer), optional number of directions (default 8)
+    /// - `negative-openness` - darkens convex relief; params as for `positive-openness`
+    /// - `local-relief` - difference from the mean elevation (local relief model) colored by the diverging ramp
+    ///   from the shading color below the mean through white to the color above it, opaque at the full difference;
+    ///   params: radius of the mean in meters (at most the buffer), difference in meters for full opacity and the end colors,
+    ///   RRGGBBAA color above the mean
+    /// - `curvature` - intensity 0.5 on planar surface, higher on concave and lower on convex one;
+    ///   params: `profile` (in the direction of the slope) or `plan` (of the contour), multiplier of the curvature in 1/m
+    /// - `tri` - terrain ruggedness index (mean absolute elevation difference to the neighbours);
+    ///   params: index in meters for intensity 0.0, index in meters for intensity 1.0
+    /// - `tpi` - topographic position index (elevation minus the mean of the neighbourhood);
+    ///   params: radius in meters (at most the buffer), index in meters for intensity 0.0, index in meters for intensity 1.0
+    /// - `hypsometric` - base layer under the other shadings, which are composited onto it by its blend mode
+    ///   (`multiply` for `weighted-average`); params: `<elevation>:<RRGGBB>` color stops;
+    ///   alpha of the shading color is used for opacity
+    /// ‎
+    /// Optional params following the method params:
+    /// - `weight=<number>` - weight of the shading, default 1.0; 0.0 disables the shading
+    /// - `blend=<mode>` - how the shading is combined with the shadings before it:
+    ///   `weighted-average` (default; consecutive such shadings are averaged), `multiply`, `screen` or `overlay`
+    /// - `contrast=<number>`, `brightness=<number>` - remap the intensity of the shading before it is combined
+    ///   as `contrast * (intensity - 0.5) + 0.5 + brightness` clamped to 0.0 - 1.0; by default it is kept
+    /// - `intensity=<number>` - `igor` only; multiplier of the slope, default 2.0
+    /// - `exponent=<number>` - `igor` only; exponent of the aspect strength, default 1.0
+    /// - `clamp=<true|false>` - `oblique` only; clamp the illumination to 0.0 - 1.0, default true;
+    ///   `contrast` and `brightness` clamp it anyway
     #[clap(long, verbatim_doc_comment)]
+    #[allow(clippy::doc_lazy_continuation)]
     pub shadings: Shadings,
 
+    /// Built-in shadings with the contrast, brightness and z-factor they are tuned for:
+    /// `default` (two Igor directions and slope), `swiss`, `archaeology` (sky-view factor and openness)
+    /// or `slope-only`; `list` prints their definitions. `--shadings` replaces the shadings of the preset,
+    /// other options given on the command line or by `--config` override its values.
+    #[clap(long)]
+    pub preset: Option<String>,
+
+    /// Slope classes blended over the shadings; `,` separated <lower bound of slope in degrees>:<RRGGBBAA color>,
+    /// e.g. `30:ffff00a0,35:ff8000a0,40:ff0000a0,45:8000ffa0`. Slopes below the first bound are transparent.
+    #[clap(long)]
+    #[serde(deserialize_with = "serialization::flag::deserialize")]
+    pub slope_classes: Option<SlopeClassesShadingParams>,
+
     /// Increase (> 1.0) or decrease (< 1.0) contrast of the shading. Use value higher than 0.0.
     #[clap(long, default_value_t = 1.0)]
     pub contrast: f64,
@@ -56,7 +286,171 @@ pub struct Options {
     #[clap(long, default_value_t = 0.0)]
     pub brightness: f64,
 
-    /// Z-factor
+    /// Aerial perspective: brightness added to the highest elevation of `--elevation-range`, linearly decreasing
+    /// to 0.0 at the lowest one. Requires `--elevation-range` so that all the supertiles are brightened alike.
+    #[clap(long, default_value_t = 0.0)]
+    pub aerial_perspective: f64,
+
+    /// Elevations in meters as `min,max` of `--aerial-perspective`; the shading of a single grid by the bindings
+    /// takes the range of the grid if not set
+    #[clap(long, value_name = "MIN,MAX")]
+    #[serde(deserialize_with = "serialization::flag::deserialize")]
+    pub elevation_range: Option<ElevationRange>,
+
+    /// Derive the contrast and the brightness of `--mode hillshade` from the 2nd and 98th percentiles of the shading
+    /// of sample supertiles composed on `--background-color` so that the tiles span the full tonal range. The values
+    /// applied to all the tiles are logged to be pinned by `--contrast` and `--brightness` in the next runs.
+    #[clap(long, conflicts_with_all = ["contrast", "brightness", "max_open_tiles", "serve"])]
+    pub auto_contrast: bool,
+
+    /// Number of the supertiles sampled by `--auto-contrast`, spread evenly over the rendered ones
+    #[clap(long, default_value_t = 16)]
+    pub auto_contrast_samples: usize,
+
+    /// Gamma correction applied after contrast and brightness. Use value higher than 0.0.
+    #[clap(long, default_value_t = 1.0)]
+    pub gamma: f64,
+
+    /// Blend colors of the shadings in linear light instead of sRGB
+    #[clap(long)]
+    pub linear_blend: bool,
+
+    /// Radius (standard deviation) in pixels of the blur of the clarity (unsharp mask) pass
+    #[clap(long, default_value_t = 2.0)]
+    pub clarity_radius: f64,
+
+    /// Amount from 0.0 to 2.0 of the clarity (unsharp mask) pass boosting local contrast of the hillshade; 0.0 disables it
+    #[clap(long, default_value_t = 0.0)]
+    pub clarity_amount: f64,
+
+    /// Interpolation of the elevations at the pixel centers:
+    /// - `natural-neighbor` - Sibson interpolation of the Delaunay triangulation; fills the gaps of the data, smooth
+    /// - `linear` - planes of the triangles of the Delaunay triangulation; faceted on low relief
+    /// - `idw` - inverse distance weighted of the nearest `--idw-max-points` within `--interpolation-radius`;
+    ///   pixels without points within the radius have no data
+    /// - `nearest` - elevation of the nearest point within `--interpolation-radius`;
+    ///   pixels without points within the radius have no data
+    #[clap(long, value_enum, default_value_t = Interpolation::NaturalNeighbor, verbatim_doc_comment)]
+    pub interpolation: Interpolation,
+
+    /// Pixels in the triangles with an edge longer than this (in meters) have no data so that the gaps of the data
+    /// are not bridged by the `natural-neighbor` and `linear` interpolations
+    #[clap(long)]
+    pub max_edge_length: Option<f64>,
+
+    /// Search radius in meters of the `idw` and `nearest` interpolations
+    #[clap(long, default_value_t = 5.0)]
+    pub interpolation_radius: f64,
+
+    /// Power of the distance of the `idw` interpolation
+    #[clap(long, default_value_t = 2.0)]
+    pub idw_power: f64,
+
+    /// Maximal number of the nearest points of the `idw` interpolation
+    #[clap(long, default_value_t = 12)]
+    pub idw_max_points: usize,
+
+    /// Standard deviation in meters of the gaussian smoothing of the elevation before shading; 0.0 disables it.
+    /// With `--mercator-scale` the meters are of the highest ground resolution within the bbox.
+    /// Three standard deviations must not exceed the buffer.
+    #[clap(long, default_value_t = 0.0)]
+    pub smooth_sigma: f64,
+
+    /// Precision of the stored elevation; `f32` halves the memory of supertiles while derivatives are still computed in `f64`
+    #[clap(long, value_enum, default_value_t = ElevationPrecision::F64)]
+    pub elevation_precision: ElevationPrecision,
+
+    /// Radius in meters of the neighbourhood of the removal of spikes (outlying ground points) before the interpolation; 0.0 disables it
+    #[clap(long, default_value_t = 0.0)]
+    pub despike_radius: f64,
+
+    /// Maximal difference in meters of a ground point from the median height of its neighbourhood; farther points are removed as spikes
+    #[clap(long, default_value_t = 2.0)]
+    pub despike_threshold: f64,
+
+    /// Merging of the ground points of every cell of `--dedup-cell-size` after the removal of spikes:
+    /// `min` keeps the lowest point, `mean` gives the point of the mean position and height, `off` keeps all the points.
+    /// Not applied to the `dsm` surface which keeps the highest point of every pixel.
+    #[clap(long, value_enum, default_value_t = Dedup::Min)]
+    pub dedup: Dedup,
+
+    /// Cell size in meters of `--dedup`, half of the pixel size by default
+    #[clap(long)]
+    pub dedup_cell_size: Option<f64>,
+
+    /// Maximal number of the ground points of every pixel after `--dedup`; the lowest points are kept.
+    /// Not applied to the `dsm` surface.
+    #[clap(long)]
+    pub max_points_per_cell: Option<usize>,
+
+    /// Minimal number of the (ground and water) points of the supertile; supertiles with fewer points are not rendered
+    #[clap(long, default_value_t = 1)]
+    pub min_points: usize,
+
+    /// Write JSON array of `[zoom, x, y]` of the tiles of the zoom level `--zoom-level` not rendered for too few points
+    #[clap(long)]
+    pub skipped_tiles: Option<PathBuf>,
+
+    /// Write JSON array of the statistics of the rendered supertiles (including the buffer): point count, thinned points and density,
+    /// elevation range, number of pixels without data and durations of the interpolation and shading
+    #[clap(long)]
+    pub stats_out: Option<PathBuf>,
+
+    /// Write JSON of the durations of the phases (listing of the files, LAZ tile DB queries, decompression, projection,
+    /// triangulation, interpolation, shading, encoding and writing) summed over the threads with their mean, max and count,
+    /// and of the bytes and points read. The summary is also logged at the end of the run.
+    #[clap(long)]
+    pub timings_out: Option<PathBuf>,
+
+    /// Read also the points flagged as withheld, which are skipped by default
+    #[clap(long)]
+    pub include_withheld: bool,
+
+    /// Skip the points flagged as synthetic
+    #[clap(long)]
+    pub exclude_synthetic: bool,
+
+    /// Skip the overlap points of the swaths, flagged as overlap or classified 12
+    #[clap(long)]
+    pub exclude_overlap: bool,
+
+    /// Read only the last returns
+    #[clap(long)]
+    pub last_return_only: bool,
+
+    /// Maximum absolute scan angle in degrees of the points to read, skipping the noisier points of the edges of the swaths
+    #[clap(long)]
+    pub max_scan_angle: Option<f32>,
+
+    /// Flatten water bodies to the median elevation of their water-classified points.
+    /// Water bodies with only a few water points are left interpolated.
+    /// Levels of water bodies crossing supertiles are shared across the run, or the batch of `--max-open-tiles`,
+    /// except for LAZ tile DB which is read per supertile.
+    /// Water points (class 9) are read regardless of `--include-classes` and `--exclude-classes`.
+    /// LAZ tile DB must contain the water points (`laztile` keeps only ground ones).
+    #[clap(long)]
+    pub flatten_water: bool,
+
+    /// Maximal gap in meters between the water points of the same water body of `--flatten-water`
+    #[clap(long, default_value_t = 10.0)]
+    pub water_link_distance: f64,
+
+    /// Scale the pixel size to the ground by the latitude (Web Mercator scale factor) for derivatives and horizons
+    #[clap(long)]
+    pub mercator_scale: bool,
+
+    /// Method of computing the derivatives (slope and aspect) from the 3x3 window:
+    /// - `horn` - weighted 8 neighbours, smoother
+    /// - `zevenbergen-thorne` - 4 direct neighbours, crisper on smooth high-resolution DTMs
+    /// - `plane-fit` - least-squares plane fitted to the window of `--derivative-radius`, suppressing noise of dense DTMs
+    #[clap(long, value_enum, default_value_t = DerivativeMethod::Horn, verbatim_doc_comment)]
+    pub derivative_method: DerivativeMethod,
+
+    /// Radius in pixels of the window of the `plane-fit` derivative method, e.g. 2 for 5x5; must not exceed the buffer
+    #[clap(long, default_value_t = 2)]
+    pub derivative_radius: usize,
+
+    /// Z-factor (vertical exaggeration). Derivatives account for the pixel size, so 1.0 gives true slopes.
     #[clap(long, default_value_t = 1.0)]
     pub z_factor: f64,
 
@@ -68,64 +462,1069 @@ pub struct Options {
     #[clap(long, default_value_t = 40)]
     pub buffer: u32,
 
-    /// Tile image format. For alpha (transparency) support use `png`.
+    /// Interpolate and shade the supertiles at 2 or 4 times the resolution, downsampled to the tile size,
+    /// against the aliasing of sharp breaklines. A supertile takes its square times the memory, so the rasterizing
+    /// threads are reduced as many times. Not supported by `--mode elevation`, `slope` and `aspect`.
+    #[clap(long, default_value_t = 1)]
+    pub supersample: u16,
+
+    /// What to render:
+    /// - `hillshade` - composited shadings
+    /// - `grayscale` - 16-bit illumination of a single shading without any compositing; requires `png` format
+    /// - `normal` - surface normal encoded as `0.5 + 0.5 * n` in RGB (X east, Y north, Z up) for client-side relighting; shadings are ignored
+    /// - `elevation` - interpolated elevation encoded according to `--encoding`; shadings are ignored; requires `png` format
+    /// - `slope` - 16-bit slope in hundredths of degree (0 - 9000), 65535 for no data; shadings are ignored; requires `png` format
+    /// - `aspect` - 16-bit aspect (downslope direction clockwise from north) in hundredths of degree (0 - 35999),
+    ///   65535 for flat pixels and no data; shadings are ignored; requires `png` format
+    /// - `canopy-height` - height of `chm` surface colored from light yellow at 0 m to dark green at `--chm-max`;
+    ///   shadings are ignored; requires `--surface chm`
+    #[clap(long, value_enum, default_value_t = Mode::Hillshade, verbatim_doc_comment)]
+    pub mode: Mode,
+
+    /// Canopy height in meters of the darkest color of `canopy-height` mode
+    #[clap(long, default_value_t = 40.0)]
+    pub chm_max: f64,
+
+    /// Encoding of the elevation in `elevation` mode:
+    /// - `terrain-rgb` - Mapbox Terrain-RGB, `height = -10000 + (R * 65536 + G * 256 + B) * 0.1`
+    /// - `terrarium` - Mapzen Terrarium, `height = (R * 256 + G + B / 256) - 32768`
+    #[clap(long, value_enum, default_value_t = Encoding::TerrainRgb, verbatim_doc_comment)]
+    pub encoding: Encoding,
+
+    /// Elevation in meters written to pixels without data in `elevation` mode. Such pixels are transparent if not set.
+    #[clap(long)]
+    pub nodata_elevation: Option<f64>,
+
+    /// Tile image format. For alpha (transparency) support use `png` or `webp`.
     #[clap(long, value_enum, default_value_t = Format::JPEG)]
     pub format: Format,
 
-    /// Quality from 0 to 100 when writing to JPEG
-    #[clap(long, default_value_t = 80)]
-    pub jpeg_quality: u8,
+    /// Quality from 0 to 100 when writing to JPEG, 80 by default, or to lossy WebP.
+    /// Without it WebP is written lossless.
+    #[clap(long, alias = "jpeg-quality")]
+    pub quality: Option<u8>,
 
-    /// Background color when writing to JPEG as it does not support alpha
-    #[clap(long, default_value = "FFFFFF")]
+    /// Background color (RRGGBB) of opaque output, e.g. when writing to JPEG as it does not support alpha
+    #[clap(long, alias = "background", default_value = "FFFFFF")]
     pub background_color: Rgb,
 
+    /// Color (RRGGBBAA, RRGGBB or a name as for the shadings) of pixels without elevation data, transparent by default
+    #[clap(long, default_value = "00000000")]
+    pub nodata_color: Rgba,
+
+    /// Composite the shading onto the background color (`opaque`) or keep the alpha (`transparent`).
+    /// Default is `transparent` for PNG and `opaque` for JPEG.
+    #[clap(long, value_enum)]
+    pub compositing: Option<Compositing>,
+
+    /// PMTiles archive can be only overwritten
     #[clap(long, value_enum)]
     pub existing_file_action: Option<ExistingFileAction>,
+
+    /// Attribution written to the metadata of the output
+    #[clap(long)]
+    pub attribution: Option<String>,
+
+    /// Write every supertile (without the buffer) as `<zoom>-<x>-<y>.tif` GeoTIFF in EPSG:3857 to the output directory
+    /// instead of the tiles. Mode `elevation` writes float elevations instead of the encoded ones.
+    #[clap(long)]
+    pub geotiff: bool,
+
+    /// Write the tiles as `<zoom>/<x>/<y>.<png|jpg|webp>` to the output directory
+    #[clap(long, conflicts_with = "geotiff")]
+    pub tile_directory: bool,
+
+    /// Additional output as `<name>:<mode>:<format>:<path>[:<shadings>]` rendered from the same interpolated supertiles,
+    /// e.g. `slope:slope:png:slope.mbtiles` or `classes:hillshade:png:classes.pmtiles:slope-classes,#000000ff,15`;
+    /// the shadings default to `--shadings`. The output is of the same kind as the main one, may be repeated.
+    #[clap(long = "layer", conflicts_with_all = ["serve", "geotiff", "resume", "incremental"])]
+    #[serde(
+        rename = "layer",
+        deserialize_with = "serialization::flags::deserialize"
+    )]
+    pub layers: Vec<OutputSpec>,
+
+    /// Write a world file next to every tile of `--tile-directory` and `tiles.vrt` GDAL mosaic
+    /// of the written tiles of the zoom level `--zoom-level` to the output directory
+    #[clap(long, requires = "tile_directory")]
+    pub world_files: bool,
+
+    /// Skip the supertiles recorded in the journal of the output directory as written by a previous run.
+    /// Interrupted supertiles are rendered again.
+    #[clap(long)]
+    pub resume: bool,
+
+    /// Regenerate only the supertiles affected by the files of `--laz-index-db` added, changed or removed
+    /// since the last regeneration of the output, updating it. The state of the files is stored in the index DB
+    /// once the tiles are written; use the same bbox for all the regenerations of the output.
+    #[clap(long, requires = "laz_index_db")]
+    pub incremental: bool,
+
+    /// Serve the tiles of the zoom levels from `--unit-zoom-level` to `--zoom-level` within the bbox over HTTP
+    /// at the address given as the output (e.g. `127.0.0.1:8080`), rendering them on demand
+    #[clap(long, conflicts_with_all = ["geotiff", "tile_directory", "resume", "incremental"])]
+    pub serve: bool,
+
+    /// Print JSON of the shadings (method as `type`, angles in degrees, colors as hex), the supertiles to render, the files to read with their total size and the estimated number
+    /// of the output tiles and exit. Only the headers of the files of `--laz-dir` are read.
+    #[clap(long, conflicts_with = "serve")]
+    pub dry_run: bool,
+
+    /// Number of the rendered supertiles cached by `--serve`
+    #[clap(long, default_value_t = 16, requires = "serve")]
+    pub serve_cache_size: usize,
+}
+
+/// Defaults of the flags with the shadings of the `default` preset, without the output, the source and the extent
+/// and with the zoom level 0
+impl Default for Options {
+    fn default() -> Self {
+        let mut options = Self::parse_from([
+            "laz2dem",
+            "-",
+            "--laz-dir=-",
+            "--bbox=0,0,1,1",
+            "--zoom-level=0",
+            &format!("--shadings={}", PRESETS[0].shadings),
+        ]);
+
+        options.output = PathBuf::new();
+
+        options.laz_dir = None;
+
+        options.bbox_3857 = None;
+
+        options
+    }
 }
 
 impl Options {
+    /// Options from a JSON object of the options named as the keys of the `--config` file,
+    /// e.g. `{"shadings": "igor,000000FF,315", "z_factor": 2, "linear_blend": true}`.
+    /// The output, the source, the extent and the zoom level are not needed for the shading of a grid.
+    pub fn from_json(config: &str) -> Result<Self, crate::error::Error> {
+        serde_json::from_str(config)
+            .map_err(|error| crate::error::Error::InvalidOptions(error.to_string()))
+    }
+
+    /// Checks the options and their relationships, also of the layers, as `render` does, and warns of suspicious values
+    pub fn validate(&self) -> Result<(), crate::error::Error> {
+        let invalid = |message: &str| Err(crate::error::Error::InvalidOptions(message.to_owned()));
+
+        if self.zoom_level < self.unit_zoom_level {
+            return invalid("Zoom level must not be lower than the unit zoom level.");
+        }
+
+        if self.zoom_level > 30 {
+            return invalid("Zoom level must not exceed 30.");
+        }
+
+        if self.tile_size == 0 || !self.tile_size.is_multiple_of(256) {
+            return invalid("Tile size must be a multiple of 256.");
+        }
+
+        if ![1, 2, 4].contains(&self.supersample) {
+            return invalid("Supersample must be 1, 2 or 4.");
+        }
+
+        if self.tile_size.checked_mul(self.supersample).is_none() {
+            return invalid("Tile size times the supersample must not exceed 65535.");
+        }
+
+        // points within the buffer are assigned to the neighbouring supertiles too
+        if self.buffer > u32::from(self.tile_size) {
+            return invalid("Buffer must not exceed the tile size.");
+        }
+
+        if self.contrast <= 0.0 {
+            return invalid("Contrast must be higher than 0.0");
+        }
+
+        if self.aerial_perspective != 0.0 && self.elevation_range.is_none() {
+            return invalid("Aerial perspective requires --elevation-range.");
+        }
+
+        if self
+            .elevation_range
+            .is_some_and(|range| range.min >= range.max)
+        {
+            return invalid("Minimal elevation of the range must be lower than the maximal one.");
+        }
+
+        if self.max_points_per_cell == Some(0) {
+            return invalid("Max points per cell must be at least 1");
+        }
+
+        if self.dedup_cell_size.is_some_and(|size| size <= 0.0) {
+            return invalid("Dedup cell size must be higher than 0.0");
+        }
+
+        if self.max_edge_length.is_some_and(|length| length <= 0.0) {
+            return invalid("Max edge length must be higher than 0.0");
+        }
+
+        if self.quality.is_some()
+            && iter::once(self.format)
+                .chain(self.layers.iter().map(|layer| layer.format))
+                .all(|format| format == Format::PNG)
+        {
+            return invalid("Quality applies only to JPEG and WebP.");
+        }
+
+        if self.water_link_distance <= 0.0 {
+            return invalid("Water link distance must be higher than 0.0");
+        }
+
+        if self.interpolation_radius <= 0.0 {
+            return invalid("Interpolation radius must be higher than 0.0");
+        }
+
+        if self.idw_power <= 0.0 {
+            return invalid("IDW power must be higher than 0.0");
+        }
+
+        if self.idw_max_points == 0 {
+            return invalid("IDW max points must be at least 1");
+        }
+
+        if self.auto_contrast_samples == 0 {
+            return invalid("Auto contrast samples must be at least 1");
+        }
+
+        if self.read_threads == Some(0) || self.compute_threads == Some(0) {
+            return invalid("Read and compute threads must be at least 1");
+        }
+
+        if let Some(tile) = self.debug_tile {
+            if tile.x.0 != tile.x.1 || tile.y.0 != tile.y.1 {
+                return invalid("Debug tile must be a single tile.");
+            }
+
+            if !(self.unit_zoom_level..=self.zoom_level).contains(&tile.zoom) {
+                return invalid(
+                    "Zoom of the debug tile must be between the unit zoom level and the zoom level.",
+                );
+            }
+        }
+
+        if self.resume && !self.is_directory() {
+            return invalid(
+                "Only output directories can be resumed. Use --existing-file-action continue for MBTiles.",
+            );
+        }
+
+        if self.incremental {
+            if self
+                .existing_file_action
+                .is_some_and(|action| action != ExistingFileAction::Update)
+            {
+                return invalid("Incremental regeneration requires --existing-file-action update.");
+            }
+
+            if self.is_pmtiles() {
+                return invalid(
+                    "PMTiles archive can't be updated by the incremental regeneration.",
+                );
+            }
+        }
+
+        if self.min_zoom > self.zoom_level {
+            return invalid("Min zoom must not exceed the zoom level.");
+        }
+
+        if self.gamma <= 0.0 {
+            return invalid("Gamma must be higher than 0.0");
+        }
+
+        if !(0.0..=2.0).contains(&self.clarity_amount) {
+            return invalid("Clarity amount must be between 0.0 and 2.0");
+        }
+
+        if self.clarity_radius <= 0.0 {
+            return invalid("Clarity radius must be higher than 0.0");
+        }
+
+        if self.smooth_sigma < 0.0 {
+            return invalid("Smooth sigma must not be negative");
+        }
+
+        if (self.smooth_sigma_pixels() * 3.0).ceil() > self.buffer as f64 {
+            return invalid(
+                "Three smooth sigmas must not exceed the buffer or tiles would not line up.",
+            );
+        }
+
+        if self.despike_radius < 0.0 {
+            return invalid("Despike radius must not be negative");
+        }
+
+        if self.despike_threshold <= 0.0 {
+            return invalid("Despike threshold must be higher than 0.0");
+        }
+
+        if self.derivative_method == DerivativeMethod::PlaneFit {
+            if self.derivative_radius == 0 {
+                return invalid("Derivative radius must be at least 1");
+            }
+
+            if self.derivative_radius > self.buffer as usize {
+                return invalid(
+                    "Derivative radius must not exceed the buffer or tiles would not line up.",
+                );
+            }
+        }
+
+        if self.chm_max <= 0.0 {
+            return invalid("CHM max must be higher than 0.0");
+        }
+
+        let layers = self.layer_options();
+
+        for output in iter::once(self).chain(&layers) {
+            output.validate_output()?;
+        }
+
+        if !layers.is_empty() {
+            if matches!(
+                self.existing_file_action,
+                Some(ExistingFileAction::Continue | ExistingFileAction::Update)
+            ) {
+                return invalid(
+                    "Layers can't be continued nor updated. Use --existing-file-action overwrite.",
+                );
+            }
+
+            let paths: HashSet<_> = iter::once(&self.output)
+                .chain(layers.iter().map(|layer| &layer.output))
+                .collect();
+
+            if paths.len() <= layers.len() {
+                return invalid("Outputs of the layers must differ.");
+            }
+        }
+
+        // airborne LIDAR rarely has more than 100 points per square meter
+        if self.pixels_per_meter() > 10.0 {
+            warn!(
+                "Pixels of {:.3} m are probably smaller than the spacing of the points; consider lower --zoom-level",
+                1.0 / self.pixels_per_meter()
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Checks the options specific to the main output or to a layer
+    fn validate_output(&self) -> Result<(), crate::error::Error> {
+        let invalid = |message: String| Err(crate::error::Error::InvalidOptions(message));
+
+        if self.format == Format::JPEG && self.compositing == Some(Compositing::Transparent) {
+            return invalid("JPEG does not support transparency. Use --format png or webp.".into());
+        }
+
+        for shading in &self.shadings.0 {
+            let radius = match &shading.method {
+                ShadingMethod::CastShadow(params) => params.max_distance,
+                ShadingMethod::SkyViewFactor(params) => params.max_radius,
+                ShadingMethod::Openness(params) => params.max_radius,
+                ShadingMethod::LocalRelief(params) => params.radius,
+                ShadingMethod::Tpi(params) => params.radius,
+                _ => continue,
+            };
+
+            if radius * self.max_pixels_per_ground_meter() > self.buffer as f64 {
+                return invalid(format!(
+                    "Radius of sky-view factor, openness, local relief and TPI and distance of cast shadow must not exceed the buffer ({} m) or tiles would not line up.",
+                    self.buffer as f64 / self.max_pixels_per_ground_meter()
+                ));
+            }
+        }
+
+        if self.mode == Mode::Grayscale {
+            if self.format != Format::PNG {
+                return invalid("Grayscale mode requires --format png.".into());
+            }
+
+            match self.shadings.0.as_slice() {
+                [shading]
+                    if self.slope_classes.is_none()
+                        && !matches!(
+                            shading.method,
+                            ShadingMethod::Hypsometric(_)
+                                | ShadingMethod::SlopeClasses(_)
+                                | ShadingMethod::LocalRelief(_)
+                        ) => {}
+                _ => {
+                    return invalid(
+                        "Grayscale mode requires exactly one shading producing illumination; hypsometric, slope classes and local relief are not supported.".into(),
+                    );
+                }
+            }
+        }
+
+        if self.mode == Mode::Elevation && self.format == Format::JPEG {
+            return invalid(
+                "Mode elevation requires --format png or webp as JPEG compression would corrupt the values.".into(),
+            );
+        }
+
+        if self.mode == Mode::Elevation && self.format == Format::WEBP && self.quality.is_some() {
+            return invalid(
+                "Mode elevation requires lossless WebP without --quality as lossy compression would corrupt the values.".into(),
+            );
+        }
+
+        if matches!(self.mode, Mode::Slope | Mode::Aspect) && self.format != Format::PNG {
+            return invalid(format!(
+                "Mode {} requires --format png as only PNG supports its 16 bit values.",
+                self.mode
+            ));
+        }
+
+        if self.supersample > 1 && matches!(self.mode, Mode::Elevation | Mode::Slope | Mode::Aspect)
+        {
+            return invalid(format!(
+                "Mode {} can't be supersampled as downsampling would mix the values.",
+                self.mode
+            ));
+        }
+
+        if self.mode == Mode::CanopyHeight && self.surface != SurfaceModel::Chm {
+            return invalid("Mode canopy-height requires --surface chm.".into());
+        }
+
+        Ok(())
+    }
+
+    /// `--read-threads` or the number of CPUs
+    pub fn read_threads(&self) -> usize {
+        self.read_threads.unwrap_or_else(cpus)
+    }
+
+    /// `--compute-threads` or the number of CPUs
+    pub fn compute_threads(&self) -> usize {
+        self.compute_threads.unwrap_or_else(cpus)
+    }
+
+    /// Options of `--supersample` times the resolution with the sizes in pixels scaled to the same ground size
+    pub fn supersampled(&self) -> Options {
+        let factor = self.supersample;
+
+        Options {
+            supersample: 1,
+            tile_size: self.tile_size * factor,
+            buffer: self.buffer * u32::from(factor),
+            derivative_radius: self.derivative_radius * usize::from(factor),
+            clarity_radius: self.clarity_radius * f64::from(factor),
+            ..self.clone()
+        }
+    }
+
     pub fn pixels_per_meter(&self) -> f64 {
         (((self.tile_size as u64) << self.zoom_level) as f64) / 2.0 / WEB_MERCATOR_EXTENT
     }
 
-    pub fn source(&self) -> Source {
-        self.laz_index_db.clone().map_or_else(
-            || {
-                self.laz_tile_db
+    /// Whether points of the LAS classification code are read
+    pub fn is_class_included(&self, class: u8) -> bool {
+        let included = match (&self.include_classes, self.surface) {
+            (Some(Classes(classes)), _) => classes.is_empty() || classes.contains(&class),
+            (None, SurfaceModel::Dtm | SurfaceModel::Chm) => class == 2,
+            (None, SurfaceModel::Dsm) => true,
+        };
+
+        included && !self.exclude_classes.0.contains(&class)
+    }
+
+    /// `--quality` of JPEG
+    pub fn jpeg_quality(&self) -> u8 {
+        self.quality.unwrap_or(80)
+    }
+
+    /// `--smooth-sigma` in pixels of the highest ground resolution within the bbox, the same for all the supertiles
+    pub fn smooth_sigma_pixels(&self) -> f64 {
+        self.smooth_sigma * self.max_pixels_per_ground_meter()
+    }
+
+    /// Maximal number of pixels per ground meter within the bbox
+    pub fn max_pixels_per_ground_meter(&self) -> f64 {
+        let scale = if self.mercator_scale {
+            (self.bbox.min_y.abs().max(self.bbox.max_y.abs()) * PI / WEB_MERCATOR_EXTENT).cosh()
+        } else {
+            1.0
+        };
+
+        self.pixels_per_meter() * scale
+    }
+
+    pub fn is_opaque(&self) -> bool {
+        match self.compositing {
+            Some(Compositing::Opaque) => true,
+            Some(Compositing::Transparent) => false,
+            None => self.format == Format::JPEG,
+        }
+    }
+
+    /// Whether the output is written to the directory
+    pub fn is_directory(&self) -> bool {
+        self.geotiff || self.tile_directory
+    }
+
+    pub fn is_pmtiles(&self) -> bool {
+        self.output
+            .extension()
+            .is_some_and(|extension| extension == "pmtiles")
+    }
+
+    /// Options of the outputs of `--layer`
+    pub fn layer_options(&self) -> Vec<Options> {
+        self.layers
+            .iter()
+            .map(|layer| Options {
+                output: layer.path.clone(),
+                mode: layer.mode,
+                format: layer.format,
+                shadings: layer
+                    .shadings
                     .clone()
-                    .map_or_else(|| unreachable!("only one"), Source::LazTileDb)
-            },
-            Source::LazIndexDb,
-        )
+                    .unwrap_or_else(|| self.shadings.clone()),
+                layers: Vec::new(),
+                ..self.clone()
+            })
+            .collect()
+    }
+
+    pub fn source(&self) -> Source {
+        match (&self.laz_tile_db, &self.laz_index_db, &self.laz_dir) {
+            (Some(path), _, _) => Source::LazTileDb(path.clone()),
+            (_, Some(path), _) => Source::LazIndexDb(path.clone()),
+            (_, _, Some(path)) => Source::LazDir(path.clone()),
+            _ => unreachable!("only one"),
+        }
     }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+/// Number of CPUs, or 1 where it can't be determined
+fn cpus() -> usize {
+    available_parallelism().map_or(1, |cpus| cpus.get())
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum ExistingFileAction {
     Overwrite,
+    /// Skip the existing tiles
     Continue,
+    /// Replace the existing tiles and extend the bounds in the metadata
+    Update,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+    Hillshade,
+    Grayscale,
+    Normal,
+    Elevation,
+    Slope,
+    Aspect,
+    CanopyHeight,
+}
+
+impl Display for Mode {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            formatter,
+            "{}",
+            match self {
+                Mode::Hillshade => "hillshade",
+                Mode::Grayscale => "grayscale",
+                Mode::Normal => "normal",
+                Mode::Elevation => "elevation",
+                Mode::Slope => "slope",
+                Mode::Aspect => "aspect",
+                Mode::CanopyHeight => "canopy-height",
+            }
+        )
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Encoding {
+    TerrainRgb,
+    Terrarium,
+}
+
+impl Display for Encoding {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            formatter,
+            "{}",
+            match self {
+                Encoding::TerrainRgb => "terrain-rgb",
+                Encoding::Terrarium => "terrarium",
+            }
+        )
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DerivativeMethod {
+    Horn,
+    ZevenbergenThorne,
+    PlaneFit,
+}
+
+impl Display for DerivativeMethod {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            formatter,
+            "{}",
+            match self {
+                DerivativeMethod::Horn => "horn",
+                DerivativeMethod::ZevenbergenThorne => "zevenbergen-thorne",
+                DerivativeMethod::PlaneFit => "plane-fit",
+            }
+        )
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Dedup {
+    Min,
+    Mean,
+    Off,
+}
+
+impl Display for Dedup {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            formatter,
+            "{}",
+            match self {
+                Dedup::Min => "min",
+                Dedup::Mean => "mean",
+                Dedup::Off => "off",
+            }
+        )
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Interpolation {
+    NaturalNeighbor,
+    Linear,
+    Idw,
+    Nearest,
+}
+
+impl Display for Interpolation {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            formatter,
+            "{}",
+            match self {
+                Interpolation::NaturalNeighbor => "natural-neighbor",
+                Interpolation::Linear => "linear",
+                Interpolation::Idw => "idw",
+                Interpolation::Nearest => "nearest",
+            }
+        )
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SurfaceModel {
+    Dtm,
+    Dsm,
+    Chm,
+}
+
+impl Display for SurfaceModel {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            formatter,
+            "{}",
+            match self {
+                SurfaceModel::Dtm => "dtm",
+                SurfaceModel::Dsm => "dsm",
+                SurfaceModel::Chm => "chm",
+            }
+        )
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    Text,
+    /// Object of `time` (Unix time in seconds), `level`, `target` and `message` per line
+    Json,
+}
+
+impl Display for LogFormat {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            formatter,
+            "{}",
+            match self {
+                LogFormat::Text => "text",
+                LogFormat::Json => "json",
+            }
+        )
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ElevationPrecision {
+    F32,
+    F64,
+}
+
+impl Display for ElevationPrecision {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            formatter,
+            "{}",
+            match self {
+                ElevationPrecision::F32 => "f32",
+                ElevationPrecision::F64 => "f64",
+            }
+        )
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Compositing {
+    Opaque,
+    Transparent,
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Rgb(pub image::Rgb<u8>);
 
+#[derive(Debug)]
+pub struct ParseRgbError();
+
+impl Display for ParseRgbError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Expected color in RRGGBB format")
+    }
+}
+
+impl Error for ParseRgbError {}
+
 impl FromStr for Rgb {
-    type Err = ParseIntError;
+    type Err = ParseRgbError;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let string = string.strip_prefix('#').unwrap_or(string);
+
+        if string.len() != 6 {
+            return Err(ParseRgbError());
+        }
+
+        u32::from_str_radix(string, 16)
+            .map(|color| {
+                let [_, r, g, b] = color.to_be_bytes();
+
+                Self(image::Rgb([r, g, b]))
+            })
+            .map_err(|_| ParseRgbError())
+    }
+}
+
+impl Serialize for Rgb {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let [r, g, b] = self.0.0;
+
+        serializer.serialize_str(&format!("#{r:02X}{g:02X}{b:02X}"))
+    }
+}
+
+impl<'de> Deserialize<'de> for Rgb {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(transparent)]
+pub struct Classes(pub Vec<u8>);
+
+/// Deserialized from the classification codes or from their string of the flag
+impl<'de> Deserialize<'de> for Classes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> de::Visitor<'de> for Visitor {
+            type Value = Classes;
+
+            fn expecting(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+                write!(f, "an array of LAS classification codes or their string")
+            }
+
+            fn visit_str<E: de::Error>(self, classes: &str) -> Result<Classes, E> {
+                classes.parse().map_err(E::custom)
+            }
+
+            fn visit_seq<A: de::SeqAccess<'de>>(self, seq: A) -> Result<Classes, A::Error> {
+                Vec::deserialize(de::value::SeqAccessDeserializer::new(seq)).map(Classes)
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseClassesError();
+
+impl Display for ParseClassesError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Expected `,` separated LAS classification codes from 0 to 255"
+        )
+    }
+}
+
+impl Error for ParseClassesError {}
+
+impl FromStr for Classes {
+    type Err = ParseClassesError;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        if string.is_empty() {
+            return Ok(Self(Vec::new()));
+        }
+
+        string
+            .split(',')
+            .map(|class| class.trim().parse().map_err(|_| ParseClassesError()))
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// Additional output of `--layer`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutputSpec {
+    pub name: String,
+    pub mode: Mode,
+    pub format: Format,
+    pub path: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shadings: Option<Shadings>,
+}
+
+#[derive(Debug)]
+pub struct ParseOutputSpecError(String);
+
+impl Display for ParseOutputSpecError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Expected <name>:<mode>:<format>:<path>[:<shadings>]: {}",
+            self.0
+        )
+    }
+}
+
+impl Error for ParseOutputSpecError {}
+
+impl FromStr for OutputSpec {
+    type Err = ParseOutputSpecError;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let mut parts = string.splitn(5, ':');
+
+        let mut part = |what: &str| {
+            parts
+                .next()
+                .filter(|part| !part.is_empty())
+                .ok_or_else(|| ParseOutputSpecError(format!("missing {what}")))
+        };
+
+        let name = part("name")?.to_owned();
+
+        let mode = Mode::from_str(part("mode")?, true).map_err(ParseOutputSpecError)?;
+
+        let format = Format::from_str(part("format")?, true).map_err(ParseOutputSpecError)?;
+
+        let path = PathBuf::from(part("path")?);
+
+        let shadings = parts
+            .next()
+            .map(Shadings::from_str)
+            .transpose()
+            .map_err(|e| ParseOutputSpecError(e.to_string()))?;
+
+        Ok(OutputSpec {
+            name,
+            mode,
+            format,
+            path,
+            shadings,
+        })
+    }
+}
+
+/// Elevation range in meters
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ElevationRange {
+    pub min: f64,
+    pub max: f64,
+}
+
+#[derive(Debug)]
+pub struct ParseElevationRangeError();
+
+impl Display for ParseElevationRangeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Expected min,max elevation in meters")
+    }
+}
+
+impl Error for ParseElevationRangeError {}
+
+impl FromStr for ElevationRange {
+    type Err = ParseElevationRangeError;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let (min, max) = string.split_once(',').ok_or(ParseElevationRangeError())?;
+
+        match (min.trim().parse(), max.trim().parse()) {
+            (Ok(min), Ok(max)) => Ok(Self { min, max }),
+            _ => Err(ParseElevationRangeError()),
+        }
+    }
+}
+
+/// Rectangle of the tiles of the zoom level
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TileRange {
+    pub zoom: u8,
+    pub x: (u32, u32),
+    pub y: (u32, u32),
+}
+
+impl TileRange {
+    /// EPSG:3857 bounding box covering the tiles
+    pub fn bbox(&self) -> BBox {
+        let tile_size = 2.0 * WEB_MERCATOR_EXTENT / f64::from(1u32 << self.zoom);
+
+        BBox::new(
+            -WEB_MERCATOR_EXTENT + f64::from(self.x.0) * tile_size,
+            WEB_MERCATOR_EXTENT - f64::from(self.y.1 + 1) * tile_size,
+            -WEB_MERCATOR_EXTENT + f64::from(self.x.1 + 1) * tile_size,
+            WEB_MERCATOR_EXTENT - f64::from(self.y.0) * tile_size,
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseTileRangeError();
+
+impl Display for ParseTileRangeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Expected zoom/x1-x2/y1-y2 with zoom up to 31, x1 <= x2 and y1 <= y2 of the tiles of the zoom"
+        )
+    }
+}
+
+impl Error for ParseTileRangeError {}
+
+impl FromStr for TileRange {
+    type Err = ParseTileRangeError;
 
     fn from_str(string: &str) -> Result<Self, Self::Err> {
-        u32::from_str_radix(string, 16).map(|color| {
-            let [_, r, g, b] = color.to_be_bytes();
+        let parts: Vec<_> = string.split('/').collect();
+
+        let [zoom, x, y] = parts[..] else {
+            return Err(ParseTileRangeError());
+        };
+
+        let zoom: u8 = zoom
+            .parse()
+            .ok()
+            .filter(|zoom| *zoom < 32)
+            .ok_or(ParseTileRangeError())?;
+
+        // single tile if it is not a range
+        let range = |range: &str| {
+            let (min, max) = range.split_once('-').unwrap_or((range, range));
 
-            Self(image::Rgb([r, g, b]))
+            match (min.parse::<u32>(), max.parse::<u32>()) {
+                (Ok(min), Ok(max)) if min <= max && u64::from(max) < 1 << zoom => Ok((min, max)),
+                _ => Err(ParseTileRangeError()),
+            }
+        };
+
+        Ok(Self {
+            zoom,
+            x: range(x)?,
+            y: range(y)?,
         })
     }
 }
 
-#[derive(ValueEnum, Debug, Clone, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rgba(pub image::Rgba<u8>);
+
+#[derive(Debug)]
+pub struct ParseRgbaError();
+
+impl Display for ParseRgbaError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Expected color in RRGGBBAA or RRGGBB format or its name")
+    }
+}
+
+impl Error for ParseRgbaError {}
+
+impl FromStr for Rgba {
+    type Err = ParseRgbaError;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        parse_color(string)
+            .map(|color| Self(image::Rgba(color.to_be_bytes())))
+            .ok_or(ParseRgbaError())
+    }
+}
+
+impl Serialize for Rgba {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialization::rgba::serialize(&u32::from_be_bytes(self.0.0), serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Rgba {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        serialization::rgba::deserialize(deserializer)
+            .map(|color| Self(image::Rgba(color.to_be_bytes())))
+    }
+}
+
+#[derive(ValueEnum, Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Format {
     JPEG,
     PNG,
+    WEBP,
 }
 
 impl Display for Format {
@@ -136,7 +1535,288 @@ impl Display for Format {
             match self {
                 Format::JPEG => "jpeg",
                 Format::PNG => "png",
+                Format::WEBP => "webp",
             }
         )
     }
 }
+
+impl From<Format> for image::ImageFormat {
+    fn from(format: Format) -> Self {
+        match format {
+            Format::JPEG => Self::Jpeg,
+            Format::PNG => Self::Png,
+            Format::WEBP => Self::WebP,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Options of the JSON object members of `Options::from_json` with an `igor-slope` shading
+    fn options(members: &str) -> Options {
+        Options::from_json(&format!(
+            r#"{{"shadings": "igor-slope,000000FF", {members}}}"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn background_color_is_rrggbb() {
+        assert_eq!(
+            "#102030".parse::<Rgb>().unwrap(),
+            Rgb(image::Rgb([0x10, 0x20, 0x30]))
+        );
+
+        for invalid in ["FFF", "FFFFFFFF", "GGGGGG", "white"] {
+            assert!(invalid.parse::<Rgb>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn output_is_opaque_for_jpeg_or_if_composited_so() {
+        assert!(options(r#""format": "jpeg""#).is_opaque());
+
+        assert!(!options(r#""format": "png", "mode": "hillshade""#).is_opaque());
+
+        assert!(options(r#""format": "png", "compositing": "opaque""#).is_opaque());
+    }
+
+    #[test]
+    fn quality_is_rejected_without_jpeg_or_webp_output() {
+        let validate =
+            |members: &str| options(&format!(r#""zoom_level": 16, {members}"#)).validate();
+
+        assert!(validate(r#""format": "jpeg", "quality": 90"#).is_ok());
+        assert!(validate(r#""format": "webp""#).is_ok());
+        assert!(validate(r#""format": "webp", "quality": 90"#).is_ok());
+        assert!(validate(r#""format": "png", "quality": 90"#).is_err());
+        assert!(
+            validate(
+                r#""format": "png", "quality": 90, "layer": "shade:hillshade:jpeg:shade.mbtiles""#
+            )
+            .is_ok()
+        );
+    }
+
+    #[test]
+    fn invalid_combinations_are_rejected() {
+        // zoom level 16 unless given
+        let error = |members: &str| {
+            let mut object: serde_json::Map<_, _> =
+                serde_json::from_str(&format!("{{{members}}}")).unwrap();
+
+            object.entry("zoom_level").or_insert(16.into());
+
+            serde_json::from_value::<Options>(object.into())
+                .unwrap()
+                .validate()
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert!(options(r#""zoom_level": 16"#).validate().is_ok());
+
+        for (members, message) in [
+            (
+                r#""zoom_level": 15"#,
+                "Zoom level must not be lower than the unit zoom level.",
+            ),
+            (r#""zoom_level": 31"#, "Zoom level must not exceed 30."),
+            (
+                r#""tile_size": 300"#,
+                "Tile size must be a multiple of 256.",
+            ),
+            (r#""supersample": 3"#, "Supersample must be 1, 2 or 4."),
+            (
+                r#""tile_size": 65280, "supersample": 2"#,
+                "Tile size times the supersample must not exceed 65535.",
+            ),
+            (r#""buffer": 300"#, "Buffer must not exceed the tile size."),
+            (r#""contrast": 0.0"#, "Contrast must be higher than 0.0"),
+            (
+                r#""aerial_perspective": 0.5"#,
+                "Aerial perspective requires --elevation-range.",
+            ),
+            (
+                r#""elevation_range": "2000,100""#,
+                "Minimal elevation of the range must be lower than the maximal one.",
+            ),
+            (
+                r#""max_points_per_cell": 0"#,
+                "Max points per cell must be at least 1",
+            ),
+            (
+                r#""dedup_cell_size": 0.0"#,
+                "Dedup cell size must be higher than 0.0",
+            ),
+            (
+                r#""max_edge_length": 0.0"#,
+                "Max edge length must be higher than 0.0",
+            ),
+            (
+                r#""water_link_distance": 0.0"#,
+                "Water link distance must be higher than 0.0",
+            ),
+            (
+                r#""interpolation_radius": 0.0"#,
+                "Interpolation radius must be higher than 0.0",
+            ),
+            (r#""idw_power": 0.0"#, "IDW power must be higher than 0.0"),
+            (
+                r#""idw_max_points": 0"#,
+                "IDW max points must be at least 1",
+            ),
+            (
+                r#""auto_contrast_samples": 0"#,
+                "Auto contrast samples must be at least 1",
+            ),
+            (
+                r#""compute_threads": 0"#,
+                "Read and compute threads must be at least 1",
+            ),
+            (
+                r#""debug_tile": "16/1-2/1""#,
+                "Debug tile must be a single tile.",
+            ),
+            (
+                r#""debug_tile": "10/1/1""#,
+                "Zoom of the debug tile must be between the unit zoom level and the zoom level.",
+            ),
+            (
+                r#""resume": true"#,
+                "Only output directories can be resumed. Use --existing-file-action continue for MBTiles.",
+            ),
+            (
+                r#""incremental": true, "existing_file_action": "overwrite""#,
+                "Incremental regeneration requires --existing-file-action update.",
+            ),
+            (
+                r#""incremental": true, "output": "out.pmtiles""#,
+                "PMTiles archive can't be updated by the incremental regeneration.",
+            ),
+            (
+                r#""min_zoom": 17"#,
+                "Min zoom must not exceed the zoom level.",
+            ),
+            (r#""gamma": 0.0"#, "Gamma must be higher than 0.0"),
+            (
+                r#""clarity_amount": 3.0"#,
+                "Clarity amount must be between 0.0 and 2.0",
+            ),
+            (
+                r#""clarity_radius": 0.0"#,
+                "Clarity radius must be higher than 0.0",
+            ),
+            (
+                r#""smooth_sigma": -1.0"#,
+                "Smooth sigma must not be negative",
+            ),
+            (
+                r#""smooth_sigma": 100.0"#,
+                "Three smooth sigmas must not exceed the buffer or tiles would not line up.",
+            ),
+            (
+                r#""despike_radius": -1.0"#,
+                "Despike radius must not be negative",
+            ),
+            (
+                r#""despike_threshold": 0.0"#,
+                "Despike threshold must be higher than 0.0",
+            ),
+            (
+                r#""derivative_method": "plane-fit", "derivative_radius": 0"#,
+                "Derivative radius must be at least 1",
+            ),
+            (
+                r#""derivative_method": "plane-fit", "derivative_radius": 41"#,
+                "Derivative radius must not exceed the buffer or tiles would not line up.",
+            ),
+            (r#""chm_max": 0.0"#, "CHM max must be higher than 0.0"),
+            (
+                r#""output": "out.mbtiles", "existing_file_action": "continue", "layer": "shade:hillshade:jpeg:shade.mbtiles""#,
+                "Layers can't be continued nor updated. Use --existing-file-action overwrite.",
+            ),
+            (
+                r#""output": "out.mbtiles", "layer": "shade:hillshade:jpeg:out.mbtiles""#,
+                "Outputs of the layers must differ.",
+            ),
+            (
+                r#""compositing": "transparent""#,
+                "JPEG does not support transparency. Use --format png or webp.",
+            ),
+            (
+                r#""shadings": "tpi,000000FF,1000,-5,5""#,
+                "Radius of sky-view factor, openness, local relief and TPI and distance of cast shadow must not exceed the buffer (95.54628535647032 m) or tiles would not line up.",
+            ),
+            (
+                r#""shadings": "sky-view-factor,000000FF,100""#,
+                "Radius of sky-view factor, openness, local relief and TPI and distance of cast shadow must not exceed the buffer (95.54628535647032 m) or tiles would not line up.",
+            ),
+            (
+                r#""shadings": "cast-shadow,000000FF,315,20,500""#,
+                "Radius of sky-view factor, openness, local relief and TPI and distance of cast shadow must not exceed the buffer (95.54628535647032 m) or tiles would not line up.",
+            ),
+            (
+                r#""mode": "grayscale""#,
+                "Grayscale mode requires --format png.",
+            ),
+            (
+                r#""mode": "grayscale", "format": "png", "shadings": "hypsometric,000000FF,100:00A000FF""#,
+                "Grayscale mode requires exactly one shading producing illumination; hypsometric, slope classes and local relief are not supported.",
+            ),
+            (
+                r#""mode": "elevation""#,
+                "Mode elevation requires --format png or webp as JPEG compression would corrupt the values.",
+            ),
+            (
+                r#""mode": "elevation", "format": "webp", "quality": 90"#,
+                "Mode elevation requires lossless WebP without --quality as lossy compression would corrupt the values.",
+            ),
+            (
+                r#""mode": "slope", "format": "webp""#,
+                "Mode slope requires --format png as only PNG supports its 16 bit values.",
+            ),
+            (
+                r#""mode": "aspect", "format": "png", "supersample": 2"#,
+                "Mode aspect can't be supersampled as downsampling would mix the values.",
+            ),
+            (
+                r#""mode": "canopy-height""#,
+                "Mode canopy-height requires --surface chm.",
+            ),
+        ] {
+            assert_eq!(error(members), message, "{members}");
+        }
+    }
+
+    #[test]
+    fn options_round_trip_through_json() {
+        let options = options(
+            r#""zoom_level": 17, "laz_index_db": "index.sqlite", "bbox": [1902000, 6190000, 1912000, 6200000],
+            "elevation_range": "100,2000", "exclude_classes": "7,18", "nodata_color": "FF000080",
+            "background_color": "102030", "layer": ["shade:hillshade:png:shade.mbtiles:igor-slope,000000FF"],
+            "slope_classes": "30:FFFF00A0,45:FF0000A0", "log_level": "debug", "mode": "canopy-height""#,
+        );
+
+        let json = serde_json::to_string(&options).unwrap();
+
+        assert_eq!(Options::from_json(&json).unwrap(), options, "{json}");
+
+        assert!(json.contains(r##""nodata_color":"#FF000080""##), "{json}");
+
+        assert!(
+            json.contains(r#""bbox":[1902000.0,6190000.0,1912000.0,6200000.0]"#),
+            "{json}"
+        );
+    }
+
+    #[test]
+    fn unknown_options_are_rejected() {
+        assert!(Options::from_json(r#"{"zoom": 17}"#).is_err());
+
+        assert!(Options::from_json(r#"{"config": "run.toml"}"#).is_err());
+    }
+}
@@ -0,0 +1,486 @@
+use crate::error::Error;
+use flate2::{Compression, write::GzEncoder};
+use maptile::tile::Tile;
+use std::{
+    collections::HashMap,
+    fs::{File, remove_file},
+    hash::{DefaultHasher, Hash, Hasher},
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+const HEADER_LENGTH: usize = 127;
+
+/// Max length of the root directory so that it is read with the header at once
+const MAX_ROOT_LENGTH: usize = 16_384 - HEADER_LENGTH;
+
+/// Archive fields which are not computed from the tiles
+pub struct Metadata {
+    /// `image/png` is 2, `image/jpeg` is 3, `image/webp` is 4
+    pub tile_type: u8,
+    pub min_zoom: u8,
+    pub max_zoom: u8,
+    /// In EPSG:4326 as `[min_lon, min_lat, max_lon, max_lat]`
+    pub bounds: [f64; 4],
+    /// JSON object
+    pub json: String,
+}
+
+struct Entry {
+    tile_id: u64,
+    offset: u64,
+    length: u32,
+    run_length: u32,
+}
+
+/// Writer of PMTiles v3 archive. Tile data are spilled to a temporary file and deduplicated
+/// to be written clustered (in the order of tile IDs) on `finish`.
+pub struct PmtilesWriter {
+    path: PathBuf,
+    temp_path: PathBuf,
+    temp: BufWriter<File>,
+    temp_length: u64,
+    /// Offsets in the temporary file by the content hash
+    contents: HashMap<(u64, u64), u64>,
+    /// Tile ID, offset in the temporary file, length
+    tiles: Vec<(u64, u64, u32)>,
+}
+
+impl PmtilesWriter {
+    pub fn new(path: &Path) -> Result<Self, Error> {
+        let mut temp_path = path.as_os_str().to_owned();
+
+        temp_path.push(".tmp");
+
+        let temp_path = PathBuf::from(temp_path);
+
+        let temp = File::create(&temp_path).map_err(write_error(&temp_path))?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            temp: BufWriter::new(temp),
+            temp_path,
+            temp_length: 0,
+            contents: HashMap::new(),
+            tiles: Vec::new(),
+        })
+    }
+
+    pub fn add(&mut self, tile: Tile, data: &[u8]) -> Result<(), Error> {
+        let hash = hash(data);
+
+        let offset = if let Some(&offset) = self.contents.get(&hash) {
+            offset
+        } else {
+            self.temp
+                .write_all(data)
+                .map_err(write_error(&self.temp_path))?;
+
+            let offset = self.temp_length;
+
+            self.temp_length += data.len() as u64;
+
+            self.contents.insert(hash, offset);
+
+            offset
+        };
+
+        self.tiles.push((
+            tile_id(tile.zoom, tile.x, tile.y),
+            offset,
+            data.len() as u32,
+        ));
+
+        Ok(())
+    }
+
+    pub fn finish(mut self, metadata: &Metadata) -> Result<(), Error> {
+        self.temp.flush().map_err(write_error(&self.temp_path))?;
+
+        self.tiles.sort_unstable_by_key(|&(tile_id, _, _)| tile_id);
+
+        // offsets in the tile data section assigned in the order of the tile IDs
+        let mut offsets = HashMap::<u64, u64>::new();
+
+        // offsets in the temporary file and lengths of the distinct contents in the tile data section order
+        let mut contents = Vec::new();
+
+        let mut data_length = 0;
+
+        let mut entries = Vec::<Entry>::new();
+
+        for &(tile_id, temp_offset, length) in &self.tiles {
+            let offset = *offsets.entry(temp_offset).or_insert_with(|| {
+                contents.push((temp_offset, length));
+
+                data_length += u64::from(length);
+
+                data_length - u64::from(length)
+            });
+
+            if let Some(last) = entries.last_mut() {
+                if last.offset == offset && last.tile_id + u64::from(last.run_length) == tile_id {
+                    last.run_length += 1;
+
+                    continue;
+                }
+            }
+
+            entries.push(Entry {
+                tile_id,
+                offset,
+                length,
+                run_length: 1,
+            });
+        }
+
+        let (root, leaves) = directories(&entries);
+
+        let metadata_json = gzip(metadata.json.as_bytes());
+
+        let root_offset = HEADER_LENGTH as u64;
+
+        let metadata_offset = root_offset + root.len() as u64;
+
+        let leaves_offset = metadata_offset + metadata_json.len() as u64;
+
+        let data_offset = leaves_offset + leaves.len() as u64;
+
+        let mut header = Vec::with_capacity(HEADER_LENGTH);
+
+        header.extend_from_slice(b"PMTiles");
+
+        header.push(3);
+
+        for value in [
+            root_offset,
+            root.len() as u64,
+            metadata_offset,
+            metadata_json.len() as u64,
+            leaves_offset,
+            leaves.len() as u64,
+            data_offset,
+            data_length,
+            self.tiles.len() as u64,
+            entries.len() as u64,
+            contents.len() as u64,
+        ] {
+            header.extend_from_slice(&value.to_le_bytes());
+        }
+
+        // clustered, gzip internal compression, no tile compression
+        header.extend_from_slice(&[
+            1,
+            2,
+            1,
+            metadata.tile_type,
+            metadata.min_zoom,
+            metadata.max_zoom,
+        ]);
+
+        let [min_lon, min_lat, max_lon, max_lat] = metadata.bounds;
+
+        for value in [min_lon, min_lat, max_lon, max_lat] {
+            header.extend_from_slice(&e7(value).to_le_bytes());
+        }
+
+        header.push(metadata.min_zoom);
+
+        header.extend_from_slice(&e7((min_lon + max_lon) / 2.0).to_le_bytes());
+
+        header.extend_from_slice(&e7((min_lat + max_lat) / 2.0).to_le_bytes());
+
+        let write = write_error(&self.path);
+
+        let mut file = BufWriter::new(File::create(&self.path).map_err(&write)?);
+
+        for section in [&header, &root, &metadata_json, &leaves] {
+            file.write_all(section).map_err(&write)?;
+        }
+
+        let read = |source| Error::Io {
+            path: self.temp_path.display().to_string(),
+            source,
+        };
+
+        let mut temp = File::open(&self.temp_path).map_err(read)?;
+
+        let mut buffer = Vec::new();
+
+        for (temp_offset, length) in contents {
+            buffer.resize(length as usize, 0);
+
+            temp.seek(SeekFrom::Start(temp_offset))
+                .and_then(|_| temp.read_exact(&mut buffer))
+                .map_err(read)?;
+
+            file.write_all(&buffer).map_err(&write)?;
+        }
+
+        file.flush().map_err(&write)?;
+
+        drop(self.temp);
+
+        remove_file(&self.temp_path).map_err(write_error(&self.temp_path))
+    }
+}
+
+fn write_error(path: &Path) -> impl Fn(io::Error) -> Error + '_ {
+    |source| Error::Write {
+        path: path.display().to_string(),
+        source,
+    }
+}
+
+/// Hilbert curve index of the tile after the tiles of the lower zoom levels
+fn tile_id(zoom: u8, mut x: u32, mut y: u32) -> u64 {
+    let n = 1u64 << zoom;
+
+    let mut id = ((1u64 << (2 * zoom)) - 1) / 3;
+
+    let mut s = n >> 1;
+
+    while s > 0 {
+        let rx = u64::from(x as u64 & s > 0);
+
+        let ry = u64::from(y as u64 & s > 0);
+
+        id += s * s * ((3 * rx) ^ ry);
+
+        if ry == 0 {
+            if rx == 1 {
+                x = (n - 1) as u32 - x;
+
+                y = (n - 1) as u32 - y;
+            }
+
+            (x, y) = (y, x);
+        }
+
+        s >>= 1;
+    }
+
+    id
+}
+
+/// Root directory and the leaf directories if all the entries don't fit the root
+fn directories(entries: &[Entry]) -> (Vec<u8>, Vec<u8>) {
+    if entries.len() < 16_384 {
+        let root = serialize_entries(entries);
+
+        if root.len() <= MAX_ROOT_LENGTH {
+            return (root, Vec::new());
+        }
+    }
+
+    let mut leaf_size = (entries.len() as f64 / 3_500.0).max(4_096.0);
+
+    loop {
+        let mut roots = Vec::new();
+
+        let mut leaves = Vec::new();
+
+        for chunk in entries.chunks(leaf_size as usize) {
+            let leaf = serialize_entries(chunk);
+
+            roots.push(Entry {
+                tile_id: chunk[0].tile_id,
+                offset: leaves.len() as u64,
+                length: leaf.len() as u32,
+                run_length: 0,
+            });
+
+            leaves.extend_from_slice(&leaf);
+        }
+
+        let root = serialize_entries(&roots);
+
+        if root.len() <= MAX_ROOT_LENGTH {
+            return (root, leaves);
+        }
+
+        leaf_size *= 1.2;
+    }
+}
+
+/// Gzipped directory of the columns of the varints of the entries
+fn serialize_entries(entries: &[Entry]) -> Vec<u8> {
+    let mut data = Vec::new();
+
+    write_varint(&mut data, entries.len() as u64);
+
+    let mut last_tile_id = 0;
+
+    for entry in entries {
+        write_varint(&mut data, entry.tile_id - last_tile_id);
+
+        last_tile_id = entry.tile_id;
+    }
+
+    for entry in entries {
+        write_varint(&mut data, u64::from(entry.run_length));
+    }
+
+    for entry in entries {
+        write_varint(&mut data, u64::from(entry.length));
+    }
+
+    for (i, entry) in entries.iter().enumerate() {
+        // 0 for the offset following the previous entry
+        let offset = match i.checked_sub(1).map(|i| &entries[i]) {
+            Some(previous) if entry.offset == previous.offset + u64::from(previous.length) => 0,
+            _ => entry.offset + 1,
+        };
+
+        write_varint(&mut data, offset);
+    }
+
+    gzip(&data)
+}
+
+fn write_varint(data: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        data.push((value & 0x7f) as u8 | 0x80);
+
+        value >>= 7;
+    }
+
+    data.push(value as u8);
+}
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+
+    encoder.write_all(data).unwrap();
+
+    encoder.finish().unwrap()
+}
+
+/// 128 bit hash so that the distinct contents practically don't collide
+fn hash(data: &[u8]) -> (u64, u64) {
+    let mut hasher = DefaultHasher::new();
+
+    data.hash(&mut hasher);
+
+    let first = hasher.finish();
+
+    data.len().hash(&mut hasher);
+
+    data.hash(&mut hasher);
+
+    (first, hasher.finish())
+}
+
+fn e7(degrees: f64) -> i32 {
+    (degrees * 10_000_000.0).round() as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::fs;
+
+    #[test]
+    fn tile_ids_follow_the_hilbert_curve_of_the_zoom_levels() {
+        assert_eq!(tile_id(0, 0, 0), 0);
+
+        let tile_ids = [(0, 0), (0, 1), (1, 1), (1, 0)].map(|(x, y)| tile_id(1, x, y));
+
+        assert_eq!(tile_ids, [1, 2, 3, 4]);
+
+        assert_eq!(tile_id(2, 0, 0), 5);
+        assert_eq!(tile_id(12, 3423, 1763), 19_078_479);
+    }
+
+    /// First `count` varints of the data
+    fn read_varints(data: &[u8], count: usize) -> Vec<u64> {
+        let mut values = Vec::new();
+
+        let (mut value, mut shift) = (0, 0);
+
+        for &byte in data {
+            value |= u64::from(byte & 0x7f) << shift;
+
+            shift += 7;
+
+            if byte & 0x80 == 0 {
+                values.push(value);
+
+                (value, shift) = (0, 0);
+
+                if values.len() == count {
+                    break;
+                }
+            }
+        }
+
+        values
+    }
+
+    #[test]
+    fn archive_has_the_header_and_the_deduplicated_tiles() {
+        let dir = std::env::temp_dir().join(format!("laz2dem-{}-pmtiles", std::process::id()));
+
+        fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("tiles.pmtiles");
+
+        let mut writer = PmtilesWriter::new(&path).unwrap();
+
+        // the same content of the first and the last tile of zoom 1
+        for (x, y, data) in [(1, 0, b"a"), (0, 0, b"a"), (1, 1, b"b")] {
+            writer.add(Tile { zoom: 1, x, y }, data).unwrap();
+        }
+
+        writer
+            .finish(&Metadata {
+                tile_type: 2,
+                min_zoom: 1,
+                max_zoom: 1,
+                bounds: [17.0, 48.0, 18.0, 49.0],
+                json: "{}".to_owned(),
+            })
+            .unwrap();
+
+        let archive = fs::read(&path).unwrap();
+
+        assert_eq!(&archive[..8], b"PMTiles\x03");
+
+        let field =
+            |i: usize| u64::from_le_bytes(archive[8 + i * 8..16 + i * 8].try_into().unwrap());
+
+        let (root_offset, root_length, data_offset) = (field(0), field(1), field(6));
+
+        assert_eq!(root_offset, HEADER_LENGTH as u64);
+
+        // addressed tiles, tile entries and tile contents
+        assert_eq!([field(8), field(9), field(10)], [3, 3, 2]);
+
+        // clustered, gzip directories, uncompressed PNG tiles of zoom 1
+        assert_eq!(&archive[96..102], &[1, 2, 1, 2, 1, 1]);
+
+        assert_eq!(
+            i32::from_le_bytes(archive[102..106].try_into().unwrap()),
+            170_000_000
+        );
+
+        let mut root = Vec::new();
+
+        GzDecoder::new(&archive[root_offset as usize..(root_offset + root_length) as usize])
+            .read_to_end(&mut root)
+            .unwrap();
+
+        let values = read_varints(&root, 13);
+
+        // count, delta encoded tile IDs, run lengths, lengths and offsets of 0 following the previous entry
+        assert_eq!(values, [3, 1, 2, 1, 1, 1, 1, 1, 1, 1, 1, 0, 1]);
+
+        // the content of 1/0/0 and 1/1/0 is stored once
+        let data = &archive[data_offset as usize..];
+
+        assert_eq!(&data[..2], b"ab");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,11 +1,141 @@
+//! Progress of a run, reported to a [`ProgressReporter`] of `Options::progress` or logged.
+
 use std::{
     collections::{HashMap, HashSet},
+    fmt::Debug,
+    sync::{Arc, Mutex},
     time::{Duration, SystemTime},
 };
 
+use log::{info, warn};
 use maptile::tile::Tile;
 
-use crate::shared_types::Job;
+use crate::{
+    options::Options,
+    shared_types::{Job, TileMeta},
+};
+
+/// Progress of reading the files
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReadReport {
+    /// Files read, including the skipped ones
+    pub files: usize,
+    pub total_files: usize,
+    /// Done fraction of the files weighted by their point counts
+    pub fraction: f64,
+    pub points_per_second: f64,
+    pub elapsed: Duration,
+}
+
+/// Progress of rasterizing the tiles
+#[derive(Clone, Debug, PartialEq)]
+pub struct RasterizeReport {
+    /// Done fraction of the tiles of all the zoom levels
+    pub fraction: f64,
+    /// Jobs waiting in the queue
+    pub queued: usize,
+    /// Overview tiles queued so far
+    pub done: usize,
+    pub elapsed: Duration,
+}
+
+/// Receiver of the progress of a run, e.g. to show progress bars. It is called by the reading and rasterizing
+/// threads after every file and tile, unthrottled.
+pub trait ProgressReporter: Send + Sync {
+    fn read(&self, report: &ReadReport);
+
+    fn rasterize(&self, report: &RasterizeReport);
+}
+
+/// Reporter of `Options::progress`, equal only to the same instance
+#[derive(Clone)]
+pub struct ProgressHook(pub Arc<dyn ProgressReporter>);
+
+impl Debug for ProgressHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ProgressHook")
+    }
+}
+
+impl PartialEq for ProgressHook {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// Reporter of the options, the logging one if none is set
+pub(crate) fn reporter(options: &Options) -> Arc<dyn ProgressReporter> {
+    match &options.progress {
+        Some(ProgressHook(reporter)) => reporter.clone(),
+        None => Arc::new(LogProgress::new()),
+    }
+}
+
+/// Logs the progress at most once a second, and the last file read
+pub struct LogProgress {
+    /// Times of the last read and rasterize logs
+    last_logs: Mutex<[SystemTime; 2]>,
+}
+
+impl LogProgress {
+    pub fn new() -> Self {
+        Self {
+            last_logs: Mutex::new([SystemTime::now(); 2]),
+        }
+    }
+
+    /// Whether a second passed since the last log of the kind
+    fn is_due(&self, kind: usize) -> bool {
+        let mut last_logs = self.last_logs.lock().unwrap();
+
+        let t = SystemTime::now();
+
+        if t.duration_since(last_logs[kind]).unwrap_or_default() <= Duration::from_millis(1000) {
+            return false;
+        }
+
+        last_logs[kind] = t;
+
+        true
+    }
+}
+
+impl Default for LogProgress {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProgressReporter for LogProgress {
+    fn read(&self, report: &ReadReport) {
+        if !self.is_due(0) && report.files < report.total_files {
+            return;
+        }
+
+        info!(
+            "READ {:.2}% {}/{} files, {:.0} points/s, ETA {}",
+            report.fraction * 100.0,
+            report.files,
+            report.total_files,
+            report.points_per_second,
+            eta(report.elapsed, report.fraction),
+        );
+    }
+
+    fn rasterize(&self, report: &RasterizeReport) {
+        if !self.is_due(1) {
+            return;
+        }
+
+        info!(
+            "RASTERIZE {:.2}% {} queued {} done, ETA {}",
+            report.fraction * 100.0,
+            report.queued,
+            report.done,
+            eta(report.elapsed, report.fraction),
+        );
+    }
+}
 
 enum State {
     Planned,    // no job yet
@@ -14,19 +144,28 @@ enum State {
     Finished,   // done
 }
 
-pub struct Progress {
+pub(crate) struct Progress {
     supertile_zoom_offset: u8,
     pub jobs: Vec<Job>,
     states: HashMap<Tile, State>,
-    last_log: SystemTime,
+    started: SystemTime,
+    reporter: Arc<dyn ProgressReporter>,
     done_count: usize,
 }
 
 impl Progress {
-    pub fn new(jobs: Vec<Job>, supertile_zoom_offset: u8) -> Self {
-        let mut states: HashMap<Tile, State> = jobs
+    /// `supertiles` of all the rasterize jobs, including the ones added later;
+    /// overviews are planned down to `min_zoom`
+    pub fn new(
+        supertiles: &[Tile],
+        jobs: Vec<Job>,
+        supertile_zoom_offset: u8,
+        min_zoom: u8,
+        reporter: Arc<dyn ProgressReporter>,
+    ) -> Self {
+        let mut states: HashMap<Tile, State> = supertiles
             .iter()
-            .flat_map(|job| job.tile().descendants(supertile_zoom_offset))
+            .flat_map(|tile| tile.descendants(supertile_zoom_offset))
             .map(|tile| (tile, State::Waiting))
             .collect();
 
@@ -35,7 +174,11 @@ impl Progress {
         loop {
             states.extend(next.iter().map(|tile| (*tile, State::Planned)));
 
-            next = next.iter().filter_map(|tile| tile.parent()).collect();
+            next = next
+                .iter()
+                .filter_map(|tile| tile.parent())
+                .filter(|tile| tile.zoom >= min_zoom)
+                .collect();
 
             if next.is_empty() {
                 break;
@@ -46,7 +189,8 @@ impl Progress {
             supertile_zoom_offset,
             jobs,
             states,
-            last_log: SystemTime::now(),
+            started: SystemTime::now(),
+            reporter,
             done_count: 0,
         }
     }
@@ -68,6 +212,32 @@ impl Progress {
         job
     }
 
+    /// Adds rasterize jobs of the tiles read later
+    pub fn extend(&mut self, tile_metas: Vec<TileMeta>) {
+        self.jobs.extend(tile_metas.into_iter().map(Job::Rasterize));
+    }
+
+    pub fn log_duration(&self) {
+        info!(
+            "RASTERIZED in {}",
+            format_duration(SystemTime::now().duration_since(self.started).unwrap())
+        );
+    }
+
+    /// Summary of the tiles written before the cancellation
+    pub fn log_cancelled(&self) {
+        let finished = self
+            .states
+            .values()
+            .filter(|state| matches!(state, State::Finished))
+            .count();
+
+        warn!(
+            "CANCELLED with {finished} of {} tiles rendered; render the rest with --resume or --existing-file-action continue",
+            self.states.len()
+        );
+    }
+
     pub fn done(&mut self, tile: Tile) {
         *self.states.get_mut(&tile).unwrap() = State::Finished;
 
@@ -92,19 +262,105 @@ impl Progress {
 
         self.jobs.push(Job::Overview(parent));
 
-        let t = SystemTime::now();
-
         self.done_count += 1;
 
-        if t.duration_since(self.last_log).unwrap() > Duration::from_millis(1000) {
-            self.last_log = t;
+        self.reporter.rasterize(&RasterizeReport {
+            fraction: self.done_count as f64 / self.states.len() as f64,
+            queued: self.jobs.len(),
+            done: self.done_count,
+            elapsed: self.started.elapsed().unwrap_or_default(),
+        });
+    }
+}
+
+/// Progress of reading the files weighted by their point counts
+pub(crate) struct ReadProgress {
+    files: usize,
+    /// Weight of the files of unknown point count
+    default_points: u64,
+    total_points: u64,
+    started: SystemTime,
+    reporter: Arc<dyn ProgressReporter>,
+    state: Mutex<ReadState>,
+}
+
+struct ReadState {
+    files: usize,
+    /// Sum of the weights of the read files
+    weighted_points: u64,
+    /// Points actually read
+    points: u64,
+}
+
+impl ReadProgress {
+    /// `point_counts` of the files to read, `None` if unknown
+    pub fn new(point_counts: &[Option<u64>], reporter: Arc<dyn ProgressReporter>) -> Self {
+        let known: Vec<_> = point_counts.iter().flatten().copied().collect();
+
+        // unknown files weigh as the mean of the known, all equally if none is known
+        let default_points = if known.is_empty() {
+            1
+        } else {
+            (known.iter().sum::<u64>() / known.len() as u64).max(1)
+        };
 
-            println!(
-                "{}% {} {}",
-                (self.done_count * 10_000 / self.states.len()) as f64 / 100.0,
-                self.jobs.len(),
-                self.done_count,
-            );
+        Self {
+            files: point_counts.len(),
+            default_points,
+            total_points: point_counts
+                .iter()
+                .map(|point_count| point_count.unwrap_or(default_points))
+                .sum(),
+            started: SystemTime::now(),
+            reporter,
+            state: Mutex::new(ReadState {
+                files: 0,
+                weighted_points: 0,
+                points: 0,
+            }),
         }
     }
+
+    /// Records the file of `point_count` as passed to `new` once `points` of it were read
+    pub fn file_done(&self, point_count: Option<u64>, points: u64) {
+        let mut state = self.state.lock().unwrap();
+
+        state.files += 1;
+
+        state.weighted_points += point_count.unwrap_or(self.default_points);
+
+        state.points += points;
+
+        let elapsed = self.started.elapsed().unwrap_or_default();
+
+        // reported under the lock to keep the reports in order
+        self.reporter.read(&ReadReport {
+            files: state.files,
+            total_files: self.files,
+            fraction: state.weighted_points as f64 / self.total_points.max(1) as f64,
+            points_per_second: state.points as f64 / elapsed.as_secs_f64().max(0.001),
+            elapsed,
+        });
+    }
+}
+
+/// Remaining time extrapolated from the elapsed time of the done fraction
+fn eta(elapsed: Duration, fraction: f64) -> String {
+    if fraction <= 0.0 {
+        return "?".to_string();
+    }
+
+    format_duration(elapsed.mul_f64((1.0 - fraction) / fraction))
+}
+
+/// Duration as `h:mm:ss`
+pub(crate) fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs_f64().round() as u64;
+
+    format!(
+        "{}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
 }
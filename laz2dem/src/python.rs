@@ -0,0 +1,165 @@
+use crate::{
+    options::Options,
+    shading::{hillshade, rgba_to_rgb},
+    shared_types::{Shading, ShadingStackBuilder, Shadings, parse_color},
+    terrain::{Elevations, Grid, Rasters},
+};
+use numpy::{IntoPyArray, PyArray3, PyReadonlyArray2, ndarray::Array3};
+use pyo3::{exceptions::PyValueError, prelude::*};
+
+/// Shading of `compute_hillshade`
+#[pyclass(name = "Shading", frozen)]
+#[derive(Clone)]
+struct PyShading(Shading);
+
+fn color(color: &str) -> PyResult<[u8; 4]> {
+    parse_color(color)
+        .map(u32::to_be_bytes)
+        .ok_or_else(|| PyValueError::new_err(format!("invalid color {color}")))
+}
+
+fn single(builder: ShadingStackBuilder) -> PyResult<PyShading> {
+    let Shadings(shadings) = builder
+        .build()
+        .map_err(|error| PyValueError::new_err(error.to_string()))?;
+
+    Ok(PyShading(
+        shadings.into_iter().next().expect("built shading"),
+    ))
+}
+
+#[pymethods]
+impl PyShading {
+    /// Shading of the `--shadings` syntax, e.g. `Shading("oblique,000000FF,315,45,blend=multiply")`
+    #[new]
+    fn new(shading: &str) -> PyResult<Self> {
+        let Shadings(mut shadings) = shading
+            .parse::<Shadings>()
+            .map_err(|error| PyValueError::new_err(error.to_string()))?;
+
+        if shadings.len() != 1 {
+            return Err(PyValueError::new_err("expected a single shading"));
+        }
+
+        Ok(Self(shadings.remove(0)))
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (azimuth, color = "000000FF", weight = 1.0))]
+    fn igor(azimuth: f64, color: &str, weight: f64) -> PyResult<Self> {
+        let [r, g, b, alpha] = self::color(color)?;
+
+        single(
+            ShadingStackBuilder::new()
+                .igor(azimuth, [r, g, b], alpha)
+                .weight(weight),
+        )
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (azimuth, altitude, color = "000000FF", weight = 1.0))]
+    fn oblique(azimuth: f64, altitude: f64, color: &str, weight: f64) -> PyResult<Self> {
+        single(
+            ShadingStackBuilder::new()
+                .oblique(azimuth, altitude, self::color(color)?)
+                .weight(weight),
+        )
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (altitude, color = "000000FF", weight = 1.0))]
+    fn oblique_slope(altitude: f64, color: &str, weight: f64) -> PyResult<Self> {
+        single(
+            ShadingStackBuilder::new()
+                .slope(altitude, self::color(color)?)
+                .weight(weight),
+        )
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "Shading({})",
+            serde_json::to_string(&self.0).unwrap_or_default()
+        )
+    }
+}
+
+/// RGB image (rows, cols, 3) of `--mode hillshade` of the elevations in meters of the rows from the north,
+/// NaN for no data, of pixels of `cell_size` ground meters, composed on the white background.
+/// C-contiguous elevations are shaded without a copy and the GIL is released while shading.
+#[pyfunction]
+#[pyo3(signature = (elevations, z_factor = 1.0, shadings = None, contrast = 1.0, brightness = 0.0, cell_size = 1.0))]
+fn compute_hillshade<'py>(
+    py: Python<'py>,
+    elevations: PyReadonlyArray2<'py, f64>,
+    z_factor: f64,
+    shadings: Option<Vec<PyShading>>,
+    contrast: f64,
+    brightness: f64,
+    cell_size: f64,
+) -> PyResult<Bound<'py, PyArray3<u8>>> {
+    let mut options = Options::from_json("{}").expect("default options");
+
+    options.z_factor = z_factor;
+
+    options.contrast = contrast;
+
+    options.brightness = brightness;
+
+    if let Some(shadings) = shadings {
+        options.shadings = shadings
+            .into_iter()
+            .fold(ShadingStackBuilder::new(), |builder, shading| {
+                builder.shading(shading.0)
+            })
+            .build()
+            .map_err(|error| PyValueError::new_err(error.to_string()))?;
+    }
+
+    let (rows, cols) = elevations.as_array().dim();
+
+    if rows == 0 || cols == 0 {
+        return Err(PyValueError::new_err("elevations must not be empty"));
+    }
+
+    // row 0 of the grid is the southmost one
+    let elevations = match elevations.as_slice() {
+        Ok(elevations) => Elevations::F64FromNorth { elevations, cols },
+        Err(_) => Elevations::F64(
+            (0..rows)
+                .rev()
+                .flat_map(|y| elevations.as_array().row(y).to_vec())
+                .collect(),
+        ),
+    };
+
+    let rgb = py.allow_threads(|| {
+        let grid = Grid {
+            elevations: &elevations,
+            rows,
+            cols,
+            cell_size,
+            mercator_y: None,
+        };
+
+        let rasters = Rasters::new(&grid, options.z_factor, &options.shadings.0);
+
+        rgba_to_rgb(
+            &hillshade(&options, &grid, &rasters),
+            options.background_color.0,
+        )
+    });
+
+    Ok(Array3::from_shape_vec((rows, cols, 3), rgb.into_raw())
+        .expect("RGB of the grid")
+        .into_pyarray(py))
+}
+
+#[pymodule]
+fn laz2dem(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<PyShading>()?;
+
+    module.add_function(wrap_pyfunction!(compute_hillshade, module)?)?;
+
+    Ok(())
+}
@@ -1,28 +1,53 @@
 use crate::{
-    options::{ExistingFileAction, Format, Options},
-    progress::Progress,
-    schema::create_schema,
-    shading::{compute_hillshade, shade},
-    shared_types::{Job, PointWithHeight, Source},
+    auto_contrast::auto_contrast,
+    dedup::{dedup, thin},
+    despike::despike,
+    encoding::{compose_elevation_overview, encode_elevation},
+    error::Error,
+    geotiff::{GeoReference, Raster, write_geotiff, write_image_geotiff},
+    journal::{Journal, recorded},
+    options::{Dedup, ExistingFileAction, Format, Mode, Options, SurfaceModel},
+    pmtiles::{Metadata, PmtilesWriter},
+    progress::{Progress, reporter},
+    read::{point_use, rejection},
+    schema::{create_schema, get_metadata, update_metadata},
+    shading::{
+        ShadingContext, compute_hillshade, hillshade, rgba_to_rgb, shade_aspect,
+        shade_canopy_height, shade_grayscale, shade_normal, shade_slope,
+    },
+    shared_types::{Job, Source, TileMeta, sort_points},
+    stats::{TileStats, write_stats},
+    surface::{highest_per_pixel, interpolate},
+    terrain::{Elevations, Grid, Rasters},
+    tile_directory::{read_tile, write_tile, write_vrt},
+    timings::{Phase, TIMINGS},
 };
-use core::f64;
 use image::{
-    GenericImage, Pixel, Rgb, RgbImage, RgbaImage,
-    codecs::{jpeg::JpegEncoder, png::PngEncoder},
-    imageops::{FilterType, crop_imm, resize},
+    DynamicImage, GenericImage, ImageBuffer, Pixel,
+    codecs::{jpeg::JpegEncoder, png::PngEncoder, webp::WebPEncoder},
+    imageops::{FilterType, resize},
     load_from_memory_with_format,
 };
 use las::Reader;
-use maptile::tile::Tile;
+use log::{debug, warn};
+use maptile::{bbox::BBox, tile::Tile};
 use proj::Proj;
-use rusqlite::{Connection, Error, ErrorCode, OpenFlags};
-use spade::{DelaunayTriangulation, Point2, Triangulation};
+use rusqlite::{Connection, ErrorCode, OpenFlags};
+use serde_json::json;
+use spade::Point2;
 use std::{
-    collections::HashMap,
-    fs::{exists, remove_file},
-    io::Cursor,
-    sync::{Arc, Mutex},
-    thread::{self, available_parallelism},
+    collections::{HashMap, HashSet},
+    fs::{create_dir_all, exists, remove_file, write},
+    io::{self, Cursor},
+    iter,
+    path::Path,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{Receiver, SyncSender, sync_channel},
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
 const SELECT_TILE_EXISTS_SQL: &str =
@@ -33,135 +58,291 @@ const SELECT_TILE_SQL: &str =
 
 const INSERT_TILE_SQL: &str = "INSERT INTO tiles VALUES (?1, ?2, ?3, ?4)";
 
+const UPSERT_TILE_SQL: &str = "INSERT OR REPLACE INTO tiles VALUES (?1, ?2, ?3, ?4)";
+
+/// Max number of the tiles written in a transaction
+const TILES_PER_TRANSACTION: usize = 1024;
+
 const SELECT_LAZTILE_SQL: &str = "SELECT data FROM tiles WHERE x = ?1 AND y = ?2";
 
-pub fn rasterize(options: &Options, jobs: Vec<Job>) {
+/// Output of the tiles, the main one or of a `--layer`
+struct Target<'a> {
+    options: &'a Options,
+    /// Name in the MBTiles and PMTiles metadata
+    name: &'a str,
+    conn: Option<Arc<Mutex<Connection>>>,
+    /// Tiles of the zoom levels above `--min-zoom` kept for their overviews
+    for_overviews: Mutex<HashMap<Tile, DynamicImage>>,
+    journal: Option<Journal>,
+}
+
+/// Renders the jobs of the `supertiles`, receiving rasterize jobs also from `batches` if set, once interpolated
+/// to the main output and to the outputs of the layers following it.
+/// Once `cancel` is set or on the first error of a thread, no more jobs are started and the outputs are closed
+/// with the tiles written so far.
+pub fn rasterize(
+    outputs: &[Options],
+    supertiles: &[Tile],
+    jobs: Vec<Job>,
+    batches: Option<Receiver<Vec<TileMeta>>>,
+    cancel: &AtomicBool,
+) -> Result<(), Error> {
+    let options = &outputs[0];
+
     let output = &options.output;
 
-    let cont = exists(output).unwrap()
-        && match options.existing_file_action {
-            Some(ExistingFileAction::Overwrite) => {
-                remove_file(output).unwrap();
+    // layers are neither continued nor updated
+    let existing_file_actions = outputs
+        .iter()
+        .map(prepare_output)
+        .collect::<Result<Vec<_>, _>>()?;
 
-                false
-            }
-            Some(ExistingFileAction::Continue) => true,
-            None => panic!("Output file already exitsts. Specify --existing-file-action."),
-        };
+    let existing_file_action = existing_file_actions[0];
 
-    let conn = Connection::open(output).unwrap();
+    let cont = existing_file_action == Some(ExistingFileAction::Continue);
 
-    {
-        let proj_3857_to_4326 = Proj::new_known_crs("EPSG:3857", "EPSG:4326", None)
-            .expect("Failed to create PROJ transformation");
-
-        let mut bounds = vec![
-            (options.bbox.min_x, options.bbox.min_y),
-            (options.bbox.max_x, options.bbox.max_y),
-        ];
-
-        proj_3857_to_4326.project_array(&mut bounds, false).unwrap();
-
-        if !cont {
-            create_schema(
-                &conn,
-                &[
-                    ("name", "Hillshade"), // TODO
-                    ("minzoom", "0"),
-                    ("maxzoom", options.zoom_level.to_string().as_ref()),
-                    ("format", &options.format.to_string()),
-                    (
-                        "bounds",
-                        &format!(
-                            "{},{},{},{}",
-                            bounds[0].0, bounds[0].1, bounds[1].0, bounds[1].1
-                        ),
-                    ),
-                ],
-            )
-            .unwrap();
-        }
-    }
+    let update = existing_file_action == Some(ExistingFileAction::Update);
+
+    let resumed = if options.resume {
+        recorded(output)
+    } else {
+        HashSet::new()
+    };
+
+    // supertiles sampled by `--auto-contrast` are interpolated once
+    let (outputs, sampled) = auto_contrast(outputs, &jobs, &resumed)?;
+
+    let outputs = outputs.as_slice();
 
-    conn.pragma_update(None, "synchronous", "OFF").unwrap();
+    let options = &outputs[0];
 
-    conn.pragma_update(None, "journal_mode", "WAL").unwrap();
+    let sampled = Mutex::new(sampled);
 
-    let conn = Arc::new(Mutex::new(conn));
+    let proj_error = |source: Box<dyn std::error::Error + Send + Sync>| Error::Proj {
+        context: "transforming the bounds of the output to EPSG:4326".to_owned(),
+        source,
+    };
+
+    let proj_3857_to_4326 = Proj::new_known_crs("EPSG:3857", "EPSG:4326", None)
+        .map_err(|source| proj_error(source.into()))?;
+
+    let mut bounds = vec![
+        (options.bbox.min_x, options.bbox.min_y),
+        (options.bbox.max_x, options.bbox.max_y),
+    ];
+
+    proj_3857_to_4326
+        .project_array(&mut bounds, false)
+        .map_err(|source| proj_error(source.into()))?;
+
+    let targets: Vec<_> = outputs
+        .iter()
+        .enumerate()
+        .map(|(i, output)| {
+            let name = i
+                .checked_sub(1)
+                .map_or("Hillshade", |layer| &options.layers[layer].name);
+
+            Ok(Target {
+                options: output,
+                name,
+                conn: (!output.is_pmtiles() && !output.is_directory())
+                    .then(|| open_mbtiles(output, name, cont, update, &bounds))
+                    .transpose()?
+                    .map(|conn| Arc::new(Mutex::new(conn))),
+                for_overviews: Mutex::new(HashMap::new()),
+                journal: output
+                    .is_directory()
+                    .then(|| Journal::open(&output.output, options.resume))
+                    .transpose()?,
+            })
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
 
     let state = Arc::new(Mutex::new(Progress::new(
+        supertiles,
         jobs,
         options.zoom_level - options.unit_zoom_level,
+        options.min_zoom,
+        reporter(options),
     )));
 
+    let batches = batches.map(Mutex::new);
+
     let laztile_conn = match options.source() {
-        Source::LazTileDb(path_buf) => Some(Arc::new(Mutex::new(
-            Connection::open_with_flags(path_buf, OpenFlags::SQLITE_OPEN_READ_ONLY).unwrap(),
+        Source::LazTileDb(path) => Some(Arc::new(Mutex::new(
+            Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+                .map_err(sqlite_error(&path))?,
         ))),
-        Source::LazIndexDb(_) => None,
+        Source::LazIndexDb(_) | Source::LazDir(_) => None,
     };
 
     let supertile_zoom_offset = options.zoom_level - options.unit_zoom_level;
 
+    // tiles of the max zoom level of the supertiles with too few points
+    let skipped = Mutex::new(Vec::<Tile>::new());
+
+    // collected only if written
+    let stats = options
+        .stats_out
+        .is_some()
+        .then(|| Mutex::new(Vec::<TileStats>::new()));
+
+    // first error of the threads, which stop then
+    let failure = Mutex::new(None);
+
     thread::scope(|scope| {
-        let jobs_len = state.lock().unwrap().jobs.len();
+        let skipped = &skipped;
 
-        let for_overviews = Arc::new(Mutex::new(HashMap::<Tile, RgbaImage>::new()));
+        let failure = &failure;
 
-        for _ in 0..(jobs_len.min(available_parallelism().unwrap().get())) {
-            let state = Arc::clone(&state);
+        let resumed = &resumed;
+
+        let stats = stats.as_ref();
+
+        let sampled = &sampled;
+
+        let targets = &targets;
+
+        let bounds = &bounds;
+
+        let tile_senders: Vec<_> = targets
+            .iter()
+            .map(|target| {
+                // single writer per output as SQLite doesn't write in parallel
+                let (tile_sender, tile_receiver) =
+                    sync_channel::<(Tile, Vec<u8>)>(TILES_PER_TRANSACTION);
 
-            let conn = Arc::clone(&conn);
+                let options = target.options;
 
-            let for_overviews = Arc::clone(&for_overviews);
+                let output = &options.output;
+
+                // output directories are written by the rasterizing threads
+                if !options.is_directory() {
+                    scope.spawn(move || {
+                        if options.is_pmtiles() {
+                            // failed before the receiver is dropped as in `write_tiles`
+                            if let Err(error) = write_pmtiles(target, &tile_receiver, bounds) {
+                                fail(failure, error);
+                            }
+
+                            return;
+                        }
+
+                        let written = Connection::open(output)
+                            .and_then(|mut conn| {
+                                conn.pragma_update(None, "synchronous", "OFF")?;
+
+                                write_tiles(
+                                    &mut conn,
+                                    &tile_receiver,
+                                    if update {
+                                        UPSERT_TILE_SQL
+                                    } else {
+                                        INSERT_TILE_SQL
+                                    },
+                                )
+                            })
+                            .map_err(sqlite_error(output));
+
+                        // the rasterizing threads stop once the receiver is dropped
+                        if let Err(error) = written {
+                            fail(failure, error);
+                        }
+                    });
+                }
+
+                tile_sender
+            })
+            .collect();
+
+        // a supersampled supertile takes the square of `--supersample` times the memory
+        let threads = (options.compute_threads() / usize::from(options.supersample.pow(2))).max(1);
+
+        for _ in 0..(supertiles.len().min(threads)) {
+            let state = Arc::clone(&state);
 
             let laztile_conn = laztile_conn.clone();
 
+            let batches = batches.as_ref();
+
+            let tile_senders = tile_senders.clone();
+
             scope.spawn(move || {
-                let save_tile = |tile: Tile, img: RgbaImage| {
-                    let mut buffer = vec![];
-
-                    match options.format {
-                        Format::JPEG => {
-                            let img = rgba_to_rgb(&img, options.background_color.0);
-                            img.write_with_encoder(JpegEncoder::new_with_quality(
-                                Cursor::new(&mut buffer),
-                                options.jpeg_quality,
-                            ))
-                            .unwrap()
-                        }
-                        Format::PNG => img
-                            .write_with_encoder(PngEncoder::new(Cursor::new(&mut buffer)))
-                            .unwrap(),
+                let save_tile = |target: &Target,
+                                 tile_sender: &SyncSender<_>,
+                                 tile: Tile,
+                                 img|
+                 -> Result<(), Error> {
+                    let started = Instant::now();
+
+                    let buffer = encode_tile(target.options, &img)?;
+
+                    TIMINGS.record(Phase::Encode, started.elapsed());
+
+                    if tile.zoom > options.min_zoom {
+                        target.for_overviews.lock().unwrap().insert(tile, img);
                     }
 
-                    for_overviews.lock().unwrap().insert(tile, img);
+                    if target.options.tile_directory {
+                        let started = Instant::now();
 
-                    let res = conn.lock().unwrap().execute(
-                        INSERT_TILE_SQL,
-                        (tile.zoom, tile.x, tile.reversed_y(), buffer),
-                    );
+                        write_tile(target.options, tile, &buffer)?;
 
-                    match res {
-                        Err(Error::SqliteFailure(ref err, _))
-                            if err.code == ErrorCode::ConstraintViolation =>
-                        {
-                            println!("DUPLICATE");
-                        }
-                        _ => {
-                            res.unwrap();
-                        }
+                        TIMINGS.record(Phase::Write, started.elapsed());
+                    } else {
+                        // timed by the writer, which failed if it is gone
+                        tile_sender
+                            .send((tile, buffer))
+                            .map_err(|_| Error::Cancelled)?;
                     }
 
-                    state.lock().unwrap().done(tile);
+                    Ok(())
                 };
 
-                loop {
-                    let Some(job) = state.lock().unwrap().next() else {
+                'jobs: loop {
+                    // the job in progress is finished so that its tiles are complete
+                    if cancel.load(Ordering::Relaxed) || failure.lock().unwrap().is_some() {
                         break;
+                    }
+
+                    let job = state.lock().unwrap().next();
+
+                    let Some(job) = job else {
+                        // wait for the next batch of the streamed supertiles
+                        let Some(batch) =
+                            batches.and_then(|batches| batches.lock().unwrap().recv().ok())
+                        else {
+                            break;
+                        };
+
+                        state.lock().unwrap().extend(batch);
+
+                        continue;
                     };
 
-                    // println!("Processing {:?}", job);
+                    if let Job::Rasterize(ref tile_meta) = job {
+                        let tile = tile_meta.tile;
+
+                        if resumed.contains(&(tile.zoom, tile.x, tile.y)) {
+                            for tile in tile.descendants(supertile_zoom_offset) {
+                                // loaded from the output directory when composing the overviews
+                                if tile.zoom > options.min_zoom {
+                                    for target in targets {
+                                        target
+                                            .for_overviews
+                                            .lock()
+                                            .unwrap()
+                                            .insert(tile, DynamicImage::default());
+                                    }
+                                }
+
+                                state.lock().unwrap().done(tile);
+                            }
+
+                            continue;
+                        }
+                    }
 
                     if cont {
                         let (tile, tiles) = match job {
@@ -172,18 +353,29 @@ pub fn rasterize(options: &Options, jobs: Vec<Job>) {
                             Job::Overview(tile) => (tile, vec![tile]),
                         };
 
-                        let conn = conn.lock().unwrap();
+                        let exists = targets[0].conn.as_ref().map_or(Ok(false), |conn| {
+                            tile_exists(&conn.lock().unwrap(), tile)
+                                .map_err(sqlite_error(&options.output))
+                        });
 
-                        let mut stmt = conn.prepare(SELECT_TILE_EXISTS_SQL).unwrap();
+                        let exists = match exists {
+                            Ok(exists) => exists,
+                            Err(error) => {
+                                fail(failure, error);
 
-                        let mut rows = stmt.query((tile.zoom, tile.x, tile.reversed_y())).unwrap();
+                                break;
+                            }
+                        };
 
-                        if rows.next().unwrap().is_some() {
+                        if exists {
                             for tile in tiles {
-                                for_overviews
-                                    .lock()
-                                    .unwrap()
-                                    .insert(tile, RgbaImage::default());
+                                for target in targets {
+                                    target
+                                        .for_overviews
+                                        .lock()
+                                        .unwrap()
+                                        .insert(tile, DynamicImage::default());
+                                }
 
                                 state.lock().unwrap().done(tile);
                             }
@@ -194,209 +386,1363 @@ pub fn rasterize(options: &Options, jobs: Vec<Job>) {
 
                     match job {
                         Job::Rasterize(tile_meta) => {
-                            let points = laztile_conn.as_ref().map_or_else(
-                                || tile_meta.points.into_inner().unwrap(),
-                                |laztile_conn| {
-                                    let laztile_conn = laztile_conn.lock().unwrap();
-
-                                    let mut stmt =
-                                        laztile_conn.prepare(SELECT_LAZTILE_SQL).unwrap();
+                            let started = Instant::now();
+
+                            let sampled = sampled.lock().unwrap().remove(&tile_meta.tile);
+
+                            let surface = match sampled {
+                                Some(surface) => surface,
+                                None => {
+                                    if let Some(laztile_conn) = &laztile_conn {
+                                        if let Err(error) = read_laz_tile(
+                                            &laztile_conn.lock().unwrap(),
+                                            &tile_meta,
+                                            options,
+                                        ) {
+                                            fail(failure, error);
+
+                                            break;
+                                        }
+                                    }
 
-                                    let mut rows =
-                                        stmt.query((tile_meta.tile.x, tile_meta.tile.y)).unwrap();
+                                    match interpolate_supertile(options, &tile_meta) {
+                                        Ok(surface) => surface,
+                                        Err(error) => {
+                                            fail(failure, error);
 
-                                    let mut points = Vec::new();
+                                            break;
+                                        }
+                                    }
+                                }
+                            };
 
-                                    while let Some(row) = rows.next().unwrap() {
-                                        let data: Vec<u8> = row.get(0).unwrap();
+                            let Some(surface) = surface else {
+                                skipped
+                                    .lock()
+                                    .unwrap()
+                                    .extend(tile_meta.tile.descendants(supertile_zoom_offset));
 
-                                        let mut reader = Reader::new(Cursor::new(data)).unwrap();
+                                for target in targets {
+                                    if let Some(journal) = &target.journal {
+                                        if let Err(error) = journal.record(tile_meta.tile) {
+                                            fail(failure, error);
 
-                                        reader.read_all_points_into(&mut points).unwrap();
+                                            break 'jobs;
+                                        }
                                     }
+                                }
 
-                                    points
-                                        .into_iter()
-                                        .map(|point| PointWithHeight {
-                                            position: Point2 {
-                                                x: point.x,
-                                                y: point.y,
-                                            },
-                                            height: point.z,
-                                        })
-                                        .collect()
-                                },
-                            );
-
-                            if points.is_empty() {
                                 state.lock().unwrap().done(tile_meta.tile);
 
                                 continue;
-                            }
-
-                            let mut triangulation = DelaunayTriangulation::<PointWithHeight>::new();
-
-                            for point in points {
-                                triangulation.insert(point).unwrap();
-                            }
+                            };
+
+                            let Surface {
+                                elevations,
+                                point_count,
+                                thinned_count,
+                                spilled_count,
+                                width_pixels,
+                                height_pixels,
+                                ..
+                            } = &surface;
+
+                            // of the shaded image
+                            let (width_pixels, height_pixels) = (
+                                *width_pixels / u32::from(options.supersample),
+                                *height_pixels / u32::from(options.supersample),
+                            );
 
                             let bbox = tile_meta.bbox;
 
                             let pixels_per_meter = options.pixels_per_meter();
 
-                            let width_pixels = (bbox.width() * pixels_per_meter).round() as u32;
+                            // zero for the supertiles interpolated by `--auto-contrast`
+                            let interpolation =
+                                surface.interpolated.saturating_duration_since(started);
+
+                            let mut tiles = tile_meta.tile.descendants(supertile_zoom_offset);
+
+                            tiles.sort_by(|a, b| a.y.cmp(&b.y).then_with(|| a.x.cmp(&b.x)));
+
+                            let mut shading = Duration::ZERO;
+
+                            // the image of every output is written and dropped before the next one
+                            for (target, tile_sender) in targets.iter().zip(&tile_senders) {
+                                let shading_started = Instant::now();
+
+                                let img = shade_supertile(target.options, &surface);
 
-                            let height_pixels = (bbox.height() * pixels_per_meter).round() as u32;
+                                shading += shading_started.elapsed();
 
-                            let mut img = Vec::new();
+                                TIMINGS.record(Phase::Shade, shading_started.elapsed());
 
-                            let natural_neighbor = triangulation.natural_neighbor();
+                                if target.options.geotiff {
+                                    let tile = tile_meta.tile;
 
-                            for y in 0..height_pixels {
-                                let cy =
-                                    bbox.min_y + y as f64 * bbox.height() / height_pixels as f64;
+                                    let path = target
+                                        .options
+                                        .output
+                                        .join(format!("{}-{}-{}.tif", tile.zoom, tile.x, tile.y));
 
-                                for x in 0..width_pixels {
-                                    let cx =
-                                        bbox.min_x + x as f64 * bbox.width() / width_pixels as f64;
+                                    let buffer_px = options.buffer;
 
-                                    img.push(
-                                        natural_neighbor
-                                            .interpolate(|v| v.data().height, Point2::new(cx, cy))
-                                            .unwrap_or(f64::NAN),
-                                    );
+                                    let buffer_m = f64::from(buffer_px) / pixels_per_meter;
+
+                                    let geo_reference = GeoReference {
+                                        min_x: bbox.min_x + buffer_m,
+                                        max_y: bbox.max_y - buffer_m,
+                                        pixel_size: 1.0 / pixels_per_meter,
+                                    };
+
+                                    let width = width_pixels - 2 * buffer_px;
+
+                                    let height = height_pixels - 2 * buffer_px;
+
+                                    let written = if target.options.mode == Mode::Elevation {
+                                        // elevations go from south to north
+                                        let data = (buffer_px..height_pixels - buffer_px)
+                                            .flat_map(|image_y| {
+                                                let y = height_pixels - 1 - image_y;
+
+                                                (buffer_px..width_pixels - buffer_px).map(
+                                                    move |x| {
+                                                        elevations
+                                                            .get((y * width_pixels + x) as usize)
+                                                            as f32
+                                                    },
+                                                )
+                                            })
+                                            .collect();
+
+                                        write_geotiff(
+                                            &path,
+                                            Raster {
+                                                width,
+                                                height,
+                                                channels: 1,
+                                                data,
+                                            },
+                                            &geo_reference,
+                                            Some(f64::NAN),
+                                        )
+                                    } else {
+                                        write_image_geotiff(
+                                            &path,
+                                            &img.crop_imm(buffer_px, buffer_px, width, height),
+                                            &geo_reference,
+                                        )
+                                    };
+
+                                    if let Err(error) = written {
+                                        fail(failure, error);
+
+                                        break 'jobs;
+                                    }
+                                } else {
+                                    let buffer_px = options.buffer;
+                                    let tile_size = options.tile_size as u32;
+
+                                    for (sector, tile) in tiles.iter().enumerate() {
+                                        let img = img.crop_imm(
+                                            buffer_px
+                                                + ((sector as u32)
+                                                    & ((1 << supertile_zoom_offset) - 1))
+                                                    * tile_size,
+                                            buffer_px
+                                                + (sector as u32 >> supertile_zoom_offset)
+                                                    * tile_size,
+                                            tile_size,
+                                            tile_size,
+                                        );
+
+                                        if let Err(error) =
+                                            save_tile(target, tile_sender, *tile, img)
+                                        {
+                                            fail(failure, error);
+
+                                            break 'jobs;
+                                        }
+                                    }
+                                }
+
+                                if let Some(journal) = &target.journal {
+                                    if let Err(error) = journal.record(tile_meta.tile) {
+                                        fail(failure, error);
+
+                                        break 'jobs;
+                                    }
                                 }
                             }
 
-                            let img = compute_hillshade(
-                                &img,
-                                options.z_factor,
-                                height_pixels as usize,
-                                width_pixels as usize,
-                                |aspect, slope| {
-                                    shade(
-                                        aspect,
-                                        slope,
-                                        options.shadings.0.as_ref(),
-                                        options.contrast,
-                                        options.brightness,
-                                    )
-                                },
+                            debug!(
+                                "RASTERIZE {}/{}/{}: interpolate {interpolation:?}, shade {shading:?}",
+                                tile_meta.tile.zoom, tile_meta.tile.x, tile_meta.tile.y,
                             );
 
-                            let mut tiles = tile_meta.tile.descendants(supertile_zoom_offset);
+                            if let Some(stats) = stats {
+                                let mut tile_stats = TileStats::new(
+                                    tile_meta.tile,
+                                    &bbox,
+                                    *point_count,
+                                    *thinned_count,
+                                    elevations,
+                                    interpolation,
+                                    shading,
+                                );
 
-                            tiles.sort_by(|a, b| a.y.cmp(&b.y).then_with(|| a.x.cmp(&b.x)));
+                                tile_stats.spilled_points = *spilled_count;
 
-                            let buffer_px = options.buffer;
-                            let tile_size = options.tile_size as u32;
-
-                            for (sector, tile) in tiles.iter().enumerate() {
-                                let img = crop_imm(
-                                    &img,
-                                    buffer_px
-                                        + ((sector as u32) & ((1 << supertile_zoom_offset) - 1))
-                                            * tile_size,
-                                    buffer_px
-                                        + (sector as u32 >> supertile_zoom_offset) * tile_size,
-                                    tile_size,
-                                    tile_size,
-                                )
-                                .to_image();
+                                stats.lock().unwrap().push(tile_stats);
+                            }
 
-                                save_tile(*tile, img);
+                            let mut state = state.lock().unwrap();
+
+                            for tile in tiles {
+                                state.done(tile);
                             }
                         }
                         Job::Overview(tile) => {
-                            let mut for_overviews = for_overviews.lock().unwrap();
+                            for (target, tile_sender) in targets.iter().zip(&tile_senders) {
+                                let options = target.options;
+
+                                let mut for_overviews = target.for_overviews.lock().unwrap();
+
+                                let imgs: Vec<_> = tile
+                                    .children()
+                                    .into_iter()
+                                    .enumerate()
+                                    .filter_map(|(i, tile)| {
+                                        for_overviews
+                                            .remove(&tile)
+                                            // possibly rendered by a previous run
+                                            .or_else(|| update.then(DynamicImage::default))
+                                            .map(|img| (i, tile, img))
+                                    })
+                                    .collect();
+
+                                drop(for_overviews);
+
+                                let imgs = imgs
+                                    .into_iter()
+                                    .filter_map(|(i, tile, img)| {
+                                        if img.width() != 0 {
+                                            return Some(Ok((i, img)));
+                                        }
+
+                                        previous_tile(target, tile)
+                                            .transpose()
+                                            .map(|img| img.map(|img| (i, img)))
+                                    })
+                                    .collect::<Result<Vec<_>, _>>();
+
+                                let imgs = match imgs {
+                                    Ok(imgs) => imgs,
+                                    Err(error) => {
+                                        fail(failure, error);
+
+                                        break 'jobs;
+                                    }
+                                };
 
-                            let imgs: Vec<_> = tile
-                                .children()
-                                .into_iter()
-                                .enumerate()
-                                .filter_map(|(i, tile)| {
-                                    for_overviews.remove(&tile).map(|img| (i, tile, img))
-                                })
-                                .collect();
+                                if imgs.is_empty() {
+                                    continue;
+                                }
 
-                            drop(for_overviews);
+                                let imgs = imgs.into_iter();
 
-                            if imgs.is_empty() {
-                                state.lock().unwrap().done(tile);
+                                let tile_size = u32::from(options.tile_size);
 
-                                continue;
+                                let img = match options.mode {
+                                    Mode::Hillshade | Mode::CanopyHeight => {
+                                        DynamicImage::from(compose_overview(
+                                            imgs.map(|(i, img)| (i, img.into_rgba8())),
+                                            tile_size,
+                                            FilterType::Lanczos3,
+                                        ))
+                                    }
+                                    Mode::Grayscale => DynamicImage::from(compose_overview(
+                                        imgs.map(|(i, img)| (i, img.into_luma16())),
+                                        tile_size,
+                                        FilterType::Lanczos3,
+                                    )),
+                                    Mode::Normal => DynamicImage::from(compose_overview(
+                                        imgs.map(|(i, img)| (i, img.into_rgb8())),
+                                        tile_size,
+                                        FilterType::Lanczos3,
+                                    )),
+                                    // values must not be mixed
+                                    Mode::Slope | Mode::Aspect => {
+                                        DynamicImage::from(compose_overview(
+                                            imgs.map(|(i, img)| (i, img.into_luma16())),
+                                            tile_size,
+                                            FilterType::Nearest,
+                                        ))
+                                    }
+                                    Mode::Elevation => {
+                                        DynamicImage::from(compose_elevation_overview(
+                                            imgs.map(|(i, img)| (i, img.into_rgba8())),
+                                            tile_size,
+                                            options.encoding,
+                                        ))
+                                    }
+                                };
+
+                                if let Err(error) = save_tile(target, tile_sender, tile, img) {
+                                    fail(failure, error);
+
+                                    break 'jobs;
+                                }
                             }
 
-                            let mut tile_img = RgbaImage::new(
-                                u32::from(options.tile_size) << 1,
-                                u32::from(options.tile_size) << 1,
-                            );
+                            state.lock().unwrap().done(tile);
+                        }
+                    };
+                }
+            });
+        }
 
-                            for (i, tile, img) in imgs {
-                                let img = if cont && img.width() == 0 {
-                                    let data: Vec<u8> = {
-                                        let conn = conn.lock().unwrap();
+        // the writers end once the rasterizing threads drop their senders
+        drop(tile_senders);
+    });
 
-                                        let mut stmt = conn.prepare(SELECT_TILE_SQL).unwrap();
+    state.lock().unwrap().log_duration();
 
-                                        let mut rows = stmt
-                                            .query((tile.zoom, tile.x, tile.reversed_y()))
-                                            .unwrap();
+    if let Some(error) = failure.into_inner().unwrap() {
+        return Err(error);
+    }
 
-                                        let row = rows.next().unwrap().unwrap();
+    if cancel.load(Ordering::Relaxed) {
+        state.lock().unwrap().log_cancelled();
+    }
 
-                                        row.get(0).unwrap()
-                                    };
+    for output in outputs {
+        if output.world_files {
+            write_vrt(output)?;
+        }
+    }
 
-                                    load_from_memory_with_format(
-                                        data.as_slice(),
-                                        image::ImageFormat::Jpeg, // TODO also support PNG
-                                    )
-                                    .unwrap()
-                                    .to_rgba8()
-                                } else {
-                                    img
-                                };
+    if let (Some(path), Some(stats)) = (&options.stats_out, stats) {
+        let mut stats = stats.into_inner().unwrap();
 
-                                tile_img
-                                    .copy_from(
-                                        &img,
-                                        ((i & 1) as u32) * options.tile_size as u32,
-                                        (i >> 1) as u32 * options.tile_size as u32,
-                                    )
-                                    .unwrap();
-                            }
+        stats.sort_unstable_by_key(|stats| (stats.tile.x, stats.tile.y));
 
-                            let img = resize(
-                                &tile_img,
-                                u32::from(options.tile_size),
-                                u32::from(options.tile_size),
-                                FilterType::Lanczos3,
-                            );
+        write_stats(path, &stats)?;
+    }
 
-                            save_tile(tile, img);
-                        }
-                    };
+    if let Some(path) = &options.skipped_tiles {
+        let mut skipped = skipped.into_inner().unwrap();
+
+        skipped.sort_unstable_by_key(|tile| (tile.zoom, tile.x, tile.y));
+
+        let tiles: Vec<_> = skipped
+            .iter()
+            .map(|tile| format!("[{},{},{}]", tile.zoom, tile.x, tile.y))
+            .collect();
+
+        write(path, format!("[{}]\n", tiles.join(","))).map_err(|source| Error::Write {
+            path: path.display().to_string(),
+            source,
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Keeps the first error of the threads
+fn fail(failure: &Mutex<Option<Error>>, error: Error) {
+    failure.lock().unwrap().get_or_insert(error);
+}
+
+fn sqlite_error(path: &Path) -> impl Fn(rusqlite::Error) -> Error + '_ {
+    |source| Error::Sqlite {
+        path: path.display().to_string(),
+        source,
+    }
+}
+
+/// Whether the MBTiles has the tile of a previous run
+fn tile_exists(conn: &Connection, tile: Tile) -> rusqlite::Result<bool> {
+    conn.prepare_cached(SELECT_TILE_EXISTS_SQL)?
+        .exists((tile.zoom, tile.x, tile.reversed_y()))
+}
+
+/// Image of the tile of a previous run of the continued or updated MBTiles or of the resumed output directory,
+/// `None` if it is missing
+fn previous_tile(target: &Target, tile: Tile) -> Result<Option<DynamicImage>, Error> {
+    let options = target.options;
+
+    let data = if let Some(conn) = &target.conn {
+        let conn = conn.lock().unwrap();
+
+        let mut stmt = conn
+            .prepare_cached(SELECT_TILE_SQL)
+            .map_err(sqlite_error(&options.output))?;
+
+        let mut rows = stmt
+            .query((tile.zoom, tile.x, tile.reversed_y()))
+            .map_err(sqlite_error(&options.output))?;
+
+        // missing if not rendered by a previous run when updating
+        match rows.next().map_err(sqlite_error(&options.output))? {
+            Some(row) => row.get(0).map_err(sqlite_error(&options.output))?,
+            None => return Ok(None),
+        }
+    } else {
+        // missing if empty
+        let Some(data) = read_tile(options, tile) else {
+            return Ok(None);
+        };
+
+        data
+    };
+
+    load_from_memory_with_format(&data, options.format.into())
+        .map(Some)
+        .map_err(|error| Error::Io {
+            path: format!(
+                "tile {}/{}/{} of {}",
+                tile.zoom,
+                tile.x,
+                tile.y,
+                options.output.display()
+            ),
+            source: io::Error::new(io::ErrorKind::InvalidData, error),
+        })
+}
+
+/// Creates the output directory or handles the existing output file according to `--existing-file-action`
+fn prepare_output(options: &Options) -> Result<Option<ExistingFileAction>, Error> {
+    let output = &options.output;
+
+    let write_error = |source| Error::Write {
+        path: output.display().to_string(),
+        source,
+    };
+
+    if options.is_directory() {
+        create_dir_all(output).map_err(write_error)?;
+    }
+
+    let existing_file_action = if options.is_directory() {
+        // tiles of the directories are written over the existing ones
+        options
+            .existing_file_action
+            .filter(|&action| action == ExistingFileAction::Update)
+    } else if exists(output).map_err(|source| Error::Io {
+        path: output.display().to_string(),
+        source,
+    })? {
+        match options.existing_file_action {
+            Some(ExistingFileAction::Overwrite) => {
+                remove_file(output).map_err(write_error)?;
+
+                None
+            }
+            Some(action) => Some(action),
+            None => {
+                return Err(Error::InvalidOptions(
+                    "Output file already exists. Specify --existing-file-action.".to_owned(),
+                ));
+            }
+        }
+    } else {
+        None
+    };
+
+    if options.is_pmtiles() && existing_file_action.is_some() {
+        return Err(Error::InvalidOptions(
+            "PMTiles archive can't be continued nor updated. Use --existing-file-action overwrite."
+                .to_owned(),
+        ));
+    }
+
+    Ok(existing_file_action)
+}
+
+/// Supertile interpolated with the buffer
+pub struct Surface {
+    pub elevations: Elevations<'static>,
+    /// Ground points used for the interpolation
+    pub point_count: usize,
+    /// Ground points dropped by `--max-points-per-cell`
+    pub thinned_count: usize,
+    /// Points read back from the files of `--max-memory`
+    pub spilled_count: usize,
+    pub bbox: BBox,
+    pub width_pixels: u32,
+    pub height_pixels: u32,
+    /// When the interpolation finished
+    pub interpolated: Instant,
+}
+
+/// Interpolates the points of the supertile taken from `tile_meta`, at `--supersample` times the resolution;
+/// `None` if there are less than `--min-points`
+pub fn interpolate_supertile(
+    options: &Options,
+    tile_meta: &TileMeta,
+) -> Result<Option<Surface>, Error> {
+    if options.supersample > 1 {
+        return interpolate_supertile(&options.supersampled(), tile_meta);
+    }
+
+    let started = Instant::now();
+
+    let ([mut points, mut water_points, mut surface_points], spilled_count) =
+        tile_meta.take_points()?;
+
+    for points in [&mut points, &mut water_points, &mut surface_points] {
+        sort_points(points);
+    }
+
+    if options.despike_radius > 0.0 {
+        let count = points.len();
+
+        points = despike(points, options.despike_radius, options.despike_threshold);
+
+        debug!(
+            "DESPIKE {}/{}/{}: dropped {} of {count} points",
+            tile_meta.tile.zoom,
+            tile_meta.tile.x,
+            tile_meta.tile.y,
+            count - points.len(),
+        );
+    }
+
+    if options.dedup != Dedup::Off && options.surface != SurfaceModel::Dsm {
+        let count = points.len();
+
+        points = dedup(
+            points,
+            options
+                .dedup_cell_size
+                .unwrap_or_else(|| 0.5 / options.pixels_per_meter()),
+            options.dedup,
+        );
+
+        debug!(
+            "DEDUP {}/{}/{}: merged {count} points to {}",
+            tile_meta.tile.zoom,
+            tile_meta.tile.x,
+            tile_meta.tile.y,
+            points.len(),
+        );
+    }
+
+    let mut thinned_count = 0;
+
+    if let Some(max_points) = options.max_points_per_cell
+        && options.surface != SurfaceModel::Dsm
+    {
+        let count = points.len();
+
+        points = thin(points, 1.0 / options.pixels_per_meter(), max_points);
+
+        thinned_count = count - points.len();
+
+        debug!(
+            "THIN {}/{}/{}: dropped {thinned_count} of {count} points",
+            tile_meta.tile.zoom, tile_meta.tile.x, tile_meta.tile.y,
+        );
+    }
+
+    if points.len() + water_points.len() < options.min_points {
+        if points.len() + water_points.len() > 0 {
+            debug!(
+                "SKIP {}/{}/{}: {} points",
+                tile_meta.tile.zoom,
+                tile_meta.tile.x,
+                tile_meta.tile.y,
+                points.len() + water_points.len(),
+            );
+        }
+
+        return Ok(None);
+    }
+
+    let bbox = tile_meta.bbox;
+
+    let pixels_per_meter = options.pixels_per_meter();
+
+    let width_pixels = (bbox.width() * pixels_per_meter).round() as u32;
+
+    let height_pixels = (bbox.height() * pixels_per_meter).round() as u32;
+
+    let point_count = points.len();
+
+    let elevations = match options.surface {
+        SurfaceModel::Dtm => interpolate(
+            options,
+            &bbox,
+            width_pixels,
+            height_pixels,
+            points,
+            &water_points,
+        ),
+        SurfaceModel::Dsm => interpolate(
+            options,
+            &bbox,
+            width_pixels,
+            height_pixels,
+            highest_per_pixel(points, &bbox, width_pixels, height_pixels),
+            &water_points,
+        ),
+        SurfaceModel::Chm => {
+            let mut elevations = interpolate(
+                options,
+                &bbox,
+                width_pixels,
+                height_pixels,
+                points,
+                &water_points,
+            );
+
+            let dsm = interpolate(
+                options,
+                &bbox,
+                width_pixels,
+                height_pixels,
+                highest_per_pixel(surface_points, &bbox, width_pixels, height_pixels),
+                &[],
+            );
+
+            for index in 0..dsm.len() {
+                let height = dsm.get(index) - elevations.get(index);
+
+                // NaN of either surface stays NaN
+                elevations.set(index, if height < 0.0 { 0.0 } else { height });
+            }
+
+            elevations
+        }
+    };
+
+    let mut elevations = elevations;
+
+    // pixels out of the clip polygon have no data
+    if let Some(clip) = &options.clip_polygon {
+        for row in 0..height_pixels as usize {
+            let y = bbox.min_y + (row as f64 + 0.5) / pixels_per_meter;
+
+            for col in 0..width_pixels as usize {
+                let x = bbox.min_x + (col as f64 + 0.5) / pixels_per_meter;
+
+                if !clip.contains(x, y) {
+                    elevations.set(row * width_pixels as usize + col, f64::NAN);
                 }
-            });
+            }
+        }
+    }
+
+    TIMINGS.record(Phase::Interpolate, started.elapsed());
+
+    Ok(Some(Surface {
+        elevations,
+        point_count,
+        thinned_count,
+        spilled_count,
+        bbox,
+        width_pixels,
+        height_pixels,
+        interpolated: Instant::now(),
+    }))
+}
+
+/// Shades the interpolated supertile according to `--mode` and `--shadings`, downsampled by `--supersample`
+pub fn shade_supertile(options: &Options, surface: &Surface) -> DynamicImage {
+    if options.supersample > 1 {
+        let factor = u32::from(options.supersample);
+
+        let img = shade_supertile(&options.supersampled(), surface);
+
+        return img.resize_exact(
+            img.width() / factor,
+            img.height() / factor,
+            FilterType::Lanczos3,
+        );
+    }
+
+    let Surface {
+        elevations,
+        bbox,
+        width_pixels,
+        height_pixels,
+        ..
+    } = surface;
+
+    let pixels_per_meter = options.pixels_per_meter();
+
+    let grid = Grid {
+        elevations,
+        rows: *height_pixels as usize,
+        cols: *width_pixels as usize,
+        cell_size: 1.0 / pixels_per_meter,
+        mercator_y: options
+            .mercator_scale
+            .then_some(bbox.min_y + 0.5 / pixels_per_meter),
+    };
+
+    let rasters = Rasters::new(&grid, options.z_factor, &options.shadings.0);
+
+    match options.mode {
+        Mode::Hillshade => DynamicImage::from(hillshade(options, &grid, &rasters)),
+        Mode::Grayscale => {
+            let context = ShadingContext::new(options, None);
+
+            DynamicImage::from(compute_hillshade(
+                &grid,
+                options.z_factor,
+                options.derivative_method,
+                options.derivative_radius,
+                |pixel| shade_grayscale(pixel, &context, &rasters),
+            ))
+        }
+        Mode::Normal => DynamicImage::from(compute_hillshade(
+            &grid,
+            options.z_factor,
+            options.derivative_method,
+            options.derivative_radius,
+            shade_normal,
+        )),
+        Mode::Slope => DynamicImage::from(compute_hillshade(
+            &grid,
+            options.z_factor,
+            options.derivative_method,
+            options.derivative_radius,
+            shade_slope,
+        )),
+        Mode::Aspect => DynamicImage::from(compute_hillshade(
+            &grid,
+            options.z_factor,
+            options.derivative_method,
+            options.derivative_radius,
+            shade_aspect,
+        )),
+        Mode::CanopyHeight => DynamicImage::from(compute_hillshade(
+            &grid,
+            options.z_factor,
+            options.derivative_method,
+            options.derivative_radius,
+            |pixel| shade_canopy_height(pixel, options.chm_max, options.nodata_color.0),
+        )),
+        Mode::Elevation => DynamicImage::from(compute_hillshade(
+            &grid,
+            options.z_factor,
+            options.derivative_method,
+            options.derivative_radius,
+            |pixel| encode_elevation(pixel, options.encoding, options.nodata_elevation),
+        )),
+    }
+}
+
+/// Encodes the tile in `--format`, on the background color if opaque
+pub fn encode_tile(options: &Options, img: &DynamicImage) -> Result<Vec<u8>, Error> {
+    let mut buffer = vec![];
+
+    let opaque_img = match img {
+        DynamicImage::ImageRgba8(rgba) if options.is_opaque() => Some(DynamicImage::from(
+            rgba_to_rgb(rgba, options.background_color.0),
+        )),
+        _ => None,
+    };
+
+    let encoded_img = opaque_img.as_ref().unwrap_or(img);
+
+    let encoding_error = |error| Error::Write {
+        path: options.output.display().to_string(),
+        source: io::Error::other(error),
+    };
+
+    match options.format {
+        Format::JPEG => encoded_img
+            .write_with_encoder(JpegEncoder::new_with_quality(
+                Cursor::new(&mut buffer),
+                options.jpeg_quality(),
+            ))
+            .map_err(encoding_error)?,
+        Format::PNG => encoded_img
+            .write_with_encoder(PngEncoder::new(Cursor::new(&mut buffer)))
+            .map_err(encoding_error)?,
+        Format::WEBP => match options.quality {
+            // lossy by libwebp, keeping the alpha
+            Some(quality) => {
+                let rgba;
+
+                let encoder = if let DynamicImage::ImageRgb8(rgb) = encoded_img {
+                    webp::Encoder::from_rgb(rgb, rgb.width(), rgb.height())
+                } else {
+                    rgba = encoded_img.to_rgba8();
+
+                    webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height())
+                };
+
+                let webp = encoder
+                    .encode_simple(false, f32::from(quality))
+                    .map_err(|error| Error::Write {
+                        path: options.output.display().to_string(),
+                        source: io::Error::other(format!("WebP encoding failed: {error:?}")),
+                    })?;
+
+                buffer.extend_from_slice(&webp);
+            }
+            None => encoded_img
+                .write_with_encoder(WebPEncoder::new_lossless(Cursor::new(&mut buffer)))
+                .map_err(encoding_error)?,
+        },
+    }
+
+    Ok(buffer)
+}
+
+/// Reads the points of the supertile from the LAZ tile DB
+pub fn read_laz_tile(
+    conn: &Connection,
+    tile_meta: &TileMeta,
+    options: &Options,
+) -> Result<(), Error> {
+    let started = Instant::now();
+
+    let path = Path::new(conn.path().unwrap_or_default());
+
+    let las_error = |source| Error::Las {
+        path: format!(
+            "{} (tile {}/{})",
+            path.display(),
+            tile_meta.tile.x,
+            tile_meta.tile.y
+        ),
+        source,
+    };
+
+    let mut stmt = conn
+        .prepare_cached(SELECT_LAZTILE_SQL)
+        .map_err(sqlite_error(path))?;
+
+    let mut rows = stmt
+        .query((tile_meta.tile.x, tile_meta.tile.y))
+        .map_err(sqlite_error(path))?;
+
+    while let Some(row) = rows.next().map_err(sqlite_error(path))? {
+        let data: Vec<u8> = row.get(0).map_err(sqlite_error(path))?;
+
+        let bytes = data.len() as u64;
+
+        let mut reader = Reader::new(Cursor::new(data)).map_err(las_error)?;
+
+        TIMINGS.read(bytes, reader.header().number_of_points());
+
+        for point in reader.points() {
+            let point = point.map_err(las_error)?;
+
+            if rejection(&point, options).is_some() {
+                continue;
+            }
+
+            tile_meta.push(
+                &point_use(&point, options),
+                Point2::new(point.x, point.y),
+                point.z,
+            );
+        }
+    }
+
+    TIMINGS.record(Phase::Query, started.elapsed());
+
+    Ok(())
+}
+
+/// Opens the MBTiles output creating its schema, or updating its metadata extending `bounds` of the previous runs
+fn open_mbtiles(
+    options: &Options,
+    name: &str,
+    cont: bool,
+    update: bool,
+    bounds: &[(f64, f64)],
+) -> Result<Connection, Error> {
+    let output = &options.output;
+
+    let sqlite_error = sqlite_error(output);
+
+    let invalid_metadata = |name: &str, value: &str| Error::Io {
+        path: output.display().to_string(),
+        source: io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid {name} {value} in the metadata"),
+        ),
+    };
+
+    let conn = Connection::open(output).map_err(&sqlite_error)?;
+
+    let mut bounds = bounds.to_vec();
+
+    if update {
+        if let Some(format) = get_metadata(&conn, "format").map_err(&sqlite_error)? {
+            if format != options.format.to_string() {
+                return Err(Error::InvalidOptions(format!(
+                    "Output file has format {format}. Use --format {format}."
+                )));
+            }
+        }
+
+        // extend the bounds of the previous runs
+        if let Some(existing) = get_metadata(&conn, "bounds").map_err(&sqlite_error)? {
+            let existing: Vec<f64> = existing
+                .split(',')
+                .map(str::parse)
+                .collect::<Result<Vec<_>, _>>()
+                .ok()
+                .filter(|values| values.len() == 4)
+                .ok_or_else(|| invalid_metadata("bounds", &existing))?;
+
+            bounds = vec![
+                (bounds[0].0.min(existing[0]), bounds[0].1.min(existing[1])),
+                (bounds[1].0.max(existing[2]), bounds[1].1.max(existing[3])),
+            ];
         }
+
+        let zoom = |name| {
+            get_metadata(&conn, name)
+                .map_err(&sqlite_error)?
+                .map(|zoom| {
+                    zoom.parse::<u8>()
+                        .map_err(|_| invalid_metadata(name, &zoom))
+                })
+                .transpose()
+        };
+
+        let maxzoom = zoom("maxzoom")?.map_or(options.zoom_level, |maxzoom| {
+            maxzoom.max(options.zoom_level)
+        });
+
+        let minzoom =
+            zoom("minzoom")?.map_or(options.min_zoom, |minzoom| minzoom.min(options.min_zoom));
+
+        update_metadata(
+            &conn,
+            &[
+                ("minzoom", minzoom.to_string().as_ref()),
+                ("maxzoom", maxzoom.to_string().as_ref()),
+                (
+                    "bounds",
+                    &format!(
+                        "{},{},{},{}",
+                        bounds[0].0, bounds[0].1, bounds[1].0, bounds[1].1
+                    ),
+                ),
+            ],
+        )
+        .map_err(&sqlite_error)?;
+    } else if !cont {
+        create_schema(
+            &conn,
+            &[
+                ("name", name),
+                ("minzoom", options.min_zoom.to_string().as_ref()),
+                ("maxzoom", options.zoom_level.to_string().as_ref()),
+                ("format", &options.format.to_string()),
+                (
+                    "bounds",
+                    &format!(
+                        "{},{},{},{}",
+                        bounds[0].0, bounds[0].1, bounds[1].0, bounds[1].1
+                    ),
+                ),
+            ],
+        )
+        .map_err(&sqlite_error)?;
+    }
+
+    if let (Some(attribution), false) = (&options.attribution, cont) {
+        update_metadata(&conn, &[("attribution", attribution)]).map_err(&sqlite_error)?;
+    }
+
+    conn.pragma_update(None, "synchronous", "OFF")
+        .map_err(&sqlite_error)?;
+
+    conn.pragma_update(None, "journal_mode", "WAL")
+        .map_err(&sqlite_error)?;
+
+    Ok(conn)
+}
+
+/// Writes the tiles received from the rasterizing threads to the PMTiles archive
+fn write_pmtiles(
+    target: &Target,
+    receiver: &Receiver<(Tile, Vec<u8>)>,
+    bounds: &[(f64, f64)],
+) -> Result<(), Error> {
+    let options = target.options;
+
+    let mut writer = PmtilesWriter::new(&options.output)?;
+
+    for (tile, buffer) in receiver {
+        let started = Instant::now();
+
+        writer.add(tile, &buffer)?;
+
+        TIMINGS.record(Phase::Write, started.elapsed());
+    }
+
+    let mut json = json!({
+        "name": target.name,
+        "format": options.format.to_string(),
     });
+
+    if let Some(attribution) = &options.attribution {
+        json["attribution"] = attribution.as_str().into();
+    }
+
+    writer.finish(&Metadata {
+        tile_type: match options.format {
+            Format::PNG => 2,
+            Format::JPEG => 3,
+            Format::WEBP => 4,
+        },
+        min_zoom: options.min_zoom,
+        max_zoom: options.zoom_level,
+        bounds: [bounds[0].0, bounds[0].1, bounds[1].0, bounds[1].1],
+        json: json.to_string(),
+    })
 }
 
-fn rgba_to_rgb(img: &RgbaImage, background: Rgb<u8>) -> RgbImage {
-    let (width, height) = img.dimensions();
+/// Writes the tiles received from the rasterizing threads in transactions of the tiles received meanwhile
+fn write_tiles(
+    conn: &mut Connection,
+    receiver: &Receiver<(Tile, Vec<u8>)>,
+    sql: &str,
+) -> rusqlite::Result<()> {
+    while let Ok(first) = receiver.recv() {
+        let started = Instant::now();
+
+        let tx = conn.transaction()?;
+
+        {
+            let mut stmt = tx.prepare_cached(sql)?;
+
+            for (tile, buffer) in
+                iter::once(first).chain(receiver.try_iter().take(TILES_PER_TRANSACTION - 1))
+            {
+                let res = stmt.execute((tile.zoom, tile.x, tile.reversed_y(), buffer));
+
+                match res {
+                    Err(rusqlite::Error::SqliteFailure(ref err, _))
+                        if err.code == ErrorCode::ConstraintViolation =>
+                    {
+                        warn!("DUPLICATE {}/{}/{}", tile.zoom, tile.x, tile.y);
+                    }
+                    _ => {
+                        res?;
+                    }
+                }
+            }
+        }
+
+        tx.commit()?;
+
+        TIMINGS.record(Phase::Write, started.elapsed());
+    }
+
+    Ok(())
+}
 
-    let mut rgb_img = RgbImage::new(width, height);
+/// Composes images of the child tiles (by index in `Tile::children`) and downsamples them to the tile size
+fn compose_overview<P>(
+    children: impl Iterator<Item = (usize, ImageBuffer<P, Vec<P::Subpixel>>)>,
+    tile_size: u32,
+    filter: FilterType,
+) -> ImageBuffer<P, Vec<P::Subpixel>>
+where
+    P: Pixel + 'static,
+{
+    let mut img = ImageBuffer::new(tile_size << 1, tile_size << 1);
+
+    for (i, child) in children {
+        img.copy_from(
+            &child,
+            ((i & 1) as u32) * tile_size,
+            (i >> 1) as u32 * tile_size,
+        )
+        .unwrap();
+    }
 
-    for (x, y, &rgba) in img.enumerate_pixels() {
-        let mut base = background.to_rgba();
+    resize(&img, tile_size, tile_size, filter)
+}
 
-        base.channels_mut()[3] = 255;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared_types::PointUse;
+    use rayon::{
+        ThreadPoolBuilder,
+        iter::{IntoParallelRefIterator, ParallelIterator},
+    };
+    use std::fs;
 
-        base.blend(&rgba);
+    #[test]
+    fn mbtiles_metadata_has_the_name_of_the_output() {
+        let dir = std::env::temp_dir().join(format!("laz2dem-{}-mbtiles", std::process::id()));
 
-        rgb_img.put_pixel(x, y, base.to_rgb());
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut options =
+            Options::from_json(r#"{"shadings": "igor-slope,000000FF", "format": "png"}"#).unwrap();
+
+        options.output = dir.join("layer.mbtiles");
+
+        let conn =
+            open_mbtiles(&options, "Slope", false, false, &[(0.0, 0.0), (1.0, 1.0)]).unwrap();
+
+        assert_eq!(
+            get_metadata(&conn, "name").unwrap().as_deref(),
+            Some("Slope")
+        );
+        assert_eq!(
+            get_metadata(&conn, "format").unwrap().as_deref(),
+            Some("png")
+        );
+
+        drop(conn);
+
+        fs::remove_dir_all(dir).unwrap();
     }
 
-    rgb_img
+    #[test]
+    fn supersampled_supertile_is_downsampled_to_its_size_with_smoother_edges() {
+        let render = |supersample| {
+            let options = Options {
+                supersample,
+                ..Options::from_json(
+                    r#"{"shadings": "igor-slope,000000FF", "dedup": "off", "zoom_level": 17}"#,
+                )
+                .unwrap()
+            };
+
+            // 64 x 64 pixels, as a supertile is a whole number of them
+            let size = 64.0 / options.pixels_per_meter();
+
+            let tile_meta = TileMeta::new(
+                Tile {
+                    zoom: 0,
+                    x: 0,
+                    y: 0,
+                },
+                BBox::new(0.0, 0.0, size, size),
+            );
+
+            let point_use = PointUse {
+                terrain: true,
+                water: false,
+                surface: false,
+            };
+
+            // 4 points per pixel of the supersampled one of the terrain of a diagonal wall 5 m high
+            for i in 0..256 * 256 {
+                let (x, y) = (
+                    f64::from(i % 256) * size / 256.0,
+                    f64::from(i / 256) * size / 256.0,
+                );
+
+                let height = if x * 0.8 + y * 0.3 > size * 0.55 {
+                    5.0
+                } else {
+                    0.0
+                };
+
+                tile_meta.push(&point_use, Point2::new(x, y), height);
+            }
+
+            let surface = interpolate_supertile(&options, &tile_meta)
+                .unwrap()
+                .unwrap();
+
+            let img = shade_supertile(&options, &surface).into_rgba8();
+
+            let (width, height) = img.dimensions();
+
+            // columns of the wall in the rows, by the centroids of the alphas off the border
+            let columns: Vec<f64> = img
+                .rows()
+                .skip(4)
+                .take(height as usize - 8)
+                .map(|row| {
+                    let (sum, weights) = row.enumerate().skip(4).take(width as usize - 8).fold(
+                        (0.0, 0.0),
+                        |(sum, weights), (x, pixel)| {
+                            let alpha = f64::from(pixel[3]);
+
+                            (sum + x as f64 * alpha, weights + alpha)
+                        },
+                    );
+
+                    sum / weights
+                })
+                .collect();
+
+            // energy of the deviations of the columns from their line, the staircase of the straight wall
+            let n = columns.len() as f64;
+
+            let (mean_row, mean_column) = ((n - 1.0) / 2.0, columns.iter().sum::<f64>() / n);
+
+            let slope = columns
+                .iter()
+                .enumerate()
+                .map(|(row, column)| (row as f64 - mean_row) * (column - mean_column))
+                .sum::<f64>()
+                / (0..columns.len())
+                    .map(|row| (row as f64 - mean_row).powi(2))
+                    .sum::<f64>();
+
+            let energy: f64 = columns
+                .iter()
+                .enumerate()
+                .map(|(row, column)| {
+                    (column - mean_column - slope * (row as f64 - mean_row)).powi(2)
+                })
+                .sum();
+
+            (
+                surface.width_pixels,
+                surface.height_pixels,
+                img.dimensions(),
+                energy,
+            )
+        };
+
+        let (width, height, dimensions, energy) = render(1);
+
+        let (supersampled_width, supersampled_height, supersampled_dimensions, supersampled_energy) =
+            render(2);
+
+        assert_eq!(
+            (supersampled_width, supersampled_height),
+            (2 * width, 2 * height)
+        );
+
+        assert_eq!(supersampled_dimensions, dimensions);
+
+        assert_eq!(dimensions, (width, height));
+
+        assert!(
+            supersampled_energy < energy,
+            "{supersampled_energy} {energy}"
+        );
+    }
+
+    #[test]
+    fn supertile_is_rendered_identically_with_1_and_8_threads() {
+        let options = |dedup| {
+            Options::from_json(&format!(
+                r#"{{"shadings": "igor,000000FF,315+igor-slope,00000080", "dedup": "{dedup}", "zoom_level": 17}}"#
+            ))
+            .unwrap()
+        };
+
+        let bbox = BBox::new(0.0, 0.0, 200.0, 200.0);
+
+        let point_use = PointUse {
+            terrain: true,
+            water: false,
+            surface: false,
+        };
+
+        // scattered points among the duplicate and the collinear ones of the degenerate configurations
+        let points: Vec<_> = (0..4000)
+            .map(|i| {
+                let position = match i % 20 {
+                    0 => (50.0, 50.0),
+                    1 => (100.0, f64::from(i % 200)),
+                    _ => ((f64::from(i) * 7.31) % 200.0, (f64::from(i) * 3.17) % 200.0),
+                };
+
+                (position, (f64::from(i) * 0.37).sin() * 10.0)
+            })
+            .collect();
+
+        let render = |options: &Options, threads| {
+            let tile_meta = TileMeta::new(
+                Tile {
+                    zoom: 0,
+                    x: 0,
+                    y: 0,
+                },
+                bbox,
+            );
+
+            ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap()
+                .install(|| {
+                    points.par_iter().for_each(|&((x, y), height)| {
+                        tile_meta.push(&point_use, Point2::new(x, y), height);
+                    });
+
+                    let surface = interpolate_supertile(options, &tile_meta).unwrap().unwrap();
+
+                    shade_supertile(options, &surface).into_bytes()
+                })
+        };
+
+        // the reduction of `mean` does not depend on the order of the points either
+        for options in [options("off"), options("mean")] {
+            let single = render(&options, 1);
+
+            for _ in 0..2 {
+                assert!(render(&options, 8) == single, "{}", options.dedup);
+            }
+        }
+    }
+
+    #[test]
+    fn mbtiles_rows_are_flipped_and_upserted() {
+        let dir = std::env::temp_dir().join(format!("laz2dem-{}-mbtiles-rows", std::process::id()));
+
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut options =
+            Options::from_json(r#"{"shadings": "igor-slope,000000FF", "format": "png"}"#).unwrap();
+
+        options.output = dir.join("rows.mbtiles");
+
+        let mut conn = open_mbtiles(
+            &options,
+            "Hillshade",
+            false,
+            false,
+            &[(0.0, 0.0), (1.0, 1.0)],
+        )
+        .unwrap();
+
+        let tile = Tile {
+            zoom: 3,
+            x: 2,
+            y: 1,
+        };
+
+        for data in [b"old", b"new"] {
+            let (sender, receiver) = sync_channel(1);
+
+            sender.send((tile, data.to_vec())).unwrap();
+
+            drop(sender);
+
+            write_tiles(&mut conn, &receiver, UPSERT_TILE_SQL).unwrap();
+        }
+
+        let rows: Vec<(u8, u32, u32, Vec<u8>)> = conn
+            .prepare("SELECT * FROM tiles")
+            .unwrap()
+            .query_map([], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(rows, [(3, 2, 6, b"new".to_vec())]);
+
+        drop(conn);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
 }
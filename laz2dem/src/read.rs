@@ -1,55 +1,806 @@
 use crate::{
-    options::Options,
-    shared_types::{PointWithHeight, Source, TileMeta},
+    copc::PointReader,
+    error::Error,
+    options::{Options, SurfaceModel},
+    progress::{ReadProgress, format_duration, reporter},
+    rasterization::read_laz_tile,
+    remote::local_path,
+    shared_types::{PointUse, Source, TileMeta, TilePoints},
+    spill::SpillDir,
+    timings::{Phase, TIMINGS},
+    water::level_water_bodies,
+};
+use core::f64::{self, consts::PI};
+use las::{Header, Point, Reader, point::Classification};
+use log::{debug, error, info, warn};
+use maptile::{
+    bbox::BBox,
+    tile::{Tile, mercator_to_tile_coords},
+    utils::bbox_covered_tiles,
 };
-use core::f64;
-use las::{Reader, point::Classification};
-use maptile::{bbox::BBox, utils::bbox_covered_tiles};
 use proj::Proj;
-use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
-use rusqlite::{Connection, OpenFlags};
+use rusqlite::{Connection, OpenFlags, Row};
 use spade::Point2;
-use std::sync::Mutex;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    ops::AddAssign,
+    path::Path,
+    slice,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::SyncSender,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use walkdir::WalkDir;
+
+/// *.laz file to read
+pub struct SourceFile {
+    pub path: String,
+    /// `None` for EPSG:3857
+    pub source_projection: Option<String>,
+    /// Bbox to read in the projection of the file
+    pub bbox_unprojected: Option<BBox>,
+    /// Bounds of the points in EPSG:3857
+    pub bounds: BBox,
+    /// Number of the points if known before reading the file
+    pub point_count: Option<u64>,
+}
+
+/// Counts of the unreadable *.laz files and of their points which were not read, and of the rejected points
+#[derive(Default)]
+pub struct Skipped {
+    /// Files which could not be opened or were not read completely
+    pub files: AtomicUsize,
+    pub points: AtomicUsize,
+    pub rejected: Mutex<Rejected>,
+}
+
+/// Counts of the points rejected at the stages of reading, in their order
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Rejected {
+    /// Outside the bbox in the projection of the file, before the projection
+    pub outside_unprojected: u64,
+    /// By the variants of `Rejection`
+    pub filtered: [u64; 5],
+    pub by_class: u64,
+    /// Outside the bbox in EPSG:3857
+    pub outside: u64,
+    pub outside_clip: u64,
+}
+
+impl AddAssign for Rejected {
+    fn add_assign(&mut self, other: Self) {
+        self.outside_unprojected += other.outside_unprojected;
+
+        for (filtered, other) in self.filtered.iter_mut().zip(other.filtered) {
+            *filtered += other;
+        }
+
+        self.by_class += other.by_class;
+
+        self.outside += other.outside;
+
+        self.outside_clip += other.outside_clip;
+    }
+}
+
+impl Rejected {
+    fn log(&self, name: &str) {
+        let [withheld, synthetic, overlap, not_last_return, scan_angle] = self.filtered;
 
-pub fn read(options: &Options) -> Vec<TileMeta> {
+        debug!(
+            "REJECTED {name}: {} points outside the bbox in the projection of the file, {withheld} withheld, {synthetic} synthetic, {overlap} overlap, {not_last_return} not last returns, {scan_angle} over the max scan angle, {} by the classification, {} outside the bbox, {} outside the clip polygon",
+            self.outside_unprojected, self.by_class, self.outside, self.outside_clip
+        );
+    }
+}
+
+impl Skipped {
+    pub fn print_summary(&self) {
+        let files = self.files.load(Ordering::Relaxed);
+
+        if files > 0 {
+            let points = self.points.load(Ordering::Relaxed);
+
+            warn!("SKIPPED {files} unreadable files ({points} points not read)");
+        }
+
+        self.rejected.lock().unwrap().log("all files");
+    }
+}
+
+/// Supertiles covering the bbox, without points
+pub fn tile_metas(options: &Options) -> Result<Vec<TileMeta>, Error> {
+    let mut tile_metas: Vec<_> = bbox_covered_tiles(&options.bbox, options.unit_zoom_level)
+        .map(|tile| tile_meta(options, tile))
+        .collect();
+
+    let open_tiles = options.max_open_tiles.unwrap_or(tile_metas.len());
+
+    if let Some(spill_dir) = SpillDir::new(options, open_tiles)? {
+        for tile_meta in &mut tile_metas {
+            tile_meta.spill_dir = Some(spill_dir.clone());
+        }
+    }
+
+    Ok(tile_metas)
+}
+
+/// Supertile of the unit zoom level with its bbox extended by the buffer
+fn tile_meta(options: &Options, supertile: Tile) -> TileMeta {
     let buffer_m = options.buffer as f64 / options.pixels_per_meter();
 
-    let tile_metas: Vec<_> = bbox_covered_tiles(&options.bbox, options.unit_zoom_level)
-        .map(|tile| TileMeta {
-            tile,
-            bbox: tile
-                .bounds(options.tile_size << (options.zoom_level - options.unit_zoom_level))
-                .to_extended(buffer_m),
-            points: Mutex::new(Vec::<PointWithHeight>::new()),
+    TileMeta::new(
+        supertile,
+        supertile
+            .bounds(options.tile_size << (options.zoom_level - options.unit_zoom_level))
+            .to_extended(buffer_m),
+    )
+}
+
+/// Reads the points of the single supertile of `--serve` or `--debug-tile` with the buffer
+pub fn read_supertile(options: &Options, supertile: Tile) -> Result<TileMeta, Error> {
+    let tile_meta = tile_meta(options, supertile);
+
+    match options.source() {
+        Source::LazTileDb(path) => read_laz_tile(
+            &Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(
+                |source| Error::Sqlite {
+                    path: path.display().to_string(),
+                    source,
+                },
+            )?,
+            &tile_meta,
+            options,
+        )?,
+        Source::LazIndexDb(_) | Source::LazDir(_) => {
+            // only the files of the supertile
+            let options = Options {
+                bbox: tile_meta.bbox,
+                ..options.clone()
+            };
+
+            let skipped = Skipped::default();
+
+            if let Some(files) = read_files(&options, &skipped)? {
+                read_points(
+                    &options,
+                    slice::from_ref(&tile_meta),
+                    &files.iter().collect::<Vec<_>>(),
+                    &skipped,
+                    &AtomicBool::new(false),
+                )?;
+
+                skipped.print_summary();
+            }
+        }
+    }
+
+    Ok(tile_meta)
+}
+
+/// *.laz files intersecting the bbox; `None` for LAZ tile DB which is read per tile while rasterizing.
+/// Files of `--laz-dir` with unreadable headers are skipped unless `--strict`.
+pub fn read_files(options: &Options, skipped: &Skipped) -> Result<Option<Vec<SourceFile>>, Error> {
+    Ok(match options.source() {
+        Source::LazTileDb(_) => None,
+        Source::LazIndexDb(path) => Some(index_files(options, &path)?),
+        Source::LazDir(path) => Some(dir_files(options, &path, skipped)?),
+    })
+}
+
+/// Reads the tiles in batches of half of `max_open_tiles` in the Morton order and sends every batch once read,
+/// so that the next batch is read while the previous one is rasterized.
+/// Files overlapping tiles of more batches are read for each of them.
+pub fn read_batches(
+    options: &Options,
+    mut tile_metas: Vec<TileMeta>,
+    files: &[SourceFile],
+    max_open_tiles: usize,
+    skipped: &Skipped,
+    sender: SyncSender<Vec<TileMeta>>,
+    cancel: &AtomicBool,
+) -> Result<(), Error> {
+    tile_metas.sort_by_cached_key(|tile_meta| tile_meta.tile.morton_code());
+
+    let batch_size = (max_open_tiles / 2).max(1);
+
+    while !tile_metas.is_empty() {
+        let batch: Vec<_> = tile_metas
+            .drain(..batch_size.min(tile_metas.len()))
+            .collect();
+
+        read_points(
+            options,
+            &batch,
+            &overlapping_files(files, &batch),
+            skipped,
+            cancel,
+        )?;
+
+        // the rasterizing stops early only when cancelled
+        if sender.send(batch).is_err() {
+            return Err(Error::Cancelled);
+        }
+    }
+
+    Ok(())
+}
+
+/// Files overlapping any of the tiles
+pub fn overlapping_files<'a>(
+    files: &'a [SourceFile],
+    tile_metas: &[TileMeta],
+) -> Vec<&'a SourceFile> {
+    files
+        .iter()
+        .filter(|file| {
+            tile_metas.iter().any(|tile_meta| {
+                file.bounds.max_x >= tile_meta.bbox.min_x
+                    && file.bounds.min_x <= tile_meta.bbox.max_x
+                    && file.bounds.max_y >= tile_meta.bbox.min_y
+                    && file.bounds.min_y <= tile_meta.bbox.max_y
+            })
         })
+        .collect()
+}
+
+/// Reads points of the files to the tiles. Unreadable files are skipped unless `--strict`
+/// but the points read before the error are kept. Once `cancel` is set, the files being read are finished.
+pub fn read_points(
+    options: &Options,
+    tile_metas: &[TileMeta],
+    files: &[&SourceFile],
+    skipped: &Skipped,
+    cancel: &AtomicBool,
+) -> Result<(), Error> {
+    let buffer_m = options.buffer as f64 / options.pixels_per_meter();
+
+    let tile_indices: HashMap<_, _> = tile_metas
+        .iter()
+        .enumerate()
+        .map(|(i, tile_meta)| ((tile_meta.tile.x, tile_meta.tile.y), i))
         .collect();
 
-    let Source::LazIndexDb(path) = options.source() else {
-        return tile_metas;
+    info!("Reading {} files", files.len());
+
+    let started = Instant::now();
+
+    let progress = ReadProgress::new(
+        &files
+            .iter()
+            .map(|file| file.point_count)
+            .collect::<Vec<_>>(),
+        reporter(options),
+    );
+
+    let read_file = |projs: &mut HashMap<String, Proj>, file: &SourceFile| -> Result<(), Error> {
+        let SourceFile {
+            path: file,
+            source_projection,
+            bbox_unprojected,
+            point_count,
+            ..
+        } = file;
+
+        debug!("READ {file}");
+
+        if let Some(source_projection) = source_projection {
+            if !projs.contains_key(source_projection) {
+                projs.insert(
+                    source_projection.clone(),
+                    new_proj(source_projection, "EPSG:3857")?,
+                );
+            }
+        }
+
+        let proj = source_projection
+            .as_ref()
+            .map(|source_projection| &projs[source_projection]);
+
+        let bbox = bbox_unprojected.as_ref().unwrap_or(&options.bbox);
+
+        let (mut reader, bytes) = match local_path(options, file).and_then(|path| {
+            let bytes = fs::metadata(&path).map_or(0, |metadata| metadata.len());
+
+            PointReader::open(options, &path, bbox)
+                .map(|reader| (reader, bytes))
+                .map_err(|source| Error::Las {
+                    path: file.clone(),
+                    source,
+                })
+        }) {
+            Ok((reader, bytes)) => (reader, bytes),
+            Err(err) if !options.strict => {
+                error!("{file}: {err}");
+
+                skipped.files.fetch_add(1, Ordering::Relaxed);
+
+                progress.file_done(*point_count, 0);
+
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        };
+
+        let number_of_points = reader.number_of_points();
+
+        // to lock the points of the tiles only once per buffer
+        let mut buffers = HashMap::<usize, TilePoints>::new();
+
+        let mut projected = 0_u64;
+
+        let mut unprojectable = 0_u64;
+
+        let mut read = number_of_points;
+
+        let mut rejected = Rejected::default();
+
+        let mut chunk = Vec::new();
+
+        let mut read_chunks = 0_u64;
+
+        let (mut decompression, mut projection) = (Duration::ZERO, Duration::ZERO);
+
+        loop {
+            chunk.clear();
+
+            let started = Instant::now();
+
+            let result = reader.read_chunk(&mut chunk);
+
+            let decompressed = Instant::now();
+
+            decompression += decompressed - started;
+
+            // points decoded before an error are kept
+            for point in &chunk {
+                // cheapest check first, the projection is the most expensive one
+                if bbox_unprojected.is_some_and(|bbox| !bbox.contains(point.x, point.y)) {
+                    rejected.outside_unprojected += 1;
+
+                    continue;
+                }
+
+                if let Some(rejection) = rejection(point, options) {
+                    rejected.filtered[rejection as usize] += 1;
+
+                    continue;
+                }
+
+                let point_use = point_use(point, options);
+
+                if !(point_use.terrain || point_use.water || point_use.surface) {
+                    rejected.by_class += 1;
+
+                    continue;
+                }
+
+                let (x, y) = match proj {
+                    Some(proj) => {
+                        projected += 1;
+
+                        // stray points out of the domain of the projection
+                        let Ok(coords) = proj.convert((point.x, point.y)) else {
+                            unprojectable += 1;
+
+                            continue;
+                        };
+
+                        coords
+                    }
+                    None => (point.x, point.y),
+                };
+
+                if !options.bbox.contains(x, y) {
+                    rejected.outside += 1;
+
+                    continue;
+                }
+
+                if options
+                    .clip_polygon
+                    .as_ref()
+                    .is_some_and(|clip| !clip.contains_buffered(x, y, buffer_m))
+                {
+                    rejected.outside_clip += 1;
+
+                    continue;
+                }
+
+                for i in point_tiles(
+                    tile_metas,
+                    &tile_indices,
+                    options.unit_zoom_level,
+                    buffer_m,
+                    x,
+                    y,
+                ) {
+                    let tile_meta = &tile_metas[i];
+
+                    let buffer = buffers.entry(i).or_default();
+
+                    buffer.push(&point_use, Point2::new(x, y), point.z);
+
+                    if buffer.len() >= options.read_buffer_points {
+                        tile_meta.append(buffer)?;
+                    }
+                }
+            }
+
+            projection += decompressed.elapsed();
+
+            read_chunks += chunk.len() as u64;
+
+            match result {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(err) if !options.strict => {
+                    error!("{file}: point {read_chunks}: {err}");
+
+                    skipped.files.fetch_add(1, Ordering::Relaxed);
+
+                    // the rest of a compressed file can't be decoded either
+                    skipped.points.fetch_add(
+                        number_of_points.saturating_sub(read_chunks) as usize,
+                        Ordering::Relaxed,
+                    );
+
+                    read = read_chunks;
+
+                    break;
+                }
+                Err(source) => {
+                    return Err(Error::Las {
+                        path: format!("{file} (point {read_chunks})"),
+                        source,
+                    });
+                }
+            }
+        }
+
+        for (i, mut buffer) in buffers {
+            tile_metas[i].append(&mut buffer)?;
+        }
+
+        TIMINGS.record(Phase::Decompress, decompression);
+
+        TIMINGS.record(Phase::Project, projection);
+
+        TIMINGS.read(bytes, read);
+
+        rejected.log(file);
+
+        *skipped.rejected.lock().unwrap() += rejected;
+
+        if unprojectable == 0 {
+            debug!("DONE {file}");
+        } else {
+            info!("DONE {file} ({unprojectable} points failed to project)");
+
+            if unprojectable as f64 > options.max_unprojectable_fraction * projected as f64 {
+                warn!(
+                    "{file}: {unprojectable} of {projected} points failed to project from {}, the CRS is probably wrong",
+                    source_projection.as_deref().unwrap_or_default()
+                );
+            }
+        }
+
+        progress.file_done(*point_count, read);
+
+        Ok(())
     };
 
-    let bbox_unprojected = options.source_projection.as_ref().map(|source_projection| {
-        let bbox_unprojected: BBox = Proj::new_known_crs("EPSG:3857", source_projection, None)
-            .expect("Failed to create PROJ transformation")
-            .transform_bounds(
-                options.bbox.min_x,
-                options.bbox.min_y,
-                options.bbox.max_x,
-                options.bbox.max_y,
-                11,
+    // the projections are cached per thread as its files mostly share them
+    let taken = in_threads(files, options.read_threads(), cancel, |projs, file| {
+        read_file(projs, file)
+    })?;
+
+    if cancel.load(Ordering::Relaxed) {
+        warn!(
+            "CANCELLED reading after {} of {} files",
+            taken.min(files.len()),
+            files.len()
+        );
+
+        return Err(Error::Cancelled);
+    }
+
+    info!(
+        "READ {} files in {}",
+        files.len(),
+        format_duration(started.elapsed())
+    );
+
+    if options.flatten_water {
+        level_water_bodies(tile_metas, options.water_link_distance);
+    }
+
+    Ok(())
+}
+
+/// Reads the items by `read` with the state of the thread in `threads` threads of their own, rather than
+/// of the rayon pool, where the parallel decompression would let a thread steal another file and hold more files
+/// open than `--read-threads`. Stops at the first error or once cancelled, giving the number of the items taken.
+fn in_threads<T: Sync, S: Default>(
+    items: &[T],
+    threads: usize,
+    cancel: &AtomicBool,
+    read: impl Fn(&mut S, &T) -> Result<(), Error> + Sync,
+) -> Result<usize, Error> {
+    let next = AtomicUsize::new(0);
+
+    let failed = AtomicBool::new(false);
+
+    thread::scope(|scope| {
+        let readers: Vec<_> = (0..threads.min(items.len()))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut state = S::default();
+
+                    while !failed.load(Ordering::Relaxed) && !cancel.load(Ordering::Relaxed) {
+                        let Some(item) = items.get(next.fetch_add(1, Ordering::Relaxed)) else {
+                            break;
+                        };
+
+                        if let Err(err) = read(&mut state, item) {
+                            failed.store(true, Ordering::Relaxed);
+
+                            return Err(err);
+                        }
+                    }
+
+                    Ok(())
+                })
+            })
+            .collect();
+
+        readers
+            .into_iter()
+            .try_for_each(|reader| reader.join().unwrap())
+    })?;
+
+    Ok(next.into_inner())
+}
+
+/// Indices of the tiles whose buffered bbox contains the point, looked up in `tile_indices` by the tiles
+/// of the corners of the buffer around the point
+fn point_tiles(
+    tile_metas: &[TileMeta],
+    tile_indices: &HashMap<(u32, u32), usize>,
+    zoom: u8,
+    buffer_m: f64,
+    x: f64,
+    y: f64,
+) -> impl Iterator<Item = usize> {
+    let tile_coords: HashSet<_> = (0..4)
+        .map(|sector| {
+            mercator_to_tile_coords(
+                x + (((sector >> 1) << 1) as f64 - 1.0) * buffer_m,
+                y + (((sector & 1) << 1) as f64 - 1.0) * buffer_m,
+                zoom,
             )
-            .unwrap()
-            .into();
+        })
+        .collect();
+
+    tile_coords
+        .into_iter()
+        .filter_map(|tile_coord| tile_indices.get(&tile_coord).copied())
+        .filter(move |&i| tile_metas[i].bbox.contains(x, y))
+}
+
+/// Filter of the flags, the return number and the scan angle rejecting the point
+#[derive(Clone, Copy)]
+pub enum Rejection {
+    Withheld,
+    Synthetic,
+    Overlap,
+    NotLastReturn,
+    ScanAngle,
+}
+
+/// Filter of the point rejecting it before its classification is considered
+pub fn rejection(point: &Point, options: &Options) -> Option<Rejection> {
+    if point.is_withheld && !options.include_withheld {
+        Some(Rejection::Withheld)
+    } else if point.is_synthetic && options.exclude_synthetic {
+        Some(Rejection::Synthetic)
+    } else if options.exclude_overlap && point.is_overlap {
+        Some(Rejection::Overlap)
+    } else if options.last_return_only && point.return_number < point.number_of_returns {
+        Some(Rejection::NotLastReturn)
+    } else if options
+        .max_scan_angle
+        .is_some_and(|max_scan_angle| point.scan_angle.abs() > max_scan_angle)
+    {
+        Some(Rejection::ScanAngle)
+    } else {
+        None
+    }
+}
+
+/// Collections the point belongs to according to its classification and return number
+pub fn point_use(point: &Point, options: &Options) -> PointUse {
+    let class = u8::from(point.classification);
+
+    let first_return = point.return_number == 1;
+
+    // DSM is made of first returns only
+    let returned = options.surface != SurfaceModel::Dsm || first_return;
+
+    let water = returned && options.flatten_water && point.classification == Classification::Water;
+
+    PointUse {
+        terrain: returned && !water && options.is_class_included(class),
+        water,
+        surface: options.surface == SurfaceModel::Chm
+            && first_return
+            && !options.exclude_classes.0.contains(&class),
+    }
+}
 
-        bbox_unprojected
-    });
+/// CRS of the LAS file from its WKT or GeoTIFF keys VLR; `lazindex` indexes the files by it
+pub fn detect_crs(header: &Header) -> Option<String> {
+    let vlrs: Vec<_> = header
+        .all_vlrs()
+        .filter(|vlr| vlr.user_id == "LASF_Projection")
+        .collect();
+
+    if let Some(vlr) = vlrs.iter().find(|vlr| vlr.record_id == 2112) {
+        let wkt = String::from_utf8_lossy(&vlr.data);
+
+        return Some(wkt.trim_end_matches('\0').to_string());
+    }
+
+    let vlr = vlrs.iter().find(|vlr| vlr.record_id == 34735)?;
+
+    let keys: Vec<_> = vlr
+        .data
+        .chunks_exact(2)
+        .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+        .collect();
+
+    // key entries follow the header of 4 values; a value is inline if its location is 0
+    let geo_key = |id| {
+        keys.get(4..)?
+            .chunks_exact(4)
+            .find(|entry| entry[0] == id && entry[1] == 0)
+            .map(|entry| entry[3])
+            // user-defined
+            .filter(|&code| code != 0 && code != 32767)
+    };
+
+    // ProjectedCSTypeGeoKey or GeographicTypeGeoKey
+    geo_key(3072)
+        .or_else(|| geo_key(2048))
+        .map(|code| format!("EPSG:{code}"))
+}
+
+/// Files of the LAZ index DB intersecting the bbox
+fn index_files(options: &Options, path: &Path) -> Result<Vec<SourceFile>, Error> {
+    let sqlite = |source| Error::Sqlite {
+        path: path.display().to_string(),
+        source,
+    };
+
+    let conn =
+        Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(sqlite)?;
+
+    // written by `lazindex` since it detects the CRS of every file
+    let has_crs = conn.prepare("SELECT crs FROM laz_index LIMIT 0").is_ok();
+
+    // projections of the groups of files to query; `None` means unknown
+    let crss: Vec<Option<String>> = if has_crs {
+        conn.prepare("SELECT DISTINCT crs FROM laz_index")
+            .map_err(sqlite)?
+            .query_map([], |row| row.get(0))
+            .map_err(sqlite)?
+            .collect::<Result<_, _>>()
+            .map_err(sqlite)?
+    } else {
+        vec![None]
+    };
+
+    // R*Tree written by `lazindex`, it spares the full scan of the index
+    let has_rtree = conn
+        .query_row(
+            "SELECT 1 FROM sqlite_master WHERE name = 'laz_index_rtree'",
+            [],
+            |_| Ok(()),
+        )
+        .is_ok();
+
+    // written by `lazindex` too, for the progress
+    let point_count = if conn
+        .prepare("SELECT point_count FROM laz_index LIMIT 0")
+        .is_ok()
+    {
+        "laz_index.point_count"
+    } else {
+        "NULL"
+    };
 
-    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).unwrap();
+    let mut sql = if has_rtree {
+        format!(
+            "SELECT file, laz_index.min_x, laz_index.min_y, laz_index.max_x, laz_index.max_y, {point_count} FROM laz_index JOIN laz_index_rtree r ON r.id = laz_index.rowid WHERE r.max_x >= ?1 AND r.min_x <= ?3 AND r.max_y >= ?2 AND r.min_y <= ?4"
+        )
+    } else {
+        format!(
+            "SELECT file, min_x, min_y, max_x, max_y, {point_count} FROM laz_index WHERE max_x >= ?1 AND min_x <= ?3 AND max_y >= ?2 AND min_y <= ?4"
+        )
+    };
 
-    let mut stmt = conn.prepare("SELECT file FROM laz_index WHERE max_x >= ?1 AND min_x <= ?3 AND max_y >= ?2 AND min_y <= ?4").unwrap();
+    if has_crs {
+        sql.push_str(" AND crs IS ?5");
+    }
+
+    let mut stmt = conn.prepare(&sql).map_err(sqlite)?;
+
+    let mut files = Vec::new();
+
+    // the bbox queried in the projection of the files covers more than the bbox, mostly near its edges
+    let extended_bbox = options
+        .bbox
+        .to_extended(options.buffer as f64 / options.pixels_per_meter());
+
+    let mut pruned = 0;
+
+    for crs in crss {
+        let source_projection = match (&crs, &options.source_projection) {
+            (Some(crs), _) => crs.clone(),
+            (None, Some(source_projection)) => source_projection.clone(),
+            (None, None) if has_crs => {
+                let unknown: Vec<String> = conn
+                    .prepare("SELECT file FROM laz_index WHERE crs IS NULL")
+                    .map_err(sqlite)?
+                    .query_map([], |row| row.get(0))
+                    .map_err(sqlite)?
+                    .collect::<Result<_, _>>()
+                    .map_err(sqlite)?;
+
+                warn!(
+                    "Skipping files without CRS, use --source-projection to read them: {}",
+                    unknown.join(", ")
+                );
 
-    let rows = stmt
-        .query_map(
+                continue;
+            }
+            (None, None) => {
+                let file: String = conn
+                    .query_row("SELECT file FROM laz_index LIMIT 1", [], |row| row.get(0))
+                    .map_err(sqlite)?;
+
+                let reader = Reader::from_path(local_path(options, &file)?).map_err(|source| {
+                    Error::Las {
+                        path: file.clone(),
+                        source,
+                    }
+                })?;
+
+                let crs = detect_crs(reader.header()).ok_or_else(|| {
+                    Error::InvalidOptions(format!(
+                        "Failed to detect CRS of {file}; use --source-projection (EPSG:3857 if no transformation is needed)"
+                    ))
+                })?;
+
+                info!("Detected CRS {crs}");
+
+                crs
+            }
+        };
+
+        let source_projection = Some(source_projection).filter(|crs| crs != "EPSG:3857");
+
+        let bbox_unprojected = source_projection
+            .as_ref()
+            .map(|source_projection| unproject_bbox(options, source_projection))
+            .transpose()?;
+
+        let [min_x, min_y, max_x, max_y] =
             <[f64; 4]>::from(bbox_unprojected.unwrap_or_else(|| {
                 BBox::new(
                     options.bbox.min_x,
@@ -57,70 +808,949 @@ pub fn read(options: &Options) -> Vec<TileMeta> {
                     options.bbox.max_x,
                     options.bbox.max_y,
                 )
-            })),
-            |row| row.get::<_, String>(0),
+            }));
+
+        let get_file = |row: &Row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                BBox::new(row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?),
+                row.get(5)?,
+            ))
+        };
+
+        let rows: Vec<_> = if has_crs {
+            stmt.query_map((min_x, min_y, max_x, max_y, &crs), get_file)
+                .map_err(sqlite)?
+                .collect::<Result<_, _>>()
+        } else {
+            stmt.query_map((min_x, min_y, max_x, max_y), get_file)
+                .map_err(sqlite)?
+                .collect::<Result<_, _>>()
+        }
+        .map_err(sqlite)?;
+
+        let proj = source_projection
+            .as_ref()
+            .map(|source_projection| new_proj(source_projection, "EPSG:3857"))
+            .transpose()?;
+
+        for (path, bounds, point_count) in rows {
+            let bounds = project_bounds(proj.as_ref(), bounds, &path)?;
+
+            if bounds.max_x < extended_bbox.min_x
+                || bounds.min_x > extended_bbox.max_x
+                || bounds.max_y < extended_bbox.min_y
+                || bounds.min_y > extended_bbox.max_y
+            {
+                pruned += 1;
+
+                continue;
+            }
+
+            files.push(SourceFile {
+                bounds,
+                path,
+                source_projection: source_projection.clone(),
+                bbox_unprojected,
+                point_count,
+            });
+        }
+    }
+
+    if pruned > 0 {
+        info!("Pruned {pruned} indexed files not intersecting the bbox in EPSG:3857");
+    }
+
+    Ok(files)
+}
+
+/// *.laz files of the directory matching `--laz-dir-pattern` whose header bounds intersect the bbox
+fn dir_files(options: &Options, path: &Path, skipped: &Skipped) -> Result<Vec<SourceFile>, Error> {
+    let walk_dir = WalkDir::new(path);
+
+    let walk_dir = if options.laz_dir_recursive {
+        walk_dir
+    } else {
+        walk_dir.max_depth(1)
+    };
+
+    // bbox in the projection and the transformation to EPSG:3857
+    let mut projections = HashMap::<String, (BBox, Proj)>::new();
+
+    let mut files = Vec::new();
+
+    let extended_bbox = options
+        .bbox
+        .to_extended(options.buffer as f64 / options.pixels_per_meter());
+
+    let mut outside = 0;
+
+    for entry in walk_dir.sort_by_file_name() {
+        let entry = entry.map_err(|err| Error::Io {
+            path: err.path().unwrap_or(path).to_string_lossy().to_string(),
+            source: err.into(),
+        })?;
+
+        if !entry.file_type().is_file()
+            || !wildcard_match(
+                &options.laz_dir_pattern,
+                &entry.file_name().to_string_lossy(),
+            )
+        {
+            continue;
+        }
+
+        let file = entry.path().to_string_lossy().to_string();
+
+        let reader = match Reader::from_path(&file) {
+            Ok(reader) => reader,
+            Err(err) if !options.strict => {
+                error!("{file}: {err}");
+
+                skipped.files.fetch_add(1, Ordering::Relaxed);
+
+                continue;
+            }
+            Err(source) => return Err(Error::Las { path: file, source }),
+        };
+
+        let header = reader.header();
+
+        let Some(source_projection) =
+            detect_crs(header).or_else(|| options.source_projection.clone())
+        else {
+            warn!("Skipping {file} without CRS, use --source-projection to read it");
+
+            continue;
+        };
+
+        let source_projection = Some(source_projection).filter(|crs| crs != "EPSG:3857");
+
+        if let Some(source_projection) = &source_projection {
+            if !projections.contains_key(source_projection) {
+                projections.insert(
+                    source_projection.clone(),
+                    (
+                        unproject_bbox(options, source_projection)?,
+                        new_proj(source_projection, "EPSG:3857")?,
+                    ),
+                );
+            }
+        }
+
+        let projection = source_projection
+            .as_ref()
+            .map(|source_projection| &projections[source_projection]);
+
+        let bbox_unprojected = projection.map(|(bbox_unprojected, _)| *bbox_unprojected);
+
+        let bbox = bbox_unprojected.unwrap_or_else(|| {
+            BBox::new(
+                options.bbox.min_x,
+                options.bbox.min_y,
+                options.bbox.max_x,
+                options.bbox.max_y,
+            )
+        });
+
+        let bounds = header.bounds();
+
+        if bounds.max.x < bbox.min_x
+            || bounds.min.x > bbox.max_x
+            || bounds.max.y < bbox.min_y
+            || bounds.min.y > bbox.max_y
+        {
+            outside += 1;
+
+            continue;
+        }
+
+        let bounds = project_bounds(
+            projection.map(|(_, proj)| proj),
+            BBox::new(bounds.min.x, bounds.min.y, bounds.max.x, bounds.max.y),
+            &file,
+        )?;
+
+        // the bbox in the projection of the file covers more than the bbox
+        if bounds.max_x < extended_bbox.min_x
+            || bounds.min_x > extended_bbox.max_x
+            || bounds.max_y < extended_bbox.min_y
+            || bounds.min_y > extended_bbox.max_y
+        {
+            outside += 1;
+
+            continue;
+        }
+
+        files.push(SourceFile {
+            source_projection,
+            bbox_unprojected,
+            bounds,
+            path: file,
+            point_count: Some(header.number_of_points()),
+        });
+    }
+
+    info!("Skipped {outside} files by the header bounds");
+
+    Ok(files)
+}
+
+pub fn new_proj(from: &str, to: &str) -> Result<Proj, Error> {
+    Proj::new_known_crs(from, to, None).map_err(|source| Error::Proj {
+        context: format!("creating transformation from {from} to {to}"),
+        source: source.into(),
+    })
+}
+
+/// EPSG:3857 bounding box of `--bbox`, `--bbox-wgs84`, `--tiles` or `--debug-tile`
+pub fn resolve_bbox(options: &Options) -> Result<BBox, Error> {
+    if let Some(tiles) = options.tiles.or(options.debug_tile) {
+        return Ok(tiles.bbox());
+    }
+
+    if let Some(bbox) = options.bbox_wgs84 {
+        // latitude of the edge of the Web Mercator square
+        let max_lat = (PI.sinh().atan()).to_degrees();
+
+        if bbox.min_x >= bbox.max_x || bbox.min_y >= bbox.max_y {
+            return Err(Error::InvalidOptions(
+                "Min of --bbox-wgs84 must be lower than max".to_owned(),
+            ));
+        }
+
+        if bbox.min_x < -180.0
+            || bbox.max_x > 180.0
+            || bbox.min_y < -max_lat
+            || bbox.max_y > max_lat
+        {
+            return Err(Error::InvalidOptions(format!(
+                "--bbox-wgs84 must be within longitudes -180 to 180 and latitudes -{max_lat:.6} to {max_lat:.6}"
+            )));
+        }
+
+        return project_bounds(
+            Some(&new_proj("EPSG:4326", "EPSG:3857")?),
+            bbox,
+            "--bbox-wgs84",
+        );
+    }
+
+    options.bbox_3857.ok_or_else(|| {
+        Error::InvalidOptions("--bbox, --bbox-wgs84 or --tiles is required".to_owned())
+    })
+}
+
+/// Bounds of the file transformed to EPSG:3857
+pub fn project_bounds(proj: Option<&Proj>, bounds: BBox, path: &str) -> Result<BBox, Error> {
+    let Some(proj) = proj else {
+        return Ok(bounds);
+    };
+
+    proj.transform_bounds(bounds.min_x, bounds.min_y, bounds.max_x, bounds.max_y, 11)
+        .map(BBox::from)
+        .map_err(|source| Error::Proj {
+            context: format!("transforming bounds of {path}"),
+            source: source.into(),
+        })
+}
+
+/// Bbox transformed from EPSG:3857 to the projection
+fn unproject_bbox(options: &Options, source_projection: &str) -> Result<BBox, Error> {
+    new_proj("EPSG:3857", source_projection)?
+        .transform_bounds(
+            options.bbox.min_x,
+            options.bbox.min_y,
+            options.bbox.max_x,
+            options.bbox.max_y,
+            11,
         )
+        .map(BBox::from)
+        .map_err(|source| Error::Proj {
+            context: format!("transforming bbox to {source_projection}"),
+            source: source.into(),
+        })
+}
+
+/// Matches the name by the pattern with `*` for any characters and `?` for a single character
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<_> = pattern.chars().collect();
+
+    let name: Vec<_> = name.chars().collect();
+
+    // matched[j] - whether the pattern so far matches the first j characters of the name
+    let mut matched = vec![false; name.len() + 1];
+
+    matched[0] = true;
+
+    for p in pattern {
+        let previous = matched.clone();
+
+        matched[0] = p == '*' && previous[0];
+
+        for j in 1..=name.len() {
+            matched[j] = match p {
+                '*' => previous[j] || matched[j - 1],
+                '?' => previous[j - 1],
+                p => previous[j - 1] && p == name[j - 1],
+            };
+        }
+    }
+
+    matched[name.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::progress::{ProgressHook, ProgressReporter, RasterizeReport, ReadReport};
+    use las::{Builder, Transform, Vector, Vlr, Writer, point::Format};
+    use std::{
+        path::PathBuf,
+        sync::{Arc, Mutex},
+    };
+
+    /// Empty directory of the test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("laz2dem-{}-{name}", std::process::id()));
+
+        let _ = fs::remove_dir_all(&dir);
+
+        fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    /// Writes the points of x, y, z and class to a LAZ file of the LAS 1.4 point format 6 with the VLRs
+    fn write_laz(path: &Path, points: &[(f64, f64, f64, u8)], vlrs: Vec<Vlr>) {
+        let mut builder = Builder::from((1, 4));
+
+        builder.point_format = Format::new(6).unwrap();
+
+        builder.vlrs = vlrs;
+
+        // below a centimeter around the first point, also in degrees
+        let transform = |offset| Transform {
+            scale: 1e-7,
+            offset,
+        };
+
+        builder.transforms = Vector {
+            x: transform(points[0].0),
+            y: transform(points[0].1),
+            z: transform(0.0),
+        };
+
+        let mut writer = Writer::from_path(path, builder.into_header().unwrap()).unwrap();
+
+        for &(x, y, z, class) in points {
+            writer
+                .write_point(Point {
+                    x,
+                    y,
+                    z,
+                    classification: Classification::new(class).unwrap(),
+                    return_number: 1,
+                    number_of_returns: 1,
+                    gps_time: Some(0.0),
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+
+        writer.close().unwrap();
+    }
+
+    /// Deterministic uniform random numbers of 0.0 - 1.0
+    fn random() -> impl FnMut() -> f64 {
+        let mut seed = 1_u64;
+
+        move || {
+            seed = seed.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1);
+
+            (seed >> 11) as f64 / (1_u64 << 53) as f64
+        }
+    }
+
+    /// Options of the JSON object members reading the supertile of the EPSG:3857 position
+    fn options(members: &str, (x, y): (f64, f64)) -> (Options, TileMeta) {
+        let mut options = Options::from_json(&format!(
+            r#"{{"shadings": "igor-slope,000000FF", "format": "png", "zoom_level": 16, {members}}}"#
+        ))
         .unwrap();
 
-    let files: Vec<String> = rows.map(|row| row.unwrap()).collect();
+        let (tile_x, tile_y) = mercator_to_tile_coords(x, y, options.unit_zoom_level);
+
+        let tile_meta = tile_meta(
+            &options,
+            Tile {
+                zoom: options.unit_zoom_level,
+                x: tile_x,
+                y: tile_y,
+            },
+        );
+
+        options.bbox = tile_meta.bbox;
+
+        (options, tile_meta)
+    }
+
+    /// Reads the points of the directory to the supertile of the EPSG:3857 position
+    fn read_dir(members: &str, dir: &Path, position: (f64, f64)) -> TileMeta {
+        let (options, tile_meta) = options(members, position);
+
+        try_read_dir(&options, &tile_meta, dir, &Skipped::default()).unwrap();
+
+        tile_meta
+    }
+
+    fn try_read_dir(
+        options: &Options,
+        tile_meta: &TileMeta,
+        dir: &Path,
+        skipped: &Skipped,
+    ) -> Result<(), Error> {
+        let files = dir_files(options, dir, skipped)?;
+
+        read_points(
+            options,
+            slice::from_ref(tile_meta),
+            &files.iter().collect::<Vec<_>>(),
+            skipped,
+            &AtomicBool::new(false),
+        )
+    }
+
+    #[test]
+    fn files_are_read_by_at_most_the_read_threads() {
+        for threads in [1, 3] {
+            let active = AtomicUsize::new(0);
+
+            let most = AtomicUsize::new(0);
+
+            let taken = in_threads(
+                &[(); 12],
+                threads,
+                &AtomicBool::new(false),
+                |_: &mut (), _| {
+                    most.fetch_max(active.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+
+                    thread::sleep(Duration::from_millis(20));
+
+                    active.fetch_sub(1, Ordering::SeqCst);
+
+                    Ok(())
+                },
+            )
+            .unwrap();
+
+            assert_eq!(most.into_inner(), threads);
+
+            // every thread takes one past the last
+            assert_eq!(taken, 12 + threads);
+        }
+
+        let read = AtomicUsize::new(0);
+
+        let failed = in_threads(&[(); 12], 3, &AtomicBool::new(false), |_: &mut (), _| {
+            read.fetch_add(1, Ordering::SeqCst);
+
+            Err(Error::Cancelled)
+        });
+
+        assert!(matches!(failed, Err(Error::Cancelled)));
+
+        // the threads stop at the first error
+        assert!(read.into_inner() <= 3);
+    }
+
+    #[test]
+    fn classes_are_included_and_excluded() {
+        let dir = test_dir("classes");
+
+        let (x, y) = (1_903_000.0, 6_133_000.0);
+
+        // ground, high vegetation, building, water and a user definable class of LAS 1.4
+        let classes = [2, 5, 6, 9, 64];
+
+        write_laz(
+            &dir.join("mixed.laz"),
+            &classes.map(|class| (x, y + f64::from(class), f64::from(class), class)),
+            Vec::new(),
+        );
+
+        let read_classes = |members: &str| {
+            let tile_meta = read_dir(
+                &format!(r#""source_projection": "EPSG:3857", {members}"#),
+                &dir,
+                (x, y),
+            );
+
+            let mut classes: Vec<_> = tile_meta
+                .points
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|point| point.height as u8)
+                .collect();
+
+            classes.sort_unstable();
+
+            classes
+        };
+
+        assert_eq!(read_classes(r#""surface": "dtm""#), [2]);
+        assert_eq!(read_classes(r#""include_classes": "2,9""#), [2, 9]);
+        assert_eq!(read_classes(r#""include_classes": "64""#), [64]);
+        assert_eq!(read_classes(r#""include_classes": """#), [2, 5, 6, 9, 64]);
+        assert_eq!(
+            read_classes(r#""surface": "dsm", "exclude_classes": "5,6""#),
+            [2, 9, 64]
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// GeoTIFF keys VLR of the single key of the EPSG code
+    fn geo_keys_vlr(key: u16, code: u16) -> Vlr {
+        Vlr {
+            user_id: "LASF_Projection".to_owned(),
+            record_id: 34735,
+            description: String::new(),
+            data: [1, 1, 0, 1, key, 0, 1, code]
+                .into_iter()
+                .flat_map(u16::to_le_bytes)
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn crs_is_detected_from_the_geotiff_keys_or_the_wkt() {
+        let dir = test_dir("crs");
+
+        let wkt = r#"PROJCS["ETRS89 / UTM zone 33N",AUTHORITY["EPSG","25833"]]"#;
+
+        let wkt_vlr = Vlr {
+            user_id: "LASF_Projection".to_owned(),
+            record_id: 2112,
+            description: String::new(),
+            data: format!("{wkt}\0").into_bytes(),
+        };
+
+        let point = [(500_000.0, 5_330_000.0, 100.0, 2)];
+
+        for (name, vlrs, crs) in [
+            (
+                "keys.laz",
+                vec![geo_keys_vlr(3072, 25833)],
+                Some("EPSG:25833"),
+            ),
+            (
+                "wkt.laz",
+                vec![wkt_vlr, geo_keys_vlr(3072, 25833)],
+                Some(wkt),
+            ),
+            ("user-defined.laz", vec![geo_keys_vlr(3072, 32767)], None),
+            ("none.laz", Vec::new(), None),
+        ] {
+            let path = dir.join(name);
+
+            write_laz(&path, &point, vlrs);
+
+            let reader = Reader::from_path(&path).unwrap();
+
+            assert_eq!(detect_crs(reader.header()).as_deref(), crs, "{name}");
+        }
 
-    println!("Reading {} files", files.len());
+        fs::remove_dir_all(dir).unwrap();
+    }
 
-    files.par_iter().for_each_init(
-        || {
-            options.source_projection.as_ref().map(|source_projection| {
-                Proj::new_known_crs("EPSG:3857", source_projection, None)
-                    .expect("Failed to create PROJ transformation")
+    #[test]
+    fn files_without_crs_are_skipped_unless_the_source_projection_is_set() {
+        let dir = test_dir("no-crs");
+
+        let (x, y) = (1_903_000.0, 6_133_000.0);
+
+        write_laz(&dir.join("none.laz"), &[(x, y, 100.0, 2)], Vec::new());
+
+        let (options, _) = self::options(r#""surface": "dtm""#, (x, y));
+
+        assert!(
+            dir_files(&options, &dir, &Skipped::default())
+                .unwrap()
+                .is_empty()
+        );
+
+        let (options, _) = self::options(r#""source_projection": "EPSG:3857""#, (x, y));
+
+        let files = dir_files(&options, &dir, &Skipped::default()).unwrap();
+
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].source_projection, None);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn points_are_projected_from_the_crs_of_the_file() {
+        let dir = test_dir("projection");
+
+        // Bratislava in WGS 84 of the GeographicTypeGeoKey
+        write_laz(
+            &dir.join("wgs84.laz"),
+            &[(17.1, 48.15, 140.0, 2)],
+            vec![geo_keys_vlr(2048, 4326)],
+        );
+
+        let (x, y) = (1_903_563.29, 6_131_845.87);
+
+        let tile_meta = read_dir(r#""surface": "dtm""#, &dir, (x, y));
+
+        let points = tile_meta.points.lock().unwrap();
+
+        assert_eq!(points.len(), 1);
+        assert!((points[0].position.x - x).abs() < 0.05);
+        assert!((points[0].position.y - y).abs() < 0.05);
+        assert_eq!(points[0].height, 140.0);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn points_are_rejected_in_the_projection_of_the_file_before_the_projection() {
+        let dir = test_dir("rejected");
+
+        // inside, outside the bbox in WGS 84 and of a class not read
+        write_laz(
+            &dir.join("wgs84.laz"),
+            &[
+                (17.1, 48.15, 140.0, 2),
+                (17.5, 48.15, 140.0, 2),
+                (17.1, 48.15, 150.0, 5),
+            ],
+            vec![geo_keys_vlr(2048, 4326)],
+        );
+
+        let (options, tile_meta) = options(
+            r#""source_projection": "EPSG:3857""#,
+            (1_903_563.29, 6_131_845.87),
+        );
+
+        let (max_x, y) = (options.bbox.max_x, options.bbox.min_y + 100.0);
+
+        // inside and outside the bbox in EPSG:3857 of the source projection
+        write_laz(
+            &dir.join("mercator.laz"),
+            &[(max_x - 10.0, y, 160.0, 2), (max_x + 10.0, y, 170.0, 2)],
+            Vec::new(),
+        );
+
+        let skipped = Skipped::default();
+
+        try_read_dir(&options, &tile_meta, &dir, &skipped).unwrap();
+
+        let mut heights: Vec<_> = tile_meta
+            .points
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|point| point.height)
+            .collect();
+
+        heights.sort_by(f64::total_cmp);
+
+        assert_eq!(heights, [140.0, 160.0]);
+
+        assert_eq!(
+            *skipped.rejected.lock().unwrap(),
+            Rejected {
+                outside_unprojected: 1,
+                by_class: 1,
+                outside: 1,
+                ..Rejected::default()
+            }
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn index_query_of_the_rtree_gives_the_files_of_the_full_scan() {
+        let dir = test_dir("rtree");
+
+        let path = dir.join("index.sqlite");
+
+        let conn = Connection::open(&path).unwrap();
+
+        conn.execute(
+            "CREATE TABLE laz_index (file TEXT, min_x REAL, max_x REAL, min_y REAL, max_y REAL, crs TEXT)",
+            (),
+        )
+        .unwrap();
+
+        let (x, y) = (1_903_000.0, 6_133_000.0);
+
+        let mut random = random();
+
+        // 300 files of random sizes up to 400 m within 4 km
+        for i in 0..300 {
+            let (min_x, min_y) = (
+                x - 2000.0 + random() * 4000.0,
+                y - 2000.0 + random() * 4000.0,
+            );
+
+            conn.execute(
+                "INSERT INTO laz_index VALUES (?1, ?2, ?3, ?4, ?5, 'EPSG:3857')",
+                (
+                    format!("{i}.laz"),
+                    min_x,
+                    min_x + random() * 400.0,
+                    min_y,
+                    min_y + random() * 400.0,
+                ),
+            )
+            .unwrap();
+        }
+
+        let (options, _) = self::options(r#""surface": "dtm""#, (x, y));
+
+        let file_set = || {
+            let mut files: Vec<_> = index_files(&options, &path)
+                .unwrap()
+                .into_iter()
+                .map(|file| file.path)
+                .collect();
+
+            files.sort();
+
+            files
+        };
+
+        let scanned = file_set();
+
+        conn.execute(
+            "CREATE VIRTUAL TABLE laz_index_rtree USING rtree(id, min_x, max_x, min_y, max_y)",
+            (),
+        )
+        .unwrap();
+
+        conn.execute(
+            "INSERT INTO laz_index_rtree SELECT rowid, min_x, max_x, min_y, max_y FROM laz_index",
+            (),
+        )
+        .unwrap();
+
+        assert!(!scanned.is_empty() && scanned.len() < 300);
+        assert_eq!(file_set(), scanned);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn tiles_of_points_are_the_buffered_tiles_containing_them() {
+        let (options, _) = self::options(r#""surface": "dtm""#, (1_903_000.0, 6_133_000.0));
+
+        let zoom = options.unit_zoom_level;
+
+        let (tile_x, tile_y) = mercator_to_tile_coords(1_903_000.0, 6_133_000.0, zoom);
+
+        // 4 x 4 tiles without the corner one
+        let tile_metas: Vec<_> = (0..16)
+            .skip(1)
+            .map(|i| {
+                tile_meta(
+                    &options,
+                    Tile {
+                        zoom,
+                        x: tile_x + i % 4,
+                        y: tile_y + i / 4,
+                    },
+                )
             })
-        },
-        |proj, file| {
-            println!("READ {file}");
+            .collect();
 
-            let mut reader = Reader::from_path(file).unwrap();
+        let tile_indices: HashMap<_, _> = tile_metas
+            .iter()
+            .enumerate()
+            .map(|(i, tile_meta)| ((tile_meta.tile.x, tile_meta.tile.y), i))
+            .collect();
 
-            for point in reader.points() {
-                let point = point.unwrap();
+        let buffer_m = options.buffer as f64 / options.pixels_per_meter();
 
-                if point.classification != Classification::Ground {
-                    continue;
-                }
+        let extent = tile_metas
+            .iter()
+            .map(|tile_meta| tile_meta.bbox)
+            .reduce(|a, b| {
+                BBox::new(
+                    a.min_x.min(b.min_x),
+                    a.min_y.min(b.min_y),
+                    a.max_x.max(b.max_x),
+                    a.max_y.max(b.max_y),
+                )
+            })
+            .unwrap();
 
-                if let Some(bbox_unprojected) = bbox_unprojected {
-                    if !bbox_unprojected.contains(point.x, point.y) {
-                        continue;
-                    }
-                }
+        let mut random = random();
 
-                let (x, y) = proj.as_ref().map_or_else(
-                    || (point.x, point.y),
-                    |proj| proj.convert((point.x, point.y)).unwrap(),
-                );
+        for _ in 0..10_000 {
+            let x = extent.min_x - 10.0 + random() * (extent.width() + 20.0);
+            let y = extent.min_y - 10.0 + random() * (extent.height() + 20.0);
 
-                if !options.bbox.contains(x, y) {
-                    continue;
-                }
+            let mut tiles: Vec<_> =
+                point_tiles(&tile_metas, &tile_indices, zoom, buffer_m, x, y).collect();
 
-                for (i, tile_meta) in tile_metas.iter().enumerate() {
-                    if !tile_meta.bbox.contains(x, y) {
-                        continue;
-                    }
+            tiles.sort_unstable();
 
-                    tile_metas
-                        .get(i)
-                        .unwrap()
-                        .points
-                        .lock()
-                        .unwrap()
-                        .push(PointWithHeight {
-                            position: Point2::new(x, y),
-                            height: point.z,
-                        });
-                }
+            let brute_force: Vec<_> = (0..tile_metas.len())
+                .filter(|&i| tile_metas[i].bbox.contains(x, y))
+                .collect();
+
+            assert_eq!(tiles, brute_force, "{x}, {y}");
+        }
+    }
+
+    #[test]
+    fn unreadable_files_are_skipped_unless_strict() {
+        let dir = test_dir("unreadable");
+
+        let (x, y) = (1_903_000.0, 6_133_000.0);
+
+        let members = r#""source_projection": "EPSG:3857""#;
+
+        write_laz(&dir.join("good.laz"), &[(x, y, 100.0, 2)], Vec::new());
+
+        let points: Vec<_> = (0..100_000)
+            .map(|i| {
+                (
+                    x + f64::from(i % 100),
+                    y + f64::from(i / 100) / 10.0,
+                    100.0,
+                    2,
+                )
+            })
+            .collect();
+
+        write_laz(&dir.join("truncated.laz"), &points, Vec::new());
+
+        let truncated = fs::read(dir.join("truncated.laz")).unwrap();
+
+        fs::write(dir.join("truncated.laz"), &truncated[..truncated.len() / 2]).unwrap();
+
+        fs::write(dir.join("corrupt.laz"), b"not a LAZ file").unwrap();
+
+        let (options, tile_meta) = self::options(members, (x, y));
+
+        let skipped = Skipped::default();
+
+        try_read_dir(&options, &tile_meta, &dir, &skipped).unwrap();
+
+        assert_eq!(skipped.files.load(Ordering::Relaxed), 2);
+        assert!(
+            tile_meta
+                .points
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|point| point.position == Point2::new(x, y))
+        );
+
+        let (options, tile_meta) = self::options(&format!(r#"{members}, "strict": true"#), (x, y));
+
+        assert!(try_read_dir(&options, &tile_meta, &dir, &Skipped::default()).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn missing_index_db_is_an_error_of_its_path() {
+        let (options, _) = self::options(r#""surface": "dtm""#, (1_903_000.0, 6_133_000.0));
+
+        let path = test_dir("missing-db").join("missing.sqlite");
+
+        let error = index_files(&options, &path).err().unwrap();
+
+        assert!(
+            matches!(&error, Error::Sqlite { path: error_path, .. } if *error_path == path.display().to_string())
+        );
+        assert!(error.to_string().contains("missing.sqlite"), "{error}");
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn points_failing_to_project_are_skipped() {
+        let dir = test_dir("unprojectable");
+
+        // a stray point beyond the pole
+        write_laz(
+            &dir.join("wgs84.laz"),
+            &[(17.1, 48.15, 140.0, 2), (17.1, 95.0, 140.0, 2)],
+            vec![geo_keys_vlr(2048, 4326)],
+        );
+
+        let (options, tile_meta) =
+            self::options(r#""surface": "dtm""#, (1_903_563.29, 6_131_845.87));
+
+        // not filtered out by the bbox in the projection of the file before projecting
+        let file = SourceFile {
+            path: dir.join("wgs84.laz").to_string_lossy().to_string(),
+            source_projection: Some("EPSG:4326".to_owned()),
+            bbox_unprojected: None,
+            bounds: options.bbox,
+            point_count: Some(2),
+        };
+
+        read_points(
+            &options,
+            slice::from_ref(&tile_meta),
+            &[&file],
+            &Skipped::default(),
+            &AtomicBool::new(false),
+        )
+        .unwrap();
+
+        assert_eq!(tile_meta.points.lock().unwrap().len(), 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn read_progress_is_reported_to_the_reporter_of_the_options() {
+        #[derive(Default)]
+        struct Recorder(Mutex<Vec<ReadReport>>);
+
+        impl ProgressReporter for Recorder {
+            fn read(&self, report: &ReadReport) {
+                self.0.lock().unwrap().push(report.clone());
             }
 
-            println!("DONE {file}");
-        },
-    );
+            fn rasterize(&self, _: &RasterizeReport) {}
+        }
+
+        let dir = test_dir("progress");
+
+        let (x, y) = (1_903_000.0, 6_133_000.0);
+
+        write_laz(&dir.join("a.laz"), &[(x, y, 100.0, 2)], Vec::new());
+
+        write_laz(&dir.join("b.laz"), &[(x, y, 100.0, 2); 3], Vec::new());
+
+        let (mut options, tile_meta) = self::options(r#""source_projection": "EPSG:3857""#, (x, y));
+
+        let recorder = Arc::new(Recorder::default());
+
+        options.progress = Some(ProgressHook(recorder.clone()));
+
+        try_read_dir(&options, &tile_meta, &dir, &Skipped::default()).unwrap();
+
+        let reports = recorder.0.lock().unwrap();
+
+        assert_eq!(reports.len(), 2);
+        assert_eq!((reports[1].files, reports[1].total_files), (2, 2));
+        assert_eq!(reports[1].fraction, 1.0);
+        // weighted by the point counts of the files read in any order
+        assert!([0.25, 0.75].contains(&reports[0].fraction));
 
-    tile_metas
+        fs::remove_dir_all(dir).unwrap();
+    }
 }
@@ -0,0 +1,204 @@
+use crate::{error::Error, options::Options};
+use log::{debug, warn};
+use std::{
+    env,
+    ffi::OsStr,
+    fs::{self, File},
+    io,
+    path::PathBuf,
+    sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::SystemTime,
+};
+
+/// Serializes the eviction of the cache
+static EVICTION: Mutex<()> = Mutex::new(());
+
+/// Distinguishes the partial downloads of the threads
+static DOWNLOADS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn is_url(file: &str) -> bool {
+    file.starts_with("http://") || file.starts_with("https://")
+}
+
+/// Local path of the file, downloaded whole to `--cache-dir` if it is an URL.
+/// The least recently used files are evicted from the cache once it exceeds `--cache-size`.
+pub fn local_path(options: &Options, file: &str) -> Result<PathBuf, Error> {
+    if !is_url(file) {
+        return Ok(PathBuf::from(file));
+    }
+
+    let io_error = |source| Error::Io {
+        path: file.to_owned(),
+        source,
+    };
+
+    let dir = options
+        .cache_dir
+        .clone()
+        .unwrap_or_else(|| env::temp_dir().join("laz2dem-cache"));
+
+    fs::create_dir_all(&dir).map_err(io_error)?;
+
+    let name = file.rsplit('/').next().unwrap_or_default();
+
+    let path = dir.join(format!("{:016x}-{name}", fnv1a(file)));
+
+    if path.exists() {
+        // the modification time orders the eviction
+        File::options()
+            .append(true)
+            .open(&path)
+            .and_then(|cached| cached.set_modified(SystemTime::now()))
+            .map_err(io_error)?;
+
+        debug!("CACHED {file}");
+
+        return Ok(path);
+    }
+
+    let mut temp_path = path.as_os_str().to_owned();
+
+    temp_path.push(format!(
+        ".{}-{}.part",
+        std::process::id(),
+        DOWNLOADS.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let mut attempt = 0;
+
+    loop {
+        debug!("FETCH {file}");
+
+        let error = match download(file, &temp_path) {
+            Ok(()) => break,
+            Err(error) => error,
+        };
+
+        if attempt == options.fetch_retries {
+            let _ = fs::remove_file(&temp_path);
+
+            return Err(io_error(error));
+        }
+
+        attempt += 1;
+
+        warn!(
+            "{file}: {error}; retrying ({attempt} of {})",
+            options.fetch_retries
+        );
+    }
+
+    fs::rename(&temp_path, &path).map_err(io_error)?;
+
+    evict(options, &dir, &path).map_err(io_error)?;
+
+    Ok(path)
+}
+
+/// Downloads the file of the URL to the path, following the redirects
+fn download(url: &str, path: &OsStr) -> io::Result<()> {
+    let response = ureq::get(url).call().map_err(io::Error::other)?;
+
+    io::copy(&mut response.into_reader(), &mut File::create(path)?)?;
+
+    Ok(())
+}
+
+/// Removes the least recently used files but the kept one until the cache fits `--cache-size`
+fn evict(options: &Options, dir: &PathBuf, keep: &PathBuf) -> io::Result<()> {
+    let _eviction = EVICTION.lock().unwrap();
+
+    let mut files = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+
+        let metadata = entry.metadata()?;
+
+        // partial downloads of the other threads
+        if metadata.is_file() && entry.path().extension().is_none_or(|ext| ext != "part") {
+            files.push((metadata.modified()?, metadata.len(), entry.path()));
+        }
+    }
+
+    let mut size: u64 = files.iter().map(|(_, len, _)| len).sum();
+
+    files.sort_unstable();
+
+    for (_, len, path) in files {
+        if size <= options.cache_size * 1024 * 1024 {
+            break;
+        }
+
+        if &path != keep {
+            debug!("EVICT {}", path.display());
+
+            fs::remove_file(&path)?;
+
+            size -= len;
+        }
+    }
+
+    Ok(())
+}
+
+/// Stable hash of the URL naming the cached file
+fn fnv1a(string: &str) -> u64 {
+    string.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    #[test]
+    fn files_of_urls_are_downloaded_once_to_the_cache() {
+        let cache_dir = env::temp_dir().join(format!("laz2dem-{}-remote", std::process::id()));
+
+        let _ = fs::remove_dir_all(&cache_dir);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let url = format!("http://{}/points.laz", listener.local_addr().unwrap());
+
+        // a single response, the second request is not sent
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+
+            let mut request = [0; 1024];
+
+            let _ = stream.read(&mut request).unwrap();
+
+            stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 6\r\nConnection: close\r\n\r\npoints",
+                )
+                .unwrap();
+        });
+
+        let options = Options {
+            cache_dir: Some(cache_dir.clone()),
+            ..Options::default()
+        };
+
+        let path = local_path(&options, &url).unwrap();
+
+        server.join().unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), b"points");
+
+        assert_eq!(local_path(&options, &url).unwrap(), path);
+
+        fs::remove_dir_all(cache_dir).unwrap();
+    }
+}
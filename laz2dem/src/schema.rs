@@ -1,4 +1,4 @@
-use rusqlite::{Connection, Error};
+use rusqlite::{Connection, Error, OptionalExtension};
 
 pub fn create_schema(conn: &Connection, meta: &[(&str, &str)]) -> Result<(), Error> {
     conn.execute(
@@ -33,3 +33,22 @@ pub fn create_schema(conn: &Connection, meta: &[(&str, &str)]) -> Result<(), Err
 
     Ok(())
 }
+
+pub fn update_metadata(conn: &Connection, meta: &[(&str, &str)]) -> Result<(), Error> {
+    let mut stmt = conn.prepare("INSERT OR REPLACE INTO metadata VALUES (?1, ?2)")?;
+
+    for item in meta {
+        stmt.execute(*item)?;
+    }
+
+    Ok(())
+}
+
+pub fn get_metadata(conn: &Connection, name: &str) -> Result<Option<String>, Error> {
+    conn.query_row(
+        "SELECT value FROM metadata WHERE name = ?1",
+        [name],
+        |row| row.get(0),
+    )
+    .optional()
+}
@@ -0,0 +1,240 @@
+//! Serde representations of the fields as on the command line: angles in degrees, colors as hex strings
+//! and values also as the strings of their flags
+
+use crate::shared_types::parse_color;
+use maptile::bbox::BBox;
+use serde::{
+    Deserialize, Deserializer, Serialize, Serializer,
+    de::{
+        self, Error, MapAccess, SeqAccess, Visitor,
+        value::{MapAccessDeserializer, SeqAccessDeserializer},
+    },
+};
+use std::{fmt::Display, marker::PhantomData, str::FromStr};
+
+/// Degrees of the radians, rounded to nanodegrees so that the degrees survive the round trip
+fn degrees(radians: f64) -> f64 {
+    (radians.to_degrees() * 1e9).round() / 1e9
+}
+
+fn color<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    let color = String::deserialize(deserializer)?;
+
+    parse_color(&color).ok_or_else(|| D::Error::custom(format!("invalid color {color}")))
+}
+
+/// Angle in radians as degrees
+pub mod angle {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(radians: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+        degrees(*radians).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+        f64::deserialize(deserializer).map(f64::to_radians)
+    }
+}
+
+/// Angles in radians as degrees
+pub mod angles {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(radians: &[f64], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(radians.iter().copied().map(degrees))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<f64>, D::Error> {
+        Vec::<f64>::deserialize(deserializer)
+            .map(|degrees| degrees.into_iter().map(f64::to_radians).collect())
+    }
+}
+
+/// Packed RGBA as `#RRGGBBAA`, deserialized from any color of `--shadings`
+pub mod rgba {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(rgba: &u32, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("#{rgba:08X}"))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+        color(deserializer)
+    }
+}
+
+/// RGBA bytes as `#RRGGBBAA`
+pub mod rgba_bytes {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(rgba: &[u8; 4], serializer: S) -> Result<S::Ok, S::Error> {
+        rgba::serialize(&u32::from_be_bytes(*rgba), serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 4], D::Error> {
+        color(deserializer).map(u32::to_be_bytes)
+    }
+}
+
+/// Elevation stops with RGB colors as `[elevation, "#RRGGBB"]`
+pub mod ramp {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(ramp: &[(f64, u32)], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(
+            ramp.iter()
+                .map(|(elevation, rgb)| (elevation, format!("#{rgb:06X}"))),
+        )
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<(f64, u32)>, D::Error> {
+        // the alpha of the shading color applies
+        stops(deserializer, |elevation| elevation, |rgba| rgba >> 8)
+    }
+}
+
+/// Slope bounds in radians with RGBA colors as `[degrees, "#RRGGBBAA"]`
+pub mod slope_classes {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        classes: &[(f64, u32)],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(
+            classes
+                .iter()
+                .map(|(slope, rgba)| (degrees(*slope), format!("#{rgba:08X}"))),
+        )
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<(f64, u32)>, D::Error> {
+        stops(deserializer, f64::to_radians, |rgba| rgba)
+    }
+}
+
+/// Stops sorted by their values
+fn stops<'de, D: Deserializer<'de>>(
+    deserializer: D,
+    value: fn(f64) -> f64,
+    color: fn(u32) -> u32,
+) -> Result<Vec<(f64, u32)>, D::Error> {
+    let mut stops = Vec::<(f64, String)>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(stop, string)| {
+            parse_color(&string)
+                .map(|rgba| (value(stop), color(rgba)))
+                .ok_or_else(|| D::Error::custom(format!("invalid color {string}")))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    stops.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    Ok(stops)
+}
+
+/// Optional bounding box as `[min_x, min_y, max_x, max_y]`
+pub mod bbox {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(bbox: &Option<BBox>, serializer: S) -> Result<S::Ok, S::Error> {
+        bbox.map(|bbox| [bbox.min_x, bbox.min_y, bbox.max_x, bbox.max_y])
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<BBox>, D::Error> {
+        Ok(Option::<[f64; 4]>::deserialize(deserializer)?
+            .map(|[min_x, min_y, max_x, max_y]| BBox::new(min_x, min_y, max_x, max_y)))
+    }
+}
+
+/// Value given as the string of its flag or as its fields
+struct Flag<T>(T);
+
+impl<'de, T> Deserialize<'de> for Flag<T>
+where
+    T: FromStr<Err: Display> + Deserialize<'de>,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct FlagVisitor<T>(PhantomData<T>);
+
+        impl<'de, T> Visitor<'de> for FlagVisitor<T>
+        where
+            T: FromStr<Err: Display> + Deserialize<'de>,
+        {
+            type Value = Flag<T>;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, "a string of the flag or an object")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Flag<T>, E> {
+                value.parse().map(Flag).map_err(E::custom)
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Flag<T>, A::Error> {
+                T::deserialize(MapAccessDeserializer::new(map)).map(Flag)
+            }
+        }
+
+        deserializer.deserialize_any(FlagVisitor(PhantomData))
+    }
+}
+
+/// Optional value given as the string of its flag or as its fields
+pub mod flag {
+    use super::*;
+
+    pub fn deserialize<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: FromStr<Err: Display> + Deserialize<'de>,
+    {
+        Ok(Option::<Flag<T>>::deserialize(deserializer)?.map(|flag| flag.0))
+    }
+}
+
+/// Values given as the strings of their flags or as their fields, a single one also without the array
+pub mod flags {
+    use super::*;
+
+    pub fn deserialize<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: FromStr<Err: Display> + Deserialize<'de>,
+    {
+        struct FlagsVisitor<T>(PhantomData<T>);
+
+        impl<'de, T> Visitor<'de> for FlagsVisitor<T>
+        where
+            T: FromStr<Err: Display> + Deserialize<'de>,
+        {
+            type Value = Vec<T>;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, "an array of the strings of the flag or of objects")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Vec<T>, E> {
+                value.parse().map(|value| vec![value]).map_err(E::custom)
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Vec<T>, A::Error> {
+                Ok(
+                    Vec::<Flag<T>>::deserialize(SeqAccessDeserializer::new(seq))?
+                        .into_iter()
+                        .map(|flag| flag.0)
+                        .collect(),
+                )
+            }
+        }
+
+        deserializer.deserialize_any(FlagsVisitor(PhantomData))
+    }
+}
@@ -0,0 +1,232 @@
+use crate::{
+    error::Error,
+    options::{Mode, Options},
+    rasterization::{encode_tile, interpolate_supertile, shade_supertile},
+    read::read_supertile,
+    tile_directory::extensions,
+};
+use image::{DynamicImage, imageops::FilterType};
+use log::{debug, info, warn};
+use maptile::tile::Tile;
+use std::{
+    collections::VecDeque,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex, OnceLock},
+    thread,
+};
+
+/// Supertile rendered once by the first request for it, `None` if empty
+type Entry = Arc<OnceLock<Result<Option<DynamicImage>, String>>>;
+
+/// Recently requested supertiles, the least recently used first
+struct Cache {
+    capacity: usize,
+    entries: VecDeque<(Tile, Entry)>,
+}
+
+impl Cache {
+    /// Entry of the supertile, a new one if it is not cached
+    fn get(&mut self, tile: Tile) -> Entry {
+        let entry = match self.entries.iter().position(|(cached, _)| *cached == tile) {
+            Some(index) => self.entries.remove(index).unwrap().1,
+            None => Entry::default(),
+        };
+
+        self.entries.push_back((tile, Arc::clone(&entry)));
+
+        while self.entries.len() > self.capacity {
+            self.entries.pop_front();
+        }
+
+        entry
+    }
+
+    /// Removes the entry of the failed rendering so that it is retried by the next request
+    fn remove(&mut self, entry: &Entry) {
+        self.entries
+            .retain(|(_, cached)| !Arc::ptr_eq(cached, entry));
+    }
+}
+
+/// Serves the tiles rendered on demand over HTTP at the address given as the output.
+/// Concurrent requests of the tiles of the same supertile wait for a single rendering.
+pub fn serve(options: &Options) -> Result<(), Error> {
+    let address = options.output.to_string_lossy();
+
+    let listener = TcpListener::bind(address.as_ref()).map_err(|source| Error::Listen {
+        address: address.to_string(),
+        source,
+    })?;
+
+    info!(
+        "Serving http://{address}/{{z}}/{{x}}/{{y}}.{} for zoom levels {} to {}",
+        extensions(options.format).0,
+        options.unit_zoom_level,
+        options.zoom_level
+    );
+
+    let cache = Mutex::new(Cache {
+        capacity: options.serve_cache_size,
+        entries: VecDeque::new(),
+    });
+
+    thread::scope(|scope| {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let cache = &cache;
+
+                    scope.spawn(move || handle(options, cache, stream));
+                }
+                Err(e) => warn!("Failed to accept connection: {e}"),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn handle(options: &Options, cache: &Mutex<Cache>, mut stream: TcpStream) {
+    let mut reader = BufReader::new(&stream);
+
+    let mut request_line = String::new();
+
+    if reader.read_line(&mut request_line).is_err() {
+        return;
+    }
+
+    // headers are not used
+    let mut line = String::new();
+
+    while reader.read_line(&mut line).is_ok_and(|length| length > 2) {
+        line.clear();
+    }
+
+    let (status, content_type, body) = match request_line.split_whitespace().collect::<Vec<_>>()[..]
+    {
+        ["GET", path, _] => match respond(options, cache, path) {
+            Ok(Some(body)) => ("200 OK", format!("image/{}", options.format), body),
+            Ok(None) => ("204 No Content", "text/plain".to_owned(), Vec::new()),
+            Err((status, message)) => (status, "text/plain".to_owned(), message.into_bytes()),
+        },
+        _ => (
+            "400 Bad Request",
+            "text/plain".to_owned(),
+            b"Only GET is supported".to_vec(),
+        ),
+    };
+
+    debug!("SERVE {} {status}", request_line.trim_end());
+
+    // the client may be gone
+    let _ = write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )
+    .and_then(|()| stream.write_all(&body));
+}
+
+/// Encoded tile of the `/{z}/{x}/{y}.{extension}` path, `None` if it is empty
+fn respond(
+    options: &Options,
+    cache: &Mutex<Cache>,
+    path: &str,
+) -> Result<Option<Vec<u8>>, (&'static str, String)> {
+    let not_found = || ("404 Not Found", format!("No tile {path}"));
+
+    let parts: Vec<_> = path.trim_start_matches('/').split('/').collect();
+
+    let [zoom, x, y] = parts[..] else {
+        return Err(not_found());
+    };
+
+    let (y, extension) = y.split_once('.').ok_or_else(not_found)?;
+
+    let (Ok(zoom), Ok(x), Ok(y)) = (zoom.parse::<u8>(), x.parse::<u32>(), y.parse::<u32>()) else {
+        return Err(not_found());
+    };
+
+    // lower zoom levels would require rendering of many supertiles
+    if extension != extensions(options.format).0
+        || zoom < options.unit_zoom_level
+        || zoom > options.zoom_level
+        || x >= 1 << zoom
+        || y >= 1 << zoom
+    {
+        return Err(not_found());
+    }
+
+    let tile = Tile { x, y, zoom };
+
+    let bounds = tile.bounds(options.tile_size);
+
+    if bounds.max_x < options.bbox.min_x
+        || bounds.min_x > options.bbox.max_x
+        || bounds.max_y < options.bbox.min_y
+        || bounds.min_y > options.bbox.max_y
+    {
+        return Err(not_found());
+    }
+
+    let offset = zoom - options.unit_zoom_level;
+
+    let supertile = Tile {
+        x: x >> offset,
+        y: y >> offset,
+        zoom: options.unit_zoom_level,
+    };
+
+    let entry = cache.lock().unwrap().get(supertile);
+
+    let rendered = entry.get_or_init(|| render(options, supertile).map_err(|e| e.to_string()));
+
+    let img = match rendered {
+        Ok(Some(img)) => img,
+        Ok(None) => return Ok(None),
+        Err(message) => {
+            cache.lock().unwrap().remove(&entry);
+
+            return Err(("500 Internal Server Error", message.clone()));
+        }
+    };
+
+    let tile_size = u32::from(options.tile_size);
+
+    // pixels of the tile in the supertile rendered at `--zoom-level`
+    let size = tile_size << (options.zoom_level - zoom);
+
+    let img = img.crop_imm(
+        options.buffer + (x - (supertile.x << offset)) * size,
+        options.buffer + (y - (supertile.y << offset)) * size,
+        size,
+        size,
+    );
+
+    let img = if size == tile_size {
+        img
+    } else {
+        img.resize_exact(
+            tile_size,
+            tile_size,
+            // values must not be mixed
+            match options.mode {
+                Mode::Slope | Mode::Aspect | Mode::Elevation => FilterType::Nearest,
+                _ => FilterType::Lanczos3,
+            },
+        )
+    };
+
+    encode_tile(options, &img)
+        .map(Some)
+        .map_err(|error| ("500 Internal Server Error", error.to_string()))
+}
+
+/// Reads the points of the supertile with the buffer and renders it
+fn render(options: &Options, supertile: Tile) -> Result<Option<DynamicImage>, Error> {
+    let tile_meta = read_supertile(options, supertile)?;
+
+    Ok(interpolate_supertile(options, &tile_meta)?
+        .map(|surface| shade_supertile(options, &surface)))
+}
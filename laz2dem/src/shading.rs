@@ -1,59 +1,245 @@
-use crate::shared_types::{
-    IgorShadingParams, ObliqueShadingParams, ObliqueSlopeShadingParams, Shading, ShadingMethod,
+use crate::{
+    filters::{for_each_row, unsharp_mask},
+    options::{DerivativeMethod, Options},
+    shared_types::{
+        BlendMode, CurvatureKind, CurvatureShadingParams, CustomShadingParams,
+        HypsometricShadingParams, IgorShadingParams, LocalReliefShadingParams,
+        MultidirectionalShadingParams, ObliqueShadingParams, ObliqueSlopeShadingParams, Shading,
+        ShadingMethod, SlopeClassesShadingParams, TpiShadingParams, TriShadingParams,
+    },
+    terrain::{Grid, Rasters},
 };
-use image::{Rgba, RgbaImage};
+use image::{ImageBuffer, Luma, Pixel, Rgb, RgbImage, Rgba, RgbaImage};
 use std::f64::{
     self,
     consts::{FRAC_PI_2, PI, TAU},
 };
 
-pub fn compute_hillshade<F>(
-    elevation: &[f64],
+/// Built-in shadings with the options they are tuned for
+pub struct Preset {
+    pub name: &'static str,
+    /// Stack of the shadings in the `--shadings` syntax
+    pub shadings: &'static str,
+    pub contrast: f64,
+    pub brightness: f64,
+    pub z_factor: f64,
+}
+
+pub const PRESETS: [Preset; 4] = [
+    Preset {
+        name: "default",
+        shadings: "igor,5060FF60,135+igor,E0D000B0,315+igor-slope,000000FF",
+        contrast: 1.0,
+        brightness: 0.0,
+        z_factor: 1.0,
+    },
+    Preset {
+        name: "swiss",
+        shadings: "multidirectional,203050FF,45,270,315,360+oblique,FFF0B060,135,30,blend=screen",
+        contrast: 1.2,
+        brightness: 0.1,
+        z_factor: 1.5,
+    },
+    Preset {
+        name: "archaeology",
+        shadings: "sky-view-factor,000000FF,10+positive-openness,000000FF,3,blend=multiply",
+        contrast: 1.5,
+        brightness: 0.0,
+        z_factor: 2.0,
+    },
+    Preset {
+        name: "slope-only",
+        shadings: "igor-slope,000000FF",
+        contrast: 1.0,
+        brightness: 0.0,
+        z_factor: 1.0,
+    },
+];
+
+impl Preset {
+    pub fn get(name: &str) -> Option<&'static Self> {
+        PRESETS.iter().find(|preset| preset.name == name)
+    }
+
+    /// Arguments the preset expands to
+    pub fn args(&self) -> Vec<String> {
+        vec![
+            format!("--shadings={}", self.shadings),
+            format!("--contrast={}", self.contrast),
+            format!("--brightness={}", self.brightness),
+            format!("--z-factor={}", self.z_factor),
+        ]
+    }
+}
+
+/// Properties of the pixel being shaded
+pub struct PixelContext {
+    pub aspect: f64,
+    pub slope: f64,
+    /// See `SurfaceDerivatives`
+    pub profile_curvature: f64,
+    /// See `SurfaceDerivatives`
+    pub plan_curvature: f64,
+    /// Interpolated elevation in meters, NaN if there is no data
+    pub elevation: f64,
+    /// Index of the pixel in the elevation grid
+    pub index: usize,
+    /// Column of the pixel in the elevation grid
+    pub x: usize,
+    /// Row of the pixel in the elevation grid, 0 is the southmost one
+    pub y: usize,
+}
+
+/// Illumination of the pixel from its aspect and slope in radians, in 0.0 - 1.0 before the contrast and the brightness
+/// of the shading. Implemented by library users for `ShadingMethod::Custom`.
+///
+/// # Examples
+///
+/// ```
+/// use laz2dem::{
+///     shading::{IlluminationModel, PixelContext},
+///     shared_types::{CustomShadingParams, Shading, ShadingMethod, ShadingStackBuilder},
+/// };
+/// use std::{f64::consts::PI, sync::Arc};
+///
+/// /// Light from the north-west, stronger on the steep slopes
+/// struct SteepNorthWest;
+///
+/// impl IlluminationModel for SteepNorthWest {
+///     fn value(&self, aspect: f64, slope: f64, _pixel: &PixelContext) -> f64 {
+///         (0.5 + 0.5 * (aspect - 1.75 * PI).cos()) * slope.sin()
+///     }
+/// }
+///
+/// let shadings = ShadingStackBuilder::new()
+///     .shading(Shading {
+///         color: [0, 0, 0, 255],
+///         weight: 1.0,
+///         blend_mode: Default::default(),
+///         brightness: None,
+///         contrast: None,
+///         method: ShadingMethod::Custom(CustomShadingParams(Arc::new(SteepNorthWest))),
+///     })
+///     .build()?;
+///
+/// assert_eq!(shadings.0.len(), 1);
+/// # Ok::<(), laz2dem::shared_types::ShadingStackError>(())
+/// ```
+pub trait IlluminationModel: Send + Sync {
+    fn value(&self, aspect: f64, slope: f64, pixel: &PixelContext) -> f64;
+}
+
+impl IlluminationModel for IgorShadingParams {
+    fn value(&self, aspect: f64, slope: f64, _pixel: &PixelContext) -> f64 {
+        igor(
+            self,
+            aspect,
+            slope,
+            normalize_angle(self.azimuth - FRAC_PI_2, TAU),
+        )
+    }
+}
+
+impl IlluminationModel for ObliqueShadingParams {
+    fn value(&self, aspect: f64, slope: f64, _pixel: &PixelContext) -> f64 {
+        clamped_oblique(
+            self,
+            oblique(
+                aspect,
+                slope,
+                self.azimuth - FRAC_PI_2,
+                (FRAC_PI_2 - self.altitude).sin_cos(),
+            ),
+        )
+    }
+}
+
+impl IlluminationModel for ObliqueSlopeShadingParams {
+    fn value(&self, _aspect: f64, slope: f64, _pixel: &PixelContext) -> f64 {
+        oblique_slope(slope, (FRAC_PI_2 - self.altitude).sin_cos())
+    }
+}
+
+pub fn compute_hillshade<P, F>(
+    grid: &Grid,
     z_factor: f64,
-    rows: usize,
-    cols: usize,
-    compute_rgb: F,
-) -> RgbaImage
+    derivative_method: DerivativeMethod,
+    derivative_radius: usize,
+    compute_pixel: F,
+) -> ImageBuffer<P, Vec<P::Subpixel>>
 where
-    F: Fn(f64, f64) -> Rgba<u8>,
+    P: Pixel + Send,
+    P::Subpixel: Send,
+    F: Fn(&PixelContext) -> P + Sync,
 {
-    let mut hillshade = RgbaImage::new(cols as u32, rows as u32);
+    let Grid { rows, cols, .. } = *grid;
+
+    let mut hillshade = ImageBuffer::<P, _>::new(cols as u32, rows as u32);
+
+    let channels = P::CHANNEL_COUNT as usize;
 
-    for y in 1..rows - 1 {
-        for x in 1..cols - 1 {
-            let (slope, aspect) = compute_slope_and_aspect(elevation, z_factor, cols, x, y);
+    for_each_row(&mut hillshade, cols * channels, |(image_y, row)| {
+        // image rows go from north to south
+        let y = rows - 1 - image_y;
 
-            *hillshade.get_pixel_mut(x as u32, (rows - y) as u32) = compute_rgb(aspect, slope);
+        for x in 0..cols {
+            let SurfaceDerivatives {
+                slope,
+                aspect,
+                profile_curvature,
+                plan_curvature,
+            } = compute_derivatives(grid, z_factor, derivative_method, derivative_radius, x, y);
+
+            *P::from_slice_mut(&mut row[x * channels..(x + 1) * channels]) =
+                compute_pixel(&PixelContext {
+                    aspect,
+                    slope,
+                    profile_curvature,
+                    plan_curvature,
+                    elevation: grid.elevation(y * cols + x),
+                    index: y * cols + x,
+                    x,
+                    y,
+                });
         }
-    }
+    });
 
     hillshade
 }
 
-fn compute_slope_and_aspect(
-    elevation: &[f64],
+/// Surface derivatives of the pixel. Curvatures are in 1/m, positive for convex and negative for concave surface.
+pub struct SurfaceDerivatives {
+    pub slope: f64,
+    pub aspect: f64,
+    /// Curvature in the direction of the slope
+    pub profile_curvature: f64,
+    /// Curvature of the contour
+    pub plan_curvature: f64,
+}
+
+pub fn compute_derivatives(
+    grid: &Grid,
     z_factor: f64,
-    cols: usize,
+    derivative_method: DerivativeMethod,
+    derivative_radius: usize,
     x: usize,
     y: usize,
-) -> (f64, f64) {
-    let off = y * cols;
-
-    // Extract 3x3 window
-    let z1 = elevation[off - cols + x - 1];
-    let z2 = elevation[off - cols + x];
-    let z3 = elevation[off - cols + x + 1];
-    let z4 = elevation[off + x - 1];
-    let z6 = elevation[off + x + 1];
-    let z7 = elevation[off + cols + x - 1];
-    let z8 = elevation[off + cols + x];
-    let z9 = elevation[off + cols + x + 1];
-
-    // Compute raw derivatives (Horn method)
-    let dz_dx = (-z1 + z3 - 2.0 * z4 + 2.0 * z6 - z7 + z9) / 8.0;
-    let dz_dy = (-z1 - 2.0 * z2 - z3 + z7 + 2.0 * z8 + z9) / 8.0;
-
-    // Apply z-factor
+) -> SurfaceDerivatives {
+    let cell_size = grid.ground_cell_size(y);
+
+    let window = Window::new(grid, x, y);
+
+    // Compute raw derivatives
+    let (dz_dx, dz_dy) = match derivative_method {
+        DerivativeMethod::PlaneFit => plane_fit_derivatives(grid, derivative_radius, x, y),
+        _ => window.derivatives(derivative_method),
+    };
+
+    // Convert to meters per meter
+    let dz_dx = dz_dx / cell_size;
+    let dz_dy = dz_dy / cell_size;
+
+    // Apply z-factor (vertical exaggeration)
     let dz_dx = dz_dx * z_factor;
     let dz_dy = dz_dy * z_factor;
 
@@ -67,86 +253,809 @@ fn compute_slope_and_aspect(
         aspect += TAU;
     }
 
+    // including the pixel without elevation itself
     if aspect.is_nan() || slope.is_nan() {
         slope = 0.0;
         aspect = 0.0;
     }
 
-    (slope, aspect)
+    let (profile_curvature, plan_curvature) = {
+        let [r, s, t] = window
+            .second_derivatives()
+            .map(|derivative| derivative / (cell_size * cell_size) * z_factor);
+
+        let (p, q) = (dz_dx, dz_dy);
+
+        let gradient = p * p + q * q;
+
+        if gradient == 0.0 || gradient.is_nan() || r.is_nan() || s.is_nan() || t.is_nan() {
+            (0.0, 0.0)
+        } else {
+            (
+                -(p * p * r + 2.0 * p * q * s + q * q * t)
+                    / (gradient * (1.0 + gradient).powf(1.5)),
+                -(q * q * r - 2.0 * p * q * s + p * p * t) / (gradient * (1.0 + gradient).sqrt()),
+            )
+        }
+    };
+
+    SurfaceDerivatives {
+        slope,
+        aspect,
+        profile_curvature,
+        plan_curvature,
+    }
 }
 
-pub fn shade(
-    aspect: f64,
-    slope: f64,
-    shadings: &[Shading],
+/// 3x3 window around the pixel clamped to the grid; at the borders it gives one-sided differences.
+/// Missing neighbours are substituted with the center so that they do not spoil the derivatives.
+struct Window {
+    /// Elevations from the south-west to the north-east, row by row
+    z: [f64; 9],
+    /// Width of the window in pixels minus one; 2 if not narrowed by the edge of the grid
+    width: usize,
+    /// Height of the window in pixels minus one; 2 if not narrowed by the edge of the grid
+    height: usize,
+}
+
+impl Window {
+    fn new(grid: &Grid, x: usize, y: usize) -> Self {
+        let Grid { rows, cols, .. } = *grid;
+
+        let (left, right) = (x.saturating_sub(1), (x + 1).min(cols - 1));
+        let (below, above) = (y.saturating_sub(1), (y + 1).min(rows - 1));
+
+        let center = grid.elevation(y * cols + x);
+
+        let z = |x: usize, y: usize| {
+            let z = grid.elevation(y * cols + x);
+
+            if z.is_nan() { center } else { z }
+        };
+
+        Self {
+            z: [
+                z(left, below),
+                z(x, below),
+                z(right, below),
+                z(left, y),
+                center,
+                z(right, y),
+                z(left, above),
+                z(x, above),
+                z(right, above),
+            ],
+            width: right - left,
+            height: above - below,
+        }
+    }
+
+    /// Derivatives in meters per pixel
+    fn derivatives(&self, derivative_method: DerivativeMethod) -> (f64, f64) {
+        let [z1, z2, z3, z4, _, z6, z7, z8, z9] = self.z;
+
+        let (dz_dx, dz_dy) = match derivative_method {
+            DerivativeMethod::Horn => (
+                (-z1 + z3 - 2.0 * z4 + 2.0 * z6 - z7 + z9) / 8.0,
+                (-z1 - 2.0 * z2 - z3 + z7 + 2.0 * z8 + z9) / 8.0,
+            ),
+            DerivativeMethod::ZevenbergenThorne => ((z6 - z4) / 2.0, (z8 - z2) / 2.0),
+            DerivativeMethod::PlaneFit => unreachable!(),
+        };
+
+        // Scale for the window narrowed at the borders
+        (
+            dz_dx * 2.0 / self.width as f64,
+            dz_dy * 2.0 / self.height as f64,
+        )
+    }
+
+    /// Second derivatives d²z/dx², d²z/dxdy and d²z/dy² in meters per square pixel (Zevenbergen-Thorne);
+    /// 0.0 along the axes narrowed at the borders
+    fn second_derivatives(&self) -> [f64; 3] {
+        let [z1, z2, z3, z4, z5, z6, z7, z8, z9] = self.z;
+
+        let full_width = self.width == 2;
+        let full_height = self.height == 2;
+
+        [
+            if full_width { z4 + z6 - 2.0 * z5 } else { 0.0 },
+            if full_width && full_height {
+                (z1 - z3 - z7 + z9) / 4.0
+            } else {
+                0.0
+            },
+            if full_height { z2 + z8 - 2.0 * z5 } else { 0.0 },
+        ]
+    }
+}
+
+/// Derivatives in meters per pixel of the least-squares plane through the center fitted to the valid elevations
+/// in the square window of the radius; the window is cut by the edges of the grid
+fn plane_fit_derivatives(grid: &Grid, radius: usize, x: usize, y: usize) -> (f64, f64) {
+    let Grid { rows, cols, .. } = *grid;
+
+    let center = grid.elevation(y * cols + x);
+
+    let mut sums = [0.0; 5];
+
+    for wy in y.saturating_sub(radius)..(y + radius + 1).min(rows) {
+        for wx in x.saturating_sub(radius)..(x + radius + 1).min(cols) {
+            let z = grid.elevation(wy * cols + wx) - center;
+
+            if z.is_nan() {
+                continue;
+            }
+
+            let dx = wx as f64 - x as f64;
+            let dy = wy as f64 - y as f64;
+
+            for (sum, value) in sums
+                .iter_mut()
+                .zip([dx * dx, dx * dy, dy * dy, dx * z, dy * z])
+            {
+                *sum += value;
+            }
+        }
+    }
+
+    let [xx, xy, yy, xz, yz] = sums;
+
+    // solve the normal equations; NaN for a degenerate window
+    let determinant = xx * yy - xy * xy;
+
+    (
+        (xz * yy - yz * xy) / determinant,
+        (yz * xx - xz * xy) / determinant,
+    )
+}
+
+/// Shadings and the final adjustments with the parts not depending on the pixel computed once per supertile
+pub struct ShadingContext<'a> {
+    shadings: Vec<PreparedShading<'a>>,
     contrast: f64,
     brightness: f64,
-) -> Rgba<u8> {
-    let alphas: Vec<_> = shadings
-        .iter()
-        .map(|shading| {
-            let intensity = match &shading.method {
-                ShadingMethod::Igor(IgorShadingParams { azimuth }) => {
-                    let aspect_diff = difference_between_angles(aspect, azimuth - FRAC_PI_2, TAU);
+    gamma: f64,
+    linear_blend: bool,
+    aerial_perspective: f64,
+    /// Elevation range of the aerial perspective
+    elevation_range: Option<(f64, f64)>,
+    nodata_color: Rgba<u8>,
+}
+
+impl<'a> ShadingContext<'a> {
+    /// Context of the options; `elevation_range` is of the shaded grid and applies if `--elevation-range` is not set
+    pub fn new(options: &'a Options, elevation_range: Option<(f64, f64)>) -> Self {
+        Self {
+            shadings: options
+                .shadings
+                .0
+                .iter()
+                .map(|shading| PreparedShading::new(shading, options.linear_blend))
+                .collect(),
+            contrast: options.contrast,
+            brightness: options.brightness,
+            gamma: options.gamma,
+            linear_blend: options.linear_blend,
+            aerial_perspective: options.aerial_perspective,
+            elevation_range: options
+                .elevation_range
+                .map(|range| (range.min, range.max))
+                .or(elevation_range),
+            nodata_color: options.nodata_color.0,
+        }
+    }
+
+    /// Brightness including the aerial perspective
+    fn brightness(&self, pixel: &PixelContext) -> f64 {
+        match self.elevation_range {
+            Some((min, max)) if max > min && !pixel.elevation.is_nan() => {
+                self.brightness + self.aerial_perspective * (pixel.elevation - min) / (max - min)
+            }
+            _ => self.brightness,
+        }
+    }
+}
+
+struct PreparedShading<'a> {
+    shading: &'a Shading,
+    /// Channels (see `channels`) and alpha of the shading color if it does not depend on the pixel
+    color: Option<([f64; 3], f64)>,
+    /// Aspects of the slopes facing the lights of `igor`, normalized, `oblique` and `multidirectional`
+    light_aspects: Vec<f64>,
+    /// Sine and cosine of the zenith angle of the light of `oblique`, `oblique-slope` and `multidirectional`
+    zenith: (f64, f64),
+}
+
+impl<'a> PreparedShading<'a> {
+    fn new(shading: &'a Shading, linear_blend: bool) -> Self {
+        let color = match shading.method {
+            ShadingMethod::LocalRelief(_)
+            | ShadingMethod::Hypsometric(_)
+            | ShadingMethod::SlopeClasses(_) => None,
+            _ => Some((
+                channels(shading.color, linear_blend),
+                f64::from(shading.color[3]) / 255.0,
+            )),
+        };
+
+        let (light_aspects, altitude) = match &shading.method {
+            ShadingMethod::Igor(IgorShadingParams { azimuth, .. }) => {
+                (vec![normalize_angle(azimuth - FRAC_PI_2, TAU)], FRAC_PI_2)
+            }
+            ShadingMethod::Oblique(ObliqueShadingParams {
+                azimuth, altitude, ..
+            }) => (vec![azimuth - FRAC_PI_2], *altitude),
+            ShadingMethod::ObliqueSlope(ObliqueSlopeShadingParams { altitude }) => {
+                (Vec::new(), *altitude)
+            }
+            ShadingMethod::Multidirectional(MultidirectionalShadingParams {
+                azimuths,
+                altitude,
+            }) => (
+                azimuths.iter().map(|azimuth| azimuth - FRAC_PI_2).collect(),
+                *altitude,
+            ),
+            _ => (Vec::new(), FRAC_PI_2),
+        };
+
+        Self {
+            shading,
+            color,
+            light_aspects,
+            zenith: (FRAC_PI_2 - altitude).sin_cos(),
+        }
+    }
 
-                    let aspect_strength = 1.0 - aspect_diff / PI;
+    /// RGBA color of the shading at the pixel
+    fn color(&self, pixel: &PixelContext, i: usize, rasters: &Rasters) -> [u8; 4] {
+        let shading = self.shading;
 
-                    slope / FRAC_PI_2 * 2.0 * aspect_strength
+        match &shading.method {
+            ShadingMethod::LocalRelief(LocalReliefShadingParams {
+                range,
+                positive_color,
+                ..
+            }) => diverging_color(
+                u32::from_be_bytes(shading.color),
+                *positive_color,
+                rasters.get(i, pixel.index) / range,
+            ),
+            ShadingMethod::Hypsometric(HypsometricShadingParams { ramp }) => {
+                let [_, r, g, b] = ramp_color(ramp, pixel.elevation).to_be_bytes();
+
+                [r, g, b, shading.color[3]]
+            }
+            ShadingMethod::SlopeClasses(SlopeClassesShadingParams { classes }) => {
+                match classes.partition_point(|(lower_bound, _)| *lower_bound <= pixel.slope) {
+                    0 => [0; 4],
+                    i => classes[i - 1].1.to_be_bytes(),
                 }
-                ShadingMethod::Oblique(ObliqueShadingParams { azimuth, altitude }) => {
-                    let zenith = FRAC_PI_2 - altitude;
+            }
+            _ => shading.color,
+        }
+    }
+
+    /// Intensity of the shading at the pixel, remapped by the shading's own contrast and brightness if set
+    fn intensity(&self, pixel: &PixelContext, i: usize, rasters: &Rasters) -> f64 {
+        let &PixelContext { aspect, slope, .. } = pixel;
+
+        let shading = self.shading;
+
+        let intensity = match &shading.method {
+            // the built-in models of the constants computed once
+            ShadingMethod::Igor(params) => igor(params, aspect, slope, self.light_aspects[0]),
+            ShadingMethod::Oblique(params) => clamped_oblique(
+                params,
+                oblique(aspect, slope, self.light_aspects[0], self.zenith),
+            ),
+            ShadingMethod::ObliqueSlope(_) => oblique_slope(slope, self.zenith),
+            ShadingMethod::Custom(CustomShadingParams(model)) => model.value(aspect, slope, pixel),
+            _ => self.method_intensity(pixel, i, rasters),
+        };
+
+        if shading.contrast.is_none() && shading.brightness.is_none() {
+            return intensity;
+        }
+
+        let contrast = shading.contrast.unwrap_or(1.0);
+
+        // always clamped once remapped, also of `oblique` with `clamp=false`
+        (contrast * (intensity - 0.5) + 0.5 + shading.brightness.unwrap_or(0.0)).clamp(0.0, 1.0)
+    }
+
+    /// Intensity of the shading of a method without an illumination model
+    fn method_intensity(&self, pixel: &PixelContext, i: usize, rasters: &Rasters) -> f64 {
+        let &PixelContext { aspect, slope, .. } = pixel;
+
+        match &self.shading.method {
+            ShadingMethod::Igor(_)
+            | ShadingMethod::Oblique(_)
+            | ShadingMethod::ObliqueSlope(_)
+            | ShadingMethod::Custom(_) => unreachable!("shaded by the illumination model"),
+            ShadingMethod::Multidirectional(_) => {
+                // weight each illumination by sin^2 of the angle between the light and the aspect (MDOW)
+                let (sum, weights_sum) = self.light_aspects.iter().fold(
+                    (0.0, f64::MIN_POSITIVE),
+                    |(sum, weights_sum), light_aspect| {
+                        let weight = (light_aspect - aspect).sin().powi(2);
+
+                        (
+                            sum + weight * oblique(aspect, slope, *light_aspect, self.zenith),
+                            weights_sum + weight,
+                        )
+                    },
+                );
+
+                sum / weights_sum
+            }
+            ShadingMethod::IgorSlope => slope / FRAC_PI_2,
+            ShadingMethod::CastShadow(_)
+            | ShadingMethod::SkyViewFactor(_)
+            | ShadingMethod::Openness(_) => rasters.get(i, pixel.index),
+            ShadingMethod::LocalRelief(LocalReliefShadingParams { range, .. }) => {
+                let residual = rasters.get(i, pixel.index);
 
-                    zenith.cos() * slope.cos()
-                        + zenith.sin() * slope.sin() * (azimuth - FRAC_PI_2 - aspect).cos()
+                if residual.is_nan() {
+                    0.0
+                } else {
+                    (residual.abs() / range).min(1.0)
                 }
-                ShadingMethod::IgorSlope => slope / FRAC_PI_2,
-                ShadingMethod::ObliqueSlope(ObliqueSlopeShadingParams { altitude }) => {
-                    let zenith = FRAC_PI_2 - altitude;
+            }
+            ShadingMethod::Curvature(CurvatureShadingParams { kind, scale }) => {
+                let curvature = match kind {
+                    CurvatureKind::Profile => pixel.profile_curvature,
+                    CurvatureKind::Plan => pixel.plan_curvature,
+                };
 
-                    zenith.cos() * slope.cos() + zenith.sin() * slope.sin()
+                (0.5 - scale * curvature).clamp(0.0, 1.0)
+            }
+            ShadingMethod::Tri(TriShadingParams { min, max })
+            | ShadingMethod::Tpi(TpiShadingParams { min, max, .. }) => {
+                let value = rasters.get(i, pixel.index);
+
+                if value.is_nan() {
+                    0.0
+                } else {
+                    ((value - min) / (max - min)).clamp(0.0, 1.0)
+                }
+            }
+            ShadingMethod::SlopeClasses(_) => 1.0,
+            ShadingMethod::Hypsometric(_) => {
+                if pixel.elevation.is_nan() {
+                    0.0
+                } else {
+                    1.0
                 }
+            }
+        }
+    }
+}
+
+/// Image of `--mode hillshade` of the grid including the clarity, with the rasters of `--shadings`
+pub fn hillshade(options: &Options, grid: &Grid, rasters: &Rasters) -> RgbaImage {
+    let context = ShadingContext::new(
+        options,
+        options
+            .elevation_range
+            .is_none()
+            .then(|| grid.elevation_range())
+            .flatten(),
+    );
+
+    let mut img = compute_hillshade(
+        grid,
+        options.z_factor,
+        options.derivative_method,
+        options.derivative_radius,
+        |pixel| shade(pixel, &context, rasters),
+    );
+
+    // on the buffered image so that there are no seams
+    if options.clarity_amount > 0.0 {
+        unsharp_mask(&mut img, options.clarity_radius, options.clarity_amount);
+    }
+
+    img
+}
+
+/// Image composed on the opaque background color
+pub fn rgba_to_rgb(img: &RgbaImage, background: Rgb<u8>) -> RgbImage {
+    let (width, height) = img.dimensions();
+
+    let mut rgb_img = RgbImage::new(width, height);
+
+    for (x, y, &rgba) in img.enumerate_pixels() {
+        let mut base = background.to_rgba();
+
+        base.channels_mut()[3] = 255;
+
+        base.blend(&rgba);
+
+        rgb_img.put_pixel(x, y, base.to_rgb());
+    }
+
+    rgb_img
+}
+
+pub fn shade(pixel: &PixelContext, context: &ShadingContext, rasters: &Rasters) -> Rgba<u8> {
+    if pixel.elevation.is_nan() {
+        return context.nodata_color;
+    }
+
+    let linear_blend = context.linear_blend;
+
+    let mut group: Option<WeightedAverage> = None;
+
+    let mut composite: Option<Layer> = None;
+
+    // hypsometric tints under the other shadings with the blend mode of the last one
+    let mut base: Option<(Layer, BlendMode)> = None;
+
+    for (i, prepared) in context.shadings.iter().enumerate() {
+        let shading = prepared.shading;
+
+        let (channels, alpha) = prepared.color.unwrap_or_else(|| {
+            let color = prepared.color(pixel, i, rasters);
+
+            (channels(color, linear_blend), f64::from(color[3]) / 255.0)
+        });
+
+        let alpha = alpha * prepared.intensity(pixel, i, rasters) * shading.weight;
+
+        let layer = Layer {
+            color: channels.map(|channel| channel / 255.0),
+            alpha: alpha.clamp(0.0, 1.0),
+        };
+
+        if let ShadingMethod::Hypsometric(_) = shading.method {
+            let blend_mode = match shading.blend_mode {
+                BlendMode::WeightedAverage => BlendMode::Multiply,
+                blend_mode => blend_mode,
             };
 
-            let intensity = shading.contrast * (intensity - 0.5) + 0.5 + shading.brightness;
+            base = Some((
+                layer.blend(base.map(|(base, _)| base), BlendMode::WeightedAverage),
+                blend_mode,
+            ));
 
-            let alpha = (shading.color & 0xFF) as f64 / 255.0;
+            continue;
+        }
 
-            alpha * intensity
-        })
-        .collect();
+        if shading.blend_mode == BlendMode::WeightedAverage {
+            group.get_or_insert_default().add(alpha, channels);
 
-    let alphas_sum = f64::MIN_POSITIVE
-        + alphas
-            .iter()
-            .enumerate()
-            .map(|(i, alpha)| alpha * shadings[i].weight)
-            .sum::<f64>();
+            continue;
+        }
 
-    let compute_channel = |shift| {
-        let sum: f64 = alphas
-            .iter()
-            .enumerate()
-            .map(|(i, alpha)| {
-                alpha * shadings[i].weight * f64::from((shadings[i].color >> shift) & 0xFF_u32)
-                    / 255.0
-            })
-            .sum();
+        if let Some(group) = group.take() {
+            composite = Some(group.finish().blend(composite, BlendMode::WeightedAverage));
+        }
 
-        let value = contrast * ((sum / alphas_sum) - 0.5) + 0.5 + brightness;
+        composite = Some(layer.blend(composite, shading.blend_mode));
+    }
 
-        (value * 255.0).clamp(0.0, 255.0) as u8
+    if let Some(group) = group.take() {
+        composite = Some(group.finish().blend(composite, BlendMode::WeightedAverage));
+    }
+
+    if let Some((base, blend_mode)) = base {
+        composite = Some(match composite {
+            Some(composite) => composite.blend(Some(base), blend_mode),
+            None => base,
+        });
+    }
+
+    let composite = composite.unwrap_or_default();
+
+    let color = if linear_blend {
+        composite.color.map(linear_to_srgb)
+    } else {
+        composite.color
     };
 
-    let alpha = 1.0 - alphas.iter().map(|alpha| 1.0 - alpha).product::<f64>();
+    let contrast = context.contrast;
+
+    let brightness = context.brightness(pixel);
+
+    let compute_channel = |value: f64| {
+        let value = contrast * (value - 0.5) + 0.5 + brightness;
+
+        let value = value.clamp(0.0, 1.0).powf(1.0 / context.gamma);
+
+        (value * 255.0).clamp(0.0, 255.0) as u8
+    };
 
     Rgba([
-        compute_channel(24),
-        compute_channel(16),
-        compute_channel(8),
-        (alpha * 255.0).clamp(0.0, 255.0) as u8,
+        compute_channel(color[0]),
+        compute_channel(color[1]),
+        compute_channel(color[2]),
+        (composite.alpha * 255.0).clamp(0.0, 255.0) as u8,
+    ])
+}
+
+/// Illumination of the first shading scaled to the full range of 16 bits
+pub fn shade_grayscale(
+    pixel: &PixelContext,
+    context: &ShadingContext,
+    rasters: &Rasters,
+) -> Luma<u16> {
+    if pixel.elevation.is_nan() {
+        return Luma([0]);
+    }
+
+    let value = context.shadings[0].intensity(pixel, 0, rasters);
+
+    Luma([(value.clamp(0.0, 1.0) * f64::from(u16::MAX)).round() as u16])
+}
+
+/// Value of slope and aspect pixels without data
+pub const NODATA: u16 = u16::MAX;
+
+/// Slope in hundredths of degree; `NODATA` if there is no elevation
+pub fn shade_slope(pixel: &PixelContext) -> Luma<u16> {
+    if pixel.elevation.is_nan() {
+        return Luma([NODATA]);
+    }
+
+    Luma([(pixel.slope.to_degrees() * 100.0).round() as u16])
+}
+
+/// Aspect (downslope direction clockwise from north) in hundredths of degree;
+/// `NODATA` for flat pixels and if there is no elevation
+pub fn shade_aspect(pixel: &PixelContext) -> Luma<u16> {
+    if pixel.elevation.is_nan() || pixel.slope == 0.0 {
+        return Luma([NODATA]);
+    }
+
+    let aspect = normalize_angle(pixel.aspect + FRAC_PI_2, TAU).to_degrees();
+
+    Luma([((aspect * 100.0).round() as u16) % 36_000])
+}
+
+/// Canopy height colored from light yellow at 0 m to dark green at `max`; `nodata_color` if there is no elevation
+pub fn shade_canopy_height(pixel: &PixelContext, max: f64, nodata_color: Rgba<u8>) -> Rgba<u8> {
+    if pixel.elevation.is_nan() {
+        return nodata_color;
+    }
+
+    let [_, r, g, b] = ramp_color(
+        &[
+            (0.0, 0xFF_FF_CC),
+            (max / 2.0, 0x41_AB_5D),
+            (max, 0x00_44_1B),
+        ],
+        pixel.elevation,
+    )
+    .to_be_bytes();
+
+    Rgba([r, g, b, 255])
+}
+
+/// Unit surface normal encoded as `0.5 + 0.5 * n` in RGB with X pointing east, Y north and Z up.
+/// It is derived from the same slope and aspect as shadings, so the z-factor is applied equally.
+/// Y is computed in grid coordinates, so flipping the rows to the image does not invert it.
+pub fn shade_normal(pixel: &PixelContext) -> Rgb<u8> {
+    let &PixelContext { aspect, slope, .. } = pixel;
+
+    let (aspect_sin, aspect_cos) = aspect.sin_cos();
+
+    let (slope_sin, slope_cos) = slope.sin_cos();
+
+    let encode = |value: f64| ((0.5 + 0.5 * value) * 255.0).round().clamp(0.0, 255.0) as u8;
+
+    Rgb([
+        encode(slope_sin * aspect_cos),
+        encode(-slope_sin * aspect_sin),
+        encode(slope_cos),
     ])
 }
 
+/// Color with straight alpha, all components in 0.0 - 1.0
+#[derive(Clone, Copy, Default)]
+struct Layer {
+    color: [f64; 3],
+    alpha: f64,
+}
+
+impl Layer {
+    /// Blends the layer onto the backdrop by the blend mode and composites the result with source-over;
+    /// `WeightedAverage` is plain source-over
+    fn blend(self, backdrop: Option<Layer>, blend_mode: BlendMode) -> Layer {
+        match blend_mode {
+            BlendMode::WeightedAverage => self.blend_onto(backdrop, |_, source| source),
+            BlendMode::Multiply => self.blend_onto(backdrop, |backdrop, source| backdrop * source),
+            BlendMode::Screen => self.blend_onto(backdrop, |backdrop, source| {
+                backdrop + source - backdrop * source
+            }),
+            BlendMode::Overlay => self.blend_onto(backdrop, |backdrop, source| {
+                if backdrop <= 0.5 {
+                    2.0 * backdrop * source
+                } else {
+                    1.0 - 2.0 * (1.0 - backdrop) * (1.0 - source)
+                }
+            }),
+        }
+    }
+
+    /// Blends the layer onto the backdrop with the blend function and composites the result with source-over
+    fn blend_onto<F>(self, backdrop: Option<Layer>, blend: F) -> Layer
+    where
+        F: Fn(f64, f64) -> f64,
+    {
+        let Some(backdrop) = backdrop else {
+            return self;
+        };
+
+        let alpha = self.alpha + backdrop.alpha * (1.0 - self.alpha);
+
+        if alpha <= 0.0 {
+            return Layer::default();
+        }
+
+        let color = [0, 1, 2].map(|i| {
+            let mixed = (1.0 - backdrop.alpha) * self.color[i]
+                + backdrop.alpha * blend(backdrop.color[i], self.color[i]);
+
+            (self.alpha * mixed + backdrop.alpha * (1.0 - self.alpha) * backdrop.color[i]) / alpha
+        });
+
+        Layer { color, alpha }
+    }
+}
+
+/// Consecutive weighted-average layers; colors are averaged by the weighted alphas
+struct WeightedAverage {
+    channel_sums: [f64; 3],
+    alphas_sum: f64,
+    transparency: f64,
+}
+
+impl Default for WeightedAverage {
+    fn default() -> Self {
+        Self {
+            channel_sums: [0.0; 3],
+            alphas_sum: 0.0,
+            transparency: 1.0,
+        }
+    }
+}
+
+impl WeightedAverage {
+    /// Adds a color with channels in 0.0 - 255.0
+    fn add(&mut self, alpha: f64, channels: [f64; 3]) {
+        for (sum, channel) in self.channel_sums.iter_mut().zip(channels) {
+            *sum += alpha * channel / 255.0;
+        }
+
+        self.alphas_sum += alpha;
+
+        self.transparency *= 1.0 - alpha.min(1.0);
+    }
+
+    fn finish(self) -> Layer {
+        let alphas_sum = f64::MIN_POSITIVE + self.alphas_sum;
+
+        Layer {
+            color: self.channel_sums.map(|sum| sum / alphas_sum),
+            alpha: 1.0 - self.transparency,
+        }
+    }
+}
+
+/// RGB channels of the RGBA color in 0.0 - 255.0, optionally converted from sRGB to linear light
+fn channels([r, g, b, _]: [u8; 4], linear: bool) -> [f64; 3] {
+    [r, g, b].map(|channel| {
+        let channel = f64::from(channel);
+
+        if linear {
+            srgb_to_linear(channel / 255.0) * 255.0
+        } else {
+            channel
+        }
+    })
+}
+
+fn srgb_to_linear(value: f64) -> f64 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f64) -> f64 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// RGBA color of the diverging ramp from the negative color at -1.0 through white at 0.0 to the positive
+/// color at 1.0, clamped to the end colors; the alpha is of the color of the side of the value
+fn diverging_color(negative: u32, positive: u32, value: f64) -> [u8; 4] {
+    if value.is_nan() {
+        return [0; 4];
+    }
+
+    let [_, r, g, b] = ramp_color(
+        &[
+            (-1.0, negative >> 8),
+            (0.0, 0xFF_FF_FF),
+            (1.0, positive >> 8),
+        ],
+        value,
+    )
+    .to_be_bytes();
+
+    let alpha = if value > 0.0 { positive } else { negative } as u8;
+
+    [r, g, b, alpha]
+}
+
+/// Linearly interpolates RGB color of the elevation from the ramp stops; clamps to the end colors.
+fn ramp_color(ramp: &[(f64, u32)], elevation: f64) -> u32 {
+    let (Some(first), Some(last)) = (ramp.first(), ramp.last()) else {
+        return 0;
+    };
+
+    if elevation <= first.0 {
+        return first.1;
+    }
+
+    if elevation >= last.0 {
+        return last.1;
+    }
+
+    let i = ramp.partition_point(|(stop, _)| *stop <= elevation);
+
+    let (low, low_color) = ramp[i - 1];
+
+    let (high, high_color) = ramp[i];
+
+    let t = (elevation - low) / (high - low);
+
+    [16, 8, 0].into_iter().fold(0, |color, shift| {
+        let low = f64::from((low_color >> shift) & 0xFF);
+
+        let high = f64::from((high_color >> shift) & 0xFF);
+
+        color | ((low + (high - low) * t).round() as u32) << shift
+    })
+}
+
+/// Illumination of `igor` by the light facing the slopes of the normalized aspect
+fn igor(params: &IgorShadingParams, aspect: f64, slope: f64, light_aspect: f64) -> f64 {
+    let aspect_diff = difference_between_angles(normalize_angle(aspect, TAU), light_aspect, TAU);
+
+    let aspect_strength = 1.0 - aspect_diff / PI;
+
+    slope / FRAC_PI_2 * params.intensity * aspect_strength.powf(params.aspect_exponent)
+}
+
+/// Illumination by the light facing the slopes of the aspect, of the azimuth minus 90°
+fn oblique(
+    aspect: f64,
+    slope: f64,
+    light_aspect: f64,
+    (zenith_sin, zenith_cos): (f64, f64),
+) -> f64 {
+    zenith_cos * slope.cos() + zenith_sin * slope.sin() * (light_aspect - aspect).cos()
+}
+
+fn clamped_oblique(params: &ObliqueShadingParams, illumination: f64) -> f64 {
+    if params.clamp {
+        illumination.clamp(0.0, 1.0)
+    } else {
+        illumination
+    }
+}
+
+fn oblique_slope(slope: f64, (zenith_sin, zenith_cos): (f64, f64)) -> f64 {
+    zenith_cos * slope.cos() + zenith_sin * slope.sin()
+}
+
 fn normalize_angle(angle: f64, normalizer: f64) -> f64 {
     let angle = angle % normalizer;
 
@@ -157,8 +1066,9 @@ fn normalize_angle(angle: f64, normalizer: f64) -> f64 {
     }
 }
 
+/// Difference between the normalized angles
 fn difference_between_angles(angle1: f64, angle2: f64, normalizer: f64) -> f64 {
-    let diff = (normalize_angle(angle1, normalizer) - normalize_angle(angle2, normalizer)).abs();
+    let diff = (angle1 - angle2).abs();
 
     if diff > normalizer / 2.0 {
         normalizer - diff
@@ -166,3 +1076,722 @@ fn difference_between_angles(angle1: f64, angle2: f64, normalizer: f64) -> f64 {
         diff
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{options::ElevationPrecision, terrain::Elevations};
+    use std::f64::consts::FRAC_PI_4;
+
+    fn options(config: &str) -> Options {
+        Options::from_json(config).unwrap()
+    }
+
+    fn pixel(aspect: f64, slope: f64) -> PixelContext {
+        PixelContext {
+            aspect,
+            slope,
+            profile_curvature: 0.0,
+            plan_curvature: 0.0,
+            elevation: 100.0,
+            index: 0,
+            x: 0,
+            y: 0,
+        }
+    }
+
+    /// Elevations of the function of the column and the row of the square grid
+    fn elevations(size: usize, elevation: impl Fn(f64, f64) -> f64) -> Elevations<'static> {
+        Elevations::F64(
+            (0..size * size)
+                .map(|index| elevation((index % size) as f64, (index / size) as f64))
+                .collect(),
+        )
+    }
+
+    fn square_grid<'a>(elevations: &'a Elevations<'a>, cell_size: f64) -> Grid<'a> {
+        let size = (elevations.len() as f64).sqrt() as usize;
+
+        Grid {
+            elevations,
+            rows: size,
+            cols: size,
+            cell_size,
+            mercator_y: None,
+        }
+    }
+
+    /// Rasters of the shadings of the options of a single pixel grid of the elevation of the pixel
+    fn rasters(options: &Options, pixel: &PixelContext) -> Rasters {
+        let elevations = Elevations::F64(vec![pixel.elevation]);
+
+        let grid = Grid {
+            elevations: &elevations,
+            rows: 1,
+            cols: 1,
+            cell_size: 1.0,
+            mercator_y: None,
+        };
+
+        Rasters::new(&grid, 1.0, &options.shadings.0)
+    }
+
+    /// Intensity of the first shading of the options at the pixel
+    fn intensity(options: &Options, pixel: &PixelContext) -> f64 {
+        PreparedShading::new(&options.shadings.0[0], false).intensity(
+            pixel,
+            0,
+            &rasters(options, pixel),
+        )
+    }
+
+    /// Shaded color of the pixel
+    fn shade_pixel(options: &Options, pixel: &PixelContext) -> Rgba<u8> {
+        shade(
+            pixel,
+            &ShadingContext::new(options, None),
+            &rasters(options, pixel),
+        )
+    }
+
+    #[test]
+    fn shading_contrast_of_1_and_brightness_of_0_are_no_op() {
+        let plain = options(
+            r#"{"shadings": "igor,000000FF,315+oblique,20408080,135,30+igor-slope,000000FF"}"#,
+        );
+
+        let mut identity = plain.clone();
+
+        for shading in &mut identity.shadings.0 {
+            shading.contrast = Some(1.0);
+
+            shading.brightness = Some(0.0);
+        }
+
+        for aspect in 0..12 {
+            for slope in 0..6 {
+                let pixel = pixel(f64::from(aspect) * 0.5, f64::from(slope) * 0.25);
+
+                assert_eq!(
+                    shade_pixel(&identity, &pixel),
+                    shade_pixel(&plain, &pixel),
+                    "{aspect} {slope}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn shading_contrast_and_brightness_remap_its_intensity() {
+        let slope = 0.5;
+
+        let plain = intensity(
+            &options(r#"{"shadings": "igor-slope,000000FF"}"#),
+            &pixel(0.0, slope),
+        );
+
+        let remapped = intensity(
+            &options(r#"{"shadings": "igor-slope,000000FF,contrast=2,brightness=0.1"}"#),
+            &pixel(0.0, slope),
+        );
+
+        assert!((plain - slope / FRAC_PI_2).abs() < 1e-9);
+
+        assert!(
+            (remapped - (2.0 * (plain - 0.5) + 0.6)).abs() < 1e-9,
+            "{remapped}"
+        );
+
+        // clamped
+        let clamped = intensity(
+            &options(r#"{"shadings": "igor-slope,000000FF,contrast=4"}"#),
+            &pixel(0.0, slope),
+        );
+
+        assert_eq!(clamped, 0.0);
+    }
+
+    #[test]
+    fn multidirectional_lights_flat_terrain_by_the_altitude() {
+        let options = options(r#"{"shadings": "multidirectional,000000FF,30"}"#);
+
+        let value = intensity(&options, &pixel(0.0, 0.0));
+
+        assert!((value - 30_f64.to_radians().sin()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn multidirectional_lights_slopes_facing_the_lights() {
+        let options = options(r#"{"shadings": "multidirectional,000000FF,45"}"#);
+
+        // aspects of the slopes facing the north-west and the south-east
+        let lit = intensity(&options, &pixel(225_f64.to_radians(), 0.5));
+
+        let unlit = intensity(&options, &pixel(45_f64.to_radians(), 0.5));
+
+        assert!(lit > 45_f64.to_radians().sin(), "{lit}");
+        assert!(unlit < 45_f64.to_radians().sin(), "{unlit}");
+    }
+
+    #[test]
+    fn multidirectional_keeps_the_contrast_of_the_cone_flank_unlit_by_oblique() {
+        // cone of 45° slopes, 20 m high
+        let elevations = elevations(64, |x, y| (20.0 - (x - 32.0).hypot(y - 32.0)).max(0.0));
+
+        let grid = square_grid(&elevations, 1.0);
+
+        // intensities of the flank facing the south-east, away from the light of oblique
+        let flank = |config| {
+            let options = options(config);
+
+            let rasters = Rasters::new(&grid, 1.0, &options.shadings.0);
+
+            let prepared = PreparedShading::new(&options.shadings.0[0], false);
+
+            let img = compute_hillshade(&grid, 1.0, DerivativeMethod::Horn, 1, |pixel| {
+                Luma([prepared.intensity(pixel, 0, &rasters) as f32])
+            });
+
+            img.enumerate_pixels()
+                .filter_map(|(x, y, value)| {
+                    let (dx, dy) = (f64::from(x) - 32.0, 32.0 - f64::from(y));
+
+                    let bearing = normalize_angle(dx.atan2(dy), TAU).to_degrees();
+
+                    ((4.0..16.0).contains(&dx.hypot(dy)) && (105.0..165.0).contains(&bearing))
+                        .then_some(f64::from(value[0]))
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let deviation = |values: &[f64]| {
+            let mean = values.iter().sum::<f64>() / values.len() as f64;
+
+            (values
+                .iter()
+                .map(|value| (value - mean).powi(2))
+                .sum::<f64>()
+                / values.len() as f64)
+                .sqrt()
+        };
+
+        let multidirectional = flank(r#"{"shadings": "multidirectional,000000FF,45"}"#);
+
+        let oblique = flank(r#"{"shadings": "oblique,000000FF,315,45"}"#);
+
+        // washed out to the black by oblique, still shaded by the lights between 225° and 360°
+        assert!(oblique.iter().all(|value| *value < 0.1), "{oblique:?}");
+
+        assert!(
+            deviation(&multidirectional) > 2.0 * deviation(&oblique),
+            "{} {}",
+            deviation(&multidirectional),
+            deviation(&oblique)
+        );
+    }
+
+    #[test]
+    fn built_in_models_shade_as_their_prepared_shadings() {
+        for shadings in [
+            "igor,000000FF,135",
+            "oblique,000000FF,315,30",
+            "oblique,000000FF,315,20,clamp=false",
+            "oblique-slope,000000FF,45",
+        ] {
+            let options = options(&format!(r#"{{"shadings": "{shadings}"}}"#));
+
+            let model: &dyn IlluminationModel = match &options.shadings.0[0].method {
+                ShadingMethod::Igor(params) => params,
+                ShadingMethod::Oblique(params) => params,
+                ShadingMethod::ObliqueSlope(params) => params,
+                _ => unreachable!(),
+            };
+
+            for aspect in [0.0, 1.0, PI, 5.0] {
+                for slope in [0.0, 0.3, FRAC_PI_2 - 0.1] {
+                    let pixel = pixel(aspect, slope);
+
+                    assert_eq!(
+                        model.value(aspect, slope, &pixel),
+                        intensity(&options, &pixel),
+                        "{shadings} at {aspect}, {slope}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn custom_shading_is_shaded_by_its_model_and_its_contrast() {
+        /// Darker with the elevation up to 1000 m
+        struct Elevation;
+
+        impl IlluminationModel for Elevation {
+            fn value(&self, _aspect: f64, _slope: f64, pixel: &PixelContext) -> f64 {
+                pixel.elevation / 1000.0
+            }
+        }
+
+        let mut options = options(r#"{"shadings": "igor-slope,000000FF"}"#);
+
+        options.shadings.0[0].method =
+            ShadingMethod::Custom(CustomShadingParams(std::sync::Arc::new(Elevation)));
+
+        // the elevation of the pixel is 100 m
+        assert!((intensity(&options, &pixel(0.0, 0.0)) - 0.1).abs() < 1e-9);
+
+        options.shadings.0[0].contrast = Some(0.5);
+
+        assert!((intensity(&options, &pixel(0.0, 0.0)) - 0.3).abs() < 1e-9);
+    }
+
+    #[test]
+    fn hypsometric_tint_is_the_base_of_the_hillshade() {
+        let options = options(
+            r#"{"shadings": "igor,000000FF,315+hypsometric,000000FF,0:00FF00,1000:0000FF"}"#,
+        );
+
+        // flat terrain is not shaded by igor
+        assert_eq!(
+            shade_pixel(&options, &pixel(0.0, 0.0)),
+            Rgba([0, 230, 25, 255])
+        );
+
+        // the hillshade darkens the tint
+        let Rgba([r, g, b, a]) = shade_pixel(&options, &pixel(0.0, 0.3));
+
+        assert_eq!((r, a), (0, 255));
+        assert!(g < 230 && b < 25, "{g} {b}");
+    }
+
+    #[test]
+    fn blend_modes_combine_the_shading_with_the_ones_below() {
+        // intensity of `igor-slope` is 1.0 on vertical slopes
+        let vertical = pixel(0.0, FRAC_PI_2);
+
+        for (blend, expected) in [
+            ("weighted-average", [127, 127, 0]),
+            ("multiply", [0, 0, 0]),
+            ("screen", [255, 255, 0]),
+            ("overlay", [255, 0, 0]),
+        ] {
+            let options = options(&format!(
+                r#"{{"shadings": "igor-slope,FF0000FF+igor-slope,00FF00FF,blend={blend}"}}"#
+            ));
+
+            let Rgba([r, g, b, a]) = shade_pixel(&options, &vertical);
+
+            assert_eq!(
+                [r, g, b, a],
+                [expected[0], expected[1], expected[2], 255],
+                "{blend}"
+            );
+        }
+    }
+
+    #[test]
+    fn multiply_of_two_50_percent_layers_gives_25_percent() {
+        let options =
+            options(r#"{"shadings": "igor-slope,808080FF+igor-slope,808080FF,blend=multiply"}"#);
+
+        let Rgba([r, g, b, a]) = shade_pixel(&options, &pixel(0.0, FRAC_PI_2));
+
+        // (128 / 255) ^ 2 * 255
+        assert_eq!([r, g, b, a], [64, 64, 64, 255]);
+    }
+
+    #[test]
+    fn order_of_the_layers_matters_for_overlay_but_not_for_multiply() {
+        let vertical = pixel(0.0, FRAC_PI_2);
+
+        let gray = |first, second, blend| {
+            let Rgba([value, ..]) = shade_pixel(
+                &options(&format!(
+                    r#"{{"shadings": "igor-slope,{first}+igor-slope,{second},blend={blend}"}}"#
+                )),
+                &vertical,
+            );
+
+            value
+        };
+
+        let (dark, light) = ("404040FF", "C0C0C0FF");
+
+        assert_eq!(gray(dark, light, "multiply"), gray(light, dark, "multiply"));
+
+        assert_ne!(gray(dark, light, "overlay"), gray(light, dark, "overlay"));
+    }
+
+    #[test]
+    fn blend_of_translucent_layer_keeps_part_of_the_backdrop() {
+        let backdrop = Layer {
+            color: [0.5, 0.5, 0.5],
+            alpha: 1.0,
+        };
+
+        let layer = Layer {
+            color: [0.0, 0.5, 1.0],
+            alpha: 0.5,
+        };
+
+        let Layer { color, alpha } = layer.blend(Some(backdrop), BlendMode::Multiply);
+
+        assert_eq!(alpha, 1.0);
+        assert_eq!(color, [0.25, 0.375, 0.5]);
+    }
+
+    #[test]
+    fn gamma_brightens_the_midtones() {
+        let vertical = pixel(0.0, FRAC_PI_2);
+
+        let gray = |config| {
+            let Rgba([value, ..]) = shade_pixel(&options(config), &vertical);
+
+            value
+        };
+
+        assert_eq!(gray(r#"{"shadings": "igor-slope,808080FF"}"#), 128);
+
+        // (128 / 255) ^ (1 / 2) * 255
+        assert_eq!(
+            gray(r#"{"shadings": "igor-slope,808080FF", "gamma": 2.0}"#),
+            180
+        );
+
+        // (128 / 255) ^ (1 / 2.2) * 255
+        assert_eq!(
+            gray(r#"{"shadings": "igor-slope,808080FF", "gamma": 2.2}"#),
+            186
+        );
+    }
+
+    #[test]
+    fn linear_blend_averages_the_colors_in_linear_light() {
+        let vertical = pixel(0.0, FRAC_PI_2);
+
+        let shadings = r#""shadings": "igor-slope,FF0000FF+igor-slope,00FF00FF""#;
+
+        assert_eq!(
+            shade_pixel(&options(&format!("{{{shadings}}}")), &vertical),
+            Rgba([127, 127, 0, 255])
+        );
+
+        // sRGB of the linear 0.5
+        assert_eq!(
+            shade_pixel(
+                &options(&format!(r#"{{{shadings}, "linear_blend": true}}"#)),
+                &vertical
+            ),
+            Rgba([187, 187, 0, 255])
+        );
+    }
+
+    #[test]
+    fn oblique_illumination_is_clamped_unless_disabled() {
+        // 45° slope facing away from the light of the north-west at 20°
+        let unlit = pixel(45_f64.to_radians(), FRAC_PI_4);
+
+        let clamped = intensity(
+            &options(r#"{"shadings": "oblique,000000FF,315,20"}"#),
+            &unlit,
+        );
+
+        let unclamped = intensity(
+            &options(r#"{"shadings": "oblique,000000FF,315,20,clamp=false"}"#),
+            &unlit,
+        );
+
+        assert_eq!(clamped, 0.0);
+
+        // cos(70°) cos(45°) - sin(70°) sin(45°)
+        assert!((unclamped - (FRAC_PI_4 + 70_f64.to_radians()).cos()).abs() < 1e-9);
+
+        // clamped once remapped, also as the identity
+        let remapped = intensity(
+            &options(r#"{"shadings": "oblique,000000FF,315,20,clamp=false,contrast=1"}"#),
+            &unlit,
+        );
+
+        assert_eq!(remapped, 0.0);
+
+        // the unlit layer adds neither darkness nor opacity to the layer averaged with it
+        let lit = shade_pixel(&options(r#"{"shadings": "igor-slope,FFFFFF80"}"#), &unlit);
+
+        let stacked = shade_pixel(
+            &options(r#"{"shadings": "oblique,000000FF,315,20+igor-slope,FFFFFF80"}"#),
+            &unlit,
+        );
+
+        assert_eq!(stacked, lit);
+
+        let Rgba([r, g, b, a]) = stacked;
+
+        assert_eq!([r, g, b], [255, 255, 255]);
+
+        // 0x80 * 45° / 90°
+        assert_eq!(a, 64);
+    }
+
+    #[test]
+    fn local_relief_is_colored_by_the_diverging_ramp() {
+        let (negative, positive) = (0x0000FFA0, 0xFF000080);
+
+        assert_eq!(
+            diverging_color(negative, positive, 0.5),
+            [0xFF, 0x80, 0x80, 0x80]
+        );
+
+        assert_eq!(
+            diverging_color(negative, positive, -0.25),
+            [0xBF, 0xBF, 0xFF, 0xA0]
+        );
+
+        // clamped beyond the range
+        assert_eq!(
+            diverging_color(negative, positive, -3.0),
+            [0x00, 0x00, 0xFF, 0xA0]
+        );
+    }
+
+    #[test]
+    fn aerial_perspective_brightens_by_the_elevation_range() {
+        let options = options(
+            r#"{"shadings": "igor-slope,808080FF", "aerial_perspective": 0.2, "elevation_range": "0,200"}"#,
+        );
+
+        let brightness = |elevation| {
+            ShadingContext::new(&options, Some((100.0, 150.0))).brightness(&PixelContext {
+                elevation,
+                ..pixel(0.0, 0.0)
+            })
+        };
+
+        // the range of the grid is overridden
+        assert!((brightness(100.0) - 0.1).abs() < 1e-9);
+        assert!((brightness(200.0) - 0.2).abs() < 1e-9);
+        assert_eq!(brightness(f64::NAN), 0.0);
+    }
+
+    #[test]
+    fn aerial_perspective_brightens_a_ramp_monotonically() {
+        let options = options(
+            r#"{"shadings": "igor-slope,808080FF", "aerial_perspective": 0.3, "elevation_range": "0,9", "z_factor": 1}"#,
+        );
+
+        let elevations = Elevations::F64((0..10).map(f64::from).collect());
+
+        let grid = Grid {
+            elevations: &elevations,
+            rows: 1,
+            cols: 10,
+            cell_size: 1.0,
+            mercator_y: None,
+        };
+
+        let img = hillshade(
+            &options,
+            &grid,
+            &Rasters::new(&grid, 1.0, &options.shadings.0),
+        );
+
+        let values: Vec<_> = (0..10).map(|x| img.get_pixel(x, 0)[0]).collect();
+
+        assert!(
+            values.windows(2).all(|pair| pair[0] <= pair[1]),
+            "{values:?}"
+        );
+        assert!(values[0] < values[9], "{values:?}");
+    }
+
+    #[test]
+    fn southmost_grid_row_is_the_bottom_image_row() {
+        // distinctive pixel at x = 1 of the southmost row of the 4x3 grid
+        let elevations = Elevations::F64(
+            (0..12)
+                .map(|index| if index == 1 { 100.0 } else { 0.0 })
+                .collect(),
+        );
+
+        let grid = Grid {
+            elevations: &elevations,
+            rows: 3,
+            cols: 4,
+            cell_size: 1.0,
+            mercator_y: None,
+        };
+
+        let img = compute_hillshade(&grid, 1.0, DerivativeMethod::Horn, 1, |pixel| {
+            Luma([pixel.elevation as f32])
+        });
+
+        for (x, y, value) in img.enumerate_pixels() {
+            let expected = if (x, y) == (1, 2) { 100.0 } else { 0.0 };
+
+            assert_eq!(value.0[0], expected, "{x},{y}");
+        }
+    }
+
+    #[test]
+    fn corners_are_shaded_by_the_window_clamped_to_the_grid() {
+        let elevations = elevations(4, |x, y| x + 2.0 * y);
+
+        let grid = square_grid(&elevations, 1.0);
+
+        let inner = compute_derivatives(&grid, 1.0, DerivativeMethod::Horn, 1, 1, 1);
+
+        for (x, y) in [(0, 0), (3, 0), (0, 3), (3, 3)] {
+            let corner = compute_derivatives(&grid, 1.0, DerivativeMethod::Horn, 1, x, y);
+
+            assert!((corner.slope - inner.slope).abs() < 1e-9, "{x},{y}");
+            assert!((corner.aspect - inner.aspect).abs() < 1e-9, "{x},{y}");
+        }
+    }
+
+    #[test]
+    fn hole_without_data_does_not_spoil_its_neighbours() {
+        let options = options(r#"{"shadings": "igor-slope,000000FF", "nodata_color": "FF0000FF"}"#);
+
+        let plane = elevations(5, |x, y| x + 2.0 * y);
+
+        let holed = elevations(5, |x, y| {
+            if (x, y) == (2.0, 2.0) {
+                f64::NAN
+            } else {
+                x + 2.0 * y
+            }
+        });
+
+        let shade = |elevations| {
+            let grid = square_grid(elevations, 1.0);
+
+            hillshade(
+                &options,
+                &grid,
+                &Rasters::new(&grid, 1.0, &options.shadings.0),
+            )
+        };
+
+        let (plane, holed) = (shade(&plane), shade(&holed));
+
+        assert_eq!(*holed.get_pixel(2, 2), Rgba([255, 0, 0, 255]));
+
+        for (x, y, pixel) in holed.enumerate_pixels() {
+            if (x, y) != (2, 2) {
+                // as of the plane up to the bias of the missing neighbour substituted by the center
+                let expected = plane.get_pixel(x, y)[3];
+
+                assert!(pixel[3].abs_diff(expected) <= 16, "{x},{y}: {pixel:?}");
+                assert!(pixel[3] > 0, "{x},{y}");
+            }
+        }
+    }
+
+    #[test]
+    fn derivative_methods_give_the_slope_and_aspect_of_a_plane() {
+        let elevations = elevations(5, |x, y| 0.5 * x + 0.25 * y);
+
+        let grid = square_grid(&elevations, 1.0);
+
+        for method in [DerivativeMethod::Horn, DerivativeMethod::ZevenbergenThorne] {
+            let SurfaceDerivatives { slope, aspect, .. } =
+                compute_derivatives(&grid, 1.0, method, 1, 2, 2);
+
+            assert!(
+                (slope - 0.5_f64.hypot(0.25).atan()).abs() < 1e-9,
+                "{method}"
+            );
+            assert!((aspect - 0.25_f64.atan2(-0.5)).abs() < 1e-9, "{method}");
+        }
+    }
+
+    #[test]
+    fn derivatives_are_divided_by_the_cell_size() {
+        // rising 2 m per pixel of 2 m
+        let elevations = elevations(3, |x, _| 2.0 * x);
+
+        let grid = square_grid(&elevations, 2.0);
+
+        let SurfaceDerivatives { slope, .. } =
+            compute_derivatives(&grid, 1.0, DerivativeMethod::Horn, 1, 1, 1);
+
+        assert!((slope - FRAC_PI_4).abs() < 1e-9, "{slope}");
+    }
+
+    #[test]
+    fn mercator_scale_steepens_the_terrain_of_higher_latitudes() {
+        let elevations = elevations(3, |x, _| x);
+
+        let slope = |latitude: f64| {
+            let grid = Grid {
+                mercator_y: Some(
+                    6_378_137.0 * (FRAC_PI_4 + latitude.to_radians() / 2.0).tan().ln() - 1.0,
+                ),
+                ..square_grid(&elevations, 1.0)
+            };
+
+            compute_derivatives(&grid, 1.0, DerivativeMethod::Horn, 1, 1, 1).slope
+        };
+
+        assert!((slope(0.0) - FRAC_PI_4).abs() < 1e-6);
+
+        // pixels of the half ground size at 60°
+        assert!((slope(60.0).tan() - 2.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn plane_fit_of_larger_window_smooths_noisy_slopes() {
+        // plane with pseudo-random noise of up to 0.5 m
+        let elevations = elevations(32, |x, y| {
+            0.2 * x + 0.5 * ((x * 12.9898 + y * 78.233).sin() * 43_758.545).fract()
+        });
+
+        let grid = square_grid(&elevations, 1.0);
+
+        let variance = |method, radius| {
+            let slopes: Vec<_> = (4..28)
+                .flat_map(|y| (4..28).map(move |x| (x, y)))
+                .map(|(x, y)| compute_derivatives(&grid, 1.0, method, radius, x, y).slope)
+                .collect();
+
+            let mean = slopes.iter().sum::<f64>() / slopes.len() as f64;
+
+            slopes
+                .iter()
+                .map(|slope| (slope - mean).powi(2))
+                .sum::<f64>()
+                / slopes.len() as f64
+        };
+
+        assert!(
+            variance(DerivativeMethod::PlaneFit, 3) < variance(DerivativeMethod::Horn, 1) / 4.0
+        );
+    }
+
+    #[test]
+    fn f32_elevations_give_the_slopes_and_aspects_of_f64_ones() {
+        let terrain = |x: f64, y: f64| 2000.0 + 30.0 * (x / 5.0).sin() * (y / 7.0).cos();
+
+        let wide = elevations(32, terrain);
+
+        let narrow = Elevations::new(
+            ElevationPrecision::F32,
+            (0..wide.len()).map(|index| wide.get(index)),
+        );
+
+        let (wide, narrow) = (square_grid(&wide, 0.5), square_grid(&narrow, 0.5));
+
+        for method in [DerivativeMethod::Horn, DerivativeMethod::PlaneFit] {
+            for (x, y) in (1..31).flat_map(|x| (1..31).map(move |y| (x, y))) {
+                let expected = compute_derivatives(&wide, 1.0, method, 1, x, y);
+
+                let actual = compute_derivatives(&narrow, 1.0, method, 1, x, y);
+
+                assert!((actual.slope - expected.slope).abs() < 1e-3, "{method}");
+                assert!(
+                    (actual.aspect - expected.aspect).abs() < 1e-2 || expected.slope < 0.01,
+                    "{method}"
+                );
+            }
+        }
+    }
+}
@@ -1,16 +1,30 @@
+use crate::{
+    error::Error,
+    serialization::{angle, angles, ramp, rgba, rgba_bytes, slope_classes},
+    shading::IlluminationModel,
+    spill::{SpillDir, SpillFile},
+};
+use log::debug;
 use maptile::{bbox::BBox, tile::Tile};
+use serde::{Deserialize, Deserializer, Serialize, de};
 use spade::{HasPosition, Point2};
 use std::{
-    error::Error,
+    error,
+    f64::consts::{FRAC_PI_2, TAU},
     fmt::{Debug, Display},
+    iter,
+    mem::take,
     path::PathBuf,
     str::FromStr,
-    sync::Mutex,
+    sync::{Arc, Mutex},
 };
 
+#[derive(Clone, Copy)]
 pub struct PointWithHeight {
     pub position: Point2<f64>,
     pub height: f64,
+    /// Water-classified point
+    pub water: bool,
 }
 
 impl HasPosition for PointWithHeight {
@@ -25,6 +39,169 @@ pub struct TileMeta {
     pub tile: Tile,
     pub bbox: BBox,
     pub points: Mutex<Vec<PointWithHeight>>,
+    /// Water points, read only with `--flatten-water`
+    pub water_points: Mutex<Vec<PointWithHeight>>,
+    /// First returns of the DSM of the `chm` surface
+    pub surface_points: Mutex<Vec<PointWithHeight>>,
+    /// Directory of `--max-memory`
+    pub spill_dir: Option<Arc<SpillDir>>,
+    /// Spilled points of `points`, `water_points` and `surface_points`
+    spilled: Mutex<[Option<Box<SpillFile>>; 3]>,
+}
+
+/// Collections of `TileMeta` the point belongs to
+pub struct PointUse {
+    pub terrain: bool,
+    pub water: bool,
+    pub surface: bool,
+}
+
+impl TileMeta {
+    pub fn new(tile: Tile, bbox: BBox) -> Self {
+        Self {
+            tile,
+            bbox,
+            points: Mutex::new(Vec::new()),
+            water_points: Mutex::new(Vec::new()),
+            surface_points: Mutex::new(Vec::new()),
+            spill_dir: None,
+            spilled: Mutex::new([None, None, None]),
+        }
+    }
+
+    pub fn push(&self, point_use: &PointUse, position: Point2<f64>, height: f64) {
+        for (used, points, water) in [
+            (point_use.terrain, &self.points, false),
+            (point_use.water, &self.water_points, true),
+            (point_use.surface, &self.surface_points, false),
+        ] {
+            if used {
+                points.lock().unwrap().push(PointWithHeight {
+                    position,
+                    height,
+                    water,
+                });
+            }
+        }
+    }
+
+    /// Moves the buffered points to the tile, locking every collection once.
+    /// A collection over the threshold of `--max-memory` is spilled to its file, except for the water points.
+    pub fn append(&self, buffer: &mut TilePoints) -> Result<(), Error> {
+        for (collection, (points, buffered)) in [
+            (&self.points, &mut buffer.points),
+            (&self.water_points, &mut buffer.water_points),
+            (&self.surface_points, &mut buffer.surface_points),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            if buffered.is_empty() {
+                continue;
+            }
+
+            let mut points = points.lock().unwrap();
+
+            points.append(buffered);
+
+            // water points are levelled across the tiles once read
+            if let Some(spill_dir) = &self.spill_dir
+                && collection != 1
+                && points.len() > spill_dir.threshold
+            {
+                let points = take(&mut *points);
+
+                debug!(
+                    "SPILL {}/{}/{}: {} points",
+                    self.tile.zoom,
+                    self.tile.x,
+                    self.tile.y,
+                    points.len()
+                );
+
+                let spilled = &mut self.spilled.lock().unwrap()[collection];
+
+                let file = match spilled {
+                    Some(file) => file,
+                    None => spilled.insert(Box::new(SpillFile::create(
+                        spill_dir, self.tile, collection,
+                    )?)),
+                };
+
+                file.write(&points)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Takes the points, water points and surface points including the spilled ones,
+    /// with the number of the spilled points
+    pub fn take_points(&self) -> Result<([Vec<PointWithHeight>; 3], usize), Error> {
+        let mut spilled = self.spilled.lock().unwrap();
+
+        let mut spilled_count = 0;
+
+        let mut points = [&self.points, &self.water_points, &self.surface_points]
+            .map(|points| take(&mut *points.lock().unwrap()));
+
+        for (collection, points) in points.iter_mut().enumerate() {
+            if let Some(file) = spilled[collection].take() {
+                spilled_count += file.count;
+
+                file.read(points, collection == 1)?;
+            }
+        }
+
+        Ok((points, spilled_count))
+    }
+}
+
+/// Points of a tile buffered by a reading thread, see `TileMeta`
+#[derive(Default)]
+pub struct TilePoints {
+    pub points: Vec<PointWithHeight>,
+    pub water_points: Vec<PointWithHeight>,
+    pub surface_points: Vec<PointWithHeight>,
+}
+
+impl TilePoints {
+    pub fn push(&mut self, point_use: &PointUse, position: Point2<f64>, height: f64) {
+        for (used, points, water) in [
+            (point_use.terrain, &mut self.points, false),
+            (point_use.water, &mut self.water_points, true),
+            (point_use.surface, &mut self.surface_points, false),
+        ] {
+            if used {
+                points.push(PointWithHeight {
+                    position,
+                    height,
+                    water,
+                });
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.points.len() + self.water_points.len() + self.surface_points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Sorts the points by position, height and the water flag so that the output does not depend on the order
+/// of reading, which varies with the threads, e.g. which of the points of the same position is kept
+pub fn sort_points(points: &mut [PointWithHeight]) {
+    points.sort_unstable_by(|a, b| {
+        a.position
+            .x
+            .total_cmp(&b.position.x)
+            .then(a.position.y.total_cmp(&b.position.y))
+            .then(a.height.total_cmp(&b.height))
+            .then(a.water.cmp(&b.water))
+    });
 }
 
 impl Debug for TileMeta {
@@ -52,47 +229,342 @@ impl Job {
 }
 
 #[derive(Clone)]
+#[allow(clippy::enum_variant_names)]
 pub enum Source {
     LazTileDb(PathBuf),
     LazIndexDb(PathBuf),
+    LazDir(PathBuf),
 }
 
-#[derive(Clone, Debug, PartialEq)]
+/// Serialized as an object of the params with the method as `type`, e.g. `{"type": "igor", "azimuth": 315}`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
 pub enum ShadingMethod {
     Igor(IgorShadingParams),
     Oblique(ObliqueShadingParams),
     IgorSlope,
     ObliqueSlope(ObliqueSlopeShadingParams),
+    Multidirectional(MultidirectionalShadingParams),
+    CastShadow(CastShadowShadingParams),
+    SkyViewFactor(SkyViewFactorShadingParams),
+    Openness(OpennessShadingParams),
+    LocalRelief(LocalReliefShadingParams),
+    Curvature(CurvatureShadingParams),
+    Tri(TriShadingParams),
+    Tpi(TpiShadingParams),
+    Hypsometric(HypsometricShadingParams),
+    SlopeClasses(SlopeClassesShadingParams),
+    /// Illumination model of a library user; not available in `--shadings` and not serialized
+    #[serde(skip)]
+    Custom(CustomShadingParams),
 }
 
-#[derive(Clone, Debug, PartialEq)]
+/// Illumination model of `ShadingMethod::Custom`, equal only to the same instance
+#[derive(Clone)]
+pub struct CustomShadingParams(pub Arc<dyn IlluminationModel>);
+
+impl Debug for CustomShadingParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CustomShadingParams")
+    }
+}
+
+impl PartialEq for CustomShadingParams {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct IgorShadingParams {
+    #[serde(with = "angle")]
     pub azimuth: f64,
+    /// Multiplier of the slope
+    #[serde(default = "default_igor_intensity")]
+    pub intensity: f64,
+    /// Exponent of the aspect strength
+    #[serde(default = "one")]
+    pub aspect_exponent: f64,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ObliqueShadingParams {
+    #[serde(with = "angle")]
     pub azimuth: f64,
+    #[serde(with = "angle")]
     pub altitude: f64,
+    /// Clamp the illumination to 0.0 - 1.0; otherwise slopes facing away from the light get over-darkened,
+    /// unless remapped by `contrast` or `brightness` which clamp it
+    #[serde(default = "yes")]
+    pub clamp: bool,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ObliqueSlopeShadingParams {
+    #[serde(with = "angle")]
+    pub altitude: f64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MultidirectionalShadingParams {
+    #[serde(with = "angles", default = "default_multidirectional_azimuths")]
+    pub azimuths: Vec<f64>,
+    #[serde(with = "angle")]
     pub altitude: f64,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CastShadowShadingParams {
+    #[serde(with = "angle")]
+    pub azimuth: f64,
+    #[serde(with = "angle")]
+    pub altitude: f64,
+    /// Maximal distance of the occluding terrain in meters
+    pub max_distance: f64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SkyViewFactorShadingParams {
+    /// Number of horizon directions to sample
+    #[serde(default = "default_sky_view_factor_directions")]
+    pub directions: usize,
+    /// Horizon search radius in meters
+    pub max_radius: f64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OpennessShadingParams {
+    /// Positive openness (above the surface) darkens concave relief, negative one (below the surface) convex relief
+    pub positive: bool,
+    /// Number of horizon directions to sample
+    #[serde(default = "default_openness_directions")]
+    pub directions: usize,
+    /// Horizon search radius in meters
+    pub max_radius: f64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LocalReliefShadingParams {
+    /// Radius of the mean filter in meters
+    pub radius: f64,
+    /// Difference from the mean elevation in meters at which the color is fully opaque and of the end of the ramp
+    pub range: f64,
+    /// RGBA color of the end of the diverging ramp above the mean elevation; the shading color is the one below it
+    #[serde(with = "rgba")]
+    pub positive_color: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CurvatureKind {
+    Profile,
+    Plan,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CurvatureShadingParams {
+    pub kind: CurvatureKind,
+    /// Multiplier of the curvature (in 1/m) giving the change of the intensity from 0.5
+    pub scale: f64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TriShadingParams {
+    /// Terrain ruggedness index in meters mapped to intensity 0.0
+    pub min: f64,
+    /// Terrain ruggedness index in meters mapped to intensity 1.0
+    pub max: f64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TpiShadingParams {
+    /// Radius of the neighbourhood in meters
+    pub radius: f64,
+    /// Topographic position index in meters mapped to intensity 0.0
+    pub min: f64,
+    /// Topographic position index in meters mapped to intensity 1.0
+    pub max: f64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HypsometricShadingParams {
+    /// Elevation stops in meters with RGB colors, sorted by elevation
+    #[serde(with = "ramp")]
+    pub ramp: Vec<(f64, u32)>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SlopeClassesShadingParams {
+    /// Lower slope bounds in radians with RGBA colors, sorted by the bound.
+    /// A class spans from its bound (inclusive) to the bound of the next one (exclusive).
+    #[serde(with = "slope_classes")]
+    pub classes: Vec<(f64, u32)>,
+}
+
+impl FromStr for SlopeClassesShadingParams {
+    type Err = ParseShadingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let classes: Result<Vec<_>, _> = s
+            .split(',')
+            .map(|class| {
+                let (slope, color) = class.split_once(':').ok_or(ParseShadingError())?;
+
+                match (slope.parse::<f64>(), parse_color(color)) {
+                    (Ok(slope), Some(color)) => Ok((slope.to_radians(), color)),
+                    _ => Err(ParseShadingError()),
+                }
+            })
+            .collect();
+
+        let mut classes = classes?;
+
+        classes.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        Ok(Self { classes })
+    }
+}
+
+impl From<SlopeClassesShadingParams> for Shading {
+    fn from(params: SlopeClassesShadingParams) -> Self {
+        Self {
+            color: [0, 0, 0, 0xFF],
+            method: ShadingMethod::SlopeClasses(params),
+            brightness: None,
+            contrast: None,
+            weight: 1.0,
+            blend_mode: BlendMode::default(),
+        }
+    }
+}
+
+const DEFAULT_MULTIDIRECTIONAL_AZIMUTHS: [f64; 4] = [225.0, 270.0, 315.0, 360.0];
+
+const DEFAULT_SKY_VIEW_FACTOR_DIRECTIONS: usize = 16;
+
+const DEFAULT_OPENNESS_DIRECTIONS: usize = 8;
+
+const DEFAULT_IGOR_INTENSITY: f64 = 2.0;
+
+// serde defaults of the optional params of `--shadings`
+
+fn default_multidirectional_azimuths() -> Vec<f64> {
+    DEFAULT_MULTIDIRECTIONAL_AZIMUTHS
+        .map(f64::to_radians)
+        .to_vec()
+}
+
+fn default_sky_view_factor_directions() -> usize {
+    DEFAULT_SKY_VIEW_FACTOR_DIRECTIONS
+}
+
+fn default_openness_directions() -> usize {
+    DEFAULT_OPENNESS_DIRECTIONS
+}
+
+fn default_igor_intensity() -> f64 {
+    DEFAULT_IGOR_INTENSITY
+}
+
+fn one() -> f64 {
+    1.0
+}
+
+fn yes() -> bool {
+    true
+}
+
+/// How a shading is combined with the shadings below it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BlendMode {
+    /// Consecutive shadings are averaged by their weighted alphas
+    #[default]
+    WeightedAverage,
+    Multiply,
+    Screen,
+    Overlay,
+}
+
+impl FromStr for BlendMode {
+    type Err = ParseShadingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "weighted-average" => Ok(Self::WeightedAverage),
+            "multiply" => Ok(Self::Multiply),
+            "screen" => Ok(Self::Screen),
+            "overlay" => Ok(Self::Overlay),
+            _ => Err(ParseShadingError()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Shading {
-    pub color: u32,
+    /// RGBA; the alpha is the opacity of the fully intense shading
+    #[serde(with = "rgba_bytes")]
+    pub color: [u8; 4],
+    #[serde(default = "one")]
     pub weight: f64,
-    pub brightness: f64,
-    pub contrast: f64,
+    #[serde(rename = "blend", default)]
+    pub blend_mode: BlendMode,
+    /// Remaps the intensity of the shading, clamped to 0.0 - 1.0, before it is composited
+    /// as `contrast * (intensity - 0.5) + 0.5 + brightness`; the intensity is kept if both are `None`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub brightness: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contrast: Option<f64>,
     pub method: ShadingMethod,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(transparent)]
 pub struct Shadings(pub Vec<Shading>);
 
+/// Deserialized from the shadings or from the string of `--shadings`, validated as by `ShadingStackBuilder`
+impl<'de> Deserialize<'de> for Shadings {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> de::Visitor<'de> for Visitor {
+            type Value = Shadings;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, "an array of shadings or a string of --shadings")
+            }
+
+            fn visit_str<E: de::Error>(self, shadings: &str) -> Result<Shadings, E> {
+                shadings.parse().map_err(E::custom)
+            }
+
+            fn visit_seq<A: de::SeqAccess<'de>>(self, seq: A) -> Result<Shadings, A::Error> {
+                Vec::<Shading>::deserialize(de::value::SeqAccessDeserializer::new(seq))?
+                    .into_iter()
+                    .fold(ShadingStackBuilder::new(), ShadingStackBuilder::shading)
+                    .build()
+                    .map_err(de::Error::custom)
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
 #[derive(Debug)]
 pub struct ParseShadingError();
 
@@ -102,84 +574,940 @@ impl Display for ParseShadingError {
     }
 }
 
-impl Error for ParseShadingError {}
+impl error::Error for ParseShadingError {}
+
+/// Invalid shading of the `+` separated shadings
+#[derive(Debug)]
+pub struct ParseShadingsError {
+    pub shading: String,
+    pub reason: Option<&'static str>,
+}
+
+impl Display for ParseShadingsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Error parsing shading {}", self.shading)?;
+
+        match self.reason {
+            Some(reason) => write!(f, ": {reason}"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl error::Error for ParseShadingsError {}
 
 impl FromStr for Shadings {
-    type Err = ParseShadingError;
+    type Err = ParseShadingsError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let shadings: Result<_, _> = s
-            .split('+')
-            .map(|shading| {
-                let params: Vec<&str> = shading.split(',').collect();
-
-                let method = match params.get(0) {
-                    Some(&"igor") => {
-                        if params.len() != 3 {
-                            Err(())
-                        } else {
-                            params[2].parse::<f64>().map_or(Err(()), |azimuth| {
-                                Ok(ShadingMethod::Igor(IgorShadingParams {
-                                    azimuth: azimuth.to_radians(),
-                                }))
-                            })
-                        }
+        let mut builder = ShadingStackBuilder::new();
+
+        // shading of the string of every expanded shading
+        let mut sources = Vec::new();
+
+        for shading in s.split('+') {
+            let error = |reason| ParseShadingsError {
+                shading: shading.to_owned(),
+                reason,
+            };
+
+            for expanded in expand_azimuths(shading).map_err(|reason| error(Some(reason)))? {
+                builder = builder.shading(parse_shading(&expanded).map_err(|_| error(None))?);
+
+                sources.push(shading);
+            }
+        }
+
+        builder.build().map_err(|error| ParseShadingsError {
+            shading: error.index().map_or(s, |index| sources[index]).to_owned(),
+            reason: Some(error.reason()),
+        })
+    }
+}
+
+/// Invalid shading stack of `ShadingStackBuilder`
+#[derive(Debug, Clone, PartialEq)]
+pub enum ShadingStackError {
+    Empty,
+    /// Angle in degrees out of its range
+    AngleOutOfRange {
+        index: usize,
+        name: &'static str,
+        value: f64,
+    },
+    /// Infinite or NaN contrast, brightness or weight
+    NonFinite {
+        index: usize,
+        name: &'static str,
+    },
+    NegativeWeight {
+        index: usize,
+    },
+    /// Zero, negative or infinite contrast, or param of the method
+    NotPositive {
+        index: usize,
+        name: &'static str,
+    },
+    /// Equal minimum and maximum of `tri` or `tpi`
+    EmptyRange {
+        index: usize,
+    },
+    /// `multidirectional` without any light
+    NoAzimuths {
+        index: usize,
+    },
+}
+
+impl ShadingStackError {
+    /// Index of the invalid shading in the stack
+    pub fn index(&self) -> Option<usize> {
+        match self {
+            Self::Empty => None,
+            Self::AngleOutOfRange { index, .. }
+            | Self::NonFinite { index, .. }
+            | Self::NegativeWeight { index }
+            | Self::NotPositive { index, .. }
+            | Self::EmptyRange { index }
+            | Self::NoAzimuths { index } => Some(*index),
+        }
+    }
+
+    fn reason(&self) -> &'static str {
+        match self {
+            Self::Empty => "no shadings",
+            Self::AngleOutOfRange {
+                name: "azimuth", ..
+            } => "azimuths must be from 0 to 360 degrees",
+            Self::AngleOutOfRange { .. } => "altitudes must be from 0 to 90 degrees",
+            Self::NonFinite { .. } => "contrast, brightness and weight must be finite",
+            Self::NegativeWeight { .. } => "weight must not be negative",
+            Self::NotPositive { .. } => {
+                "contrast, intensity, exponent, radius, distance, range and directions must be positive and finite"
+            }
+            Self::EmptyRange { .. } => "minimum and maximum must differ",
+            Self::NoAzimuths { .. } => "azimuths must not be empty",
+        }
+    }
+}
+
+impl Display for ShadingStackError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => write!(f, "Empty shading stack"),
+            Self::AngleOutOfRange { index, name, value } => {
+                write!(f, "Shading {index}: {name} {value} out of range")
+            }
+            Self::NonFinite { index, name } => write!(f, "Shading {index}: {name} is not finite"),
+            Self::NegativeWeight { index } => write!(f, "Shading {index}: negative weight"),
+            Self::NotPositive { index, name } => {
+                write!(f, "Shading {index}: {name} is not positive")
+            }
+            Self::EmptyRange { index } => write!(f, "Shading {index}: empty range"),
+            Self::NoAzimuths { index } => write!(f, "Shading {index}: no azimuths"),
+        }
+    }
+}
+
+impl error::Error for ShadingStackError {}
+
+/// Stack of the shadings from the bottom one, with angles in degrees and RGBA colors.
+/// `contrast` and `brightness` apply to the whole stack, to its shadings without their own,
+/// `weight` and `blend` to the last added shading.
+///
+/// # Examples
+///
+/// ```
+/// use laz2dem::shared_types::{BlendMode, ShadingStackBuilder};
+///
+/// let shadings = ShadingStackBuilder::new()
+///     .igor(315.0, [0, 0, 0], 255)
+///     .slope(60.0, [0, 0, 0, 128])
+///     .weight(0.5)
+///     .blend(BlendMode::Multiply)
+///     .contrast(1.5)
+///     .build()?;
+///
+/// assert_eq!(shadings.0.len(), 2);
+/// assert!(shadings.0.iter().all(|shading| shading.contrast == Some(1.5)));
+/// # Ok::<(), laz2dem::shared_types::ShadingStackError>(())
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ShadingStackBuilder {
+    shadings: Vec<Shading>,
+    contrast: Option<f64>,
+    brightness: Option<f64>,
+}
+
+impl ShadingStackBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn shading(mut self, shading: Shading) -> Self {
+        self.shadings.push(shading);
+
+        self
+    }
+
+    fn method(self, color: [u8; 4], method: ShadingMethod) -> Self {
+        self.shading(Shading {
+            color,
+            method,
+            brightness: None,
+            contrast: None,
+            weight: 1.0,
+            blend_mode: BlendMode::default(),
+        })
+    }
+
+    /// `igor` of the intensity 2.0 and the aspect exponent 1.0, of the RGB color and its alpha
+    pub fn igor(self, azimuth: f64, [r, g, b]: [u8; 3], alpha: u8) -> Self {
+        self.method(
+            [r, g, b, alpha],
+            ShadingMethod::Igor(IgorShadingParams {
+                azimuth: azimuth.to_radians(),
+                intensity: DEFAULT_IGOR_INTENSITY,
+                aspect_exponent: 1.0,
+            }),
+        )
+    }
+
+    /// Clamped `oblique`
+    pub fn oblique(self, azimuth: f64, altitude: f64, color: [u8; 4]) -> Self {
+        self.method(
+            color,
+            ShadingMethod::Oblique(ObliqueShadingParams {
+                azimuth: azimuth.to_radians(),
+                altitude: altitude.to_radians(),
+                clamp: true,
+            }),
+        )
+    }
+
+    /// `oblique-slope`
+    pub fn slope(self, altitude: f64, color: [u8; 4]) -> Self {
+        self.method(
+            color,
+            ShadingMethod::ObliqueSlope(ObliqueSlopeShadingParams {
+                altitude: altitude.to_radians(),
+            }),
+        )
+    }
+
+    fn last(mut self, update: impl FnOnce(&mut Shading)) -> Self {
+        if let Some(shading) = self.shadings.last_mut() {
+            update(shading);
+        }
+
+        self
+    }
+
+    pub fn contrast(mut self, contrast: f64) -> Self {
+        self.contrast = Some(contrast);
+
+        self
+    }
+
+    pub fn brightness(mut self, brightness: f64) -> Self {
+        self.brightness = Some(brightness);
+
+        self
+    }
+
+    pub fn weight(self, weight: f64) -> Self {
+        self.last(|shading| shading.weight = weight)
+    }
+
+    pub fn blend(self, blend_mode: BlendMode) -> Self {
+        self.last(|shading| shading.blend_mode = blend_mode)
+    }
+
+    pub fn build(mut self) -> Result<Shadings, ShadingStackError> {
+        if self.shadings.is_empty() {
+            return Err(ShadingStackError::Empty);
+        }
+
+        for (index, shading) in self.shadings.iter_mut().enumerate() {
+            shading.contrast = shading.contrast.or(self.contrast);
+
+            shading.brightness = shading.brightness.or(self.brightness);
+
+            validate(index, shading)?;
+        }
+
+        Ok(Shadings(self.shadings))
+    }
+}
+
+fn validate(index: usize, shading: &Shading) -> Result<(), ShadingStackError> {
+    for (name, value) in [
+        ("contrast", shading.contrast.unwrap_or(1.0)),
+        ("brightness", shading.brightness.unwrap_or(0.0)),
+        ("weight", shading.weight),
+    ] {
+        if !value.is_finite() {
+            return Err(ShadingStackError::NonFinite { index, name });
+        }
+    }
+
+    if shading.weight < 0.0 {
+        return Err(ShadingStackError::NegativeWeight { index });
+    }
+
+    let positive = match &shading.method {
+        ShadingMethod::Igor(params) => vec![
+            ("intensity", params.intensity),
+            ("exponent", params.aspect_exponent),
+        ],
+        ShadingMethod::SkyViewFactor(params) => vec![
+            ("radius", params.max_radius),
+            ("directions", params.directions as f64),
+        ],
+        ShadingMethod::Openness(params) => vec![
+            ("radius", params.max_radius),
+            ("directions", params.directions as f64),
+        ],
+        ShadingMethod::CastShadow(params) => vec![("distance", params.max_distance)],
+        ShadingMethod::LocalRelief(params) => {
+            vec![("radius", params.radius), ("range", params.range)]
+        }
+        ShadingMethod::Tpi(params) => vec![("radius", params.radius)],
+        _ => vec![],
+    };
+
+    if let Some((name, _)) = iter::once(("contrast", shading.contrast.unwrap_or(1.0)))
+        .chain(positive)
+        .find(|(_, value)| !value.is_finite() || *value <= 0.0)
+    {
+        return Err(ShadingStackError::NotPositive { index, name });
+    }
+
+    if let ShadingMethod::Tri(TriShadingParams { min, max })
+    | ShadingMethod::Tpi(TpiShadingParams { min, max, .. }) = shading.method
+        && min == max
+    {
+        return Err(ShadingStackError::EmptyRange { index });
+    }
+
+    if let ShadingMethod::Multidirectional(params) = &shading.method
+        && params.azimuths.is_empty()
+    {
+        return Err(ShadingStackError::NoAzimuths { index });
+    }
+
+    let (azimuths, altitude) = match &shading.method {
+        ShadingMethod::Igor(params) => (vec![params.azimuth], None),
+        ShadingMethod::Oblique(params) => (vec![params.azimuth], Some(params.altitude)),
+        ShadingMethod::ObliqueSlope(params) => (vec![], Some(params.altitude)),
+        ShadingMethod::Multidirectional(params) => (params.azimuths.clone(), Some(params.altitude)),
+        ShadingMethod::CastShadow(params) => (vec![params.azimuth], Some(params.altitude)),
+        _ => (vec![], None),
+    };
+
+    let out_of_range = |name, value: f64| ShadingStackError::AngleOutOfRange {
+        index,
+        name,
+        value: value.to_degrees(),
+    };
+
+    if let Some(azimuth) = azimuths
+        .into_iter()
+        .find(|azimuth| !(0.0..=TAU).contains(azimuth))
+    {
+        return Err(out_of_range("azimuth", azimuth));
+    }
+
+    match altitude {
+        Some(altitude) if !(0.0..=FRAC_PI_2).contains(&altitude) => {
+            Err(out_of_range("altitude", altitude))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Splits `igor` shading of more azimuths to the shadings of the single azimuths sharing the other params.
+/// Weights of the azimuths given as `<azimuth>*<weight>` multiply the `weight` param.
+fn expand_azimuths(shading: &str) -> Result<Vec<String>, &'static str> {
+    let (named_params, params): (Vec<&str>, Vec<&str>) =
+        shading.split(',').partition(|param| param.contains('='));
+
+    // color is required
+    if params.len() < 2 || params[0] != "igor" {
+        return Ok(vec![shading.to_owned()]);
+    }
+
+    if params.len() == 2 {
+        return Err("igor requires at least one azimuth");
+    }
+
+    let (weights, named_params): (Vec<&str>, Vec<&str>) = named_params
+        .into_iter()
+        .partition(|param| param.starts_with("weight="));
+
+    let weight = match weights.last() {
+        Some(weight) => weight["weight=".len()..]
+            .parse::<f64>()
+            .map_err(|_| "invalid weight")?,
+        None => 1.0,
+    };
+
+    params[2..]
+        .iter()
+        .map(|param| {
+            let (azimuth, azimuth_weight) = match param.split_once('*') {
+                Some((azimuth, azimuth_weight)) => (
+                    azimuth,
+                    azimuth_weight
+                        .parse::<f64>()
+                        .map_err(|_| "invalid weight of the azimuth")?,
+                ),
+                None => (*param, 1.0),
+            };
+
+            if azimuth.parse::<f64>().is_err() {
+                return Err("invalid azimuth");
+            }
+
+            let mut expanded = format!("igor,{},{azimuth}", params[1]);
+
+            for param in &named_params {
+                expanded.push(',');
+
+                expanded.push_str(param);
+            }
+
+            Ok(format!("{expanded},weight={}", weight * azimuth_weight))
+        })
+        .collect()
+}
+
+fn parse_shading(shading: &str) -> Result<Shading, ParseShadingError> {
+    // optional params are in `key=value` form and may follow the method params
+    let (named_params, params): (Vec<&str>, Vec<&str>) =
+        shading.split(',').partition(|param| param.contains('='));
+
+    let method = match params.get(0) {
+        Some(&"igor") => {
+            if params.len() != 3 {
+                Err(())
+            } else {
+                params[2].parse::<f64>().map_or(Err(()), |azimuth| {
+                    Ok(ShadingMethod::Igor(IgorShadingParams {
+                        azimuth: azimuth.to_radians(),
+                        intensity: DEFAULT_IGOR_INTENSITY,
+                        aspect_exponent: 1.0,
+                    }))
+                })
+            }
+        }
+        Some(&"oblique") => {
+            if params.len() != 4 {
+                Err(())
+            } else {
+                let azimuth = params[2].parse::<f64>();
+
+                let altitude = params[3].parse::<f64>();
+
+                match (azimuth, altitude) {
+                    (Ok(azimuth), Ok(altitude)) => {
+                        Ok(ShadingMethod::Oblique(ObliqueShadingParams {
+                            azimuth: azimuth.to_radians(),
+                            altitude: altitude.to_radians(),
+                            clamp: true,
+                        }))
                     }
-                    Some(&"oblique") => {
-                        if params.len() != 4 {
-                            Err(())
-                        } else {
-                            let azimuth = params[2].parse::<f64>();
-
-                            let altitude = params[3].parse::<f64>();
-
-                            match (azimuth, altitude) {
-                                (Ok(azimuth), Ok(altitude)) => {
-                                    Ok(ShadingMethod::Oblique(ObliqueShadingParams {
-                                        azimuth: azimuth.to_radians(),
-                                        altitude: altitude.to_radians(),
-                                    }))
-                                }
-                                _ => Err(()),
-                            }
-                        }
+                    _ => Err(()),
+                }
+            }
+        }
+        Some(&"oblique-slope") => {
+            if params.len() != 3 {
+                Err(())
+            } else {
+                params[2].parse::<f64>().map_or(Err(()), |altitude| {
+                    Ok(ShadingMethod::ObliqueSlope(ObliqueSlopeShadingParams {
+                        altitude: altitude.to_radians(),
+                    }))
+                })
+            }
+        }
+        Some(&"multidirectional") => {
+            if params.len() < 3 {
+                Err(())
+            } else {
+                let altitude = params[2].parse::<f64>();
+
+                let azimuths: Result<Vec<_>, _> = if params.len() == 3 {
+                    Ok(DEFAULT_MULTIDIRECTIONAL_AZIMUTHS.to_vec())
+                } else {
+                    params[3..]
+                        .iter()
+                        .map(|param| param.parse::<f64>())
+                        .collect()
+                };
+
+                match (azimuths, altitude) {
+                    (Ok(azimuths), Ok(altitude)) => Ok(ShadingMethod::Multidirectional(
+                        MultidirectionalShadingParams {
+                            azimuths: azimuths
+                                .into_iter()
+                                .map(|azimuth| azimuth.to_radians())
+                                .collect(),
+                            altitude: altitude.to_radians(),
+                        },
+                    )),
+                    _ => Err(()),
+                }
+            }
+        }
+        Some(&"cast-shadow") => {
+            if params.len() != 5 {
+                Err(())
+            } else {
+                let azimuth = params[2].parse::<f64>();
+
+                let altitude = params[3].parse::<f64>();
+
+                let max_distance = params[4].parse::<f64>();
+
+                match (azimuth, altitude, max_distance) {
+                    (Ok(azimuth), Ok(altitude), Ok(max_distance)) => {
+                        Ok(ShadingMethod::CastShadow(CastShadowShadingParams {
+                            azimuth: azimuth.to_radians(),
+                            altitude: altitude.to_radians(),
+                            max_distance,
+                        }))
                     }
-                    Some(&"oblique-slope") => {
-                        if params.len() != 3 {
-                            Err(())
-                        } else {
-                            params[2].parse::<f64>().map_or(Err(()), |altitude| {
-                                Ok(ShadingMethod::ObliqueSlope(ObliqueSlopeShadingParams {
-                                    altitude: altitude.to_radians(),
-                                }))
-                            })
-                        }
+                    _ => Err(()),
+                }
+            }
+        }
+        Some(&"sky-view-factor") => {
+            if params.len() != 3 && params.len() != 4 {
+                Err(())
+            } else {
+                let max_radius = params[2].parse::<f64>();
+
+                let directions = params
+                    .get(3)
+                    .map_or(Ok(DEFAULT_SKY_VIEW_FACTOR_DIRECTIONS), |directions| {
+                        directions.parse::<usize>()
+                    });
+
+                match (max_radius, directions) {
+                    (Ok(max_radius), Ok(directions)) => {
+                        Ok(ShadingMethod::SkyViewFactor(SkyViewFactorShadingParams {
+                            directions,
+                            max_radius,
+                        }))
                     }
-                    Some(&"igor-slope") => {
-                        if params.len() != 2 {
-                            Err(())
-                        } else {
-                            Ok(ShadingMethod::IgorSlope)
-                        }
+                    _ => Err(()),
+                }
+            }
+        }
+        Some(&method @ ("positive-openness" | "negative-openness")) => {
+            if params.len() != 3 && params.len() != 4 {
+                Err(())
+            } else {
+                let max_radius = params[2].parse::<f64>();
+
+                let directions = params
+                    .get(3)
+                    .map_or(Ok(DEFAULT_OPENNESS_DIRECTIONS), |directions| {
+                        directions.parse::<usize>()
+                    });
+
+                match (max_radius, directions) {
+                    (Ok(max_radius), Ok(directions)) => {
+                        Ok(ShadingMethod::Openness(OpennessShadingParams {
+                            positive: method == "positive-openness",
+                            directions,
+                            max_radius,
+                        }))
+                    }
+                    _ => Err(()),
+                }
+            }
+        }
+        Some(&"local-relief") => {
+            if params.len() != 5 {
+                Err(())
+            } else {
+                match (
+                    params[2].parse::<f64>(),
+                    params[3].parse::<f64>(),
+                    parse_color(params[4]),
+                ) {
+                    (Ok(radius), Ok(range), Some(positive_color)) => {
+                        Ok(ShadingMethod::LocalRelief(LocalReliefShadingParams {
+                            radius,
+                            range,
+                            positive_color,
+                        }))
                     }
                     _ => Err(()),
+                }
+            }
+        }
+        Some(&"curvature") => {
+            if params.len() != 4 {
+                Err(())
+            } else {
+                let kind = match params[2] {
+                    "profile" => Ok(CurvatureKind::Profile),
+                    "plan" => Ok(CurvatureKind::Plan),
+                    _ => Err(()),
                 };
 
-                let color = u32::from_str_radix(params[1], 16);
-
-                match (color, method) {
-                    (Ok(color), Ok(method)) => Ok(Shading {
-                        color,
-                        method,
-                        brightness: 0.0,
-                        contrast: 1.0,
-                        weight: 1.0, // (i * 10 + 1) as f64 / 2.0,
-                    }),
-                    _ => Err(ParseShadingError()),
+                match (kind, params[3].parse::<f64>()) {
+                    (Ok(kind), Ok(scale)) => Ok(ShadingMethod::Curvature(CurvatureShadingParams {
+                        kind,
+                        scale,
+                    })),
+                    _ => Err(()),
+                }
+            }
+        }
+        Some(&"tri") => {
+            if params.len() != 4 {
+                Err(())
+            } else {
+                match (params[2].parse::<f64>(), params[3].parse::<f64>()) {
+                    (Ok(min), Ok(max)) => Ok(ShadingMethod::Tri(TriShadingParams { min, max })),
+                    _ => Err(()),
+                }
+            }
+        }
+        Some(&"tpi") => {
+            if params.len() != 5 {
+                Err(())
+            } else {
+                match (
+                    params[2].parse::<f64>(),
+                    params[3].parse::<f64>(),
+                    params[4].parse::<f64>(),
+                ) {
+                    (Ok(radius), Ok(min), Ok(max)) => {
+                        Ok(ShadingMethod::Tpi(TpiShadingParams { radius, min, max }))
+                    }
+                    _ => Err(()),
                 }
+            }
+        }
+        Some(&"hypsometric") => {
+            if params.len() < 3 {
+                Err(())
+            } else {
+                let ramp: Result<Vec<_>, _> = params[2..]
+                    .iter()
+                    .map(|stop| {
+                        let (elevation, color) = stop.split_once(':').ok_or(())?;
+
+                        // the alpha of the shading color applies
+                        match (elevation.parse::<f64>(), parse_color(color)) {
+                            (Ok(elevation), Some(color)) => Ok((elevation, color >> 8)),
+                            _ => Err(()),
+                        }
+                    })
+                    .collect();
+
+                ramp.map(|mut ramp| {
+                    ramp.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+                    ShadingMethod::Hypsometric(HypsometricShadingParams { ramp })
+                })
+            }
+        }
+        Some(&"igor-slope") => {
+            if params.len() != 2 {
+                Err(())
+            } else {
+                Ok(ShadingMethod::IgorSlope)
+            }
+        }
+        _ => Err(()),
+    };
+
+    let color = params
+        .get(1)
+        .and_then(|color| parse_color(color))
+        .map(u32::to_be_bytes)
+        .ok_or(());
+
+    let mut shading = match (color, method) {
+        (Ok(color), Ok(method)) => Shading {
+            color,
+            method,
+            brightness: None,
+            contrast: None,
+            weight: 1.0,
+            blend_mode: BlendMode::default(),
+        },
+        _ => return Err(ParseShadingError()),
+    };
+
+    let parse = |value: &str| value.parse::<f64>().map_err(|_| ParseShadingError());
+
+    for param in named_params {
+        let (key, value) = param.split_once('=').ok_or(ParseShadingError())?;
+
+        match (key, &mut shading.method) {
+            ("weight", _) => shading.weight = parse(value)?,
+            ("blend", _) => shading.blend_mode = value.parse()?,
+            ("contrast", _) => shading.contrast = Some(parse(value)?),
+            ("brightness", _) => shading.brightness = Some(parse(value)?),
+            ("intensity", ShadingMethod::Igor(igor)) => igor.intensity = parse(value)?,
+            ("exponent", ShadingMethod::Igor(igor)) => igor.aspect_exponent = parse(value)?,
+            ("clamp", ShadingMethod::Oblique(oblique)) => {
+                oblique.clamp = value.parse().map_err(|_| ParseShadingError())?;
+            }
+            _ => return Err(ParseShadingError()),
+        }
+    }
+
+    Ok(shading)
+}
+
+/// Packed RGBA of `RRGGBBAA`, `RRGGBB` (opaque), optionally prefixed by `#`, or of the color name
+pub fn parse_color(color: &str) -> Option<u32> {
+    let hex = color.strip_prefix('#').unwrap_or(color);
+
+    let rgba = match hex.len() {
+        8 => u32::from_str_radix(hex, 16).ok(),
+        6 => u32::from_str_radix(hex, 16).ok().map(|rgb| rgb << 8 | 0xFF),
+        _ => None,
+    };
+
+    rgba.or_else(|| {
+        Some(match color {
+            "black" => 0x000000FF,
+            "white" => 0xFFFFFFFF,
+            "gray" | "grey" => 0x808080FF,
+            "red" => 0xFF0000FF,
+            "green" => 0x008000FF,
+            "blue" => 0x0000FFFF,
+            "yellow" => 0xFFFF00FF,
+            "orange" => 0xFFA500FF,
+            "brown" => 0xA52A2AFF,
+            "transparent" => 0x00000000,
+            _ => return None,
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shading::PixelContext;
+
+    fn shading(json: &str) -> Shading {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn every_shading_method_round_trips_through_json() {
+        for method in [
+            r#"{"type": "igor", "azimuth": 315.0, "intensity": 1.5, "aspect_exponent": 2.0}"#,
+            r#"{"type": "oblique", "azimuth": 300.5, "altitude": 45.0, "clamp": false}"#,
+            r#"{"type": "igor-slope"}"#,
+            r#"{"type": "oblique-slope", "altitude": 60.0}"#,
+            r#"{"type": "multidirectional", "azimuths": [45.0, 270.0, 315.0], "altitude": 30.0}"#,
+            r#"{"type": "cast-shadow", "azimuth": 135.0, "altitude": 20.0, "max_distance": 500.0}"#,
+            r#"{"type": "sky-view-factor", "directions": 32, "max_radius": 10.0}"#,
+            r#"{"type": "openness", "positive": false, "directions": 8, "max_radius": 3.0}"#,
+            r##"{"type": "local-relief", "radius": 20.0, "range": 2.0, "positive_color": "#FF000080"}"##,
+            r#"{"type": "curvature", "kind": "plan", "scale": 50.0}"#,
+            r#"{"type": "tri", "min": 0.0, "max": 2.5}"#,
+            r#"{"type": "tpi", "radius": 30.0, "min": -5.0, "max": 5.0}"#,
+            r##"{"type": "hypsometric", "ramp": [[100.0, "#00A000"], [1000.0, "#FFFFFF"]]}"##,
+            r##"{"type": "slope-classes", "classes": [[30.0, "#FFFF00A0"], [45.0, "#8000FFA0"]]}"##,
+        ] {
+            let shading = shading(&format!(
+                r##"{{"method": {method}, "color": "#203060C0", "weight": 0.5, "blend": "multiply", "contrast": 1.2, "brightness": -0.1}}"##
+            ));
+
+            let json = serde_json::to_string(&shading).unwrap();
+
+            assert_eq!(self::shading(&json), shading, "{json}");
+
+            // the degrees and the colors are serialized as they were given
+            assert_eq!(
+                serde_json::from_str::<serde_json::Value>(&json).unwrap()["method"],
+                serde_json::from_str::<serde_json::Value>(method).unwrap(),
+                "{json}"
+            );
+        }
+    }
+
+    #[test]
+    fn angles_are_deserialized_from_degrees_and_optional_params_default() {
+        let shading =
+            shading(r##"{"method": {"type": "igor", "azimuth": 315}, "color": "#000000FF"}"##);
+
+        assert_eq!(
+            shading,
+            Shading {
+                color: [0, 0, 0, 0xFF],
+                weight: 1.0,
+                blend_mode: BlendMode::WeightedAverage,
+                brightness: None,
+                contrast: None,
+                method: ShadingMethod::Igor(IgorShadingParams {
+                    azimuth: 315f64.to_radians(),
+                    intensity: 2.0,
+                    aspect_exponent: 1.0,
+                }),
+            }
+        );
+
+        let json = serde_json::to_string(&shading).unwrap();
+
+        assert!(json.contains(r##""color":"#000000FF""##), "{json}");
+
+        assert!(json.contains(r#""azimuth":315.0"#), "{json}");
+    }
+
+    #[test]
+    fn unknown_fields_and_methods_are_rejected() {
+        for json in [
+            r##"{"method": {"type": "igor", "azimuth": 315, "altitude": 45}, "color": "#000000FF"}"##,
+            r##"{"method": {"type": "igor-slope"}, "color": "#000000FF", "opacity": 1}"##,
+            r##"{"method": {"type": "sunny"}, "color": "#000000FF"}"##,
+            r##"{"method": {"type": "igor-slope"}, "color": "#GG0000FF"}"##,
+            r#"{"method": {"type": "igor-slope"}}"#,
+        ] {
+            assert!(serde_json::from_str::<Shading>(json).is_err(), "{json}");
+        }
+    }
+
+    #[test]
+    fn shadings_are_deserialized_from_the_string_of_the_flag_and_validated() {
+        assert_eq!(
+            serde_json::from_str::<Shadings>(r#""igor,000000FF,315+oblique-slope,00000080,60""#)
+                .unwrap(),
+            serde_json::from_str::<Shadings>(
+                r##"[
+                    {"method": {"type": "igor", "azimuth": 315}, "color": "#000000FF"},
+                    {"method": {"type": "oblique-slope", "altitude": 60}, "color": "#00000080"}
+                ]"##
+            )
+            .unwrap()
+        );
+
+        for json in [
+            "[]",
+            r##"[{"method": {"type": "igor", "azimuth": 400}, "color": "#000000FF"}]"##,
+            r##"[{"method": {"type": "igor-slope"}, "color": "#000000FF", "weight": -1}]"##,
+            r##"[{"method": {"type": "igor-slope"}, "color": "#000000FF", "contrast": 0}]"##,
+            r##"[{"method": {"type": "tri", "min": 1, "max": 1}, "color": "#000000FF"}]"##,
+            r##"[{"method": {"type": "tpi", "radius": 0, "min": -5, "max": 5}, "color": "#000000FF"}]"##,
+            r##"[{"method": {"type": "sky-view-factor", "directions": 0, "max_radius": 10}, "color": "#000000FF"}]"##,
+            r##"[{"method": {"type": "local-relief", "radius": 20, "range": 0, "positive_color": "#FF0000"}, "color": "#000000FF"}]"##,
+            r##"[{"method": {"type": "sky-view-factor", "max_radius": 0}, "color": "#000000FF"}]"##,
+            r##"[{"method": {"type": "openness", "positive": true, "max_radius": -5}, "color": "#000000FF"}]"##,
+            r##"[{"method": {"type": "cast-shadow", "azimuth": 315, "altitude": 30, "max_distance": 0}, "color": "#000000FF"}]"##,
+            r##"[{"method": {"type": "multidirectional", "azimuths": [], "altitude": 45}, "color": "#000000FF"}]"##,
+        ] {
+            assert!(serde_json::from_str::<Shadings>(json).is_err(), "{json}");
+        }
+    }
+
+    #[test]
+    fn the_flag_and_the_builder_share_the_validation() {
+        for (shadings, reason) in [
+            (
+                "igor-slope,000000FF,contrast=0",
+                "must be positive and finite",
+            ),
+            ("tri,000000FF,1,1", "must differ"),
+            ("tpi,000000FF,0,-5,5", "must be positive and finite"),
+            (
+                "positive-openness,000000FF,10,0",
+                "must be positive and finite",
+            ),
+            (
+                "sky-view-factor,000000FF,inf",
+                "must be positive and finite",
+            ),
+            (
+                "cast-shadow,000000FF,315,30,-1",
+                "must be positive and finite",
+            ),
+            (
+                "oblique-slope,000000FF,60,weight=-1",
+                "must not be negative",
+            ),
+        ] {
+            let error = shadings.parse::<Shadings>().unwrap_err();
+
+            assert!(error.reason.unwrap().ends_with(reason), "{shadings}");
+        }
+
+        assert_eq!(
+            ShadingStackBuilder::new()
+                .slope(60.0, [0, 0, 0, 255])
+                .contrast(-1.0)
+                .build(),
+            Err(ShadingStackError::NotPositive {
+                index: 0,
+                name: "contrast"
             })
-            .collect();
+        );
+    }
+
+    #[test]
+    fn builder_contrast_and_brightness_apply_to_the_shadings_without_their_own() {
+        let Shadings(shadings) = ShadingStackBuilder::new()
+            .igor(315.0, [0, 0, 0], 128)
+            .shading(shading(
+                r##"{"method": {"type": "igor-slope"}, "color": "#000000FF", "contrast": 2}"##,
+            ))
+            .contrast(1.5)
+            .brightness(0.1)
+            .build()
+            .unwrap();
+
+        assert_eq!(shadings[0].color, [0, 0, 0, 128]);
+
+        assert_eq!(
+            shadings
+                .iter()
+                .map(|shading| (shading.contrast, shading.brightness))
+                .collect::<Vec<_>>(),
+            [(Some(1.5), Some(0.1)), (Some(2.0), Some(0.1))]
+        );
+    }
+
+    #[test]
+    fn non_finite_values_give_valid_json_which_is_rejected() {
+        let mut shading = shading(r##"{"method": {"type": "igor-slope"}, "color": "#000000FF"}"##);
+
+        shading.weight = f64::NAN;
+
+        let json = serde_json::to_string(&Shadings(vec![shading])).unwrap();
+
+        assert!(
+            serde_json::from_str::<serde_json::Value>(&json).is_ok(),
+            "{json}"
+        );
+
+        assert!(serde_json::from_str::<Shadings>(&json).is_err(), "{json}");
+    }
+
+    #[test]
+    fn custom_shading_is_not_serialized() {
+        struct Flat;
+
+        impl IlluminationModel for Flat {
+            fn value(&self, _aspect: f64, _slope: f64, _pixel: &PixelContext) -> f64 {
+                0.5
+            }
+        }
+
+        let mut shading = shading(r##"{"method": {"type": "igor-slope"}, "color": "#000000FF"}"##);
+
+        shading.method = ShadingMethod::Custom(CustomShadingParams(Arc::new(Flat)));
 
-        Ok(Self(shadings?))
+        assert!(serde_json::to_string(&shading).is_err());
     }
 }
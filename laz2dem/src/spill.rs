@@ -0,0 +1,196 @@
+use crate::{error::Error, options::Options, shared_types::PointWithHeight};
+use log::debug;
+use maptile::tile::Tile;
+use spade::Point2;
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    mem::size_of,
+    path::{Path, PathBuf},
+    process,
+    sync::Arc,
+};
+
+/// Bytes of a record of x, y and z as little-endian f64
+const RECORD_SIZE: usize = 24;
+
+/// Temporary directory of the points of the supertiles over their share of `--max-memory`,
+/// removed with the last supertile holding it. The directories of the runs which panicked or were killed are removed
+/// by the next run spilling to the same directory.
+pub struct SpillDir {
+    path: PathBuf,
+    /// Points of a collection of a supertile held in memory before they are spilled
+    pub threshold: usize,
+}
+
+impl SpillDir {
+    /// `None` without `--max-memory`
+    pub fn new(options: &Options, open_tiles: usize) -> Result<Option<Arc<Self>>, Error> {
+        let Some(max_memory) = options.max_memory else {
+            return Ok(None);
+        };
+
+        let max_memory = max_memory * 1024 * 1024;
+
+        let parent = options.spill_dir.clone().unwrap_or_else(env::temp_dir);
+
+        remove_stale(&parent);
+
+        let path = parent.join(format!("laz2dem-spill-{}", process::id()));
+
+        fs::create_dir_all(&path).map_err(write_error(&path))?;
+
+        let threshold =
+            (max_memory as usize / size_of::<PointWithHeight>() / open_tiles.max(1)).max(1);
+
+        debug!(
+            "SPILL to {} over {threshold} points per supertile",
+            path.display()
+        );
+
+        Ok(Some(Arc::new(Self { path, threshold })))
+    }
+}
+
+/// Removes the spill directories of the processes which are not running anymore
+#[cfg(all(feature = "native", unix))]
+fn remove_stale(parent: &Path) {
+    let Ok(entries) = fs::read_dir(parent) else {
+        return;
+    };
+
+    for entry in entries.flatten() {
+        let Some(pid) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_prefix("laz2dem-spill-"))
+            .and_then(|pid| pid.parse::<libc::pid_t>().ok())
+        else {
+            continue;
+        };
+
+        // signal 0 only checks that the process exists
+        let running = unsafe { libc::kill(pid, 0) } == 0
+            || io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH);
+
+        if !running {
+            debug!("SPILL removing stale {}", entry.path().display());
+
+            let _ = fs::remove_dir_all(entry.path());
+        }
+    }
+}
+
+/// Processes can't be checked without `libc`
+#[cfg(not(all(feature = "native", unix)))]
+fn remove_stale(_parent: &Path) {}
+
+fn write_error(path: &Path) -> impl Fn(io::Error) -> Error + '_ {
+    |source| Error::Write {
+        path: path.display().to_string(),
+        source,
+    }
+}
+
+impl Drop for SpillDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Points of a collection of a supertile moved to a file of fixed-size records
+pub struct SpillFile {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    pub count: usize,
+}
+
+impl SpillFile {
+    pub fn create(dir: &SpillDir, tile: Tile, collection: usize) -> Result<Self, Error> {
+        let path = dir
+            .path
+            .join(format!("{}-{}-{}-{collection}", tile.zoom, tile.x, tile.y));
+
+        Ok(Self {
+            writer: BufWriter::new(File::create(&path).map_err(write_error(&path))?),
+            path,
+            count: 0,
+        })
+    }
+
+    pub fn write(&mut self, points: &[PointWithHeight]) -> Result<(), Error> {
+        for point in points {
+            for value in [point.position.x, point.position.y, point.height] {
+                self.writer
+                    .write_all(&value.to_le_bytes())
+                    .map_err(write_error(&self.path))?;
+            }
+        }
+
+        self.count += points.len();
+
+        Ok(())
+    }
+
+    /// Streams the points back, appending them to the ones held in memory, and removes the file
+    pub fn read(self, points: &mut Vec<PointWithHeight>, water: bool) -> Result<(), Error> {
+        let file = self
+            .writer
+            .into_inner()
+            .map_err(|error| write_error(&self.path)(error.into_error()))?;
+
+        drop(file);
+
+        let mut reader = BufReader::new(File::open(&self.path).map_err(write_error(&self.path))?);
+
+        points.reserve(self.count);
+
+        let mut record = [0; RECORD_SIZE];
+
+        for _ in 0..self.count {
+            reader
+                .read_exact(&mut record)
+                .map_err(write_error(&self.path))?;
+
+            let value = |i: usize| f64::from_le_bytes(record[i * 8..i * 8 + 8].try_into().unwrap());
+
+            points.push(PointWithHeight {
+                position: Point2::new(value(0), value(1)),
+                height: value(2),
+                water,
+            });
+        }
+
+        let _ = fs::remove_file(&self.path);
+
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "native", unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_spill_directories_of_dead_processes_are_removed() {
+        let parent = env::temp_dir().join(format!("laz2dem-{}-stale-spill", process::id()));
+
+        // above any PID of the system
+        let stale = parent.join(format!("laz2dem-spill-{}", libc::pid_t::MAX));
+
+        let running = parent.join(format!("laz2dem-spill-{}", process::id()));
+
+        for dir in [&stale, &running] {
+            fs::create_dir_all(dir).unwrap();
+        }
+
+        remove_stale(&parent);
+
+        assert!(!stale.exists());
+
+        assert!(running.exists());
+
+        fs::remove_dir_all(parent).unwrap();
+    }
+}
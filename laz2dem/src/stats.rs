@@ -0,0 +1,142 @@
+use crate::{error::Error, terrain::Elevations};
+use maptile::{bbox::BBox, tile::Tile};
+use serde_json::json;
+use std::{fs, path::Path, time::Duration};
+
+/// Statistics of the rendered supertile, including its buffer
+pub struct TileStats {
+    pub tile: Tile,
+    /// Ground points used for the interpolation
+    pub points: usize,
+    /// Ground points dropped by `--max-points-per-cell`
+    pub thinned_points: usize,
+    /// Points spilled to the files of `--max-memory`
+    pub spilled_points: usize,
+    /// Ground points per square meter of EPSG:3857
+    pub density: f64,
+    /// Min, max and mean elevation; `None` if no pixel has data
+    pub elevations: Option<(f64, f64, f64)>,
+    pub nodata_pixels: usize,
+    pub interpolation: Duration,
+    pub shading: Duration,
+}
+
+impl TileStats {
+    pub fn new(
+        tile: Tile,
+        bbox: &BBox,
+        points: usize,
+        thinned_points: usize,
+        elevations: &Elevations,
+        interpolation: Duration,
+        shading: Duration,
+    ) -> Self {
+        let mut nodata_pixels = 0;
+
+        let (mut min, mut max, mut sum) = (f64::INFINITY, f64::NEG_INFINITY, 0.0);
+
+        for index in 0..elevations.len() {
+            let elevation = elevations.get(index);
+
+            if elevation.is_nan() {
+                nodata_pixels += 1;
+            } else {
+                min = min.min(elevation);
+
+                max = max.max(elevation);
+
+                sum += elevation;
+            }
+        }
+
+        let count = elevations.len() - nodata_pixels;
+
+        Self {
+            tile,
+            points,
+            thinned_points,
+            spilled_points: 0,
+            density: points as f64 / (bbox.width() * bbox.height()),
+            elevations: (count > 0).then(|| (min, max, sum / count as f64)),
+            nodata_pixels,
+            interpolation,
+            shading,
+        }
+    }
+}
+
+/// Writes JSON array of the statistics; non-finite numbers are written as `null`
+pub fn write_stats(path: &Path, stats: &[TileStats]) -> Result<(), Error> {
+    let stats: Vec<_> = stats
+        .iter()
+        .map(|stats| {
+            let (min, max, mean) = stats
+                .elevations
+                .map_or((None, None, None), |(min, max, mean)| {
+                    (Some(min), Some(max), Some(mean))
+                });
+
+            json!({
+                "z": stats.tile.zoom,
+                "x": stats.tile.x,
+                "y": stats.tile.y,
+                "points": stats.points,
+                "thinned_points": stats.thinned_points,
+                "spilled_points": stats.spilled_points,
+                "density": stats.density,
+                "min_elevation": min,
+                "max_elevation": max,
+                "mean_elevation": mean,
+                "nodata_pixels": stats.nodata_pixels,
+                "interpolation_seconds": stats.interpolation.as_secs_f64(),
+                "shading_seconds": stats.shading.as_secs_f64(),
+            })
+        })
+        .collect();
+
+    // numbers and strings only
+    let json = serde_json::to_string_pretty(&stats).expect("stats are serializable");
+
+    fs::write(path, json + "\n").map_err(|source| Error::Write {
+        path: path.display().to_string(),
+        source,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[test]
+    fn stats_of_empty_supertile_are_valid_json() {
+        let path = std::env::temp_dir().join(format!("laz2dem-{}-stats.json", std::process::id()));
+
+        // no points in an empty bbox
+        let stats = TileStats::new(
+            Tile {
+                zoom: 14,
+                x: 9000,
+                y: 5700,
+            },
+            &BBox::new(0.0, 0.0, 0.0, 0.0),
+            0,
+            0,
+            &Elevations::F64(vec![f64::NAN; 4]),
+            Duration::from_millis(1500),
+            Duration::ZERO,
+        );
+
+        write_stats(&path, &[stats]).unwrap();
+
+        let json: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(json[0]["x"], 9000);
+        assert_eq!(json[0]["nodata_pixels"], 4);
+        assert_eq!(json[0]["interpolation_seconds"], 1.5);
+        assert!(json[0]["density"].is_null());
+        assert!(json[0]["mean_elevation"].is_null());
+    }
+}
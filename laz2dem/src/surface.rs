@@ -0,0 +1,551 @@
+use crate::{
+    filters::gaussian_blur,
+    options::{Interpolation, Options},
+    shared_types::PointWithHeight,
+    terrain::Elevations,
+    timings::{Phase, TIMINGS},
+    water::{MIN_WATER_POINTS, flatten_water},
+};
+use maptile::bbox::BBox;
+use rayon::slice::ParallelSliceMut;
+use spade::{
+    DelaunayTriangulation, FloatTriangulation, Point2, PositionInTriangulation, Triangulation,
+};
+use std::{collections::HashMap, time::Instant};
+
+/// Keeps only the highest point of every pixel, moved to the pixel center,
+/// so that the interpolation gives the maximum to the pixels with points and fills only the empty ones
+pub fn highest_per_pixel(
+    points: Vec<PointWithHeight>,
+    bbox: &BBox,
+    cols: u32,
+    rows: u32,
+) -> Vec<PointWithHeight> {
+    let (cols, rows) = (cols as usize, rows as usize);
+
+    let mut highest = vec![f64::NAN; rows * cols];
+
+    for point in points {
+        let x = ((point.position.x - bbox.min_x) / bbox.width() * cols as f64).floor();
+
+        let y = ((point.position.y - bbox.min_y) / bbox.height() * rows as f64).floor();
+
+        // points on the max edges belong to the last pixel
+        let index =
+            (y.max(0.0) as usize).min(rows - 1) * cols + (x.max(0.0) as usize).min(cols - 1);
+
+        if highest[index].is_nan() || point.height > highest[index] {
+            highest[index] = point.height;
+        }
+    }
+
+    highest
+        .into_iter()
+        .enumerate()
+        .filter(|(_, height)| !height.is_nan())
+        .map(|(index, height)| PointWithHeight {
+            position: Point2::new(
+                bbox.min_x + ((index % cols) as f64 + 0.5) * bbox.width() / cols as f64,
+                bbox.min_y + ((index / cols) as f64 + 0.5) * bbox.height() / rows as f64,
+            ),
+            height,
+            water: false,
+        })
+        .collect()
+}
+
+/// Points bucketed by the cells of the search radius
+struct Buckets {
+    radius: f64,
+    points: Vec<PointWithHeight>,
+    cells: HashMap<(i64, i64), Vec<usize>>,
+}
+
+impl Buckets {
+    fn new(points: Vec<PointWithHeight>, radius: f64) -> Self {
+        let mut cells = HashMap::<_, Vec<usize>>::new();
+
+        for (i, point) in points.iter().enumerate() {
+            cells
+                .entry(Self::cell(radius, point.position))
+                .or_default()
+                .push(i);
+        }
+
+        Self {
+            radius,
+            points,
+            cells,
+        }
+    }
+
+    fn cell(radius: f64, position: Point2<f64>) -> (i64, i64) {
+        (
+            (position.x / radius).floor() as i64,
+            (position.y / radius).floor() as i64,
+        )
+    }
+
+    /// Points within the radius with their squared distances
+    fn neighbours(&self, position: Point2<f64>) -> Vec<(f64, &PointWithHeight)> {
+        let (cx, cy) = Self::cell(self.radius, position);
+
+        let radius2 = self.radius * self.radius;
+
+        (cx - 1..=cx + 1)
+            .flat_map(|x| (cy - 1..=cy + 1).map(move |y| (x, y)))
+            .filter_map(|key| self.cells.get(&key))
+            .flatten()
+            .map(|&i| &self.points[i])
+            .map(|point| {
+                let dx = point.position.x - position.x;
+                let dy = point.position.y - position.y;
+
+                (dx * dx + dy * dy, point)
+            })
+            .filter(|(distance2, _)| *distance2 <= radius2)
+            .collect()
+    }
+
+    /// `idw` or `nearest` interpolation of the value of the points, `None` if there is none within the radius
+    fn interpolate(
+        &self,
+        options: &Options,
+        position: Point2<f64>,
+        value: fn(&PointWithHeight) -> f64,
+    ) -> Option<f64> {
+        let mut neighbours = self.neighbours(position);
+
+        if options.interpolation == Interpolation::Nearest {
+            return neighbours
+                .into_iter()
+                .min_by(|a, b| a.0.total_cmp(&b.0))
+                .map(|(_, point)| value(point));
+        }
+
+        if neighbours.len() > options.idw_max_points {
+            neighbours
+                .select_nth_unstable_by(options.idw_max_points - 1, |a, b| a.0.total_cmp(&b.0));
+
+            neighbours.truncate(options.idw_max_points);
+        }
+
+        let (mut sum, mut weights) = (0.0, 0.0);
+
+        for (distance2, point) in neighbours {
+            if distance2 == 0.0 {
+                return Some(value(point));
+            }
+
+            let weight = distance2.powf(-options.idw_power / 2.0);
+
+            sum += weight * value(point);
+
+            weights += weight;
+        }
+
+        (weights > 0.0).then(|| sum / weights)
+    }
+}
+
+/// Interpolation of the points at the pixel centers by `--interpolation` followed by the optional smoothing
+/// and flattening of the water bodies of the water points
+pub fn interpolate(
+    options: &Options,
+    bbox: &BBox,
+    cols: u32,
+    rows: u32,
+    points: Vec<PointWithHeight>,
+    water_points: &[PointWithHeight],
+) -> Elevations<'static> {
+    let points = points.into_iter().chain(water_points.iter().copied());
+
+    match options.interpolation {
+        Interpolation::NaturalNeighbor | Interpolation::Linear => {
+            let mut points: Vec<_> = points.collect();
+
+            // the stable sort along the Hilbert curve, with the points of the same position next to each other,
+            // keeps them in the order of the water points after the ground ones, both sorted by height
+            points.par_sort_by_cached_key(|point| {
+                (
+                    hilbert_index(bbox, point.position),
+                    point.position.x.to_bits(),
+                    point.position.y.to_bits(),
+                )
+            });
+
+            // only the last of the points of the same position is kept, as by the insertion one by one
+            points.dedup_by(|point, kept| {
+                let same = point.position == kept.position;
+
+                if same {
+                    *kept = *point;
+                }
+
+                same
+            });
+
+            let started = Instant::now();
+
+            let triangulation =
+                DelaunayTriangulation::<PointWithHeight>::bulk_load(points).unwrap();
+
+            TIMINGS.record(Phase::Triangulate, started.elapsed());
+
+            // both give `None` outside the convex hull
+            if options.interpolation == Interpolation::Linear {
+                let barycentric = &triangulation.barycentric();
+
+                sample_pixels(options, bbox, cols, rows, water_points, |point, value| {
+                    if in_gap(&triangulation, options.max_edge_length, point) {
+                        None
+                    } else {
+                        barycentric.interpolate(|v| value(v.data()), point)
+                    }
+                })
+            } else {
+                let natural_neighbor = &triangulation.natural_neighbor();
+
+                sample_pixels(options, bbox, cols, rows, water_points, |point, value| {
+                    if in_gap(&triangulation, options.max_edge_length, point) {
+                        None
+                    } else {
+                        natural_neighbor.interpolate(|v| value(v.data()), point)
+                    }
+                })
+            }
+        }
+        Interpolation::Idw | Interpolation::Nearest => {
+            let buckets = Buckets::new(points.collect(), options.interpolation_radius);
+
+            sample_pixels(options, bbox, cols, rows, water_points, |point, value| {
+                buckets.interpolate(options, point, value)
+            })
+        }
+    }
+}
+
+/// Index of the cell of the position on the Hilbert curve through the 2^16 x 2^16 grid of the bbox
+fn hilbert_index(bbox: &BBox, position: Point2<f64>) -> u64 {
+    const N: u32 = 1 << 16;
+
+    let cell = |value: f64, min: f64, size: f64| {
+        ((value - min) / size * f64::from(N)).clamp(0.0, f64::from(N - 1)) as u32
+    };
+
+    let mut x = cell(position.x, bbox.min_x, bbox.width());
+
+    let mut y = cell(position.y, bbox.min_y, bbox.height());
+
+    let mut index = 0;
+
+    let mut s = N / 2;
+
+    while s > 0 {
+        let rx = u32::from(x & s > 0);
+
+        let ry = u32::from(y & s > 0);
+
+        index += u64::from(s) * u64::from(s) * u64::from((3 * rx) ^ ry);
+
+        // rotation of the quadrant
+        if ry == 0 {
+            if rx == 1 {
+                x = N - 1 - x;
+
+                y = N - 1 - y;
+            }
+
+            (x, y) = (y, x);
+        }
+
+        s /= 2;
+    }
+
+    index
+}
+
+/// Whether the point is in a triangle with an edge longer than `max_edge_length`
+fn in_gap(
+    triangulation: &DelaunayTriangulation<PointWithHeight>,
+    max_edge_length: Option<f64>,
+    point: Point2<f64>,
+) -> bool {
+    let Some(max_edge_length) = max_edge_length else {
+        return false;
+    };
+
+    let max_length2 = max_edge_length * max_edge_length;
+
+    match triangulation.locate(point) {
+        PositionInTriangulation::OnFace(face) => triangulation
+            .face(face)
+            .adjacent_edges()
+            .iter()
+            .any(|edge| edge.length_2() > max_length2),
+        PositionInTriangulation::OnEdge(edge) => {
+            triangulation.directed_edge(edge).length_2() > max_length2
+        }
+        _ => false,
+    }
+}
+
+/// Elevations of the interpolation of the value of the points at the pixel centers
+fn sample_pixels(
+    options: &Options,
+    bbox: &BBox,
+    cols: u32,
+    rows: u32,
+    water_points: &[PointWithHeight],
+    interpolate: impl Fn(Point2<f64>, fn(&PointWithHeight) -> f64) -> Option<f64>,
+) -> Elevations<'static> {
+    // sample pixel centers
+    let pixel_centers = || {
+        (0..rows).flat_map(move |y| {
+            let cy = bbox.min_y + (y as f64 + 0.5) * bbox.height() / rows as f64;
+
+            (0..cols).map(move |x| {
+                let cx = bbox.min_x + (x as f64 + 0.5) * bbox.width() / cols as f64;
+
+                Point2::new(cx, cy)
+            })
+        })
+    };
+
+    let mut elevations = Elevations::new(
+        options.elevation_precision,
+        pixel_centers().map(|point| interpolate(point, |point| point.height).unwrap_or(f64::NAN)),
+    );
+
+    if options.smooth_sigma > 0.0 {
+        let smoothed = gaussian_blur(
+            |index| elevations.get(index),
+            rows as usize,
+            cols as usize,
+            options.smooth_sigma_pixels(),
+        );
+
+        // keep missing elevations missing
+        for (index, smoothed) in smoothed.into_iter().enumerate() {
+            if !elevations.get(index).is_nan() {
+                elevations.set(index, smoothed);
+            }
+        }
+    }
+
+    // after smoothing to keep the water flat
+    if options.flatten_water && water_points.len() >= MIN_WATER_POINTS {
+        // pixels where the water points prevail over the ground ones
+        let water: Vec<_> = pixel_centers()
+            .map(|point| {
+                interpolate(point, |point| if point.water { 1.0 } else { 0.0 })
+                    .is_some_and(|water| water >= 0.5)
+            })
+            .collect();
+
+        flatten_water(
+            &mut elevations,
+            &water,
+            rows as usize,
+            cols as usize,
+            bbox,
+            water_points,
+        );
+    }
+
+    elevations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared_types::sort_points;
+
+    /// Elevations of the square pixels within the bbox of 200 m interpolated by the method
+    fn elevations(interpolation: Interpolation, points: &[PointWithHeight], size: u32) -> Vec<f64> {
+        let options = Options {
+            interpolation,
+            interpolation_radius: 15.0,
+            ..Options::default()
+        };
+
+        let elevations = interpolate(
+            &options,
+            &BBox::new(0.0, 0.0, 200.0, 200.0),
+            size,
+            size,
+            points.to_vec(),
+            &[],
+        );
+
+        (0..elevations.len()).map(|i| elevations.get(i)).collect()
+    }
+
+    fn point(x: f64, y: f64, height: f64) -> PointWithHeight {
+        PointWithHeight {
+            position: Point2::new(x, y),
+            height,
+            water: false,
+        }
+    }
+
+    #[test]
+    fn hilbert_curve_goes_through_the_neighbor_cells() {
+        let bbox = BBox::new(0.0, 0.0, 200.0, 200.0);
+
+        // centers of the 8 x 8 cells of the bbox along the curve
+        let mut cells: Vec<_> = (0..64)
+            .map(|cell: u32| (cell % 8, cell / 8))
+            .map(|(x, y)| {
+                let position = Point2::new(f64::from(x) * 25.0 + 12.5, f64::from(y) * 25.0 + 12.5);
+
+                (hilbert_index(&bbox, position), x, y)
+            })
+            .collect();
+
+        cells.sort_unstable();
+
+        assert_eq!((cells[0].1, cells[0].2), (0, 0));
+
+        assert_eq!((cells[63].1, cells[63].2), (7, 0));
+
+        for pair in cells.windows(2) {
+            let [(index, x, y), (next_index, next_x, next_y)] = pair else {
+                unreachable!()
+            };
+
+            assert!(index < next_index);
+
+            assert_eq!(x.abs_diff(*next_x) + y.abs_diff(*next_y), 1, "{pair:?}");
+        }
+    }
+
+    #[test]
+    fn sorted_points_are_triangulated_the_same_in_any_order_of_reading() {
+        // collinear and duplicate points of the degenerate configurations
+        let mut points: Vec<_> = (0..200)
+            .map(|i| {
+                let i = f64::from(i);
+
+                point(
+                    (i * 7.0) % 200.0,
+                    (i * 3.0).min(199.0),
+                    (i * 0.37).sin() * 10.0,
+                )
+            })
+            .chain((0..20).map(|i| point(100.0, f64::from(i) * 10.0, f64::from(i))))
+            .chain((0..20).map(|i| point(50.0, 50.0, f64::from(i))))
+            .collect();
+
+        let mut reversed = points.clone();
+
+        reversed.reverse();
+
+        for points in [&mut points, &mut reversed] {
+            sort_points(points);
+        }
+
+        for interpolation in [Interpolation::Linear, Interpolation::NaturalNeighbor] {
+            let elevations = elevations(interpolation, &points, 20);
+
+            assert_eq!(
+                elevations.iter().map(|e| e.to_bits()).collect::<Vec<_>>(),
+                self::elevations(interpolation, &reversed, 20)
+                    .iter()
+                    .map(|e| e.to_bits())
+                    .collect::<Vec<_>>(),
+            );
+        }
+    }
+
+    #[test]
+    fn only_the_triangulation_fills_the_gaps_beyond_the_radius() {
+        // points at the pixel centers of the 6 left columns and of the right one, a gap of 130 m between them
+        let points: Vec<_> = (0..20)
+            .flat_map(|y| {
+                (0..6)
+                    .chain([19])
+                    .map(move |x| point(x as f64 * 10.0 + 5.0, y as f64 * 10.0 + 5.0, x as f64))
+            })
+            .collect();
+
+        // pixel at the middle of the gap
+        let gap = 10 * 20 + 12;
+
+        for interpolation in [Interpolation::NaturalNeighbor, Interpolation::Linear] {
+            let elevations = elevations(interpolation, &points, 20);
+
+            assert!(elevations.iter().all(|elevation| elevation.is_finite()));
+
+            assert!(
+                elevations[gap] > 5.0 && elevations[gap] < 19.0,
+                "{interpolation}"
+            );
+        }
+
+        for interpolation in [Interpolation::Idw, Interpolation::Nearest] {
+            let elevations = elevations(interpolation, &points, 20);
+
+            for x in 7..18 {
+                assert!(elevations[10 * 20 + x].is_nan(), "{interpolation} {x}");
+            }
+
+            // pixels with a point get its elevation
+            assert_eq!(elevations[10 * 20 + 3], 3.0, "{interpolation}");
+
+            assert_eq!(elevations[10 * 20 + 19], 19.0, "{interpolation}");
+        }
+    }
+
+    #[test]
+    fn natural_neighbor_interpolation_reduces_the_facets_and_does_not_extrapolate() {
+        let hill =
+            |x: f64, y: f64| 20.0 * (-((x - 100.0).powi(2) + (y - 100.0).powi(2)) / 5000.0).exp();
+
+        // sparse points of a linear congruential generator within the circle of 90 m
+        let mut seed = 1u64;
+
+        let mut random = || {
+            seed = seed.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1);
+
+            (seed >> 11) as f64 / (1u64 << 53) as f64 * 180.0 + 10.0
+        };
+
+        let points: Vec<_> = (0..2000)
+            .map(|_| (random(), random()))
+            .filter(|(x, y)| (x - 100.0).hypot(y - 100.0) < 90.0)
+            .take(150)
+            .map(|(x, y)| point(x, y, hill(x, y)))
+            .collect();
+
+        // sum of the squared second differences within the hull
+        let energy = |elevations: &[f64]| {
+            let at = |x: usize, y: usize| elevations[y * 100 + x];
+
+            (1..99)
+                .flat_map(|y| (1..99).map(move |x| (x, y)))
+                .map(|(x, y)| {
+                    (at(x - 1, y) + at(x + 1, y) - 2.0 * at(x, y)).powi(2)
+                        + (at(x, y - 1) + at(x, y + 1) - 2.0 * at(x, y)).powi(2)
+                })
+                .filter(|energy| energy.is_finite())
+                .sum::<f64>()
+        };
+
+        let linear = elevations(Interpolation::Linear, &points, 100);
+
+        let natural_neighbor = elevations(Interpolation::NaturalNeighbor, &points, 100);
+
+        assert!(
+            energy(&natural_neighbor) < energy(&linear) * 0.8,
+            "{} {}",
+            energy(&natural_neighbor),
+            energy(&linear)
+        );
+
+        // corners are out of the convex hull
+        for elevations in [&linear, &natural_neighbor] {
+            assert!(elevations[0].is_nan() && elevations[100 * 100 - 1].is_nan());
+        }
+    }
+}
@@ -0,0 +1,454 @@
+use crate::{
+    filters::{for_each_row, mean_filter},
+    options::ElevationPrecision,
+    shared_types::{
+        CastShadowShadingParams, LocalReliefShadingParams, OpennessShadingParams, Shading,
+        ShadingMethod, SkyViewFactorShadingParams, TpiShadingParams,
+    },
+};
+use maptile::constants::WEB_MERCATOR_EXTENT;
+use std::f64::consts::{FRAC_PI_2, PI, TAU};
+
+const EARTH_RADIUS: f64 = WEB_MERCATOR_EXTENT / PI;
+
+/// Elevations in meters stored in the chosen precision, NaN marks missing data
+pub enum Elevations<'a> {
+    F32(Vec<f32>),
+    F64(Vec<f64>),
+    /// Borrowed rows of `cols` elevations from the north, e.g. of a NumPy array, copied once set
+    F64FromNorth {
+        elevations: &'a [f64],
+        cols: usize,
+    },
+}
+
+impl Elevations<'_> {
+    pub fn new(precision: ElevationPrecision, elevations: impl Iterator<Item = f64>) -> Self {
+        match precision {
+            ElevationPrecision::F32 => {
+                Self::F32(elevations.map(|elevation| elevation as f32).collect())
+            }
+            ElevationPrecision::F64 => Self::F64(elevations.collect()),
+        }
+    }
+
+    pub fn get(&self, index: usize) -> f64 {
+        match self {
+            Self::F32(elevations) => f64::from(elevations[index]),
+            Self::F64(elevations) => elevations[index],
+            &Self::F64FromNorth { elevations, cols } => {
+                elevations[elevations.len() - (index / cols + 1) * cols + index % cols]
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Self::F32(elevations) => elevations.len(),
+            Self::F64(elevations) => elevations.len(),
+            Self::F64FromNorth { elevations, .. } => elevations.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn set(&mut self, index: usize, elevation: f64) {
+        match self {
+            Self::F32(elevations) => elevations[index] = elevation as f32,
+            Self::F64(elevations) => elevations[index] = elevation,
+            &mut Self::F64FromNorth { elevations, cols } => {
+                *self = Self::F64(elevations.chunks(cols).rev().flatten().copied().collect());
+
+                self.set(index, elevation);
+            }
+        }
+    }
+}
+
+/// Elevation grid of a buffered supertile; row 0 is the southmost one
+pub struct Grid<'a> {
+    pub elevations: &'a Elevations<'a>,
+    pub rows: usize,
+    pub cols: usize,
+    /// Size of a pixel in meters of the projection
+    pub cell_size: f64,
+    /// Web Mercator y of the centers of row 0 to scale the cell size to the ground by the latitude
+    pub mercator_y: Option<f64>,
+}
+
+impl Grid<'_> {
+    /// Elevation of the pixel at the index (`y * cols + x`) in meters
+    pub fn elevation(&self, index: usize) -> f64 {
+        self.elevations.get(index)
+    }
+
+    /// Ground size of a pixel of the row in meters
+    pub fn ground_cell_size(&self, y: usize) -> f64 {
+        match self.mercator_y {
+            Some(mercator_y) => {
+                self.cell_size / ((mercator_y + y as f64 * self.cell_size) / EARTH_RADIUS).cosh()
+            }
+            None => self.cell_size,
+        }
+    }
+
+    /// Minimal and maximal valid elevation, if any
+    pub fn elevation_range(&self) -> Option<(f64, f64)> {
+        (0..self.rows * self.cols)
+            .map(|index| self.elevation(index))
+            .filter(|elevation| !elevation.is_nan())
+            .fold(None, |range, elevation| match range {
+                None => Some((elevation, elevation)),
+                Some((min, max)) => Some((f64::min(min, elevation), f64::max(max, elevation))),
+            })
+    }
+
+    /// Samples elevations along a ray from the pixel towards the azimuth (clockwise from north).
+    /// Yields distance in pixels and elevation; the ray stops at the edge of the grid.
+    fn ray(
+        &self,
+        x: usize,
+        y: usize,
+        azimuth: f64,
+        max_steps: usize,
+    ) -> impl Iterator<Item = (f64, f64)> + '_ {
+        let (dx, dy) = azimuth.sin_cos();
+
+        (1..=max_steps)
+            .map(move |step| {
+                let distance = step as f64;
+
+                (
+                    distance,
+                    (x as f64 + dx * distance).round(),
+                    (y as f64 + dy * distance).round(),
+                )
+            })
+            .take_while(|&(_, sx, sy)| {
+                sx >= 0.0 && sy >= 0.0 && sx < self.cols as f64 && sy < self.rows as f64
+            })
+            .map(|(distance, sx, sy)| {
+                (
+                    distance,
+                    self.elevation(sy as usize * self.cols + sx as usize),
+                )
+            })
+            .filter(|(_, elevation)| !elevation.is_nan())
+    }
+
+    /// Elevation angle of the horizon seen from the pixel towards the azimuth.
+    /// Negative z-factor turns the terrain upside down, giving the depression angle of the nadir horizon.
+    /// Returns 0.0 if the ray finds no valid elevation.
+    fn horizon_angle(
+        &self,
+        x: usize,
+        y: usize,
+        azimuth: f64,
+        max_distance: f64,
+        z_factor: f64,
+    ) -> f64 {
+        let elevation = self.elevation(y * self.cols + x);
+
+        let cell_size = self.ground_cell_size(y);
+
+        let max_steps = (max_distance / cell_size).ceil() as usize;
+
+        self.ray(x, y, azimuth, max_steps)
+            .map(|(distance, sample)| (sample - elevation) * z_factor / (distance * cell_size))
+            .reduce(f64::max)
+            .map_or(0.0, f64::atan)
+    }
+}
+
+/// Rasters of shading methods which need more than the 3x3 neighbourhood of the pixel
+pub struct Rasters(Vec<Option<Vec<f64>>>);
+
+impl Rasters {
+    pub fn new(grid: &Grid, z_factor: f64, shadings: &[Shading]) -> Self {
+        Self(
+            shadings
+                .iter()
+                .map(|shading| match &shading.method {
+                    ShadingMethod::CastShadow(params) => Some(cast_shadow(grid, z_factor, params)),
+                    ShadingMethod::SkyViewFactor(params) => {
+                        Some(sky_view_factor(grid, z_factor, params))
+                    }
+                    ShadingMethod::Openness(params) => Some(openness(grid, z_factor, params)),
+                    ShadingMethod::LocalRelief(params) => Some(local_relief(grid, params)),
+                    ShadingMethod::Tri(_) => Some(tri(grid)),
+                    ShadingMethod::Tpi(params) => Some(tpi(grid, params)),
+                    _ => None,
+                })
+                .collect(),
+        )
+    }
+
+    pub fn get(&self, shading: usize, index: usize) -> f64 {
+        self.0[shading].as_ref().expect("raster is not computed")[index]
+    }
+}
+
+fn compute_raster<F>(grid: &Grid, compute_value: F) -> Vec<f64>
+where
+    F: Fn(usize, usize) -> f64 + Sync,
+{
+    let mut raster = vec![0.0; grid.rows * grid.cols];
+
+    for_each_row(&mut raster, grid.cols, |(y, row)| {
+        for (x, value) in row.iter_mut().enumerate() {
+            *value = compute_value(x, y);
+        }
+    });
+
+    raster
+}
+
+/// 1.0 for pixels occluded from the sun, 0.0 otherwise
+fn cast_shadow(
+    grid: &Grid,
+    z_factor: f64,
+    CastShadowShadingParams {
+        azimuth,
+        altitude,
+        max_distance,
+    }: &CastShadowShadingParams,
+) -> Vec<f64> {
+    let altitude_tan = altitude.tan();
+
+    compute_raster(grid, |x, y| {
+        let elevation = grid.elevation(y * grid.cols + x);
+
+        if elevation.is_nan() {
+            return 0.0;
+        }
+
+        let cell_size = grid.ground_cell_size(y);
+
+        let occluded = grid
+            .ray(x, y, *azimuth, (max_distance / cell_size).ceil() as usize)
+            .any(|(distance, sample)| {
+                (sample - elevation) * z_factor / (distance * cell_size) > altitude_tan
+            });
+
+        if occluded { 1.0 } else { 0.0 }
+    })
+}
+
+/// 1.0 minus the sky-view factor, i.e. the portion of the sky hidden by the surrounding terrain.
+/// Rays truncated by the edge of the grid contribute only the horizon found so far.
+fn sky_view_factor(
+    grid: &Grid,
+    z_factor: f64,
+    SkyViewFactorShadingParams {
+        directions,
+        max_radius,
+    }: &SkyViewFactorShadingParams,
+) -> Vec<f64> {
+    let azimuths = azimuths(*directions);
+
+    compute_raster(grid, |x, y| {
+        if grid.elevation(y * grid.cols + x).is_nan() {
+            return 0.0;
+        }
+
+        azimuths
+            .iter()
+            .map(|azimuth| {
+                grid.horizon_angle(x, y, *azimuth, *max_radius, z_factor)
+                    .max(0.0)
+                    .sin()
+            })
+            .sum::<f64>()
+            / azimuths.len() as f64
+    })
+}
+
+/// Deficit of the mean openness from the one of flat terrain (90°) relative to it, clamped to 0.0 - 1.0.
+/// Concave relief for positive openness, convex relief for the negative one.
+fn openness(
+    grid: &Grid,
+    z_factor: f64,
+    OpennessShadingParams {
+        positive,
+        directions,
+        max_radius,
+    }: &OpennessShadingParams,
+) -> Vec<f64> {
+    let azimuths = azimuths(*directions);
+
+    let z_factor = if *positive { z_factor } else { -z_factor };
+
+    compute_raster(grid, |x, y| {
+        if grid.elevation(y * grid.cols + x).is_nan() {
+            return 0.0;
+        }
+
+        let mean_horizon_angle = azimuths
+            .iter()
+            .map(|azimuth| grid.horizon_angle(x, y, *azimuth, *max_radius, z_factor))
+            .sum::<f64>()
+            / azimuths.len() as f64;
+
+        (mean_horizon_angle / FRAC_PI_2).clamp(0.0, 1.0)
+    })
+}
+
+/// Elevation minus its mean in the radius (local relief model) in meters; NaN where elevation is missing
+fn local_relief(
+    grid: &Grid,
+    LocalReliefShadingParams { radius, .. }: &LocalReliefShadingParams,
+) -> Vec<f64> {
+    elevation_minus_mean(grid, *radius)
+}
+
+/// Topographic position index: elevation minus its mean in the radius in meters; NaN where elevation is missing
+fn tpi(grid: &Grid, TpiShadingParams { radius, .. }: &TpiShadingParams) -> Vec<f64> {
+    elevation_minus_mean(grid, *radius)
+}
+
+fn elevation_minus_mean(grid: &Grid, radius: f64) -> Vec<f64> {
+    let cell_size = grid.ground_cell_size(grid.rows / 2);
+
+    let mean = mean_filter(grid, (radius / cell_size).round() as usize);
+
+    mean.into_iter()
+        .enumerate()
+        .map(|(index, mean)| grid.elevation(index) - mean)
+        .collect()
+}
+
+/// Terrain ruggedness index: mean absolute elevation difference to the valid neighbours in meters;
+/// NaN where elevation is missing
+fn tri(grid: &Grid) -> Vec<f64> {
+    compute_raster(grid, |x, y| {
+        let elevation = grid.elevation(y * grid.cols + x);
+
+        let (sum, count) = (y.saturating_sub(1)..(y + 2).min(grid.rows))
+            .flat_map(|ny| (x.saturating_sub(1)..(x + 2).min(grid.cols)).map(move |nx| (nx, ny)))
+            .filter(|&neighbour| neighbour != (x, y))
+            .map(|(nx, ny)| grid.elevation(ny * grid.cols + nx))
+            .filter(|neighbour| !neighbour.is_nan())
+            .fold((0.0, 0), |(sum, count), neighbour| {
+                (sum + (neighbour - elevation).abs(), count + 1)
+            });
+
+        if count > 0 {
+            sum / f64::from(count)
+        } else {
+            f64::NAN
+        }
+    })
+}
+
+fn azimuths(directions: usize) -> Vec<f64> {
+    (0..directions)
+        .map(|i| i as f64 * TAU / directions as f64)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Elevations of the function of the column and the row of the square grid
+    fn elevations(size: usize, elevation: impl Fn(f64, f64) -> f64) -> Elevations<'static> {
+        Elevations::F64(
+            (0..size * size)
+                .map(|index| elevation((index % size) as f64, (index / size) as f64))
+                .collect(),
+        )
+    }
+
+    fn square_grid<'a>(elevations: &'a Elevations<'a>) -> Grid<'a> {
+        let size = (elevations.len() as f64).sqrt() as usize;
+
+        Grid {
+            elevations,
+            rows: size,
+            cols: size,
+            cell_size: 1.0,
+            mercator_y: None,
+        }
+    }
+
+    #[test]
+    fn rows_from_the_north_are_borrowed_until_set() {
+        // 2 rows of 3 elevations from the north
+        let north = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0];
+
+        let mut elevations = Elevations::F64FromNorth {
+            elevations: &north,
+            cols: 3,
+        };
+
+        assert_eq!(
+            (0..6)
+                .map(|index| elevations.get(index))
+                .collect::<Vec<_>>(),
+            [4.0, 5.0, 6.0, 1.0, 2.0, 3.0]
+        );
+
+        elevations.set(1, 0.0);
+
+        assert!(matches!(
+            elevations,
+            Elevations::F64(ref elevations) if elevations == &[4.0, 0.0, 6.0, 1.0, 2.0, 3.0]
+        ));
+    }
+
+    #[test]
+    fn tpi_is_positive_on_the_summit_and_negative_at_the_base_of_a_gaussian_hill() {
+        let elevations = elevations(61, |x, y| {
+            100.0 * (-((x - 30.0).powi(2) + (y - 30.0).powi(2)) / (2.0 * 8.0 * 8.0)).exp()
+        });
+
+        let grid = square_grid(&elevations);
+
+        let tpi = tpi(
+            &grid,
+            &TpiShadingParams {
+                radius: 4.0,
+                min: 0.0,
+                max: 1.0,
+            },
+        );
+
+        // summit and 2 sigmas from it, past the inflection at 1 sigma
+        assert!(tpi[30 * 61 + 30] > 5.0);
+        assert!(tpi[30 * 61 + 46] < 0.0);
+        assert!(tpi[46 * 61 + 30] < 0.0);
+    }
+
+    #[test]
+    fn tri_of_a_plane_is_the_mean_difference_to_the_neighbours() {
+        let elevations = elevations(5, |x, _| 2.0 * x);
+
+        let ruggedness = tri(&square_grid(&elevations));
+
+        // 3 neighbours 2 m higher, 3 lower and 2 level
+        assert_eq!(ruggedness[2 * 5 + 2], 1.5);
+
+        // 3 neighbours 2 m higher and 2 level at the west edge
+        assert_eq!(ruggedness[2 * 5], 1.2);
+    }
+
+    #[test]
+    fn tri_skips_missing_neighbours() {
+        let mut elevations = elevations(3, |x, _| x);
+
+        for index in [0, 1, 3, 6, 7] {
+            elevations.set(index, f64::NAN);
+        }
+
+        // only the east neighbours, all 1 m higher
+        assert_eq!(tri(&square_grid(&elevations))[4], 1.0);
+
+        elevations.set(2, f64::NAN);
+        elevations.set(5, f64::NAN);
+        elevations.set(8, f64::NAN);
+
+        assert!(tri(&square_grid(&elevations))[4].is_nan());
+    }
+}
@@ -0,0 +1,232 @@
+use crate::{
+    error::Error,
+    journal::write_atomically,
+    options::{Format, Options},
+};
+use image::{ColorType, load_from_memory_with_format};
+use maptile::{constants::WEB_MERCATOR_EXTENT, tile::Tile};
+use std::{
+    fmt::Write,
+    fs::{create_dir_all, read, read_dir},
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Extensions of the tile and of its world file
+pub fn extensions(format: Format) -> (&'static str, &'static str) {
+    match format {
+        Format::PNG => ("png", "pgw"),
+        Format::JPEG => ("jpg", "jgw"),
+        Format::WEBP => ("webp", "wpw"),
+    }
+}
+
+/// Writes the tile as `<zoom>/<x>/<y>.<png|jpg|webp>` to the output directory,
+/// with the world file if `--world-files`
+pub fn write_tile(options: &Options, tile: Tile, data: &[u8]) -> Result<(), Error> {
+    let (extension, world_file_extension) = extensions(options.format);
+
+    let dir = options
+        .output
+        .join(tile.zoom.to_string())
+        .join(tile.x.to_string());
+
+    create_dir_all(&dir).map_err(|source| Error::Write {
+        path: dir.display().to_string(),
+        source,
+    })?;
+
+    let path = dir.join(format!("{}.{extension}", tile.y));
+
+    write_atomically(&path, data)?;
+
+    if !options.world_files {
+        return Ok(());
+    }
+
+    let bbox = tile.bounds(options.tile_size);
+
+    let pixel_size = bbox.width() / f64::from(options.tile_size);
+
+    // lines are the pixel size in x, rotations, negative pixel size in y
+    // and the coordinates of the center of the top left pixel
+    write_atomically(
+        &path.with_extension(world_file_extension),
+        format!(
+            "{pixel_size}\n0\n0\n{}\n{}\n{}\n",
+            -pixel_size,
+            bbox.min_x + pixel_size / 2.0,
+            bbox.max_y - pixel_size / 2.0,
+        ),
+    )
+}
+
+/// Tile of the output directory, `None` if not written
+pub fn read_tile(options: &Options, tile: Tile) -> Option<Vec<u8>> {
+    read(tile_path(options, tile.zoom, tile.x, tile.y)).ok()
+}
+
+fn tile_path(options: &Options, zoom: u8, x: u32, y: u32) -> PathBuf {
+    options
+        .output
+        .join(format!("{zoom}/{x}/{y}.{}", extensions(options.format).0))
+}
+
+/// Writes `tiles.vrt` mosaic of all the tiles of the zoom level `--zoom-level` in the output directory,
+/// including the ones of the previous runs
+pub fn write_vrt(options: &Options) -> Result<(), Error> {
+    let extension = extensions(options.format).0;
+
+    let zoom_dir = options.output.join(options.zoom_level.to_string());
+
+    let Ok(x_dirs) = read_dir(&zoom_dir) else {
+        return Ok(());
+    };
+
+    let read_error = |path: &Path| {
+        let path = path.display().to_string();
+
+        |source| Error::Io { path, source }
+    };
+
+    let mut tiles = Vec::new();
+
+    for x_dir in x_dirs {
+        let x_dir = x_dir.map_err(read_error(&zoom_dir))?;
+
+        let Some(x) = x_dir.file_name().to_str().and_then(|x| x.parse().ok()) else {
+            continue;
+        };
+
+        let x_path = x_dir.path();
+
+        for file in read_dir(&x_path).map_err(read_error(&x_path))? {
+            let file_name = file.map_err(read_error(&x_path))?.file_name();
+
+            // temporary files of an interrupted run are skipped
+            let Some(y) = file_name
+                .to_str()
+                .and_then(|file_name| file_name.strip_suffix(extension)?.strip_suffix('.'))
+                .and_then(|y| y.parse().ok())
+            else {
+                continue;
+            };
+
+            tiles.push((x, y));
+        }
+    }
+
+    let Some(&(x, y)) = tiles.first() else {
+        return Ok(());
+    };
+
+    let path = tile_path(options, options.zoom_level, x, y);
+
+    let data = read(&path).map_err(read_error(&path))?;
+
+    let color_type = load_from_memory_with_format(&data, options.format.into())
+        .map_err(|error| read_error(&path)(io::Error::new(io::ErrorKind::InvalidData, error)))?
+        .color();
+
+    tiles.sort_unstable();
+
+    write_atomically(
+        &options.output.join("tiles.vrt"),
+        vrt(&tiles, color_type, extension, options),
+    )
+}
+
+/// GDAL VRT of the tiles of the zoom level `--zoom-level` placed by their coordinates
+fn vrt(tiles: &[(u32, u32)], color_type: ColorType, extension: &str, options: &Options) -> String {
+    let tile_size = u32::from(options.tile_size);
+
+    let min_x = tiles.iter().map(|&(x, _)| x).min().unwrap();
+    let max_x = tiles.iter().map(|&(x, _)| x).max().unwrap();
+    let min_y = tiles.iter().map(|&(_, y)| y).min().unwrap();
+    let max_y = tiles.iter().map(|&(_, y)| y).max().unwrap();
+
+    let pixel_size =
+        2.0 * WEB_MERCATOR_EXTENT / f64::from(tile_size) / f64::from(1u32 << options.zoom_level);
+
+    // top left corner of the top left tile
+    let origin_x = -WEB_MERCATOR_EXTENT + f64::from(min_x) * f64::from(tile_size) * pixel_size;
+
+    let origin_y = WEB_MERCATOR_EXTENT - f64::from(min_y) * f64::from(tile_size) * pixel_size;
+
+    let (data_type, color_interps): (_, &[_]) = match color_type {
+        ColorType::L8 => ("Byte", &["Gray"]),
+        ColorType::La8 => ("Byte", &["Gray", "Alpha"]),
+        ColorType::Rgb8 => ("Byte", &["Red", "Green", "Blue"]),
+        ColorType::Rgba8 => ("Byte", &["Red", "Green", "Blue", "Alpha"]),
+        ColorType::L16 => ("UInt16", &["Gray"]),
+        ColorType::La16 => ("UInt16", &["Gray", "Alpha"]),
+        ColorType::Rgb16 => ("UInt16", &["Red", "Green", "Blue"]),
+        ColorType::Rgba16 => ("UInt16", &["Red", "Green", "Blue", "Alpha"]),
+        _ => panic!("Unsupported color type {color_type:?}"),
+    };
+
+    let mut vrt = String::new();
+
+    writeln!(
+        vrt,
+        r#"<VRTDataset rasterXSize="{}" rasterYSize="{}">"#,
+        (max_x - min_x + 1) * tile_size,
+        (max_y - min_y + 1) * tile_size,
+    )
+    .unwrap();
+
+    writeln!(vrt, "  <SRS>EPSG:3857</SRS>").unwrap();
+
+    writeln!(
+        vrt,
+        "  <GeoTransform>{origin_x}, {pixel_size}, 0, {origin_y}, 0, {}</GeoTransform>",
+        -pixel_size,
+    )
+    .unwrap();
+
+    for (band, color_interp) in color_interps.iter().enumerate() {
+        writeln!(
+            vrt,
+            r#"  <VRTRasterBand dataType="{data_type}" band="{}">"#,
+            band + 1
+        )
+        .unwrap();
+
+        writeln!(vrt, "    <ColorInterp>{color_interp}</ColorInterp>").unwrap();
+
+        for &(x, y) in tiles {
+            writeln!(vrt, "    <SimpleSource>").unwrap();
+
+            writeln!(
+                vrt,
+                r#"      <SourceFilename relativeToVRT="1">{}/{x}/{y}.{extension}</SourceFilename>"#,
+                options.zoom_level,
+            )
+            .unwrap();
+
+            writeln!(vrt, "      <SourceBand>{}</SourceBand>", band + 1).unwrap();
+
+            writeln!(
+                vrt,
+                r#"      <SrcRect xOff="0" yOff="0" xSize="{tile_size}" ySize="{tile_size}"/>"#
+            )
+            .unwrap();
+
+            writeln!(
+                vrt,
+                r#"      <DstRect xOff="{}" yOff="{}" xSize="{tile_size}" ySize="{tile_size}"/>"#,
+                (x - min_x) * tile_size,
+                (y - min_y) * tile_size,
+            )
+            .unwrap();
+
+            writeln!(vrt, "    </SimpleSource>").unwrap();
+        }
+
+        writeln!(vrt, "  </VRTRasterBand>").unwrap();
+    }
+
+    writeln!(vrt, "</VRTDataset>").unwrap();
+
+    vrt
+}
@@ -0,0 +1,166 @@
+use crate::error::Error;
+use log::info;
+use serde_json::{Map, json};
+use std::{
+    fmt::Write,
+    fs,
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Phases of the run timed by `TIMINGS`, each recorded per file or per supertile
+#[derive(Clone, Copy)]
+pub enum Phase {
+    /// Listing of the *.laz files of the LAZ index DB or the directory
+    List,
+    /// Reading of the supertile of the LAZ tile DB
+    Query,
+    /// Decompression of the *.laz file
+    Decompress,
+    /// Filtering and projection of the points of the *.laz file and their assignment to the supertiles
+    Project,
+    /// Triangulation of the `linear` and `natural-neighbor` interpolations
+    Triangulate,
+    /// Interpolation of the supertile including the triangulation, despiking and dedup
+    Interpolate,
+    Shade,
+    Encode,
+    /// Writing of the tiles to the output directory, MBTiles or PMTiles
+    Write,
+}
+
+const PHASES: [(Phase, &str); 9] = [
+    (Phase::List, "list"),
+    (Phase::Query, "query"),
+    (Phase::Decompress, "decompress"),
+    (Phase::Project, "project"),
+    (Phase::Triangulate, "triangulate"),
+    (Phase::Interpolate, "interpolate"),
+    (Phase::Shade, "shade"),
+    (Phase::Encode, "encode"),
+    (Phase::Write, "write"),
+];
+
+/// Nanoseconds of a phase summed over the threads
+struct PhaseTimings {
+    total: AtomicU64,
+    max: AtomicU64,
+    count: AtomicU64,
+}
+
+/// Durations of the phases, bytes and points read during the run, cheap to record from any thread
+pub struct Timings {
+    phases: [PhaseTimings; PHASES.len()],
+    bytes_read: AtomicU64,
+    points_read: AtomicU64,
+}
+
+pub static TIMINGS: Timings = Timings {
+    phases: [const {
+        PhaseTimings {
+            total: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }; PHASES.len()],
+    bytes_read: AtomicU64::new(0),
+    points_read: AtomicU64::new(0),
+};
+
+impl Timings {
+    pub fn record(&self, phase: Phase, duration: Duration) {
+        let phase = &self.phases[phase as usize];
+
+        let nanos = duration.as_nanos() as u64;
+
+        phase.total.fetch_add(nanos, Ordering::Relaxed);
+
+        phase.max.fetch_max(nanos, Ordering::Relaxed);
+
+        phase.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Bytes of the read files or blobs of the LAZ tile DB and their decoded points
+    pub fn read(&self, bytes: u64, points: u64) {
+        self.bytes_read.fetch_add(bytes, Ordering::Relaxed);
+
+        self.points_read.fetch_add(points, Ordering::Relaxed);
+    }
+
+    /// Total, mean and max duration and the count of the recorded phases
+    fn phases(
+        &self,
+    ) -> impl Iterator<Item = (&'static str, Duration, Duration, Duration, u64)> + '_ {
+        PHASES.iter().filter_map(|&(phase, name)| {
+            let phase = &self.phases[phase as usize];
+
+            let count = phase.count.load(Ordering::Relaxed);
+
+            let total = phase.total.load(Ordering::Relaxed);
+
+            (count > 0).then(|| {
+                (
+                    name,
+                    Duration::from_nanos(total),
+                    Duration::from_nanos(total / count),
+                    Duration::from_nanos(phase.max.load(Ordering::Relaxed)),
+                    count,
+                )
+            })
+        })
+    }
+
+    pub fn log_summary(&self) {
+        let mut table = format!(
+            "{:<12}{:>12}{:>12}{:>12}{:>10}",
+            "phase", "total", "mean", "max", "count"
+        );
+
+        for (name, total, mean, max, count) in self.phases() {
+            write!(
+                table,
+                "\n{name:<12}{:>12}{:>12}{:>12}{count:>10}",
+                format!("{total:.3?}"),
+                format!("{mean:.3?}"),
+                format!("{max:.3?}"),
+            )
+            .unwrap();
+        }
+
+        info!(
+            "TIMINGS summed over the threads, {} bytes and {} points read\n{table}",
+            self.bytes_read.load(Ordering::Relaxed),
+            self.points_read.load(Ordering::Relaxed)
+        );
+    }
+
+    /// JSON of the phases in seconds, the bytes and the points read for `--timings-out`
+    pub fn write(&self, path: &Path) -> Result<(), Error> {
+        let phases: Map<_, _> = self
+            .phases()
+            .map(|(name, total, mean, max, count)| {
+                (
+                    name.to_owned(),
+                    json!({
+                        "total_seconds": total.as_secs_f64(),
+                        "mean_seconds": mean.as_secs_f64(),
+                        "max_seconds": max.as_secs_f64(),
+                        "count": count,
+                    }),
+                )
+            })
+            .collect();
+
+        let json = json!({
+            "phases": phases,
+            "bytes_read": self.bytes_read.load(Ordering::Relaxed),
+            "points_read": self.points_read.load(Ordering::Relaxed),
+        });
+
+        fs::write(path, format!("{json}\n")).map_err(|source| Error::Write {
+            path: path.display().to_string(),
+            source,
+        })
+    }
+}
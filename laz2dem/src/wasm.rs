@@ -0,0 +1,85 @@
+use crate::{
+    options::Options,
+    shading::hillshade,
+    terrain::{Elevations, Grid, Rasters},
+};
+use wasm_bindgen::prelude::*;
+
+/// Shades the grid of `width` x `height` elevations in meters, rows from the north and NaN for no data, of pixels
+/// of `pixel_size` ground meters to the RGBA bytes of the image, as `--mode hillshade` shades a supertile.
+/// The config is the JSON of `Options::from_json`.
+#[wasm_bindgen(js_name = shadeGrid)]
+pub fn shade_grid(
+    elevations: &[f32],
+    width: usize,
+    height: usize,
+    pixel_size: f64,
+    config: &str,
+) -> Result<Vec<u8>, JsError> {
+    if width == 0 || height == 0 || elevations.len() != width * height {
+        return Err(JsError::new(
+            "elevations must be a non-empty grid of width * height",
+        ));
+    }
+
+    let options = Options::from_json(config).map_err(|error| JsError::new(&error.to_string()))?;
+
+    // row 0 of the grid is the southmost one
+    let elevations = Elevations::F32(elevations.chunks(width).rev().flatten().copied().collect());
+
+    let grid = Grid {
+        elevations: &elevations,
+        rows: height,
+        cols: width,
+        cell_size: pixel_size,
+        mercator_y: None,
+    };
+
+    let rasters = Rasters::new(&grid, options.z_factor, &options.shadings.0);
+
+    Ok(hillshade(&options, &grid, &rasters).into_raw())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(target_arch = "wasm32")]
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    const SIZE: usize = 64;
+
+    const CONFIG: &str = r#"{"shadings": "igor,000000FF,315+igor-slope,00000080"}"#;
+
+    /// Elevations of the rows from the north of the ridges rising to the south
+    fn elevations() -> Vec<f32> {
+        (0..SIZE * SIZE)
+            .map(|index| {
+                let (x, y) = ((index % SIZE) as f32, (index / SIZE) as f32);
+
+                3.0 * (x * 0.2).sin() + 0.2 * y
+            })
+            .collect()
+    }
+
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen_test)]
+    #[cfg_attr(not(target_arch = "wasm32"), test)]
+    fn grid_is_shaded_as_by_the_native_code() {
+        let rgba = shade_grid(&elevations(), SIZE, SIZE, 1.0, CONFIG).unwrap();
+
+        assert_eq!(rgba.len(), SIZE * SIZE * 4);
+
+        // values of the native build, the shading math does not depend on the target
+        for (x, y, pixel) in [
+            (0, 0, [0, 0, 0, 173]),
+            (10, 10, [0, 0, 0, 67]),
+            (20, 5, [0, 0, 0, 80]),
+            (25, 50, [0, 0, 0, 95]),
+            (32, 40, [0, 0, 0, 172]),
+            (63, 63, [0, 0, 0, 173]),
+        ] {
+            let index = (y * SIZE + x) * 4;
+
+            assert_eq!(rgba[index..index + 4], pixel, "{x} {y}");
+        }
+    }
+}
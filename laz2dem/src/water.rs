@@ -0,0 +1,310 @@
+use crate::{
+    shared_types::{PointWithHeight, TileMeta},
+    terrain::Elevations,
+};
+use log::debug;
+use maptile::bbox::BBox;
+use spade::Point2;
+use std::collections::{HashMap, HashSet};
+
+/// Minimal number of water points of a water body (and of a tile) to flatten it
+pub const MIN_WATER_POINTS: usize = 16;
+
+/// Flattens every 4-connected component of the `water` pixels to the median height of the water points within it.
+/// Components with fewer than [`MIN_WATER_POINTS`] water points are left interpolated.
+pub fn flatten_water(
+    elevations: &mut Elevations,
+    water: &[bool],
+    rows: usize,
+    cols: usize,
+    bbox: &BBox,
+    water_points: &[PointWithHeight],
+) {
+    // 0 for not yet labeled pixels
+    let mut labels = vec![0usize; rows * cols];
+
+    let mut components = 0;
+
+    let mut stack = Vec::new();
+
+    for start in 0..rows * cols {
+        if !water[start] || labels[start] != 0 {
+            continue;
+        }
+
+        components += 1;
+
+        labels[start] = components;
+
+        stack.push(start);
+
+        while let Some(index) = stack.pop() {
+            let (x, y) = (index % cols, index / cols);
+
+            let neighbours = [
+                (x > 0).then(|| index - 1),
+                (x + 1 < cols).then(|| index + 1),
+                (y > 0).then(|| index - cols),
+                (y + 1 < rows).then(|| index + cols),
+            ];
+
+            for neighbour in neighbours.into_iter().flatten() {
+                if water[neighbour] && labels[neighbour] == 0 {
+                    labels[neighbour] = components;
+
+                    stack.push(neighbour);
+                }
+            }
+        }
+    }
+
+    let mut heights = vec![Vec::new(); components];
+
+    for point in water_points {
+        let x = ((point.position.x - bbox.min_x) / bbox.width() * cols as f64).floor();
+
+        let y = ((point.position.y - bbox.min_y) / bbox.height() * rows as f64).floor();
+
+        if x < 0.0 || y < 0.0 || x >= cols as f64 || y >= rows as f64 {
+            continue;
+        }
+
+        let label = labels[y as usize * cols + x as usize];
+
+        if label != 0 {
+            heights[label - 1].push(point.height);
+        }
+    }
+
+    let medians: Vec<_> = heights.into_iter().map(median).collect();
+
+    for (index, label) in labels.into_iter().enumerate() {
+        if label == 0 {
+            continue;
+        }
+
+        if let Some(median) = medians[label - 1] {
+            elevations.set(index, median);
+        }
+    }
+}
+
+/// Levels the water points of the tiles to the median height of their water body, so that a water body crossing
+/// supertiles is flattened to the same level in all of them. Water points in the same or adjacent cells
+/// of `link_distance` are of the same water body; points shared by the buffers of tiles are counted once.
+pub fn level_water_bodies(tile_metas: &[TileMeta], link_distance: f64) {
+    let cell = |position: Point2<f64>| {
+        (
+            (position.x / link_distance).floor() as i64,
+            (position.y / link_distance).floor() as i64,
+        )
+    };
+
+    let mut points = HashSet::new();
+
+    for tile_meta in tile_metas {
+        for point in tile_meta.water_points.lock().unwrap().iter() {
+            points.insert((
+                point.position.x.to_bits(),
+                point.position.y.to_bits(),
+                point.height.to_bits(),
+            ));
+        }
+    }
+
+    let mut cells = HashMap::<_, Vec<f64>>::new();
+
+    for &(x, y, height) in &points {
+        cells
+            .entry(cell(Point2::new(f64::from_bits(x), f64::from_bits(y))))
+            .or_default()
+            .push(f64::from_bits(height));
+    }
+
+    // 8-connected components of the cells
+    let mut bodies = HashMap::new();
+
+    let mut levels = Vec::new();
+
+    let mut stack = Vec::new();
+
+    for &start in cells.keys() {
+        if bodies.contains_key(&start) {
+            continue;
+        }
+
+        bodies.insert(start, levels.len());
+
+        stack.push(start);
+
+        let mut heights = Vec::new();
+
+        while let Some((x, y)) = stack.pop() {
+            heights.extend_from_slice(&cells[&(x, y)]);
+
+            for neighbour in (x - 1..=x + 1).flat_map(|x| (y - 1..=y + 1).map(move |y| (x, y))) {
+                if cells.contains_key(&neighbour) && !bodies.contains_key(&neighbour) {
+                    bodies.insert(neighbour, levels.len());
+
+                    stack.push(neighbour);
+                }
+            }
+        }
+
+        levels.push(median(heights));
+    }
+
+    debug!(
+        "LEVEL {} water bodies of {} water points",
+        levels.len(),
+        points.len()
+    );
+
+    for tile_meta in tile_metas {
+        for point in tile_meta.water_points.lock().unwrap().iter_mut() {
+            if let Some(level) = levels[bodies[&cell(point.position)]] {
+                point.height = level;
+            }
+        }
+    }
+}
+
+/// Median of the heights, `None` for fewer than [`MIN_WATER_POINTS`]
+fn median(mut heights: Vec<f64>) -> Option<f64> {
+    if heights.len() < MIN_WATER_POINTS {
+        return None;
+    }
+
+    heights.sort_unstable_by(f64::total_cmp);
+
+    Some(heights[heights.len() / 2])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maptile::tile::Tile;
+    use std::slice;
+
+    #[test]
+    fn lake_crossing_supertiles_is_flattened_to_the_same_level() {
+        // supertiles of 0 - 100 m and 100 - 200 m buffered by 20 m
+        let tile_metas: Vec<_> = [0.0, 100.0]
+            .into_iter()
+            .enumerate()
+            .map(|(x, min_x)| {
+                TileMeta::new(
+                    Tile {
+                        zoom: 0,
+                        x: x as u32,
+                        y: 0,
+                    },
+                    BBox {
+                        min_x: min_x - 20.0,
+                        min_y: 0.0,
+                        max_x: min_x + 120.0,
+                        max_y: 100.0,
+                    },
+                )
+            })
+            .collect();
+
+        // lake of 20 - 180 m rising to the east, with noise of its returns
+        for x in (20..180).step_by(2) {
+            for y in (20..80).step_by(2) {
+                let point = PointWithHeight {
+                    position: Point2::new(f64::from(x), f64::from(y)),
+                    height: 300.0 + f64::from(x) / 100.0 + f64::from(x * y % 7) / 10.0,
+                    water: true,
+                };
+
+                for tile_meta in &tile_metas {
+                    if tile_meta.bbox.contains(point.position.x, point.position.y) {
+                        tile_meta.water_points.lock().unwrap().push(point);
+                    }
+                }
+            }
+        }
+
+        level_water_bodies(&tile_metas, 10.0);
+
+        // elevation of the flattened pixel of 1 m at the boundary of the supertiles in each of them
+        let levels: Vec<_> = tile_metas
+            .iter()
+            .map(|tile_meta| {
+                let (rows, cols) = (100, 140);
+
+                let mut elevations = Elevations::F64(vec![0.0; rows * cols]);
+
+                let water: Vec<_> = (0..rows * cols)
+                    .map(|index| {
+                        let x = tile_meta.bbox.min_x + (index % cols) as f64;
+                        let y = (index / cols) as f64;
+
+                        (20.0..180.0).contains(&x) && (20.0..80.0).contains(&y)
+                    })
+                    .collect();
+
+                flatten_water(
+                    &mut elevations,
+                    &water,
+                    rows,
+                    cols,
+                    &tile_meta.bbox,
+                    &tile_meta.water_points.lock().unwrap(),
+                );
+
+                let x = (100.0 - tile_meta.bbox.min_x) as usize;
+
+                elevations.get(50 * cols + x)
+            })
+            .collect();
+
+        assert!(levels[0] > 300.0);
+        assert_eq!(levels[0], levels[1]);
+    }
+
+    #[test]
+    fn separate_water_bodies_keep_their_levels() {
+        let tile_meta = TileMeta::new(
+            Tile {
+                zoom: 0,
+                x: 0,
+                y: 0,
+            },
+            BBox {
+                min_x: 0.0,
+                min_y: 0.0,
+                max_x: 100.0,
+                max_y: 100.0,
+            },
+        );
+
+        for (min_x, height) in [(0, 200.0), (60, 250.0)] {
+            for x in min_x..min_x + 20 {
+                tile_meta
+                    .water_points
+                    .lock()
+                    .unwrap()
+                    .push(PointWithHeight {
+                        position: Point2::new(f64::from(x), 50.0),
+                        height: height + f64::from(x % 3),
+                        water: true,
+                    });
+            }
+        }
+
+        level_water_bodies(slice::from_ref(&tile_meta), 5.0);
+
+        let heights: Vec<_> = tile_meta
+            .water_points
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|point| point.height)
+            .collect();
+
+        assert!(heights[..20].iter().all(|&height| height == 201.0));
+        assert!(heights[20..].iter().all(|&height| height == 251.0));
+    }
+}
@@ -0,0 +1,61 @@
+// Smoke test of the C functions, see the C section of the README
+#include <math.h>
+#include <stdio.h>
+#include <string.h>
+
+#include "laz2dem.h"
+
+#define ROWS 16
+#define COLS 16
+
+#define CHECK(condition)                                                     \
+  if (!(condition)) {                                                        \
+    fprintf(stderr, "%s:%d: %s failed\n", __FILE__, __LINE__, #condition); \
+    return 1;                                                                \
+  }
+
+int main(void) {
+  Laz2demConfig *config = laz2dem_config_new("{\"shadings\": \"igor,000000FF,315\"}");
+
+  CHECK(config != NULL);
+
+  // slope rising to the east
+  double elevations[ROWS * COLS];
+
+  for (int i = 0; i < ROWS * COLS; i++) {
+    elevations[i] = 0.5 * (i % COLS);
+  }
+
+  uint8_t output[ROWS * COLS * 4];
+
+  memset(output, 0, sizeof(output));
+
+  CHECK(laz2dem_shade(config, elevations, ROWS, COLS, 1.0, 1.0, output, sizeof(output)) == LAZ2DEM_OK);
+
+  // the slope facing the west is shaded a little by the light from the north-west
+  CHECK(output[(ROWS / 2 * COLS + COLS / 2) * 4 + 3] > 0);
+
+  elevations[0] = NAN;
+
+  CHECK(laz2dem_shade(config, elevations, ROWS, COLS, 1.0, 1.0, output, sizeof(output)) == LAZ2DEM_OK);
+
+  CHECK(laz2dem_shade(config, elevations, ROWS, COLS, 1.0, 1.0, output, sizeof(output) - 1) ==
+        LAZ2DEM_INVALID_ARGUMENT);
+
+  CHECK(laz2dem_last_error() != NULL);
+
+  CHECK(laz2dem_shade(NULL, elevations, ROWS, COLS, 1.0, 1.0, output, sizeof(output)) ==
+        LAZ2DEM_INVALID_ARGUMENT);
+
+  laz2dem_config_free(config);
+
+  CHECK(laz2dem_config_new("{\"shadings\": \"sunny\"}") == NULL);
+
+  CHECK(strstr(laz2dem_last_error(), "sunny") != NULL);
+
+  laz2dem_config_free(NULL);
+
+  puts("ok");
+
+  return 0;
+}
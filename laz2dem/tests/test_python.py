@@ -0,0 +1,92 @@
+"""Tests of the Python module, run by `pytest` after `maturin develop --release`"""
+
+import numpy as np
+import pytest
+
+from laz2dem import Shading, compute_hillshade
+
+SIZE = 64
+
+
+def grid():
+    """Column and row indices of the square grid, rows from the north"""
+    y, x = np.mgrid[0:SIZE, 0:SIZE]
+
+    return x.astype(np.float64), y.astype(np.float64)
+
+
+def darkness(rgb):
+    """Mean darkness of the image without its border"""
+    return 255 - rgb[2:-2, 2:-2].mean()
+
+
+def test_synthetic_dem_is_shaded_to_rgb():
+    x, y = grid()
+
+    rgb = compute_hillshade(50 * np.sin(x / 20) * np.cos(y / 30), z_factor=2)
+
+    assert rgb.shape == (SIZE, SIZE, 3)
+    assert rgb.dtype == np.uint8
+    assert darkness(rgb) > 0
+
+
+def test_flat_dem_is_not_shaded_by_igor():
+    rgb = compute_hillshade(np.zeros((SIZE, SIZE)), shadings=[Shading.igor(315)])
+
+    assert (rgb == 255).all()
+
+
+def test_rows_are_from_the_north():
+    x, y = grid()
+
+    shadings = [Shading.igor(315)]
+
+    # slopes facing the north and the west are symmetric by the light from the north-west
+    north = darkness(compute_hillshade(0.2 * y, shadings=shadings))
+
+    assert north == pytest.approx(darkness(compute_hillshade(0.2 * x, shadings=shadings)), abs=0.5)
+
+    assert north != pytest.approx(darkness(compute_hillshade(-0.2 * x, shadings=shadings)), abs=0.5)
+
+
+def test_strided_elevations_are_shaded_as_contiguous():
+    x, y = grid()
+
+    dem = 10 * np.sin(x / 7) + y
+
+    strided = np.repeat(dem, 2, axis=1)[:, ::2]
+
+    assert not strided.flags.c_contiguous
+
+    shadings = [Shading.oblique(315, 45), Shading("igor-slope,00000080")]
+
+    np.testing.assert_array_equal(
+        compute_hillshade(strided, shadings=shadings),
+        compute_hillshade(np.ascontiguousarray(strided), shadings=shadings),
+    )
+
+
+def test_contrast_and_brightness_apply():
+    x, _ = grid()
+
+    dem = 10 * np.sin(x / 7)
+
+    plain = compute_hillshade(dem)
+
+    assert darkness(compute_hillshade(dem, brightness=0.2)) < darkness(plain)
+
+
+def test_shading_is_validated_as_the_flag():
+    for shading in ["sunny", "igor,000000FF,400", "tri,000000FF,1,1"]:
+        with pytest.raises(ValueError):
+            Shading(shading)
+
+    with pytest.raises(ValueError):
+        Shading.oblique_slope(60, weight=-1)
+
+    assert "igor" in repr(Shading.igor(315))
+
+
+def test_empty_elevations_are_rejected():
+    with pytest.raises(ValueError):
+        compute_hillshade(np.zeros((0, SIZE)))
@@ -1,65 +1,220 @@
 use clap::Parser;
 use las::Reader;
+use laz2dem::detect_crs;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use rusqlite::Connection;
-use std::path::PathBuf;
+use std::{collections::HashMap, path::PathBuf, time::UNIX_EPOCH};
 use walkdir::WalkDir;
 
 #[derive(Parser, Debug, PartialEq)]
 struct Options {
-    /// Directory with *.laz files
+    /// Directory with *.laz (or *.las) files
     directory: PathBuf, // "/home/martin/18TB"
 
-    /// Output database file
+    /// Output database file; if it exists then only new and changed files are indexed and missing ones are removed
     database: PathBuf, // "/home/martin/14TB/sk-new-dmr/laztiles.sqlite"
 }
 
+/// Columns added after the first version of the index
+const ADDED_COLUMNS: [(&str, &str); 6] = [
+    ("crs", "VARCHAR"),
+    ("min_z", "NUMBER"),
+    ("max_z", "NUMBER"),
+    ("point_count", "INTEGER"),
+    ("size", "INTEGER"),
+    ("mtime", "INTEGER"),
+];
+
 fn main() {
     let options = Options::parse();
 
-    let conn = Connection::open(options.database).unwrap();
+    let mut conn = Connection::open(options.database).unwrap();
 
     conn.execute(
-      "CREATE TABLE laz_index (min_x NUMBER, max_x NUMBER, min_y NUMBER, max_y NUMBER, file VARCHAR)", ()
+      "CREATE TABLE IF NOT EXISTS laz_index (min_x NUMBER, max_x NUMBER, min_y NUMBER, max_y NUMBER, file VARCHAR)", ()
   )
   .unwrap();
 
-    let mut stmt = conn
-        .prepare("INSERT INTO laz_index VALUES (?1, ?2, ?3, ?4, ?5)")
-        .unwrap();
+    let columns: Vec<String> = conn
+        .prepare("SELECT name FROM pragma_table_info('laz_index')")
+        .unwrap()
+        .query_map((), |row| row.get(0))
+        .unwrap()
+        .map(|row| row.unwrap())
+        .collect();
+
+    for (name, column_type) in ADDED_COLUMNS {
+        if !columns.iter().any(|column| column == name) {
+            conn.execute(
+                &format!("ALTER TABLE laz_index ADD COLUMN {name} {column_type}"),
+                (),
+            )
+            .unwrap();
+        }
+    }
+
+    // size and mtime of the indexed files; NULL for files indexed by the first version
+    let indexed: HashMap<String, (Option<u64>, Option<u64>)> = conn
+        .prepare("SELECT file, size, mtime FROM laz_index")
+        .unwrap()
+        .query_map((), |row| Ok((row.get(0)?, (row.get(1)?, row.get(2)?))))
+        .unwrap()
+        .map(|row| row.unwrap())
+        .collect();
+
+    let mut found = HashMap::new();
 
     for dir in WalkDir::new(options.directory) {
         let dir = dir.unwrap();
 
-        println!("{}", dir.file_name().to_string_lossy());
-
-        if dir
-            .path()
-            .extension()
-            .map(|ext| ext == "laz")
-            .unwrap_or(false)
+        if dir.file_type().is_file()
+            && dir.path().extension().is_some_and(|ext| {
+                ext.eq_ignore_ascii_case("laz") || ext.eq_ignore_ascii_case("las")
+            })
         {
-            let reader = Reader::from_path(dir.path()).unwrap();
+            let metadata = dir.metadata().unwrap();
+
+            let mtime = metadata
+                .modified()
+                .unwrap()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+
+            found.insert(
+                dir.path().to_string_lossy().to_string(),
+                (metadata.len(), mtime),
+            );
+        }
+    }
+
+    let removed: Vec<_> = indexed
+        .keys()
+        .filter(|file| !found.contains_key(*file))
+        .collect();
+
+    let changed: Vec<_> = found
+        .into_iter()
+        .filter(|(file, (size, mtime))| indexed.get(file) != Some(&(Some(*size), Some(*mtime))))
+        .collect();
+
+    println!(
+        "Indexing {} new or changed files, removing {} missing ones",
+        changed.len(),
+        removed.len()
+    );
+
+    let rows: Vec<_> = changed
+        .into_par_iter()
+        .map(|(file, (size, mtime))| {
+            println!("{file}");
+
+            let reader = Reader::from_path(&file).unwrap();
+
+            let header = reader.header();
+
+            let bounds = header.bounds();
+
+            (
+                bounds,
+                file,
+                detect_crs(header),
+                header.number_of_points(),
+                size,
+                mtime,
+            )
+        })
+        .collect();
+
+    let has_rtree = conn
+        .query_row(
+            "SELECT 1 FROM sqlite_master WHERE name = 'laz_index_rtree'",
+            (),
+            |_| Ok(()),
+        )
+        .is_ok();
+
+    let tx = conn.transaction().unwrap();
 
-            let bounds = reader.header().bounds();
+    if !has_rtree {
+        // id is rowid of laz_index
+        tx.execute(
+            "CREATE VIRTUAL TABLE laz_index_rtree USING rtree(id, min_x, max_x, min_y, max_y)",
+            (),
+        )
+        .unwrap();
+
+        tx.execute(
+            "INSERT INTO laz_index_rtree SELECT rowid, min_x, max_x, min_y, max_y FROM laz_index",
+            (),
+        )
+        .unwrap();
+    }
+
+    {
+        let mut delete_rtree_stmt = tx
+            .prepare(
+                "DELETE FROM laz_index_rtree WHERE id IN (SELECT rowid FROM laz_index WHERE file = ?1)",
+            )
+            .unwrap();
+
+        let mut delete_stmt = tx.prepare("DELETE FROM laz_index WHERE file = ?1").unwrap();
+
+        let mut insert_rtree_stmt = tx
+            .prepare("INSERT INTO laz_index_rtree VALUES (?1, ?2, ?3, ?4, ?5)")
+            .unwrap();
+
+        for file in removed {
+            delete_rtree_stmt.execute((file,)).unwrap();
+
+            delete_stmt.execute((file,)).unwrap();
+        }
+
+        let mut insert_stmt = tx
+            .prepare("INSERT INTO laz_index (min_x, max_x, min_y, max_y, file, crs, min_z, max_z, point_count, size, mtime) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)")
+            .unwrap();
 
-            let _ = stmt
+        for (bounds, file, crs, point_count, size, mtime) in rows {
+            delete_rtree_stmt.execute((&file,)).unwrap();
+
+            delete_stmt.execute((&file,)).unwrap();
+
+            insert_stmt
                 .execute((
                     bounds.min.x,
                     bounds.max.x,
                     bounds.min.y,
                     bounds.max.y,
-                    dir.path().to_string_lossy(),
+                    file,
+                    crs,
+                    bounds.min.z,
+                    bounds.max.z,
+                    point_count,
+                    size,
+                    mtime,
+                ))
+                .unwrap();
+
+            insert_rtree_stmt
+                .execute((
+                    tx.last_insert_rowid(),
+                    bounds.min.x,
+                    bounds.max.x,
+                    bounds.min.y,
+                    bounds.max.y,
                 ))
                 .unwrap();
         }
     }
 
+    tx.commit().unwrap();
+
     for query in [
-        "CREATE UNIQUE INDEX laz_file_unique ON laz_index (file)",
-        "CREATE INDEX laz_min_x_index ON laz_index (min_x)",
-        "CREATE INDEX laz_max_x_index ON laz_index (max_x)",
-        "CREATE INDEX laz_min_y_index ON laz_index (min_y)",
-        "CREATE INDEX laz_max_y_index ON laz_index (max_y)",
+        "CREATE UNIQUE INDEX IF NOT EXISTS laz_file_unique ON laz_index (file)",
+        "CREATE INDEX IF NOT EXISTS laz_min_x_index ON laz_index (min_x)",
+        "CREATE INDEX IF NOT EXISTS laz_max_x_index ON laz_index (max_x)",
+        "CREATE INDEX IF NOT EXISTS laz_min_y_index ON laz_index (min_y)",
+        "CREATE INDEX IF NOT EXISTS laz_max_y_index ON laz_index (max_y)",
     ] {
         conn.execute(query, ()).unwrap();
     }